@@ -0,0 +1,10 @@
+fn main() {
+    // The gRPC service is generated from proto/ingest.proto; protoc is vendored
+    // so building with `--features grpc` needs no system packages
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("proto/ingest.proto").expect("compile proto/ingest.proto");
+    }
+}
@@ -0,0 +1,148 @@
+//! Access control for the listeners other machines can reach, `--http` and
+//! `--listen-ws`: a shared token clients must present (`--auth-token`) and,
+//! in builds with the tls feature, TLS (`--tls-cert`, `--tls-key`).
+//!
+//! Clients send the token as `Authorization: Bearer <token>`. Browsers can't
+//! set headers on a WebSocket, so `--listen-ws` also takes it as a `token`
+//! query parameter.
+
+#[cfg(feature = "tls")]
+use anyhow::Context;
+use anyhow::{anyhow, Result};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::warn;
+
+/// What a listener asks of its clients.
+#[derive(Clone, Default)]
+pub struct Access {
+    token: Option<Arc<str>>,
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<Tls>>,
+}
+
+/// A PEM certificate chain and the private key it was issued for.
+#[cfg(feature = "tls")]
+pub struct Tls {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+impl Access {
+    /// Require `token`, if given, and serve TLS with the `(cert, key)` files.
+    pub fn new(token: Option<&str>, tls: Option<(&Path, &Path)>) -> Result<Self> {
+        #[cfg(feature = "tls")]
+        let tls = match tls {
+            Some((cert, key)) => Some(Arc::new(Tls {
+                cert: std::fs::read(cert)
+                    .with_context(|| format!("Failed to read --tls-cert {:?}", cert))?,
+                key: std::fs::read(key)
+                    .with_context(|| format!("Failed to read --tls-key {:?}", key))?,
+            })),
+            None => None,
+        };
+        #[cfg(not(feature = "tls"))]
+        if tls.is_some() {
+            return Err(anyhow!(
+                "--tls-cert needs virtual-mic built with the tls feature"
+            ));
+        }
+        Ok(Self {
+            token: token.map(Arc::from),
+            #[cfg(feature = "tls")]
+            tls,
+        })
+    }
+
+    /// Whether a client presenting `given` (or nothing) may in.
+    pub fn allows(&self, given: Option<&str>) -> bool {
+        match (&self.token, given) {
+            (None, _) => true,
+            (Some(token), Some(given)) => same(token.as_bytes(), given.as_bytes()),
+            (Some(_), None) => false,
+        }
+    }
+
+    /// Whether clients must present a token.
+    pub fn needs_token(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Whether connections are encrypted.
+    pub fn secure(&self) -> bool {
+        #[cfg(feature = "tls")]
+        return self.tls.is_some();
+        #[cfg(not(feature = "tls"))]
+        false
+    }
+
+    /// Warn if `what`, listening on `addr`, lets other machines in unchecked.
+    pub fn warn_if_open(&self, what: &str, addr: SocketAddr) {
+        if self.token.is_none() && !addr.ip().is_loopback() {
+            warn!(
+                "{} on {} takes commands from anyone who can reach it; set --auth-token",
+                what, addr
+            );
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Tls {
+    /// The configuration to serve this certificate with rustls.
+    pub fn server_config(&self) -> Result<Arc<rustls::ServerConfig>> {
+        let certs = rustls_pemfile::certs(&mut self.cert.as_slice())
+            .collect::<Result<Vec<_>, _>>()
+            .context("Invalid --tls-cert")?;
+        let key = rustls_pemfile::private_key(&mut self.key.as_slice())
+            .context("Invalid --tls-key")?
+            .ok_or_else(|| anyhow!("--tls-key holds no private key"))?;
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("Unusable --tls-cert and --tls-key")?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// The token of an `Authorization` header.
+pub fn bearer(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then_some(token.trim())
+}
+
+/// Compare every byte, so the time taken doesn't tell how much of a guess
+/// was right.
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_bearer_tokens() {
+        assert_eq!(bearer("Bearer s3cret"), Some("s3cret"));
+        assert_eq!(bearer("  bearer   s3cret "), Some("s3cret"));
+        assert_eq!(bearer("Basic dXNlcjpwYXNz"), None);
+        assert_eq!(bearer("Bearer"), None);
+        assert_eq!(bearer(""), None);
+    }
+
+    #[test]
+    fn allows_only_the_token() {
+        let open = Access::new(None, None).unwrap();
+        assert!(open.allows(None));
+        assert!(open.allows(Some("anything")));
+
+        let locked = Access::new(Some("s3cret"), None).unwrap();
+        assert!(locked.allows(Some("s3cret")));
+        assert!(!locked.allows(Some("s3cre")));
+        assert!(!locked.allows(Some("s3cret!")));
+        assert!(!locked.allows(None));
+    }
+}
@@ -0,0 +1,151 @@
+//! Capture streams that read from existing PipeWire nodes: a real microphone
+//! for `--mix-source`, or a sink's monitor for `--capture-sink-monitor`.
+//!
+//! PipeWire converts whatever the node produces to the format we ask for, so
+//! captured audio arrives as interleaved f32 at the output rate and channel
+//! count, ready to be summed with the decoded audio.
+
+use anyhow::{anyhow, Result};
+use pipewire as pw;
+use pw::spa::pod::Pod;
+use pw::stream::{Stream, StreamFlags, StreamListener};
+use std::collections::VecDeque;
+use tracing::{debug, info};
+
+/// A connected capture stream; dropping it disconnects.
+pub struct Capture {
+    _listener: StreamListener<()>,
+    _stream: Stream,
+}
+
+impl Capture {
+    /// Capture from the node named `target`, handing each buffer of samples to
+    /// `on_samples` on the main loop thread. With `sink_monitor`, `target` is a
+    /// sink and its monitor (whatever is playing to it) is captured.
+    ///
+    /// `format_pod` must describe interleaved f32 audio.
+    pub fn connect<F>(
+        core: &pw::core::Core,
+        node_name: &str,
+        target: &str,
+        sink_monitor: bool,
+        format_pod: &[u8],
+        mut on_samples: F,
+    ) -> Result<Self>
+    where
+        F: FnMut(&[f32]) + 'static,
+    {
+        let mut props = pw::properties::properties! {
+            *pw::keys::MEDIA_TYPE => "Audio",
+            *pw::keys::MEDIA_CATEGORY => "Capture",
+            *pw::keys::MEDIA_ROLE => "Communication",
+            *pw::keys::NODE_NAME => node_name,
+            "node.target" => target,
+            // Don't fall back to the default source when the target goes away
+            "node.dont-reconnect" => "true",
+        };
+        if sink_monitor {
+            props.insert("stream.capture.sink", "true");
+        }
+        let stream = Stream::new(core, node_name, props)?;
+
+        let mut samples: Vec<f32> = Vec::new();
+        let listener = stream
+            .add_local_listener_with_user_data(())
+            .state_changed(|_, _, old, new| debug!("Capture state: {:?} -> {:?}", old, new))
+            .process(move |stream, _| {
+                let Some(mut buffer) = stream.dequeue_buffer() else {
+                    return;
+                };
+                let Some(data) = buffer.datas_mut().first_mut() else {
+                    return;
+                };
+                let (offset, size) = (data.chunk().offset() as usize, data.chunk().size() as usize);
+                let Some(bytes) = data.data() else {
+                    return;
+                };
+                let Some(bytes) = bytes.get(offset..offset + size) else {
+                    return;
+                };
+                samples.clear();
+                samples.extend(
+                    bytes
+                        .chunks_exact(4)
+                        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+                );
+                on_samples(&samples);
+            })
+            .register()?;
+
+        let mut params = [Pod::from_bytes(format_pod).ok_or_else(|| anyhow!("Invalid pod"))?];
+        // Like the playback stream, processed on the main loop thread
+        stream.connect(
+            pw::spa::utils::Direction::Input,
+            None,
+            StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS,
+            &mut params,
+        )?;
+        info!("Capturing from '{}'", target);
+
+        Ok(Self {
+            _listener: listener,
+            _stream: stream,
+        })
+    }
+}
+
+/// Captured audio waiting to be summed into the output.
+pub struct MixBuffer {
+    samples: VecDeque<f32>,
+    channels: usize,
+    gain: f32,
+    /// The `--max-volume` ceiling the sum is clipped to.
+    ceiling: f32,
+    /// Samples kept at most; older ones are dropped so latency can't build up.
+    capacity: usize,
+}
+
+impl MixBuffer {
+    pub fn new(gain: f32, ceiling: f32, channels: usize, capacity_frames: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(capacity_frames * channels),
+            channels,
+            gain,
+            ceiling,
+            capacity: capacity_frames * channels,
+        }
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        self.samples.extend(samples);
+        if self.samples.len() > self.capacity {
+            // Drop whole frames to keep the channels aligned
+            let excess = (self.samples.len() - self.capacity).div_ceil(self.channels);
+            self.samples.drain(..excess * self.channels);
+        }
+    }
+
+    /// Add the buffered audio into `output`, clipping the sum to the ceiling. If
+    /// too little has been captured the rest of `output` is left as it is.
+    pub fn mix_into(&mut self, output: &mut [f32]) {
+        let len = output.len().min(self.samples.len());
+        for (out, sample) in output.iter_mut().zip(self.samples.drain(..len)) {
+            *out = (*out + sample * self.gain).clamp(-self.ceiling, self.ceiling);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mix_stays_under_the_ceiling() {
+        let mut mix = MixBuffer::new(1.0, 0.5, 1, 4);
+        mix.push(&[0.25, 0.5, -0.5]);
+        let mut output = [0.125, 0.375, -0.375, 0.75];
+        mix.mix_into(&mut output);
+        // The last sample had nothing to mix, so it is left alone
+        assert_eq!(output, [0.375, 0.5, -0.5, 0.75]);
+    }
+}
@@ -0,0 +1,329 @@
+//! Runtime control over a Unix socket.
+//!
+//! Clients connect to `--control-socket` and send one command per line; each
+//! gets a single reply line, `ok` or `error: <reason>`. Commands are handed to
+//! the main loop, which owns the decoder, and applied there between buffers.
+//!
+//! A client that sends `events` instead gets `ok` and from then on a line for
+//! each playback event, such as `started <path>`.
+
+use crate::time;
+use anyhow::{anyhow, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// A command understood by the control socket.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ControlCommand {
+    /// Replace the playing file once it has opened, keeping the device and stream.
+    Load(PathBuf),
+    /// Add a file (or playlist, or directory) to the end of the playlist.
+    Enqueue(PathBuf),
+    /// Play a clip over the playing file, which then carries on where it was.
+    Interject(PathBuf),
+    /// Jump to a playlist entry, counting from 1.
+    Track(usize),
+    /// Skip to the next playlist entry.
+    Next,
+    /// Skip back to the previous playlist entry.
+    Previous,
+    /// Play silence in place of the input, which stays where it is.
+    Pause,
+    /// Carry on from where `Pause` stopped.
+    Resume,
+    /// Pause if playing, resume if paused.
+    TogglePause,
+    /// Send silence while the input plays on, like a microphone's mute button.
+    Mute,
+    /// Undo `Mute`.
+    Unmute,
+    /// Mute if live, unmute if muted.
+    ToggleMute,
+    /// The `--ptt` trigger was pressed (`true`) or released.
+    PushToTalk(bool),
+    /// Play the `soundboard` clip on this pad, counting from 1.
+    Pad(usize),
+    /// Change the volume, ramping to it.
+    Volume(VolumeChange),
+    /// Jump to another position in the playing file.
+    Seek(SeekTarget),
+    /// Open the playing file again from its start, picking up changes to it.
+    Reopen,
+    /// Report where playback is, answered with `key=value` fields.
+    Position,
+    /// Report the state of playback: `Position`'s fields and more.
+    Status,
+    /// Report the playlist entries, answered with a JSON array.
+    Playlist,
+    /// Shut down as on Ctrl+C.
+    Quit,
+}
+
+/// Where a `seek` goes: to a position, or forward or back from the current one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeekTarget {
+    To(Duration),
+    Forward(Duration),
+    Back(Duration),
+}
+
+/// A `volume` change: to a level, or up or down from the current one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VolumeChange {
+    To(f32),
+    By(f32),
+}
+
+impl VolumeChange {
+    /// Parse `<level>`, `+<step>` or `-<step>`.
+    fn parse(arg: &str) -> Option<Self> {
+        let value: f32 = arg.parse().ok().filter(|value: &f32| value.is_finite())?;
+        if arg.starts_with(['+', '-']) {
+            Some(VolumeChange::By(value))
+        } else {
+            Some(VolumeChange::To(value))
+        }
+    }
+
+    /// The volume this gives, from `volume`, within 0.0 - 2.0.
+    pub fn resolve(self, volume: f32) -> f32 {
+        match self {
+            VolumeChange::To(to) => to,
+            VolumeChange::By(by) => volume + by,
+        }
+        .clamp(0.0, 2.0)
+    }
+}
+
+impl SeekTarget {
+    /// Parse `<time>`, `+<time>` or `-<time>`.
+    fn parse(arg: &str) -> Option<Self> {
+        if let Some(time) = arg.strip_prefix('+') {
+            time::parse(time).map(SeekTarget::Forward)
+        } else if let Some(time) = arg.strip_prefix('-') {
+            time::parse(time).map(SeekTarget::Back)
+        } else {
+            time::parse(arg).map(SeekTarget::To)
+        }
+    }
+
+    /// The position this lands on, seeking from `position`.
+    pub fn resolve(self, position: Duration) -> Duration {
+        match self {
+            SeekTarget::To(to) => to,
+            SeekTarget::Forward(by) => position + by,
+            SeekTarget::Back(by) => position.saturating_sub(by),
+        }
+    }
+}
+
+impl ControlCommand {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (name, arg) = match line.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (line, ""),
+        };
+
+        match name {
+            "load" if arg.is_empty() => Err("usage: load <path>".to_string()),
+            // The rest of the line is the path, so it may contain spaces
+            "load" => Ok(ControlCommand::Load(PathBuf::from(arg))),
+            "enqueue" if arg.is_empty() => Err("usage: enqueue <path>".to_string()),
+            "enqueue" => Ok(ControlCommand::Enqueue(PathBuf::from(arg))),
+            "interject" if arg.is_empty() => Err("usage: interject <path>".to_string()),
+            "interject" => Ok(ControlCommand::Interject(PathBuf::from(arg))),
+            "track" => match arg.parse() {
+                Ok(track) if track > 0 => Ok(ControlCommand::Track(track)),
+                _ => Err("usage: track <number>".to_string()),
+            },
+            "next" => Ok(ControlCommand::Next),
+            "prev" | "previous" => Ok(ControlCommand::Previous),
+            "pause" => Ok(ControlCommand::Pause),
+            "resume" => Ok(ControlCommand::Resume),
+            "toggle-pause" => Ok(ControlCommand::TogglePause),
+            "mute" => Ok(ControlCommand::Mute),
+            "unmute" => Ok(ControlCommand::Unmute),
+            "toggle-mute" => Ok(ControlCommand::ToggleMute),
+            "ptt-down" => Ok(ControlCommand::PushToTalk(true)),
+            "ptt-up" => Ok(ControlCommand::PushToTalk(false)),
+            "pad" => match arg.parse() {
+                Ok(pad) if pad > 0 => Ok(ControlCommand::Pad(pad)),
+                _ => Err("usage: pad <number>".to_string()),
+            },
+            "volume" => VolumeChange::parse(arg)
+                .map(ControlCommand::Volume)
+                .ok_or_else(|| {
+                    "usage: volume <level>, volume +<step> or volume -<step>".to_string()
+                }),
+            "position" => Ok(ControlCommand::Position),
+            "status" => Ok(ControlCommand::Status),
+            "playlist" => Ok(ControlCommand::Playlist),
+            "quit" => Ok(ControlCommand::Quit),
+            "seek" => SeekTarget::parse(arg)
+                .map(ControlCommand::Seek)
+                .ok_or_else(|| "usage: seek <time>, seek +<time> or seek -<time>".to_string()),
+            "reopen" => Ok(ControlCommand::Reopen),
+            "" => Err("empty command".to_string()),
+            other => Err(format!("unknown command '{}'", other)),
+        }
+    }
+}
+
+/// A parsed command plus where to send its reply.
+pub struct Request {
+    pub command: ControlCommand,
+    reply: Sender<Result<String, String>>,
+}
+
+impl Request {
+    /// A request for `command`, and where its reply arrives.
+    pub fn new(command: ControlCommand) -> (Self, Receiver<Result<String, String>>) {
+        let (reply, response) = mpsc::channel();
+        (Self { command, reply }, response)
+    }
+
+    pub fn respond(self, result: Result<(), String>) {
+        self.send(result.map(|()| String::new()));
+    }
+
+    /// Reply `ok` followed by `answer`.
+    pub fn answer(self, answer: String) {
+        self.send(Ok(answer));
+    }
+
+    fn send(self, result: Result<String, String>) {
+        // The client may have hung up already; nothing to do then
+        let _ = self.reply.send(result);
+    }
+}
+
+/// Where playback events go: to every client that asked for `events`.
+#[derive(Clone, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Sender<String>>>>,
+}
+
+impl Events {
+    /// Send an event line to the subscribed clients, forgetting any that left.
+    pub fn emit(&self, event: String) {
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Receive the events emitted from now on.
+    pub fn subscribe(&self) -> Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        subscribers.push(tx);
+        rx
+    }
+}
+
+/// Listens on the control socket; removes the socket file when dropped.
+pub struct ControlSocket {
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Bind the socket and start accepting clients on a background thread,
+    /// passing their commands to `tx`.
+    pub fn start(path: &Path, events: Events, tx: Sender<Request>) -> Result<Self> {
+        let listener = bind(path)?;
+        info!("Control socket listening on {:?}", path);
+
+        std::thread::Builder::new()
+            .name("control".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let tx = tx.clone();
+                            let events = events.clone();
+                            let _ = std::thread::Builder::new()
+                                .name("control-client".into())
+                                .spawn(move || serve_client(stream, tx, events));
+                        }
+                        Err(e) => warn!("Control socket accept failed: {}", e),
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+/// Bind a Unix socket at `path`, replacing a socket file left behind by a
+/// previous run. One that still answers belongs to a running instance.
+pub fn bind(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(anyhow!("{:?} is in use by another process", path));
+        }
+        std::fs::remove_file(path)?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve_client(stream: UnixStream, requests: Sender<Request>, events: Events) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            warn!("Control client error: {}", e);
+            return;
+        }
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { return };
+        debug!("Control command: {}", line.trim());
+        if line.trim() == "events" {
+            // The connection carries nothing but events from here on
+            let events = events.subscribe();
+            if writeln!(writer, "ok").is_err() {
+                return;
+            }
+            for event in events {
+                if writeln!(writer, "{}", event).is_err() {
+                    return;
+                }
+            }
+            return;
+        }
+
+        let result = match ControlCommand::parse(&line) {
+            Ok(command) => {
+                let (request, response) = Request::new(command);
+                if requests.send(request).is_err() {
+                    return;
+                }
+                response
+                    .recv()
+                    .unwrap_or_else(|_| Err("shutting down".to_string()))
+            }
+            Err(e) => Err(e),
+        };
+
+        let reply = match result {
+            Ok(answer) if answer.is_empty() => "ok".to_string(),
+            Ok(answer) => format!("ok {}", answer),
+            Err(e) => format!("error: {}", e),
+        };
+        if writeln!(writer, "{}", reply).is_err() {
+            return;
+        }
+    }
+}
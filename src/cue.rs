@@ -0,0 +1,204 @@
+//! Cue sheets: tracks laid out over one or more audio files.
+//!
+//! Only what playback needs is read: `FILE`, `TRACK`, `TITLE`, `PERFORMER`
+//! and `INDEX 01`, which marks where a track starts. A track ends where the
+//! next one in the same file starts, so any pregap plays at the end of the
+//! track before it. Data tracks are skipped.
+
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+
+/// Cue sheet times count frames of 1/75 s (CD sectors).
+const FRAMES_PER_SECOND: u64 = 75;
+
+/// One audio track of a cue sheet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CueTrack {
+    /// The `FILE` the track is in, as written in the sheet.
+    pub file: String,
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub start: Duration,
+    /// `None` for the last track of a file, which plays to its end.
+    pub end: Option<Duration>,
+}
+
+impl CueTrack {
+    /// "Performer - Title", or whichever of the two is known.
+    pub fn name(&self) -> Option<String> {
+        match (&self.performer, &self.title) {
+            (Some(performer), Some(title)) => Some(format!("{} - {}", performer, title)),
+            (performer, title) => title.clone().or(performer.clone()),
+        }
+    }
+}
+
+/// Split a line into its command and arguments, honouring double quotes.
+pub fn tokens(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '"' {
+            chars.next();
+            tokens.push(chars.by_ref().take_while(|&c| c != '"').collect());
+        } else {
+            let mut token = String::new();
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                token.push(c);
+            }
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+/// Parse an `mm:ss:ff` time.
+fn parse_time(time: &str) -> Option<Duration> {
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (minutes, seconds, frames) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || seconds >= 60 || frames >= FRAMES_PER_SECOND {
+        return None;
+    }
+    let frames = (minutes * 60 + seconds) * FRAMES_PER_SECOND + frames;
+    Some(Duration::from_nanos(
+        frames * 1_000_000_000 / FRAMES_PER_SECOND,
+    ))
+}
+
+/// The audio tracks of a cue sheet, in order.
+pub fn parse(contents: &str) -> Result<Vec<CueTrack>> {
+    let mut tracks: Vec<CueTrack> = Vec::new();
+    let mut file: Option<String> = None;
+    // Set before the first TRACK, where it describes the whole disc
+    let mut album_performer = None;
+    // Whether lines currently apply to an audio track
+    let mut in_audio_track = false;
+
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim_start_matches('\u{feff}');
+        let tokens = tokens(line);
+        let Some(command) = tokens.first() else {
+            continue;
+        };
+        let arg = tokens.get(1).cloned();
+        let invalid = || anyhow!("Invalid cue sheet line {}: {}", number + 1, line.trim());
+
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                file = Some(arg.ok_or_else(invalid)?);
+                in_audio_track = false;
+            }
+            "TRACK" => {
+                let track = arg.and_then(|n| n.parse().ok()).ok_or_else(invalid)?;
+                let kind = tokens.get(2).ok_or_else(invalid)?;
+                in_audio_track = kind.eq_ignore_ascii_case("AUDIO");
+                if in_audio_track {
+                    tracks.push(CueTrack {
+                        file: file.clone().ok_or_else(invalid)?,
+                        number: track,
+                        title: None,
+                        performer: album_performer.clone(),
+                        start: Duration::MAX,
+                        end: None,
+                    });
+                }
+            }
+            "TITLE" if in_audio_track => {
+                if let Some(track) = tracks.last_mut() {
+                    track.title = arg;
+                }
+            }
+            "PERFORMER" if in_audio_track => {
+                if let Some(track) = tracks.last_mut() {
+                    track.performer = arg;
+                }
+            }
+            "PERFORMER" if tracks.is_empty() => album_performer = arg,
+            // INDEX 00 (the pregap) and any later indexes don't start the track
+            "INDEX"
+                if in_audio_track && arg.as_deref().and_then(|n| n.parse().ok()) == Some(1u32) =>
+            {
+                let start = tokens.get(2).and_then(|time| parse_time(time));
+                if let Some(track) = tracks.last_mut() {
+                    track.start = start.ok_or_else(invalid)?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(track) = tracks.iter().find(|track| track.start == Duration::MAX) {
+        return Err(anyhow!("Cue sheet track {} has no INDEX 01", track.number));
+    }
+    for i in 1..tracks.len() {
+        if tracks[i].file == tracks[i - 1].file {
+            tracks[i - 1].end = Some(tracks[i].start);
+        }
+    }
+    Ok(tracks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_lines_honouring_quotes() {
+        assert_eq!(
+            tokens(r#"  FILE "Side A.flac" WAVE"#),
+            ["FILE", "Side A.flac", "WAVE"]
+        );
+        assert_eq!(tokens(r#"TITLE """#), ["TITLE", ""]);
+        assert!(tokens("   ").is_empty());
+    }
+
+    #[test]
+    fn parses_tracks_across_files() {
+        let sheet = [
+            "\u{feff}PERFORMER \"The Band\"",
+            "TITLE \"The Album\"",
+            "FILE \"side a.flac\" WAVE",
+            "  TRACK 01 AUDIO",
+            "    TITLE \"Opener\"",
+            "    INDEX 01 00:00:00",
+            "  TRACK 02 AUDIO",
+            "    TITLE \"Second\"",
+            "    PERFORMER \"Guest\"",
+            "    INDEX 00 03:58:00",
+            "    INDEX 01 04:00:30",
+            "  TRACK 03 MODE1/2352",
+            "    INDEX 01 08:00:00",
+            "FILE \"side b.flac\" WAVE",
+            "  TRACK 04 AUDIO",
+            "    INDEX 01 00:00:00",
+        ]
+        .join("\n");
+        let tracks = parse(&sheet).unwrap();
+        assert_eq!(tracks.len(), 3);
+
+        assert_eq!(tracks[0].file, "side a.flac");
+        assert_eq!(tracks[0].name().as_deref(), Some("The Band - Opener"));
+        // A track ends where the next one in its file starts, after the pregap
+        assert_eq!(tracks[0].end, Some(tracks[1].start));
+        assert_eq!(tracks[1].start, Duration::from_millis(240_400));
+        assert_eq!(tracks[1].name().as_deref(), Some("Guest - Second"));
+        // The data track is skipped, so the last audio track plays to its end
+        assert_eq!(tracks[1].end, None);
+
+        assert_eq!(tracks[2].file, "side b.flac");
+        assert_eq!(tracks[2].number, 4);
+        assert_eq!(tracks[2].name().as_deref(), Some("The Band"));
+    }
+
+    #[test]
+    fn rejects_tracks_without_a_start_or_file() {
+        let error = parse("FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 00 00:00:00").unwrap_err();
+        assert!(error.to_string().contains("no INDEX 01"), "{}", error);
+        assert!(parse("TRACK 01 AUDIO\nINDEX 01 00:00:00").is_err());
+        assert!(parse("FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 00:60:00").is_err());
+        assert!(parse("FILE a.wav WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:75").is_err());
+    }
+}
@@ -0,0 +1,316 @@
+//! `--dbus`: the `org.virtualmic.Player1` service on the session bus, so
+//! desktop tooling and scripts can drive the mic with `busctl`, `gdbus` or
+//! any D-Bus library.
+//!
+//! The object at `/org/virtualmic/Player1` has methods for the control
+//! commands, properties read from `status` as they are asked for, and
+//! signals for playback events. Methods are carried out by the main loop like
+//! control socket commands, and return once they have been.
+
+use crate::control::{ControlCommand, Events, Request, SeekTarget, VolumeChange};
+use crate::webhook;
+use anyhow::{anyhow, Result};
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use zbus::blocking::object_server::InterfaceRef;
+use zbus::object_server::SignalEmitter;
+use zbus::{fdo, interface};
+
+/// Well-known name and interface of the service.
+pub const NAME: &str = "org.virtualmic.Player1";
+
+/// Path of the player object.
+const PATH: &str = "/org/virtualmic/Player1";
+
+/// Hands D-Bus calls to the main loop as control commands.
+pub struct Commands {
+    requests: Sender<Request>,
+}
+
+impl Commands {
+    pub fn new(requests: Sender<Request>) -> Self {
+        Self { requests }
+    }
+
+    /// Carry out `command` and return its answer.
+    pub async fn run(&self, command: ControlCommand) -> fdo::Result<String> {
+        let (request, response) = Request::new(command);
+        let shutting_down = || fdo::Error::Failed("shutting down".to_string());
+        self.requests.send(request).map_err(|_| shutting_down())?;
+        // Waiting for a `load` can take a while; don't hold up the bus meanwhile
+        blocking::unblock(move || response.recv())
+            .await
+            .map_err(|_| shutting_down())?
+            .map_err(fdo::Error::Failed)
+    }
+
+    /// The fields of `status`.
+    pub async fn status(&self) -> fdo::Result<Map<String, Value>> {
+        Ok(webhook::fields(&self.run(ControlCommand::Status).await?))
+    }
+
+    /// A string from `status`.
+    pub async fn text(&self, field: &str) -> fdo::Result<String> {
+        let status = self.status().await?;
+        Ok(status
+            .get(field)
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    /// A number from `status`, or `unknown` if it isn't known.
+    pub async fn number(&self, field: &str, unknown: f64) -> fdo::Result<f64> {
+        let status = self.status().await?;
+        Ok(status.get(field).and_then(Value::as_f64).unwrap_or(unknown))
+    }
+}
+
+/// The player object.
+struct Player {
+    commands: Commands,
+}
+
+#[interface(name = "org.virtualmic.Player1")]
+impl Player {
+    /// Resume playback.
+    async fn play(&self) -> fdo::Result<()> {
+        self.commands.run(ControlCommand::Resume).await.map(drop)
+    }
+
+    async fn pause(&self) -> fdo::Result<()> {
+        self.commands.run(ControlCommand::Pause).await.map(drop)
+    }
+
+    async fn play_pause(&self) -> fdo::Result<()> {
+        self.commands
+            .run(ControlCommand::TogglePause)
+            .await
+            .map(drop)
+    }
+
+    async fn next(&self) -> fdo::Result<()> {
+        self.commands.run(ControlCommand::Next).await.map(drop)
+    }
+
+    async fn previous(&self) -> fdo::Result<()> {
+        self.commands.run(ControlCommand::Previous).await.map(drop)
+    }
+
+    /// Replace the playing file, returning once it plays.
+    async fn load(&self, path: String) -> fdo::Result<()> {
+        self.commands
+            .run(ControlCommand::Load(PathBuf::from(path)))
+            .await
+            .map(drop)
+    }
+
+    async fn enqueue(&self, path: String) -> fdo::Result<()> {
+        self.commands
+            .run(ControlCommand::Enqueue(PathBuf::from(path)))
+            .await
+            .map(drop)
+    }
+
+    async fn interject(&self, path: String) -> fdo::Result<()> {
+        self.commands
+            .run(ControlCommand::Interject(PathBuf::from(path)))
+            .await
+            .map(drop)
+    }
+
+    /// Jump forward, or back if negative, by `offset` seconds.
+    async fn seek(&self, offset: f64) -> fdo::Result<()> {
+        if !offset.is_finite() {
+            return Err(fdo::Error::InvalidArgs("offset must be finite".to_string()));
+        }
+        let by = Duration::from_secs_f64(offset.abs());
+        let target = match offset < 0.0 {
+            true => SeekTarget::Back(by),
+            false => SeekTarget::Forward(by),
+        };
+        self.commands
+            .run(ControlCommand::Seek(target))
+            .await
+            .map(drop)
+    }
+
+    /// Jump to `position` seconds into the playing file.
+    async fn set_position(&self, position: f64) -> fdo::Result<()> {
+        let position = Duration::try_from_secs_f64(position)
+            .map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+        self.commands
+            .run(ControlCommand::Seek(SeekTarget::To(position)))
+            .await
+            .map(drop)
+    }
+
+    async fn mute(&self) -> fdo::Result<()> {
+        self.commands.run(ControlCommand::Mute).await.map(drop)
+    }
+
+    async fn unmute(&self) -> fdo::Result<()> {
+        self.commands.run(ControlCommand::Unmute).await.map(drop)
+    }
+
+    async fn toggle_mute(&self) -> fdo::Result<()> {
+        self.commands
+            .run(ControlCommand::ToggleMute)
+            .await
+            .map(drop)
+    }
+
+    /// Shut down as on Ctrl+C.
+    async fn quit(&self) -> fdo::Result<()> {
+        self.commands.run(ControlCommand::Quit).await.map(drop)
+    }
+
+    /// `playing`, `paused` or `stopped`.
+    #[zbus(property)]
+    async fn playback_state(&self) -> fdo::Result<String> {
+        self.commands.text("state").await
+    }
+
+    /// Seconds into the playing file.
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn position(&self) -> fdo::Result<f64> {
+        self.commands.number("position_secs", 0.0).await
+    }
+
+    /// Length of the playing file in seconds, or -1 if it isn't known.
+    #[zbus(property)]
+    async fn duration(&self) -> fdo::Result<f64> {
+        self.commands.number("duration_secs", -1.0).await
+    }
+
+    #[zbus(property)]
+    async fn volume(&self) -> fdo::Result<f64> {
+        self.commands.number("volume", 1.0).await
+    }
+
+    #[zbus(property)]
+    async fn set_volume(&self, volume: f64) -> zbus::Result<()> {
+        if !volume.is_finite() {
+            return Err(fdo::Error::InvalidArgs("volume must be finite".to_string()).into());
+        }
+        let command = ControlCommand::Volume(VolumeChange::To(volume as f32));
+        Ok(self.commands.run(command).await.map(drop)?)
+    }
+
+    #[zbus(property)]
+    async fn muted(&self) -> fdo::Result<bool> {
+        let status = self.commands.status().await?;
+        Ok(status
+            .get("muted")
+            .and_then(Value::as_bool)
+            .unwrap_or_default())
+    }
+
+    #[zbus(property)]
+    async fn current_file(&self) -> fdo::Result<String> {
+        self.commands.text("path").await
+    }
+
+    /// Playlist position, counting from 1.
+    #[zbus(property)]
+    async fn track(&self) -> fdo::Result<u32> {
+        Ok(self.commands.number("track", 0.0).await? as u32)
+    }
+
+    #[zbus(property)]
+    async fn tracks(&self) -> fdo::Result<u32> {
+        Ok(self.commands.number("tracks", 0.0).await? as u32)
+    }
+
+    /// A file (or cue sheet track, or segment) starts playing.
+    #[zbus(signal)]
+    async fn started(emitter: &SignalEmitter<'_>, path: &str) -> zbus::Result<()>;
+
+    /// The input of a file has run out.
+    #[zbus(signal)]
+    async fn finished(emitter: &SignalEmitter<'_>, path: &str) -> zbus::Result<()>;
+
+    /// Nothing is left to play.
+    #[zbus(signal)]
+    async fn ended(emitter: &SignalEmitter<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn interject_started(emitter: &SignalEmitter<'_>, path: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn interject_finished(emitter: &SignalEmitter<'_>, path: &str) -> zbus::Result<()>;
+}
+
+/// Owns the service name; gives it up when dropped.
+pub struct DbusService {
+    _connection: zbus::blocking::Connection,
+}
+
+impl DbusService {
+    /// Take the service name on the session bus and serve the player,
+    /// passing calls to `tx` and signalling `events`.
+    pub fn start(events: Events, tx: Sender<Request>) -> Result<Self> {
+        let player = Player {
+            commands: Commands::new(tx),
+        };
+        let connection = zbus::blocking::connection::Builder::session()
+            .and_then(|builder| builder.name(NAME))
+            .and_then(|builder| builder.serve_at(PATH, player))
+            .and_then(|builder| builder.build())
+            .map_err(|e| anyhow!("Failed to register {} on the session bus: {}", NAME, e))?;
+        info!("D-Bus service {} is up", NAME);
+
+        let player = connection.object_server().interface::<_, Player>(PATH)?;
+        let events = events.subscribe();
+        std::thread::Builder::new()
+            .name("dbus-signals".into())
+            .spawn(move || {
+                for event in events {
+                    if let Err(e) = signal(&player, &event) {
+                        warn!("Failed to signal {:?} on D-Bus: {}", event, e);
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            _connection: connection,
+        })
+    }
+}
+
+/// Send the signals for an event line: its own, or the properties it changed.
+fn signal(player: &InterfaceRef<Player>, event: &str) -> zbus::Result<()> {
+    let (name, rest) = event.split_once(' ').unwrap_or((event, ""));
+    let emitter = player.signal_emitter();
+    let player = player.get();
+    zbus::block_on(async {
+        match name {
+            "started" => {
+                Player::started(emitter, rest).await?;
+                player.current_file_changed(emitter).await?;
+                player.duration_changed(emitter).await
+            }
+            "finished" => Player::finished(emitter, rest).await,
+            "ended" => {
+                Player::ended(emitter).await?;
+                player.playback_state_changed(emitter).await
+            }
+            "interject-started" => Player::interject_started(emitter, rest).await,
+            "interject-finished" => Player::interject_finished(emitter, rest).await,
+            "track" => {
+                player.track_changed(emitter).await?;
+                player.tracks_changed(emitter).await
+            }
+            "paused" | "resumed" => player.playback_state_changed(emitter).await,
+            "muted" | "unmuted" => player.muted_changed(emitter).await,
+            "volume" => player.volume_changed(emitter).await,
+            _ => {
+                debug!("No D-Bus signal for {:?}", event);
+                Ok(())
+            }
+        }
+    })
+}
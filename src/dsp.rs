@@ -0,0 +1,790 @@
+//! Processing stages applied to decoded audio, in a configurable order.
+//!
+//! Decoded audio is first mixed to the output channel count and resampled to
+//! the output rate; everything after that runs through a [`ProcessChain`].
+
+use anyhow::{anyhow, Result};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::debug;
+
+/// Cutoff of the DC blocker; low enough to leave speech and music untouched.
+const DC_BLOCK_CUTOFF_HZ: f32 = 10.0;
+
+/// First sample magnitude above which playback fades in instead of starting abruptly.
+const DECLICK_THRESHOLD: f32 = 0.05;
+
+/// Length of the startup fade-in used by the declicker.
+const DECLICK_RAMP_MS: u32 = 5;
+
+/// How much quiet audio `--trim-silence` holds back looking for the end of the file.
+const TRIM_LOOKAHEAD_SECS: u32 = 5;
+
+/// Time constant of the level detector `--agc` follows, in milliseconds.
+const AGC_DETECTOR_MS: u32 = 300;
+
+/// Furthest `--agc` will move the gain away from unity, in dB.
+const AGC_MAX_GAIN_DB: f32 = 24.0;
+
+/// Level below which `--agc` holds its gain instead of boosting noise, in dBFS.
+const AGC_GATE_DBFS: f32 = -60.0;
+
+/// How long the volume takes to move by 1.0 when changed while playing.
+const VOLUME_RAMP_MS: u32 = 50;
+
+/// Fraction of clipped samples in a check interval that counts as sustained clipping.
+const SUSTAINED_CLIP_RATIO: f64 = 0.001;
+
+/// A processing step with its own persistent state.
+pub trait Stage {
+    /// Process a block of interleaved frames in place; stages may change its length.
+    fn process(&mut self, frames: &mut Vec<f32>, channels: usize);
+
+    /// Called whenever a new play-through of the input starts.
+    fn reset(&mut self) {}
+}
+
+/// The stages `--chain-order` can arrange.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StageKind {
+    /// DC-offset removal (--dc-block)
+    DcBlock,
+    /// Leading/trailing silence removal (--trim-silence)
+    TrimSilence,
+    /// Shortening of long pauses (--skip-silence)
+    SkipSilence,
+    /// Loudness normalization (--normalize)
+    Normalize,
+    /// Automatic gain control (--agc)
+    Agc,
+    /// Startup fade-in for abrupt starts (--dc-block)
+    Declick,
+    /// Volume, auto-gain, pan and channel gains
+    Gain,
+    /// Clip counting and the --max-volume ceiling
+    Limit,
+}
+
+/// Order used for stages not named in `--chain-order`: gain, filters, AGC,
+/// limiter, fades.
+///
+/// Normalization stands in for the ReplayGain part of `gain`, so it comes
+/// with it. The AGC evens out the level the gain set, and the limiter
+/// catches whatever is still over the ceiling. The declicker's fade-in comes
+/// last so nothing after it can bring the click back; the other fades are not
+/// stages, and act on the output after the buffer so they take effect at once.
+pub const DEFAULT_ORDER: [StageKind; 8] = [
+    StageKind::Normalize,
+    StageKind::Gain,
+    StageKind::DcBlock,
+    StageKind::TrimSilence,
+    StageKind::SkipSilence,
+    StageKind::Agc,
+    StageKind::Limit,
+    StageKind::Declick,
+];
+
+/// Resolve `--chain-order`: the named stages first, then the rest in default order.
+pub fn resolve_order(requested: &[StageKind]) -> Result<Vec<StageKind>> {
+    let mut order = Vec::with_capacity(DEFAULT_ORDER.len());
+    for &kind in requested {
+        if order.contains(&kind) {
+            return Err(anyhow!("--chain-order lists {:?} more than once", kind));
+        }
+        order.push(kind);
+    }
+    for kind in DEFAULT_ORDER {
+        if !order.contains(&kind) {
+            order.push(kind);
+        }
+    }
+    Ok(order)
+}
+
+/// An ordered list of stages that every block of decoded audio passes through.
+#[derive(Default)]
+pub struct ProcessChain {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl ProcessChain {
+    pub fn push(&mut self, stage: Box<dyn Stage>) {
+        self.stages.push(stage);
+    }
+
+    pub fn process(&mut self, frames: &mut Vec<f32>, channels: usize) {
+        for stage in &mut self.stages {
+            stage.process(frames, channels);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for stage in &mut self.stages {
+            stage.reset();
+        }
+    }
+}
+
+/// First-order DC-blocking high-pass filter: `y[n] = x[n] - x[n-1] + r * y[n-1]`.
+///
+/// State is kept per channel so consecutive packets filter seamlessly.
+pub struct DcBlocker {
+    coeff: f32,
+    prev_input: Vec<f32>,
+    prev_output: Vec<f32>,
+}
+
+impl DcBlocker {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        let coeff = (-2.0 * std::f32::consts::PI * DC_BLOCK_CUTOFF_HZ / sample_rate as f32).exp();
+        Self {
+            coeff,
+            prev_input: vec![0.0; channels],
+            prev_output: vec![0.0; channels],
+        }
+    }
+}
+
+impl Stage for DcBlocker {
+    fn process(&mut self, frames: &mut Vec<f32>, channels: usize) {
+        for frame in frames.chunks_mut(channels) {
+            for (ch, sample) in frame.iter_mut().enumerate() {
+                let output = *sample - self.prev_input[ch] + self.coeff * self.prev_output[ch];
+                self.prev_input[ch] = *sample;
+                self.prev_output[ch] = output;
+                *sample = output;
+            }
+        }
+    }
+}
+
+/// Ramps into a freshly opened file when its first frame is loud enough to pop.
+pub struct Declicker {
+    ramp_frames: usize,
+    armed: bool,
+    position: Option<usize>,
+}
+
+impl Declicker {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            ramp_frames: (sample_rate * DECLICK_RAMP_MS / 1000) as usize,
+            armed: false,
+            position: None,
+        }
+    }
+}
+
+impl Stage for Declicker {
+    fn process(&mut self, frames: &mut Vec<f32>, channels: usize) {
+        for frame in frames.chunks_mut(channels) {
+            if self.armed {
+                self.armed = false;
+                if frame.iter().any(|s| s.abs() > DECLICK_THRESHOLD) {
+                    debug!("Declicking abrupt start over {} frames", self.ramp_frames);
+                    self.position = Some(0);
+                }
+            }
+
+            if let Some(position) = self.position {
+                let gain = position as f32 / self.ramp_frames as f32;
+                for sample in frame.iter_mut() {
+                    *sample *= gain;
+                }
+                self.position = (position + 1 < self.ramp_frames).then_some(position + 1);
+            }
+        }
+    }
+
+    /// Check the next frame that is emitted, since the file was (re)opened.
+    fn reset(&mut self) {
+        self.armed = true;
+    }
+}
+
+/// Drops quiet audio at the start and end of each play-through of a file.
+///
+/// Leading frames are skipped until the first one above the threshold. Quiet
+/// frames after that are held back (up to a lookahead window) and only emitted
+/// once louder audio follows, so whatever is still pending at end of file is
+/// trailing silence and gets discarded by `reset`.
+pub struct SilenceTrimmer {
+    threshold: f32,
+    max_pending: usize,
+    leading: bool,
+    pending: VecDeque<f32>,
+}
+
+impl SilenceTrimmer {
+    pub fn new(threshold_dbfs: f32, channels: usize, sample_rate: u32) -> Self {
+        Self {
+            threshold: 10f32.powf(threshold_dbfs / 20.0),
+            max_pending: (sample_rate * TRIM_LOOKAHEAD_SECS) as usize * channels,
+            leading: true,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl Stage for SilenceTrimmer {
+    fn process(&mut self, frames: &mut Vec<f32>, channels: usize) {
+        let mut output = Vec::with_capacity(frames.len());
+
+        for frame in frames.chunks(channels) {
+            let loud = frame.iter().any(|s| s.abs() >= self.threshold);
+            if self.leading {
+                if !loud {
+                    continue;
+                }
+                self.leading = false;
+            }
+
+            if loud {
+                output.extend(self.pending.drain(..));
+                output.extend_from_slice(frame);
+            } else {
+                self.pending.extend(frame);
+                // Silence longer than the lookahead is mid-file, so let the oldest through
+                while self.pending.len() > self.max_pending {
+                    output.extend(self.pending.drain(..channels));
+                }
+            }
+        }
+
+        *frames = output;
+    }
+
+    /// Start a new play-through, discarding any held-back trailing silence.
+    fn reset(&mut self) {
+        if !self.pending.is_empty() {
+            debug!("Trimmed {} trailing silent samples", self.pending.len());
+        }
+        self.leading = true;
+        self.pending.clear();
+    }
+}
+
+/// Shortens silent stretches anywhere in the audio, for `--skip-silence`.
+///
+/// A pause longer than `keep` is cut down to `keep`: its first frames play as
+/// usual and the rest are dropped, so speech keeps a natural gap between
+/// sentences.
+pub struct SilenceSkipper {
+    threshold: f32,
+    keep: usize,
+    /// Frames of the current silent stretch so far.
+    quiet: usize,
+}
+
+impl SilenceSkipper {
+    pub fn new(threshold_dbfs: f32, keep: Duration, sample_rate: u32) -> Self {
+        Self {
+            threshold: 10f32.powf(threshold_dbfs / 20.0),
+            keep: (keep.as_secs_f64() * sample_rate as f64) as usize,
+            quiet: 0,
+        }
+    }
+}
+
+impl Stage for SilenceSkipper {
+    fn process(&mut self, frames: &mut Vec<f32>, channels: usize) {
+        let mut kept = 0;
+        for start in (0..frames.len()).step_by(channels) {
+            let frame = start..start + channels;
+            if frames[frame.clone()]
+                .iter()
+                .any(|s| s.abs() >= self.threshold)
+            {
+                if self.quiet > self.keep {
+                    debug!("Skipped {} silent frames", self.quiet - self.keep);
+                }
+                self.quiet = 0;
+            } else {
+                self.quiet += 1;
+                if self.quiet > self.keep {
+                    continue;
+                }
+            }
+            frames.copy_within(frame, kept);
+            kept += channels;
+        }
+        frames.truncate(kept);
+    }
+
+    fn reset(&mut self) {
+        self.quiet = 0;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AgcOptions {
+    pub target_dbfs: f32,
+    pub attack_ms: u32,
+    pub release_ms: u32,
+}
+
+/// Slow automatic gain control for streams whose level drifts over time.
+///
+/// A mean-square level detector drives a gain that moves towards the value
+/// that would put the signal at the target, using the attack time when turning
+/// down and the (much slower) release time when turning up. Both the envelope
+/// and the gain carry over between packets and loops.
+pub struct Agc {
+    target: f32,
+    detector_coeff: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+    gate: f32,
+    max_gain: f32,
+    envelope: f32,
+    gain: f32,
+}
+
+/// One-pole smoothing coefficient for a time constant of `ms` at `sample_rate`.
+fn smoothing_coeff(ms: u32, sample_rate: u32) -> f32 {
+    let samples = ms.max(1) as f32 * sample_rate as f32 / 1000.0;
+    1.0 - (-1.0 / samples).exp()
+}
+
+impl Agc {
+    pub fn new(options: AgcOptions, sample_rate: u32) -> Self {
+        Self {
+            target: 10f32.powf(options.target_dbfs / 20.0),
+            detector_coeff: smoothing_coeff(AGC_DETECTOR_MS, sample_rate),
+            attack_coeff: smoothing_coeff(options.attack_ms, sample_rate),
+            release_coeff: smoothing_coeff(options.release_ms, sample_rate),
+            // The envelope is a mean square, so the gate is compared squared too
+            gate: 10f32.powf(AGC_GATE_DBFS / 10.0),
+            max_gain: 10f32.powf(AGC_MAX_GAIN_DB / 20.0),
+            envelope: 0.0,
+            gain: 1.0,
+        }
+    }
+}
+
+impl Stage for Agc {
+    fn process(&mut self, frames: &mut Vec<f32>, channels: usize) {
+        for frame in frames.chunks_mut(channels) {
+            let power = frame.iter().map(|s| s * s).sum::<f32>() / frame.len() as f32;
+            self.envelope += (power - self.envelope) * self.detector_coeff;
+
+            if self.envelope > self.gate {
+                let desired =
+                    (self.target / self.envelope.sqrt()).clamp(1.0 / self.max_gain, self.max_gain);
+                let coeff = if desired < self.gain {
+                    self.attack_coeff
+                } else {
+                    self.release_coeff
+                };
+                self.gain += (desired - self.gain) * coeff;
+            }
+
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+    }
+}
+
+/// Volume, `--auto-gain` reduction and per-channel (pan) gains.
+pub struct Gain {
+    /// Set from outside the chain by the `volume` command.
+    volume: Rc<Cell<f32>>,
+    /// Where the volume is on its way to `volume`, so changes don't zipper.
+    level: f32,
+    /// Furthest `level` moves in a frame.
+    max_step: f32,
+    /// Adjusted from outside the chain by the periodic clipping check.
+    auto_gain: Rc<Cell<f32>>,
+    /// The playing file's own gain, e.g. from its ReplayGain tags.
+    file_gain: Rc<Cell<f32>>,
+    channel_gains: Vec<f32>,
+}
+
+impl Gain {
+    pub fn new(
+        volume: Rc<Cell<f32>>,
+        auto_gain: Rc<Cell<f32>>,
+        file_gain: Rc<Cell<f32>>,
+        channel_gains: Vec<f32>,
+        sample_rate: u32,
+    ) -> Self {
+        Self {
+            level: volume.get(),
+            volume,
+            max_step: 1000.0 / (VOLUME_RAMP_MS * sample_rate) as f32,
+            auto_gain,
+            file_gain,
+            channel_gains,
+        }
+    }
+}
+
+impl Stage for Gain {
+    fn process(&mut self, frames: &mut Vec<f32>, channels: usize) {
+        let target = self.volume.get();
+        let gain = self.auto_gain.get() * self.file_gain.get();
+        for frame in frames.chunks_mut(channels) {
+            if self.level != target {
+                self.level += (target - self.level).clamp(-self.max_step, self.max_step);
+            }
+            for (sample, channel_gain) in frame.iter_mut().zip(&self.channel_gains) {
+                *sample *= self.level * gain * channel_gain;
+            }
+        }
+    }
+}
+
+/// Clipping statistics accumulated since the last check.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClipStats {
+    /// Samples over the ceiling, out of all those measured.
+    pub clipped: u64,
+    pub samples: u64,
+    peak: f32,
+    /// The limiter's ceiling, as a fraction of full scale.
+    ceiling: f32,
+}
+
+impl ClipStats {
+    fn measure(&mut self, sample: f32, ceiling: f32) {
+        let level = sample.abs();
+        self.samples += 1;
+        if level > ceiling {
+            self.clipped += 1;
+        }
+        self.peak = self.peak.max(level);
+        self.ceiling = ceiling;
+    }
+
+    pub fn peak_dbfs(&self) -> f32 {
+        20.0 * self.peak.max(f32::MIN_POSITIVE).log10()
+    }
+
+    /// How far the peak went over the ceiling, in dB.
+    pub fn overshoot_db(&self) -> f32 {
+        self.peak_dbfs() - 20.0 * self.ceiling.max(f32::MIN_POSITIVE).log10()
+    }
+
+    pub fn is_sustained(&self) -> bool {
+        self.samples > 0 && self.clipped as f64 / self.samples as f64 > SUSTAINED_CLIP_RATIO
+    }
+}
+
+/// Peak level of each channel of the outgoing audio, measured as it is sent
+/// and taken by a display on another thread.
+#[derive(Clone)]
+pub struct Meter {
+    /// The bits of each channel's peak since the last `take`; the bits of
+    /// non-negative floats order as the floats do.
+    peaks: Arc<[AtomicU32]>,
+}
+
+impl Meter {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            peaks: (0..channels).map(|_| AtomicU32::new(0)).collect(),
+        }
+    }
+
+    /// Measure a block of interleaved samples.
+    pub fn measure(&self, samples: &[f32]) {
+        for (channel, peak) in self.peaks.iter().enumerate() {
+            let level = samples
+                .iter()
+                .skip(channel)
+                .step_by(self.peaks.len())
+                .fold(0.0f32, |level, sample| level.max(sample.abs()));
+            peak.fetch_max(level.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    /// Each channel's peak since the last call, as a linear level.
+    #[cfg(feature = "tui")]
+    pub fn take(&self) -> Vec<f32> {
+        self.peaks
+            .iter()
+            .map(|peak| f32::from_bits(peak.swap(0, Ordering::Relaxed)))
+            .collect()
+    }
+}
+
+/// Counts samples over the `--max-volume` ceiling, then hard-limits to it. The
+/// ceiling is full scale unless `--max-volume` lowers it.
+pub struct Limiter {
+    ceiling: f32,
+    stats: Rc<Cell<ClipStats>>,
+}
+
+impl Limiter {
+    pub fn new(ceiling: f32, stats: Rc<Cell<ClipStats>>) -> Self {
+        Self { ceiling, stats }
+    }
+}
+
+impl Stage for Limiter {
+    fn process(&mut self, frames: &mut Vec<f32>, _channels: usize) {
+        let mut stats = self.stats.get();
+        for sample in frames.iter_mut() {
+            stats.measure(*sample, self.ceiling);
+            *sample = sample.clamp(-self.ceiling, self.ceiling);
+        }
+        self.stats.set(stats);
+    }
+}
+
+/// A gain on the outgoing audio that moves in a straight line to a target,
+/// for fades that must act at once rather than after what is buffered.
+#[derive(Debug, Clone, Copy)]
+pub struct Ramp {
+    gain: f32,
+    target: f32,
+    /// Gain change per frame.
+    step: f32,
+}
+
+impl Default for Ramp {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            target: 1.0,
+            step: 0.0,
+        }
+    }
+}
+
+impl Ramp {
+    /// Set the gain at once, e.g. to silence before fading in.
+    pub fn jump_to(&mut self, gain: f32) {
+        self.gain = gain;
+        self.target = gain;
+        self.step = 0.0;
+    }
+
+    /// Move to `target` over `frames` frames, from wherever the gain is now.
+    pub fn fade_to(&mut self, target: f32, frames: u64) {
+        self.target = target;
+        self.step = (target - self.gain) / frames.max(1) as f32;
+    }
+
+    pub fn gain(&self) -> f32 {
+        self.gain
+    }
+
+    /// Scale interleaved frames, moving the gain along as they go.
+    pub fn apply(&mut self, samples: &mut [f32], channels: usize) {
+        if self.gain == self.target && self.gain == 1.0 {
+            return;
+        }
+        for frame in samples.chunks_mut(channels) {
+            if self.gain != self.target {
+                self.gain += self.step;
+                // Land exactly on the target instead of overshooting it
+                if (self.step > 0.0) == (self.gain >= self.target) {
+                    self.gain = self.target;
+                }
+            }
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `Gain` stage at unity volume with the given channel gains.
+    fn gain(channel_gains: Vec<f32>) -> Gain {
+        let unity = || Rc::new(Cell::new(1.0));
+        Gain::new(unity(), unity(), unity(), channel_gains, 48_000)
+    }
+
+    #[test]
+    fn hard_left_pan_silences_the_right_channel() {
+        let gains = crate::output_gains(2, Some(-1.0), crate::ChannelGains::default());
+        let mut frames: Vec<f32> = (0..256).map(|i| (i as f32 * 0.1).sin()).collect();
+        let left = frames.clone();
+        gain(gains).process(&mut frames, 2);
+
+        assert!(frames.iter().skip(1).step_by(2).all(|&right| right == 0.0));
+        assert_eq!(
+            frames.iter().step_by(2).collect::<Vec<_>>(),
+            left.iter().step_by(2).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn dc_blocker_removes_an_offset() {
+        // A 440 Hz tone riding on a 0.3 offset, one second of stereo
+        let mut frames: Vec<f32> = (0..48_000)
+            .flat_map(|i| {
+                let tone = (2.0 * std::f32::consts::PI * 440.0 * i as f32 / 48_000.0).sin();
+                [0.3 + 0.5 * tone; 2]
+            })
+            .collect();
+        DcBlocker::new(2, 48_000).process(&mut frames, 2);
+
+        // The last 100 ms hold a whole number of periods, so only DC is left in the mean
+        for ch in 0..2 {
+            let tail: Vec<f32> = frames[frames.len() - 9_600..]
+                .iter()
+                .skip(ch)
+                .step_by(2)
+                .copied()
+                .collect();
+            let mean = tail.iter().sum::<f32>() / tail.len() as f32;
+            assert!(mean.abs() < 1e-3, "channel {} mean {}", ch, mean);
+        }
+    }
+
+    #[test]
+    fn limiter_counts_samples_over_its_ceiling() {
+        let stats = Rc::new(Cell::new(ClipStats::default()));
+        let mut limiter = Limiter::new(0.5, stats.clone());
+        let mut frames = vec![0.25, -0.5, 0.75, -1.0];
+        limiter.process(&mut frames, 2);
+
+        assert_eq!(frames, [0.25, -0.5, 0.5, -0.5]);
+        let stats = stats.get();
+        assert_eq!((stats.clipped, stats.samples), (2, 4));
+        assert_eq!(stats.peak_dbfs(), 0.0);
+        assert!((stats.overshoot_db() - 6.02).abs() < 0.01);
+    }
+
+    #[test]
+    fn trimmer_starts_at_the_first_sample_over_the_threshold() {
+        // -40 dBFS is 0.01; the right channel crosses it first, in the second block
+        let mut trimmer = SilenceTrimmer::new(-40.0, 2, 48_000);
+        let mut first = vec![0.0, 0.001, -0.005, 0.009];
+        trimmer.process(&mut first, 2);
+        let mut second = vec![0.002, 0.0, 0.003, -0.02, 0.0, 0.0, 0.5, 0.4];
+        trimmer.process(&mut second, 2);
+
+        assert!(first.is_empty());
+        // Quiet audio after the start is held back until something loud follows
+        assert_eq!(second, [0.003, -0.02, 0.0, 0.0, 0.5, 0.4]);
+    }
+
+    /// `secs` of a mono 440 Hz tone at 16 kHz whose RMS is `dbfs`.
+    fn tone(dbfs: f32, secs: usize) -> Vec<f32> {
+        let amplitude = 10f32.powf(dbfs / 20.0) * std::f32::consts::SQRT_2;
+        (0..16_000 * secs)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * 440.0 * i as f32 / 16_000.0).sin())
+            .collect()
+    }
+
+    /// RMS level of the last second of mono 16 kHz `samples`, in dBFS.
+    fn last_second_dbfs(samples: &[f32]) -> f32 {
+        let tail = &samples[samples.len() - 16_000..];
+        let power = tail.iter().map(|s| s * s).sum::<f32>() / tail.len() as f32;
+        10.0 * power.log10()
+    }
+
+    #[test]
+    fn agc_converges_to_its_target_from_either_side() {
+        let mut agc = Agc::new(
+            AgcOptions {
+                target_dbfs: -20.0,
+                attack_ms: 200,
+                release_ms: 3000,
+            },
+            16_000,
+        );
+        // Quiet material is raised, then a step up is brought back down
+        let mut quiet = tone(-35.0, 20);
+        agc.process(&mut quiet, 1);
+        let mut loud = tone(-5.0, 5);
+        agc.process(&mut loud, 1);
+
+        for output in [&quiet, &loud] {
+            let level = last_second_dbfs(output);
+            assert!((level + 20.0).abs() < 1.0, "settled at {:.1} dBFS", level);
+        }
+    }
+
+    #[test]
+    fn skipper_cuts_long_pauses_down_to_keep() {
+        // Two frames of a pause are kept at 1 kHz
+        let mut skipper = SilenceSkipper::new(-40.0, Duration::from_millis(2), 1000);
+        let mut frames = vec![0.5, 0.0, 0.0, 0.0, 0.0, 0.0, 0.5, 0.0, 0.5];
+        skipper.process(&mut frames, 1);
+        assert_eq!(frames, [0.5, 0.0, 0.0, 0.5, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn declicker_ramps_only_a_loud_start() {
+        let ramp = DECLICK_RAMP_MS as usize;
+        let mut declicker = Declicker::new(1000);
+        declicker.reset();
+        let mut loud = vec![0.8; 2 * ramp];
+        declicker.process(&mut loud, 1);
+        assert_eq!(loud[0], 0.0);
+        assert!(loud[..ramp].windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(loud[ramp..].iter().all(|&sample| sample == 0.8));
+
+        declicker.reset();
+        let mut quiet = vec![0.01, 0.8];
+        declicker.process(&mut quiet, 1);
+        assert_eq!(quiet, [0.01, 0.8]);
+    }
+
+    #[test]
+    fn gain_ramps_to_a_new_volume() {
+        let volume = Rc::new(Cell::new(1.0));
+        let unity = || Rc::new(Cell::new(1.0));
+        let mut gain = Gain::new(volume.clone(), unity(), unity(), vec![1.0], 1000);
+        volume.set(0.5);
+        let mut frames = vec![1.0; VOLUME_RAMP_MS as usize];
+        gain.process(&mut frames, 1);
+
+        // Half of full scale takes half the ramp time
+        let half = VOLUME_RAMP_MS as usize / 2;
+        assert!(frames[0] > 0.95);
+        assert!(frames[..half].windows(2).all(|pair| pair[0] > pair[1]));
+        assert!(frames[half..].iter().all(|&sample| sample == 0.5));
+    }
+
+    #[test]
+    fn chain_order_puts_named_stages_first() {
+        assert_eq!(resolve_order(&[]).unwrap(), DEFAULT_ORDER);
+        let order = resolve_order(&[StageKind::Gain, StageKind::Agc]).unwrap();
+        assert_eq!(
+            order[..3],
+            [StageKind::Gain, StageKind::Agc, StageKind::Normalize]
+        );
+        assert_eq!(order.len(), DEFAULT_ORDER.len());
+        assert!(resolve_order(&[StageKind::Limit, StageKind::Limit]).is_err());
+    }
+
+    #[test]
+    fn chain_runs_its_stages_in_order() {
+        let stats = Rc::new(Cell::new(ClipStats::default()));
+        let gain = || {
+            let double = || Rc::new(Cell::new(2.0));
+            let unity = || Rc::new(Cell::new(1.0));
+            Box::new(Gain::new(double(), unity(), unity(), vec![1.0], 48_000))
+        };
+        let limit = || Box::new(Limiter::new(1.0, stats.clone()));
+
+        let mut limited = ProcessChain::default();
+        limited.push(gain());
+        limited.push(limit());
+        let mut frames = vec![0.25, 0.75];
+        limited.process(&mut frames, 1);
+        assert_eq!(frames, [0.5, 1.0]);
+
+        let mut unlimited = ProcessChain::default();
+        unlimited.push(limit());
+        unlimited.push(gain());
+        let mut frames = vec![0.25, 0.75];
+        unlimited.process(&mut frames, 1);
+        assert_eq!(frames, [0.5, 1.5]);
+        assert_eq!(stats.get().clipped, 1);
+    }
+}
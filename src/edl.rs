@@ -0,0 +1,132 @@
+//! Edit decision lists: segments of audio files played back to back.
+//!
+//! Each line names a file and the stretch of it to play:
+//!
+//! ```text
+//! # file                start    end
+//! greeting.wav          0        1.25
+//! "long interview.flac" 12:03.5  12:07
+//! greeting.wav          2.5      -
+//! ```
+//!
+//! Times are seconds, `m:ss`, `h:mm:ss` or `1m30s` style, with an optional
+//! fraction; an end of `-` (or none) runs to the end of the file. Files with spaces in their names
+//! are quoted, and `#` starts a comment line.
+
+use crate::{cue, time};
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+
+/// One segment of an edit decision list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    /// The file, as written in the list.
+    pub file: String,
+    pub start: Duration,
+    /// `None` to play to the end of the file.
+    pub end: Option<Duration>,
+}
+
+/// The segments of an edit decision list, in order.
+pub fn parse(contents: &str) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim_start_matches('\u{feff}').trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason: &str| {
+            anyhow!(
+                "Invalid edit list line {} ({}): {}",
+                number + 1,
+                reason,
+                line
+            )
+        };
+
+        let fields = cue::tokens(line);
+        let (file, start, end) = match fields.as_slice() {
+            [file, start] => (file, start, None),
+            [file, start, end] => (file, start, Some(end)),
+            _ => return Err(invalid("expected <file> <start> [<end>]")),
+        };
+        let start = time::parse(start).ok_or_else(|| invalid("bad start time"))?;
+        let end = match end.map(String::as_str) {
+            None | Some("-") => None,
+            Some(end) => Some(time::parse(end).ok_or_else(|| invalid("bad end time"))?),
+        };
+        if end.is_some_and(|end| end <= start) {
+            return Err(invalid("the segment ends before it starts"));
+        }
+        segments.push(Segment {
+            file: file.clone(),
+            start,
+            end,
+        });
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_segments_with_quoted_files_and_open_ends() {
+        let list = [
+            "# file                start    end",
+            "greeting.wav          0        1.25",
+            "",
+            "\"long interview.flac\" 12:03.5  12:07",
+            "greeting.wav          2.5      -",
+            "outro.wav             1m",
+        ]
+        .join("\n");
+        assert_eq!(
+            parse(&list).unwrap(),
+            [
+                Segment {
+                    file: "greeting.wav".to_string(),
+                    start: Duration::ZERO,
+                    end: Some(Duration::from_millis(1250)),
+                },
+                Segment {
+                    file: "long interview.flac".to_string(),
+                    start: Duration::from_millis(723_500),
+                    end: Some(Duration::from_secs(727)),
+                },
+                Segment {
+                    file: "greeting.wav".to_string(),
+                    start: Duration::from_millis(2500),
+                    end: None,
+                },
+                Segment {
+                    file: "outro.wav".to_string(),
+                    start: Duration::from_secs(60),
+                    end: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        for (list, reason) in [
+            ("a.wav", "expected <file>"),
+            ("a.wav 1 2 3", "expected <file>"),
+            ("a.wav soon", "bad start time"),
+            ("a.wav 1 later", "bad end time"),
+            ("a.wav 2 1", "ends before it starts"),
+            ("a.wav 2 2", "ends before it starts"),
+        ] {
+            let error = parse(&format!("ok.wav 0\n{}", list))
+                .unwrap_err()
+                .to_string();
+            assert!(
+                error.contains("line 2") && error.contains(reason),
+                "{}",
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Key events from Linux input devices (`/dev/input/event*`).
+//!
+//! Unlike the terminal, these report keys being held and released, and work
+//! whichever window has focus. Reading them needs read access to the devices,
+//! which usually means being in the `input` group.
+
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// `input_event.type` of key and button events.
+const EV_KEY: u16 = 0x01;
+
+/// Key names and their codes (from `linux/input-event-codes.h`), without the
+/// `KEY_` prefix.
+const KEY_NAMES: &[(&str, u16)] = &[
+    ("esc", 1),
+    ("minus", 12),
+    ("equal", 13),
+    ("backspace", 14),
+    ("tab", 15),
+    ("leftbrace", 26),
+    ("rightbrace", 27),
+    ("enter", 28),
+    ("leftctrl", 29),
+    ("semicolon", 39),
+    ("apostrophe", 40),
+    ("grave", 41),
+    ("leftshift", 42),
+    ("backslash", 43),
+    ("comma", 51),
+    ("dot", 52),
+    ("slash", 53),
+    ("rightshift", 54),
+    ("leftalt", 56),
+    ("space", 57),
+    ("capslock", 58),
+    ("numlock", 69),
+    ("scrolllock", 70),
+    ("rightctrl", 97),
+    ("sysrq", 99),
+    ("rightalt", 100),
+    ("home", 102),
+    ("up", 103),
+    ("pageup", 104),
+    ("left", 105),
+    ("right", 106),
+    ("end", 107),
+    ("down", 108),
+    ("pagedown", 109),
+    ("insert", 110),
+    ("delete", 111),
+    ("mute", 113),
+    ("volumedown", 114),
+    ("volumeup", 115),
+    ("pause", 119),
+    ("leftmeta", 125),
+    ("rightmeta", 126),
+    ("compose", 127),
+    ("nextsong", 163),
+    ("playpause", 164),
+    ("previoussong", 165),
+    // Mouse buttons
+    ("btn_left", 0x110),
+    ("btn_right", 0x111),
+    ("btn_middle", 0x112),
+    ("btn_side", 0x113),
+    ("btn_extra", 0x114),
+    ("btn_forward", 0x115),
+    ("btn_back", 0x116),
+];
+
+/// Letter keys by keyboard row, from the first key's code.
+const LETTER_ROWS: &[(&str, u16)] = &[("qwertyuiop", 16), ("asdfghjkl", 30), ("zxcvbnm", 44)];
+
+/// A key pressed or released. Auto-repeats aren't reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: u16,
+    pub pressed: bool,
+}
+
+/// The code of a key given by name (`space`, `KEY_F13`, `btn_side`) or
+/// number. A single digit is that digit's key, not a code.
+pub fn key_code(name: &str) -> Option<u16> {
+    if name.len() > 1 {
+        if let Ok(code) = name.parse() {
+            return Some(code);
+        }
+    }
+    let name = name.to_ascii_lowercase();
+    let name = name.strip_prefix("key_").unwrap_or(&name);
+    if let Some(&(_, code)) = KEY_NAMES.iter().find(|(key, _)| *key == name) {
+        return Some(code);
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.as_str()) {
+        (Some(c), "") if c.is_ascii_lowercase() => LETTER_ROWS
+            .iter()
+            .find_map(|(row, first)| row.find(c).map(|offset| first + offset as u16)),
+        // KEY_1 is 2, through KEY_9 at 10 and KEY_0 at 11
+        (Some(c), "") if c.is_ascii_digit() => Some(match c {
+            '0' => 11,
+            c => c as u16 - '0' as u16 + 1,
+        }),
+        (Some('f'), number) => match number.parse::<u16>().ok()? {
+            n @ 1..=10 => Some(58 + n),
+            11 => Some(87),
+            12 => Some(88),
+            n @ 13..=24 => Some(170 + n),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The input devices there are, which may include ones this user can't read.
+pub fn devices() -> Vec<PathBuf> {
+    let mut devices: Vec<PathBuf> = std::fs::read_dir("/dev/input")
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("event"))
+        })
+        .collect();
+    devices.sort();
+    devices
+}
+
+/// Watch `device`, or every input device there is, passing their key events
+/// to a clone of `on_key` each. Returns how many devices are being read.
+pub fn watch_all<F>(device: Option<&Path>, on_key: F) -> Result<usize>
+where
+    F: FnMut(KeyEvent) -> bool + Clone + Send + 'static,
+{
+    let devices = match device {
+        Some(device) => vec![device.to_path_buf()],
+        None => devices(),
+    };
+    // Any keyboard will do, so only fail if none can be read
+    let mut watching = 0;
+    for device in &devices {
+        match watch(device, on_key.clone()) {
+            Ok(()) => watching += 1,
+            Err(e) if devices.len() == 1 => return Err(e),
+            Err(_) => {}
+        }
+    }
+    if watching == 0 {
+        return Err(anyhow!(
+            "No input device could be read; add yourself to the input group"
+        ));
+    }
+    Ok(watching)
+}
+
+/// Read the key events of `device` on a background thread, passing them to
+/// `on_key` until it returns `false` or the device goes away.
+pub fn watch(
+    device: &Path,
+    mut on_key: impl FnMut(KeyEvent) -> bool + Send + 'static,
+) -> Result<()> {
+    let mut file = File::open(device).map_err(|e| anyhow!("Failed to open {:?}: {}", device, e))?;
+    let device = device.to_path_buf();
+    std::thread::Builder::new()
+        .name("evdev".into())
+        .spawn(move || {
+            let mut event = [0u8; std::mem::size_of::<libc::input_event>()];
+            loop {
+                if let Err(e) = file.read_exact(&mut event) {
+                    warn!("Stopped reading {:?}: {}", device, e);
+                    return;
+                }
+                // SAFETY: input_event is plain integers, and the buffer is its size
+                let event: libc::input_event =
+                    unsafe { std::ptr::read_unaligned(event.as_ptr().cast()) };
+                // Value 2 is an auto-repeat of a held key
+                if event.type_ != EV_KEY || event.value > 1 {
+                    continue;
+                }
+                let key = KeyEvent {
+                    code: event.code,
+                    pressed: event.value == 1,
+                };
+                if !on_key(key) {
+                    return;
+                }
+            }
+        })?;
+    Ok(())
+}
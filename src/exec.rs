@@ -0,0 +1,128 @@
+//! A child process whose stdout is the input, for `--exec` and text-to-speech
+//! engines.
+//!
+//! `--exec` commands run through `sh -c`, so quoting and pipelines work as in
+//! a shell. Their first bytes decide how the output is read: a recognised
+//! container or codec signature goes to the decoder, anything else is taken to
+//! be raw PCM.
+
+use std::io::{self, Cursor, Read, Write};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use tracing::{info, warn};
+
+/// Leading bytes inspected to tell encoded output from raw PCM.
+const SNIFF_BYTES: usize = 64;
+
+/// The running child; reading returns its stdout. Dropping it kills the child.
+pub struct ChildOutput {
+    child: Child,
+    stdout: ChildStdout,
+    command: String,
+}
+
+impl ChildOutput {
+    /// Run a shell command with empty stdin.
+    pub fn spawn(command: &str) -> io::Result<Self> {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        Self::start(shell, None, command)
+    }
+
+    /// Run `command`, writing `input` to its stdin from a background thread
+    /// so a child that writes before it has read everything can't deadlock.
+    /// `name` is used in log messages.
+    pub fn start(mut command: Command, input: Option<Vec<u8>>, name: &str) -> io::Result<Self> {
+        let stdin = if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        };
+        let mut child = command.stdin(stdin).stdout(Stdio::piped()).spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            std::thread::Builder::new()
+                .name("exec-stdin".into())
+                .spawn(move || {
+                    // Dropping stdin afterwards closes it, ending the child's input
+                    let _ = stdin.write_all(&input);
+                })?;
+        }
+        info!("Started `{}` (pid {})", name, child.id());
+        Ok(Self {
+            child,
+            stdout,
+            command: name.to_string(),
+        })
+    }
+
+    /// Read the first bytes of output, returning them and whether they look
+    /// like an encoded format rather than raw PCM.
+    pub fn sniff(&mut self) -> io::Result<(Vec<u8>, bool)> {
+        let mut head = Vec::with_capacity(SNIFF_BYTES);
+        (&mut self.stdout)
+            .take(SNIFF_BYTES as u64)
+            .read_to_end(&mut head)?;
+        let encoded = looks_encoded(&head);
+        Ok((head, encoded))
+    }
+
+    /// The output with already sniffed bytes put back in front.
+    pub fn with_head(self, head: Vec<u8>) -> impl Read + Send + Sync {
+        Cursor::new(head).chain(self)
+    }
+}
+
+impl Read for ChildOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            match self.child.wait() {
+                Ok(status) if status.success() => info!("`{}` finished", self.command),
+                Ok(status) => warn!("`{}` exited with {}", self.command, status),
+                Err(e) => warn!("Failed to wait for `{}`: {}", self.command, e),
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for ChildOutput {
+    fn drop(&mut self) {
+        // Already reaped children make kill fail harmlessly
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Whether the leading bytes carry a container or codec signature.
+fn looks_encoded(head: &[u8]) -> bool {
+    const MAGIC: &[&[u8]] = &[
+        b"RIFF",
+        b"RF64",
+        b"FORM",
+        b"caff",
+        b"fLaC",
+        b"OggS",
+        b"ID3",
+        // EBML: Matroska and WebM
+        &[0x1a, 0x45, 0xdf, 0xa3],
+    ];
+    if MAGIC.iter().any(|magic| head.starts_with(magic)) {
+        return true;
+    }
+    if head.get(4..8) == Some(b"ftyp") {
+        return true;
+    }
+    match head {
+        // ADTS (AAC)
+        [0xff, b1, ..] if b1 & 0xf6 == 0xf0 => true,
+        // MPEG audio frame header: non-zero layer, valid bitrate and sample rate
+        [0xff, b1, b2, ..] => {
+            b1 & 0xe0 == 0xe0
+                && (b1 >> 1) & 0x3 != 0
+                && !matches!(b2 >> 4, 0x0 | 0xf)
+                && (b2 >> 2) & 0x3 != 0x3
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,48 @@
+//! Exit codes, so a script running virtual-mic can tell why it stopped.
+//!
+//! Errors are tagged with an [`Exit`] as they are returned, which also heads
+//! the message printed for them; untagged errors exit with 1.
+
+use std::fmt;
+use std::process::ExitCode;
+
+/// Why virtual-mic gave up, as its exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// An input file or URL could not be opened or read.
+    Unreadable = 2,
+    /// The virtual device (null sink and remap source) could not be created.
+    Device = 3,
+    /// Connecting to PipeWire or creating the stream failed.
+    PipeWire = 4,
+    /// A packet failed to decode, with `--strict`.
+    Decode = 5,
+    /// Invalid command line arguments.
+    Usage = 64,
+}
+
+/// Any other error.
+const FAILURE: u8 = 1;
+
+impl Exit {
+    /// The exit code for `error`: that of the [`Exit`] it was tagged with, if any.
+    pub fn code(error: &anyhow::Error) -> ExitCode {
+        ExitCode::from(
+            error
+                .downcast_ref::<Exit>()
+                .map_or(FAILURE, |&exit| exit as u8),
+        )
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Exit::Unreadable => "Could not read the input",
+            Exit::Device => "Could not create the virtual device",
+            Exit::PipeWire => "PipeWire error",
+            Exit::Decode => "Decode error",
+            Exit::Usage => "Invalid arguments",
+        })
+    }
+}
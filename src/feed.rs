@@ -0,0 +1,56 @@
+//! Audio pushed in by a background thread, such as a FIFO reader or a network
+//! listener.
+//!
+//! The producer blocks once the queue is full, which paces fast writers to
+//! playback speed. The audio thread never blocks: it plays silence while
+//! nothing is queued.
+
+use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError};
+
+/// Decoded chunks buffered ahead of playback.
+const QUEUE_CHUNKS: usize = 32;
+
+/// Frames of silence returned per poll while the feed is idle (20 ms at 48 kHz).
+const IDLE_FRAMES: usize = 960;
+
+/// Interleaved samples, their channel count and their sample rate.
+pub type RatedChunk = (Vec<f32>, usize, Option<u32>);
+
+/// The receiving end of a feed.
+pub struct Feed {
+    chunks: Receiver<RatedChunk>,
+    /// Rate and channel count of the last chunk, used for idle silence.
+    sample_rate: Option<u32>,
+    channels: usize,
+}
+
+impl Feed {
+    /// A feed and the sender its producer pushes chunks into.
+    pub fn channel() -> (SyncSender<RatedChunk>, Self) {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_CHUNKS);
+        (
+            tx,
+            Self {
+                chunks: rx,
+                sample_rate: None,
+                channels: 1,
+            },
+        )
+    }
+
+    /// The next queued chunk, or a short stretch of silence when none is waiting.
+    pub fn next_chunk(&mut self) -> RatedChunk {
+        match self.chunks.try_recv() {
+            Ok((samples, channels, sample_rate)) => {
+                self.channels = channels;
+                self.sample_rate = sample_rate;
+                (samples, channels, sample_rate)
+            }
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => (
+                vec![0.0; IDLE_FRAMES * self.channels],
+                self.channels,
+                self.sample_rate,
+            ),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Named pipe input that outlives its writers.
+//!
+//! A background thread opens the FIFO, decodes whatever a writer sends, and
+//! reopens it when the writer closes, so scripts can feed the mic whenever they
+//! like. Playback continues with silence while nobody is writing.
+
+use crate::feed::{Feed, RatedChunk};
+use crate::source::{InputSpec, Source};
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::mpsc::SyncSender;
+use tracing::{info, warn};
+
+pub fn is_fifo(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_fifo())
+}
+
+/// Start reading `spec.path` on a background thread.
+pub fn spawn(spec: InputSpec) -> std::io::Result<Feed> {
+    let (tx, feed) = Feed::channel();
+    std::thread::Builder::new()
+        .name("fifo".into())
+        .spawn(move || read_sessions(spec, tx))?;
+    Ok(feed)
+}
+
+/// Decode one writer after another until playback stops listening.
+fn read_sessions(spec: InputSpec, chunks: SyncSender<RatedChunk>) {
+    loop {
+        // Opening blocks until a writer connects
+        let mut source = match Source::open_direct(&spec) {
+            Ok(source) => source,
+            Err(e) => {
+                warn!("Could not read from FIFO {:?}: {}", spec.path, e);
+                // Don't spin if the pipe itself is gone
+                std::thread::sleep(std::time::Duration::from_secs(1));
+                continue;
+            }
+        };
+        info!("FIFO writer connected: {:?}", spec.path);
+
+        loop {
+            match source.next_chunk() {
+                Ok(Some((samples, channels))) => {
+                    let chunk = (samples, channels, source.sample_rate);
+                    // Stop once playback has moved on to another input
+                    if chunks.send(chunk).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("FIFO decode error: {}", e);
+                    break;
+                }
+            }
+        }
+        info!("FIFO writer closed; waiting for the next one");
+    }
+}
@@ -0,0 +1,379 @@
+//! Built-in test signals for `--generate` and DTMF digits for `--dtmf`.
+//!
+//! Signals are synthesized in mono at the output rate, so they reach the mic
+//! without resampling, and pass through the processing chain like decoded
+//! audio. Tones peak at -6 dBFS and noise has an RMS level of -18 dBFS before
+//! `--volume` is applied, unless `--generate-level` asks for another RMS level.
+
+use std::f64::consts::TAU;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Peak amplitude of sine tones and sweeps (-6 dBFS).
+const TONE_PEAK: f64 = 0.5;
+/// RMS level of white and pink noise (-18 dBFS).
+const NOISE_RMS: f64 = 0.125;
+/// RMS of the pink filter's output for uniform white input in [-1, 1].
+const PINK_FILTER_RMS: f64 = 1.76;
+/// Corners of the speech-shaped noise filter in Hz: the spectrum rises below
+/// the first and falls 6 dB per octave faster than pink noise above the second,
+/// approximating the long-term speech spectrum of ITU-T P.50.
+const SPEECH_HIGHPASS: f64 = 200.0;
+const SPEECH_LOWPASS: f64 = 800.0;
+/// Length of one sweep when no --duration is given; it then repeats.
+const DEFAULT_SWEEP: Duration = Duration::from_secs(10);
+/// Frames produced per chunk.
+const CHUNK_FRAMES: usize = 1024;
+/// Peak amplitude of each of the two DTMF tones, so together they peak at -6 dBFS.
+const DTMF_PEAK: f64 = 0.25;
+/// DTMF row frequencies in Hz (ITU-T Q.23).
+const DTMF_LOW: [f64; 4] = [697.0, 770.0, 852.0, 941.0];
+/// DTMF column frequencies in Hz.
+const DTMF_HIGH: [f64; 4] = [1209.0, 1336.0, 1477.0, 1633.0];
+/// Keypad layout: digit at [row][column].
+const DTMF_KEYS: [[char; 4]; 4] = [
+    ['1', '2', '3', 'A'],
+    ['4', '5', '6', 'B'],
+    ['7', '8', '9', 'C'],
+    ['*', '0', '#', 'D'],
+];
+
+/// A test signal, as given to --generate, or a --dtmf sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signal {
+    /// Sine tone at this frequency in Hz
+    Sine(f64),
+    White,
+    Pink,
+    /// Noise with the long-term spectrum of speech
+    Speech,
+    /// Logarithmic sweep between two frequencies in Hz
+    Sweep(f64, f64),
+    /// DTMF digits, each a `tone` followed by a `gap`; a comma is a silent digit
+    Dtmf {
+        digits: String,
+        tone: Duration,
+        gap: Duration,
+    },
+}
+
+impl FromStr for Signal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = match s.split_once(':') {
+            Some((kind, arg)) => (kind, Some(arg)),
+            None => (s, None),
+        };
+        let frequency = |value: &str| -> Result<f64, String> {
+            let hz: f64 = value
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid frequency: {}", value))?;
+            if !(hz.is_finite() && hz > 0.0) {
+                return Err(format!("frequency must be positive, got {}", value));
+            }
+            Ok(hz)
+        };
+        match (kind.to_ascii_lowercase().as_str(), arg) {
+            ("sine", Some(hz)) => Ok(Self::Sine(frequency(hz)?)),
+            ("white", None) => Ok(Self::White),
+            ("pink", None) => Ok(Self::Pink),
+            ("speech", None) => Ok(Self::Speech),
+            ("sweep", Some(range)) => {
+                let (from, to) = range
+                    .split_once('-')
+                    .ok_or_else(|| format!("sweep needs a range like 20-20000, got {}", range))?;
+                Ok(Self::Sweep(frequency(from)?, frequency(to)?))
+            }
+            _ => Err(format!(
+                "unknown signal '{}' (expected sine:HZ, white, pink, speech or sweep:HZ-HZ)",
+                s
+            )),
+        }
+    }
+}
+
+impl Signal {
+    /// The highest frequency in the signal, for tones and sweeps.
+    pub fn max_frequency(&self) -> Option<f64> {
+        match *self {
+            Self::Sine(hz) => Some(hz),
+            Self::Sweep(from, to) => Some(from.max(to)),
+            Self::Dtmf { .. } => Some(DTMF_HIGH[3]),
+            Self::White | Self::Pink | Self::Speech => None,
+        }
+    }
+
+    /// RMS level of the signal when no --generate-level is given.
+    fn default_rms(&self) -> f64 {
+        match self {
+            Self::Sine(_) | Self::Sweep(..) => TONE_PEAK / 2f64.sqrt(),
+            Self::White | Self::Pink | Self::Speech => NOISE_RMS,
+            // Two tones of equal level
+            Self::Dtmf { .. } => DTMF_PEAK,
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sine(hz) => write!(f, "sine:{}", hz),
+            Self::White => write!(f, "white"),
+            Self::Pink => write!(f, "pink"),
+            Self::Speech => write!(f, "speech"),
+            Self::Sweep(from, to) => write!(f, "sweep:{}-{}", from, to),
+            Self::Dtmf { digits, .. } => write!(f, "dtmf:{}", digits),
+        }
+    }
+}
+
+/// The row and column frequencies of a DTMF digit, or `None` for a pause.
+fn dtmf_frequencies(digit: char) -> Option<(f64, f64)> {
+    DTMF_KEYS.iter().enumerate().find_map(|(row, keys)| {
+        let column = keys.iter().position(|&key| key == digit)?;
+        Some((DTMF_LOW[row], DTMF_HIGH[column]))
+    })
+}
+
+/// Check and normalize a --dtmf sequence: digits 0-9, *, #, A-D and commas.
+pub fn parse_dtmf_digits(s: &str) -> Result<String, String> {
+    let digits: String = s
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if let Some(bad) = s.chars().find(|c| {
+        !c.is_whitespace() && *c != ',' && dtmf_frequencies(c.to_ascii_uppercase()).is_none()
+    }) {
+        return Err(format!(
+            "'{}' is not a DTMF digit (use 0-9, *, #, A-D, and , to pause)",
+            bad
+        ));
+    }
+    if digits.is_empty() {
+        return Err("no DTMF digits given".to_string());
+    }
+    Ok(digits)
+}
+
+/// What to generate, from the command line.
+#[derive(Debug, Clone)]
+pub struct GenerateOptions {
+    pub signal: Signal,
+    pub rate: u32,
+    /// Endless when unset. DTMF sequences always end after their last digit.
+    pub duration: Option<Duration>,
+    /// RMS level in dBFS, instead of the signal's default.
+    pub level: Option<f32>,
+}
+
+impl GenerateOptions {
+    /// How long the signal plays, if it ends.
+    pub fn length(&self) -> Option<Duration> {
+        match &self.signal {
+            Signal::Dtmf { digits, tone, gap } => Some((*tone + *gap) * digits.len() as u32),
+            _ => self.duration,
+        }
+    }
+}
+
+/// A running signal, yielding mono chunks.
+pub struct Generator {
+    signal: Signal,
+    rate: f64,
+    /// Frames left before the signal ends, if it ends.
+    remaining: Option<u64>,
+    /// Frames in one sweep.
+    sweep_frames: u64,
+    /// DTMF digits with their frequencies, and the frames of each tone and gap.
+    dtmf: Vec<Option<(f64, f64)>>,
+    dtmf_tone: u64,
+    dtmf_period: u64,
+    frame: u64,
+    phase: f64,
+    noise: Noise,
+    /// Only set up for speech-shaped noise.
+    speech: Option<SpeechShaper>,
+    /// Scales the signal from its default level to the requested one.
+    gain: f64,
+}
+
+impl Generator {
+    pub fn new(options: GenerateOptions) -> Self {
+        let rate = options.rate as f64;
+        let frames = |duration: Duration| (duration.as_secs_f64() * rate).round() as u64;
+        let (dtmf, dtmf_tone, dtmf_period) = match &options.signal {
+            Signal::Dtmf { digits, tone, gap } => (
+                digits.chars().map(dtmf_frequencies).collect(),
+                frames(*tone),
+                frames(*tone) + frames(*gap),
+            ),
+            _ => (Vec::new(), 0, 0),
+        };
+        let gain = options.level.map_or(1.0, |db| {
+            10f64.powf(db as f64 / 20.0) / options.signal.default_rms()
+        });
+        let speech = (options.signal == Signal::Speech).then(|| SpeechShaper::new(rate));
+        Self {
+            remaining: match options.signal {
+                Signal::Dtmf { .. } => Some(dtmf.len() as u64 * dtmf_period),
+                _ => options.duration.map(frames),
+            },
+            sweep_frames: frames(options.duration.unwrap_or(DEFAULT_SWEEP)).max(1),
+            dtmf,
+            dtmf_tone,
+            dtmf_period,
+            signal: options.signal,
+            rate,
+            frame: 0,
+            phase: 0.0,
+            noise: Noise::new(),
+            speech,
+            gain,
+        }
+    }
+
+    /// The next chunk of samples, or `None` once the duration has passed.
+    pub fn next_chunk(&mut self) -> Option<Vec<f32>> {
+        let frames = match self.remaining {
+            Some(0) => return None,
+            Some(remaining) => remaining.min(CHUNK_FRAMES as u64) as usize,
+            None => CHUNK_FRAMES,
+        };
+        if let Some(remaining) = &mut self.remaining {
+            *remaining -= frames as u64;
+        }
+        Some(
+            (0..frames)
+                .map(|_| (self.next_sample() * self.gain) as f32)
+                .collect(),
+        )
+    }
+
+    fn next_sample(&mut self) -> f64 {
+        let sample = match self.signal {
+            Signal::Sine(hz) => self.tone(hz),
+            Signal::Sweep(from, to) => {
+                let progress = (self.frame % self.sweep_frames) as f64 / self.sweep_frames as f64;
+                self.tone(from * (to / from).powf(progress))
+            }
+            Signal::White => self.noise.white() * NOISE_RMS * 3f64.sqrt(),
+            Signal::Pink => self.noise.pink() * NOISE_RMS / PINK_FILTER_RMS,
+            Signal::Speech => {
+                let pink = self.noise.pink();
+                self.speech
+                    .as_mut()
+                    .map_or(0.0, |speech| speech.shape(pink))
+                    * NOISE_RMS
+            }
+            Signal::Dtmf { .. } => self.dtmf_sample(),
+        };
+        self.frame += 1;
+        sample
+    }
+
+    /// Each tone starts at zero phase, so digits begin without a click.
+    fn dtmf_sample(&self) -> f64 {
+        let digit = (self.frame / self.dtmf_period) as usize;
+        let offset = self.frame % self.dtmf_period;
+        match self.dtmf.get(digit) {
+            Some(Some((low, high))) if offset < self.dtmf_tone => {
+                let t = offset as f64 / self.rate;
+                ((TAU * low * t).sin() + (TAU * high * t).sin()) * DTMF_PEAK
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// One sample of a tone whose frequency may change from sample to sample.
+    fn tone(&mut self, hz: f64) -> f64 {
+        let sample = self.phase.sin() * TONE_PEAK;
+        self.phase = (self.phase + TAU * hz / self.rate) % TAU;
+        sample
+    }
+}
+
+/// Noise source: xorshift for white noise, shaped to pink by Paul Kellet's filter.
+struct Noise {
+    state: u64,
+    pink: [f64; 7],
+}
+
+impl Noise {
+    fn new() -> Self {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        Self {
+            // xorshift gets stuck at zero
+            state: seed | 1,
+            pink: [0.0; 7],
+        }
+    }
+
+    /// Uniform in [-1, 1).
+    fn white(&mut self) -> f64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+
+    fn pink(&mut self) -> f64 {
+        let white = self.white();
+        let b = &mut self.pink;
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.1538520;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let pink = b.iter().sum::<f64>() + white * 0.5362;
+        b[6] = white * 0.115926;
+        pink
+    }
+}
+
+/// Filters pink noise into speech-shaped noise, normalized to unit RMS.
+struct SpeechShaper {
+    highpass_coeff: f64,
+    lowpass_coeff: f64,
+    /// Low frequencies removed by the highpass.
+    rumble: f64,
+    lowpass: f64,
+    /// Output gain for unit RMS, measured once for the sample rate.
+    gain: f64,
+}
+
+impl SpeechShaper {
+    fn new(rate: f64) -> Self {
+        let coeff = |corner: f64| 1.0 - (-TAU * corner / rate).exp();
+        let mut shaper = Self {
+            highpass_coeff: coeff(SPEECH_HIGHPASS),
+            lowpass_coeff: coeff(SPEECH_LOWPASS),
+            rumble: 0.0,
+            lowpass: 0.0,
+            gain: 1.0,
+        };
+        // The filter's level depends on the rate, so measure it on a few seconds of noise
+        let mut noise = Noise::new();
+        let frames = (rate * 4.0) as usize;
+        let power = (0..frames)
+            .map(|_| shaper.shape(noise.pink()).powi(2))
+            .sum::<f64>()
+            / frames as f64;
+        shaper.gain = 1.0 / power.sqrt();
+        shaper.rumble = 0.0;
+        shaper.lowpass = 0.0;
+        shaper
+    }
+
+    fn shape(&mut self, pink: f64) -> f64 {
+        self.rumble += self.highpass_coeff * (pink - self.rumble);
+        self.lowpass += self.lowpass_coeff * (pink - self.rumble - self.lowpass);
+        self.lowpass * self.gain
+    }
+}
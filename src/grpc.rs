@@ -0,0 +1,205 @@
+//! gRPC ingest for `--listen-grpc` (`AudioIngest` in `proto/ingest.proto`).
+//!
+//! Clients call the client-streaming `PushAudio` RPC and send chunks of raw
+//! PCM. A chunk is only taken off the stream once the playback queue has room
+//! for it, so HTTP/2 flow control paces senders to playback speed. Chunk
+//! timestamps place the audio on the sender's timeline: gaps become silence
+//! and overlaps are trimmed. As with `--listen-ws`, only the most recent
+//! stream is played.
+
+use crate::feed::{Feed, RatedChunk};
+use crate::raw::RawFormat;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::{Request, Response, Status, Streaming};
+use tracing::{debug, info, warn};
+
+mod proto {
+    tonic::include_proto!("virtualmic");
+}
+
+use proto::audio_ingest_server::{AudioIngest, AudioIngestServer};
+use proto::{AudioChunk, PushSummary, SampleFormat};
+
+/// How often a sender blocked on a full queue checks again.
+const QUEUE_POLL: Duration = Duration::from_millis(5);
+
+/// Timestamp jitter tolerated before a gap or overlap is acted on.
+const TOLERANCE_US: u64 = 5_000;
+
+/// Gaps longer than this are taken as the sender restarting its clock rather
+/// than as missing audio, and are not filled.
+const MAX_GAP_US: u64 = 2_000_000;
+
+/// Bind the listener and serve the ingest service on a background thread.
+pub fn listen(addr: SocketAddr) -> io::Result<Feed> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    info!("gRPC ingest listening on {}", listener.local_addr()?);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let (tx, feed) = Feed::channel();
+    let service = Ingest {
+        chunks: tx,
+        active: Arc::new(AtomicU64::new(0)),
+    };
+    std::thread::Builder::new()
+        .name("grpc".into())
+        .spawn(move || {
+            runtime.block_on(async move {
+                let listener = match tokio::net::TcpListener::from_std(listener) {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        warn!("gRPC listener failed: {}", e);
+                        return;
+                    }
+                };
+                let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+                if let Err(e) = tonic::transport::Server::builder()
+                    .add_service(AudioIngestServer::new(service))
+                    .serve_with_incoming(incoming)
+                    .await
+                {
+                    warn!("gRPC server stopped: {}", e);
+                }
+            })
+        })?;
+    Ok(feed)
+}
+
+fn raw_format(format: SampleFormat) -> RawFormat {
+    match format {
+        SampleFormat::S16le => RawFormat::S16le,
+        SampleFormat::U8 => RawFormat::U8,
+        SampleFormat::S24le => RawFormat::S24le,
+        SampleFormat::S32le => RawFormat::S32le,
+        SampleFormat::F32le => RawFormat::F32le,
+    }
+}
+
+fn frames_to_us(frames: usize, rate: u32) -> u64 {
+    frames as u64 * 1_000_000 / u64::from(rate)
+}
+
+fn us_to_frames(us: u64, rate: u32) -> usize {
+    (us * u64::from(rate) / 1_000_000) as usize
+}
+
+struct Ingest {
+    chunks: SyncSender<RatedChunk>,
+    /// Number of the most recent stream; older streams stop when it changes.
+    active: Arc<AtomicU64>,
+}
+
+impl Ingest {
+    /// Queue a chunk, waiting for room without blocking the runtime.
+    async fn send(&self, mut chunk: RatedChunk, stream: u64) -> Result<(), Status> {
+        loop {
+            if self.active.load(Ordering::SeqCst) != stream {
+                info!("gRPC stream replaced by a newer one");
+                return Err(Status::aborted("replaced by a newer stream"));
+            }
+            match self.chunks.try_send(chunk) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(pending)) => {
+                    chunk = pending;
+                    tokio::time::sleep(QUEUE_POLL).await;
+                }
+                // Playback has moved on to another input
+                Err(TrySendError::Disconnected(_)) => {
+                    return Err(Status::unavailable("playback has stopped"))
+                }
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AudioIngest for Ingest {
+    async fn push_audio(
+        &self,
+        request: Request<Streaming<AudioChunk>>,
+    ) -> Result<Response<PushSummary>, Status> {
+        let peer = request.remote_addr();
+        let stream = self.active.fetch_add(1, Ordering::SeqCst) + 1;
+        info!("gRPC stream started: {:?}", peer);
+
+        let mut messages = request.into_inner();
+        let mut summary = PushSummary::default();
+        // Sender timestamp at which the next chunk is expected to start
+        let mut expected: Option<u64> = None;
+
+        while let Some(chunk) = messages.message().await? {
+            let format = raw_format(chunk.format());
+            let (rate, channels) = (chunk.sample_rate, chunk.channels as usize);
+            if !(1000..=384_000).contains(&rate) || !(1..=32).contains(&channels) {
+                return Err(Status::invalid_argument(format!(
+                    "unsupported format: {} Hz, {} channels",
+                    rate, channels
+                )));
+            }
+            let frame_bytes = format.bytes_per_sample() * channels;
+            if chunk.pcm.len() % frame_bytes != 0 {
+                return Err(Status::invalid_argument(format!(
+                    "chunk of {} bytes doesn't hold whole {}-byte frames",
+                    chunk.pcm.len(),
+                    frame_bytes
+                )));
+            }
+            let mut pcm = &chunk.pcm[..];
+            summary.chunks += 1;
+
+            let start = match (chunk.timestamp_us, expected) {
+                (0, Some(expected)) => expected,
+                (0, None) => 0,
+                (timestamp, Some(expected)) if timestamp > expected + TOLERANCE_US => {
+                    let gap = timestamp - expected;
+                    if gap <= MAX_GAP_US {
+                        debug!("Filling a {} us gap in the gRPC stream", gap);
+                        let frames = us_to_frames(gap, rate);
+                        summary.gap_frames += frames as u64;
+                        self.send((vec![0.0; frames * channels], channels, Some(rate)), stream)
+                            .await?;
+                    }
+                    timestamp
+                }
+                (timestamp, Some(expected)) if timestamp + TOLERANCE_US < expected => {
+                    // Drop the part that overlaps audio already queued
+                    let overlap = us_to_frames(expected - timestamp, rate) * frame_bytes;
+                    if overlap >= pcm.len() {
+                        debug!("Dropping a late gRPC chunk");
+                        summary.late_chunks += 1;
+                        continue;
+                    }
+                    pcm = &pcm[overlap..];
+                    expected
+                }
+                (timestamp, _) => timestamp,
+            };
+
+            let frames = pcm.len() / frame_bytes;
+            expected = Some(start + frames_to_us(frames, rate));
+            if frames == 0 {
+                continue;
+            }
+            summary.frames += frames as u64;
+            let samples = pcm
+                .chunks_exact(format.bytes_per_sample())
+                .map(|sample| format.decode(sample))
+                .collect();
+            self.send((samples, channels, Some(rate)), stream).await?;
+        }
+
+        info!(
+            "gRPC stream finished: {:?} ({} chunks, {} frames)",
+            peer, summary.chunks, summary.frames
+        );
+        Ok(Response::new(summary))
+    }
+}
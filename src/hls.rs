@@ -0,0 +1,328 @@
+//! HTTP Live Streaming input.
+//!
+//! A background thread follows the media playlist, downloads each new
+//! segment in order and hands the audio to the decoder as one continuous
+//! stream. Transport stream segments are demuxed first; packed audio segments
+//! (`.aac`, `.mp3`) only lose their ID3 header. Playback starts once a couple
+//! of segments are buffered, which absorbs jitter in segment delivery.
+
+use crate::http;
+use crate::ts;
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Read};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Segments downloaded before playback starts.
+const PREBUFFER_SEGMENTS: usize = 2;
+
+/// Segments queued ahead of the decoder before downloading pauses.
+const QUEUE_SEGMENTS: usize = 3;
+
+/// How far from the live edge playback starts, in segments.
+const LIVE_EDGE_SEGMENTS: usize = 3;
+
+/// Consecutive playlist or segment failures tolerated before giving up.
+const MAX_FAILURES: u32 = 10;
+
+/// Whether playlist text is HLS rather than a plain list of files.
+pub fn is_hls(contents: &str) -> bool {
+    contents.contains("#EXT-X-TARGETDURATION") || contents.contains("#EXT-X-STREAM-INF")
+}
+
+/// The value of an `ATTRIBUTE=value` pair in a tag's attribute list.
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let (_, list) = tag.split_once(':')?;
+    let mut rest = list;
+    while !rest.is_empty() {
+        let (key, value) = rest.split_once('=')?;
+        let (value, tail) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"')?;
+                (&quoted[..end], quoted[end + 1..].trim_start_matches(','))
+            }
+            None => value.split_once(',').unwrap_or((value, "")),
+        };
+        if key.trim() == name {
+            return Some(value);
+        }
+        rest = tail;
+    }
+    None
+}
+
+/// Pick the media playlist to follow from a master playlist: an audio-only
+/// rendition if there is one, otherwise the lowest-bandwidth variant.
+fn select_variant(url: &str, contents: &str) -> Option<String> {
+    let audio = contents
+        .lines()
+        .filter(|line| line.starts_with("#EXT-X-MEDIA:"))
+        .filter(|line| attribute(line, "TYPE") == Some("AUDIO"))
+        .find_map(|line| attribute(line, "URI"));
+    if let Some(uri) = audio {
+        return Some(http::resolve(url, uri));
+    }
+
+    let mut lines = contents.lines().map(str::trim);
+    let mut best: Option<(u64, &str)> = None;
+    while let Some(line) = lines.next() {
+        if !line.starts_with("#EXT-X-STREAM-INF:") {
+            continue;
+        }
+        let bandwidth = attribute(line, "BANDWIDTH")
+            .and_then(|b| b.parse().ok())
+            .unwrap_or(u64::MAX);
+        let Some(uri) = lines.next() else { break };
+        if best.is_none_or(|(lowest, _)| bandwidth < lowest) {
+            best = Some((bandwidth, uri));
+        }
+    }
+    best.map(|(_, uri)| http::resolve(url, uri))
+}
+
+struct MediaPlaylist {
+    target_duration: Duration,
+    /// Sequence number of the first segment listed.
+    media_sequence: u64,
+    segments: Vec<String>,
+    /// `#EXT-X-ENDLIST` was present: nothing more will be added.
+    ended: bool,
+}
+
+fn parse_media_playlist(url: &str, contents: &str) -> Result<MediaPlaylist> {
+    let mut playlist = MediaPlaylist {
+        target_duration: Duration::from_secs(6),
+        media_sequence: 0,
+        segments: Vec::new(),
+        ended: false,
+    };
+    for line in contents.lines().map(str::trim) {
+        if let Some(value) = line.strip_prefix("#EXT-X-TARGETDURATION:") {
+            if let Ok(secs) = value.parse::<f64>() {
+                playlist.target_duration = Duration::from_secs_f64(secs.max(1.0));
+            }
+        } else if let Some(value) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            playlist.media_sequence = value.parse().unwrap_or(0);
+        } else if line == "#EXT-X-ENDLIST" {
+            playlist.ended = true;
+        } else if line.starts_with("#EXT-X-KEY:") {
+            if attribute(line, "METHOD").is_some_and(|method| method != "NONE") {
+                return Err(anyhow!("Encrypted HLS streams are not supported"));
+            }
+        } else if line.starts_with("#EXT-X-MAP:") {
+            return Err(anyhow!("HLS streams with fMP4 segments are not supported"));
+        } else if !line.is_empty() && !line.starts_with('#') {
+            playlist.segments.push(http::resolve(url, line));
+        }
+    }
+    Ok(playlist)
+}
+
+/// Skip an ID3v2 tag, which packed audio segments start with.
+fn strip_id3(data: &[u8]) -> &[u8] {
+    if data.len() < 10 || &data[..3] != b"ID3" {
+        return data;
+    }
+    // The size is "syncsafe": 7 bits per byte
+    let size = data[6..10]
+        .iter()
+        .fold(0usize, |size, byte| size << 7 | usize::from(byte & 0x7f));
+    data.get(10 + size..).unwrap_or_default()
+}
+
+/// Guess the probe hint for a packed audio segment from its first frame header.
+fn sniff_extension(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0xff, b, ..] if b & 0xf6 == 0xf0 => Some("aac"),
+        [0xff, b, ..] if b & 0xe0 == 0xe0 => Some("mp3"),
+        _ => None,
+    }
+}
+
+/// A segment's audio, ready for the decoder, plus a probe hint for it.
+fn extract_audio(data: Vec<u8>) -> Result<(Vec<u8>, Option<&'static str>)> {
+    if ts::is_transport_stream(&data) {
+        let (audio, codec) = ts::demux_audio(&data)
+            .ok_or_else(|| anyhow!("HLS segment has no AAC or MP3 audio stream"))?;
+        return Ok((audio, Some(codec.extension())));
+    }
+    let audio = strip_id3(&data);
+    let extension = sniff_extension(audio);
+    Ok((audio.to_vec(), extension))
+}
+
+/// The audio of an HLS stream, read segment by segment.
+pub struct HlsStream {
+    // Behind a mutex only because media sources must be `Sync`
+    segments: Mutex<Receiver<Vec<u8>>>,
+    /// Segments downloaded before playback started.
+    prebuffered: VecDeque<Vec<u8>>,
+    current: Cursor<Vec<u8>>,
+    extension: Option<&'static str>,
+}
+
+impl HlsStream {
+    /// Resolve the media playlist and buffer the first segments.
+    pub fn open(url: &str) -> Result<Self> {
+        let mut media_url = url.to_string();
+        let mut contents = http::fetch_text(url)?;
+        if !is_hls(&contents) {
+            return Err(anyhow!("{} is not an HLS playlist", url));
+        }
+        if contents.contains("#EXT-X-STREAM-INF") {
+            media_url = select_variant(url, &contents)
+                .ok_or_else(|| anyhow!("No playable variant in {}", url))?;
+            debug!("HLS variant: {}", media_url);
+            contents = http::fetch_text(&media_url)?;
+        }
+
+        let playlist = parse_media_playlist(&media_url, &contents)?;
+        if playlist.segments.is_empty() {
+            return Err(anyhow!("HLS playlist {} has no segments", media_url));
+        }
+        // Live streams start a few segments back from the edge, VOD from the start
+        let skip = if playlist.ended {
+            0
+        } else {
+            playlist.segments.len().saturating_sub(LIVE_EDGE_SEGMENTS)
+        };
+        info!(
+            "Streaming HLS {} ({})",
+            url,
+            if playlist.ended { "on demand" } else { "live" }
+        );
+
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SEGMENTS);
+        let (first_tx, first_rx) = mpsc::channel();
+        let follower = Follower {
+            url: media_url,
+            next_sequence: playlist.media_sequence + skip as u64,
+            segments: tx,
+        };
+        std::thread::Builder::new()
+            .name("hls".into())
+            .spawn(move || follower.run(playlist, first_tx))?;
+
+        // The first segment decides the format; wait for a small buffer before playing
+        let extension = first_rx
+            .recv()
+            .map_err(|_| anyhow!("Failed to download the first HLS segment"))?;
+        let prebuffered: VecDeque<_> = rx.iter().take(PREBUFFER_SEGMENTS).collect();
+
+        Ok(Self {
+            segments: Mutex::new(rx),
+            prebuffered,
+            current: Cursor::new(Vec::new()),
+            extension,
+        })
+    }
+
+    /// Probe hint for the audio inside the segments.
+    pub fn extension(&self) -> Option<&'static str> {
+        self.extension
+    }
+}
+
+impl Read for HlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let next = match self.prebuffered.pop_front() {
+                Some(segment) => segment,
+                // The follower hung up: the stream ended or failed for good
+                None => match self.segments.get_mut().unwrap().recv() {
+                    Ok(segment) => segment,
+                    Err(_) => return Ok(0),
+                },
+            };
+            self.current = Cursor::new(next);
+        }
+    }
+}
+
+/// Follows a media playlist, sending each new segment's audio in order.
+struct Follower {
+    url: String,
+    next_sequence: u64,
+    segments: SyncSender<Vec<u8>>,
+}
+
+impl Follower {
+    fn run(mut self, mut playlist: MediaPlaylist, first: mpsc::Sender<Option<&'static str>>) {
+        let mut first = Some(first);
+        let mut failures = 0;
+
+        loop {
+            let mut added = false;
+            for (i, segment_url) in playlist.segments.iter().enumerate() {
+                let sequence = playlist.media_sequence + i as u64;
+                if sequence < self.next_sequence {
+                    continue;
+                }
+                let audio = http::fetch_bytes(segment_url).and_then(extract_audio);
+                self.next_sequence = sequence + 1;
+                added = true;
+                match audio {
+                    Ok((audio, extension)) => {
+                        failures = 0;
+                        if let Some(first) = first.take() {
+                            let _ = first.send(extension);
+                        }
+                        if self.segments.send(audio).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Skipping HLS segment {}: {}", sequence, e);
+                        failures += 1;
+                        if failures >= MAX_FAILURES {
+                            return;
+                        }
+                    }
+                }
+            }
+
+            if playlist.ended {
+                return;
+            }
+
+            // Reload after a target duration, or half that if nothing was new
+            let wait = if added {
+                playlist.target_duration
+            } else {
+                playlist.target_duration / 2
+            };
+            std::thread::sleep(wait);
+
+            match http::fetch_text(&self.url)
+                .and_then(|text| parse_media_playlist(&self.url, &text))
+            {
+                Ok(reloaded) => {
+                    if reloaded.media_sequence > self.next_sequence {
+                        warn!(
+                            "HLS playback fell behind; skipping {} segments",
+                            reloaded.media_sequence - self.next_sequence
+                        );
+                        self.next_sequence = reloaded.media_sequence;
+                    }
+                    playlist = reloaded;
+                }
+                Err(e) => {
+                    warn!("Failed to reload HLS playlist: {}", e);
+                    failures += 1;
+                    if failures >= MAX_FAILURES {
+                        return;
+                    }
+                    // Don't fetch the same segments again
+                    playlist.segments.clear();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! `--on-track-start` and `--on-track-end`: shell commands run as each track
+//! starts and ends, for keeping other tools (a screen recorder, a test
+//! harness) in step with playback.
+//!
+//! Commands run through `sh -c` in the background, so a slow one doesn't hold
+//! up the audio. The track is described in the environment:
+//!
+//! | Variable | Value |
+//! |----------|-------|
+//! | `VIRTUAL_MIC_EVENT` | `track-start` or `track-end` |
+//! | `VIRTUAL_MIC_NAME` | The virtual microphone's name |
+//! | `VIRTUAL_MIC_TRACK_PATH` | The file, URL or input name |
+//! | `VIRTUAL_MIC_TRACK_INDEX` | 1-based playlist position |
+//! | `VIRTUAL_MIC_TRACK_COUNT` | Playlist length |
+//! | `VIRTUAL_MIC_TRACK_TITLE` | The cue sheet track's title, if it is one |
+//! | `VIRTUAL_MIC_TRACK_DURATION` | Length in seconds, if known |
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// The track a hook runs for.
+pub struct Track {
+    pub path: PathBuf,
+    pub index: usize,
+    pub count: usize,
+    pub title: Option<String>,
+    pub duration: Option<Duration>,
+}
+
+/// The commands to run, if any were given.
+#[derive(Debug, Clone, Default)]
+pub struct TrackHooks {
+    name: String,
+    on_start: Option<String>,
+    on_end: Option<String>,
+}
+
+impl TrackHooks {
+    pub fn new(name: &str, on_start: Option<String>, on_end: Option<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            on_start,
+            on_end,
+        }
+    }
+
+    /// Run `--on-track-start` for `track`.
+    pub fn started(&self, track: &Track) {
+        if let Some(command) = &self.on_start {
+            self.run(command, "track-start", track);
+        }
+    }
+
+    /// Run `--on-track-end` for `track`.
+    pub fn ended(&self, track: &Track) {
+        if let Some(command) = &self.on_end {
+            self.run(command, "track-end", track);
+        }
+    }
+
+    fn run(&self, command: &str, event: &str, track: &Track) {
+        let mut shell = Command::new("sh");
+        shell
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::null())
+            .env("VIRTUAL_MIC_EVENT", event)
+            .env("VIRTUAL_MIC_NAME", &self.name)
+            .env("VIRTUAL_MIC_TRACK_PATH", &track.path)
+            .env("VIRTUAL_MIC_TRACK_INDEX", track.index.to_string())
+            .env("VIRTUAL_MIC_TRACK_COUNT", track.count.to_string());
+        if let Some(title) = &track.title {
+            shell.env("VIRTUAL_MIC_TRACK_TITLE", title);
+        }
+        if let Some(duration) = track.duration {
+            shell.env(
+                "VIRTUAL_MIC_TRACK_DURATION",
+                format!("{:.3}", duration.as_secs_f64()),
+            );
+        }
+
+        let mut child = match shell.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Failed to run {} hook `{}`: {}", event, command, e);
+                return;
+            }
+        };
+        debug!("Started {} hook `{}` (pid {})", event, command, child.id());
+        // Wait on another thread, so the exit is reported and the child reaped
+        let command = command.to_string();
+        let event = event.to_string();
+        let waited = std::thread::Builder::new()
+            .name("hook".into())
+            .spawn(move || match child.wait() {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("The {} hook `{}` failed ({})", event, command, status),
+                Err(e) => warn!("Failed to wait for the {} hook `{}`: {}", event, command, e),
+            });
+        if let Err(e) = waited {
+            warn!("Failed to watch the hook: {}", e);
+        }
+    }
+}
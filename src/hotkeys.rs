@@ -0,0 +1,195 @@
+//! `--global-keys`: hotkeys read straight from the input devices, so they
+//! work whichever window has focus, e.g. while a video call is in front.
+//!
+//! Bindings are written `<hotkey>=<command>`, comma-separated, where the
+//! hotkey is a key name (see [`crate::evdev::key_code`]) with any of the
+//! `ctrl+`, `alt+`, `shift+` and `meta+` modifiers, and the command is one the
+//! control socket takes: `ctrl+alt+m=toggle-mute,f13=next`.
+
+use crate::control::ControlCommand;
+use crate::evdev;
+use anyhow::Result;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver};
+use tracing::{debug, info};
+
+/// Bindings used by a bare `--global-keys`: the terminal's keys, with Ctrl+Alt.
+pub const DEFAULT_BINDINGS: &str =
+    "ctrl+alt+p=toggle-pause,ctrl+alt+m=toggle-mute,ctrl+alt+n=next,ctrl+alt+b=previous";
+
+/// Modifier names, their bit in [`Hotkey::modifiers`], and the codes of the
+/// left and right keys.
+const MODIFIERS: &[(&str, u8, [u16; 2])] = &[
+    ("ctrl", 1, [29, 97]),
+    ("shift", 2, [42, 54]),
+    ("alt", 4, [56, 100]),
+    ("meta", 8, [125, 126]),
+];
+
+/// A key pressed with exactly these modifiers held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotkey {
+    modifiers: u8,
+    code: u16,
+}
+
+/// A hotkey and the command it sends.
+#[derive(Debug, Clone)]
+pub struct Binding {
+    hotkey: Hotkey,
+    command: ControlCommand,
+    /// The binding as given, for the logs.
+    text: String,
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// The `--global-keys` bindings.
+#[derive(Debug, Clone, Default)]
+pub struct Bindings(Vec<Binding>);
+
+/// Parse `--global-keys` bindings.
+pub fn parse_bindings(s: &str) -> Result<Bindings, String> {
+    let mut bindings = Vec::new();
+    for binding in s.split(',').map(str::trim).filter(|b| !b.is_empty()) {
+        let invalid =
+            |reason: String| format!("invalid --global-keys binding '{}': {}", binding, reason);
+        let (hotkey, command) = binding
+            .split_once('=')
+            .ok_or_else(|| invalid("expected <hotkey>=<command>".to_string()))?;
+        let hotkey = parse_hotkey(hotkey.trim()).map_err(invalid)?;
+        let command = ControlCommand::parse(command).map_err(invalid)?;
+        if bindings.iter().any(|b: &Binding| b.hotkey == hotkey) {
+            return Err(invalid("the hotkey is bound more than once".to_string()));
+        }
+        bindings.push(Binding {
+            hotkey,
+            command,
+            text: binding.to_string(),
+        });
+    }
+    if bindings.is_empty() {
+        return Err("--global-keys needs at least one <hotkey>=<command> binding".to_string());
+    }
+    Ok(Bindings(bindings))
+}
+
+fn parse_hotkey(s: &str) -> Result<Hotkey, String> {
+    let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+    let key = parts.pop().unwrap_or_default();
+    let mut modifiers = 0;
+    for part in parts {
+        let (_, bit, _) = MODIFIERS
+            .iter()
+            .find(|(name, _, _)| name.eq_ignore_ascii_case(part))
+            .ok_or_else(|| format!("unknown modifier '{}'; use ctrl, alt, shift or meta", part))?;
+        modifiers |= bit;
+    }
+    let code = evdev::key_code(key)
+        .ok_or_else(|| format!("unknown key '{}'; use a name like m or f13, or a code", key))?;
+    Ok(Hotkey { modifiers, code })
+}
+
+/// The modifier bit of the key with `code`, if it is a modifier.
+fn modifier(code: u16) -> Option<u8> {
+    MODIFIERS
+        .iter()
+        .find(|(_, _, codes)| codes.contains(&code))
+        .map(|&(_, bit, _)| bit)
+}
+
+/// Watch every input device for the hotkeys, sending their commands.
+pub fn watch(bindings: &Bindings) -> Result<Receiver<ControlCommand>> {
+    let (tx, rx) = mpsc::channel();
+    let bindings = bindings.0.clone();
+    let hotkeys: Vec<String> = bindings.iter().map(Binding::to_string).collect();
+    // Modifiers are followed per device, so each gets its own copy
+    let mut held = 0u8;
+    let watching = evdev::watch_all(None, move |key| {
+        if let Some(bit) = modifier(key.code) {
+            if key.pressed {
+                held |= bit;
+            } else {
+                held &= !bit;
+            }
+            return true;
+        }
+        if !key.pressed {
+            return true;
+        }
+        let pressed = Hotkey {
+            modifiers: held,
+            code: key.code,
+        };
+        match bindings.iter().find(|binding| binding.hotkey == pressed) {
+            Some(binding) => {
+                debug!("Hotkey {}", binding);
+                tx.send(binding.command.clone()).is_ok()
+            }
+            None => true,
+        }
+    })?;
+    info!(
+        "Global keys from {} input device(s): {}",
+        watching,
+        hotkeys.join(", ")
+    );
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hotkeys_with_modifiers() {
+        let bindings = parse_bindings(" Ctrl+ALT+m = toggle-mute , f13=next,,").unwrap();
+        let [mute, next] = bindings.0.as_slice() else {
+            panic!("expected two bindings, got {:?}", bindings);
+        };
+        assert_eq!(
+            mute.hotkey,
+            Hotkey {
+                modifiers: 1 | 4,
+                code: 50
+            }
+        );
+        assert_eq!(mute.command, ControlCommand::ToggleMute);
+        assert_eq!(
+            next.hotkey,
+            Hotkey {
+                modifiers: 0,
+                code: 183
+            }
+        );
+        assert_eq!(next.command, ControlCommand::Next);
+        assert_eq!(parse_bindings(DEFAULT_BINDINGS).unwrap().0.len(), 4);
+    }
+
+    #[test]
+    fn rejects_bad_bindings() {
+        for (bindings, reason) in [
+            ("", "at least one"),
+            ("ctrl+m", "expected <hotkey>=<command>"),
+            ("hyper+m=next", "unknown modifier 'hyper'"),
+            ("ctrl+=next", "unknown key ''"),
+            ("ctrl+m=dance", "ctrl+m=dance"),
+            ("ctrl+m=next,CTRL+M=prev", "bound more than once"),
+        ] {
+            let error = parse_bindings(bindings).unwrap_err();
+            assert!(error.contains(reason), "{:?}: {}", bindings, error);
+        }
+    }
+
+    #[test]
+    fn both_modifier_keys_count() {
+        assert_eq!(modifier(29), Some(1));
+        assert_eq!(modifier(97), Some(1));
+        assert_eq!(modifier(126), Some(8));
+        assert_eq!(modifier(50), None);
+    }
+}
@@ -0,0 +1,449 @@
+//! Streaming HTTP/HTTPS inputs.
+//!
+//! A background thread downloads ahead of the decoder into a bounded buffer,
+//! so short network stalls don't reach the audio thread. Dropped connections
+//! are resumed with a range request; seeks outside the buffer restart the
+//! download at the new offset. Internet radio streams never end: they are
+//! reconnected with backoff for as long as they're being played.
+
+use crate::icy::{self, IcyReader};
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use symphonia::core::io::MediaSource;
+use tracing::{debug, info, warn};
+
+/// Bytes downloaded ahead of the decoder before the download pauses.
+const READ_AHEAD: usize = 1024 * 1024;
+
+/// Bytes requested from the connection per read.
+const DOWNLOAD_CHUNK: usize = 16 * 1024;
+
+/// Reconnection attempts after a dropped connection, with doubling delays.
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+
+/// Longest wait between reconnection attempts to a radio stream.
+const MAX_RADIO_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(15);
+
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|s| s.starts_with("http://") || s.starts_with("https://"))
+}
+
+/// A URL without its query string or fragment, for finding the extension.
+pub fn without_query(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
+
+/// Undo the `%XX` escapes of a URI path or query.
+pub fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        let escaped = (byte == b'%')
+            .then(|| after.get(..2))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &after[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = after;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// The value of `key` in a URL query string, decoded.
+pub fn query_param(query: &str, key: &str) -> Option<String> {
+    query.split('&').find_map(|pair| {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        (name == key).then(|| percent_decode(&value.replace('+', " ")))
+    })
+}
+
+struct Shared {
+    /// Downloaded bytes not yet read, starting at stream offset `start`.
+    buffer: VecDeque<u8>,
+    start: u64,
+    /// Set by a seek outside the buffer; the downloader reconnects there.
+    restart_at: Option<u64>,
+    eof: bool,
+    error: Option<String>,
+    /// Set when the reader is dropped, so the downloader stops.
+    closed: bool,
+}
+
+/// A remote file read through a read-ahead buffer.
+pub struct HttpStream {
+    shared: Arc<(Mutex<Shared>, Condvar)>,
+    position: u64,
+    len: Option<u64>,
+    seekable: bool,
+    content_type: Option<String>,
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .build()
+}
+
+/// Request `url` from byte `offset`, failing if the server ignores the range.
+fn connect(agent: &ureq::Agent, url: &str, offset: u64) -> Result<ureq::Response> {
+    // Radio servers only send titles when asked; everyone else ignores this
+    let mut request = agent.get(url).set("Icy-MetaData", "1");
+    if offset > 0 {
+        request = request.set("Range", &format!("bytes={}-", offset));
+    }
+    let response = request
+        .call()
+        .map_err(|e| anyhow!("HTTP request for {} failed: {}", url, e))?;
+    if offset > 0 && response.status() != 206 {
+        return Err(anyhow!("{} does not support range requests", url));
+    }
+    Ok(response)
+}
+
+/// Download a small text resource, such as a remote playlist.
+pub fn fetch_text(url: &str) -> Result<String> {
+    agent()
+        .get(url)
+        .call()
+        .map_err(|e| anyhow!("HTTP request for {} failed: {}", url, e))?
+        .into_string()
+        .map_err(|e| anyhow!("Failed to read {}: {}", url, e))
+}
+
+/// Download a small binary resource, such as a stream segment.
+pub fn fetch_bytes(url: &str) -> Result<Vec<u8>> {
+    let response = agent()
+        .get(url)
+        .call()
+        .map_err(|e| anyhow!("HTTP request for {} failed: {}", url, e))?;
+    let mut data = Vec::new();
+    response
+        .into_reader()
+        .read_to_end(&mut data)
+        .map_err(|e| anyhow!("Failed to read {}: {}", url, e))?;
+    Ok(data)
+}
+
+/// Resolve a possibly relative reference found in the document at `base`.
+pub fn resolve(base: &str, reference: &str) -> String {
+    if reference.contains("://") {
+        return reference.to_string();
+    }
+    let (scheme, rest) = base.split_once("://").unwrap_or(("http", base));
+    if let Some(network_path) = reference.strip_prefix("//") {
+        return format!("{}://{}", scheme, network_path);
+    }
+    if reference.starts_with('/') {
+        let host = rest.split('/').next().unwrap_or(rest);
+        return format!("{}://{}{}", scheme, host, reference);
+    }
+    let base = without_query(base);
+    let dir = match base.rfind('/') {
+        Some(slash) if slash > scheme.len() + 2 => &base[..=slash],
+        _ => return format!("{}/{}", base, reference),
+    };
+    format!("{}{}", dir, reference)
+}
+
+type BodyReader = Box<dyn Read + Send + Sync>;
+
+/// The response body with any ICY metadata stripped out.
+fn body_reader(response: ureq::Response) -> BodyReader {
+    match icy::metaint(&response) {
+        Some(metaint) => Box::new(IcyReader::new(response.into_reader(), metaint)),
+        None => response.into_reader(),
+    }
+}
+
+impl HttpStream {
+    /// Connect to `url` and start downloading in the background.
+    pub fn open(url: &str) -> Result<Self> {
+        let agent = agent();
+        let response = connect(&agent, url, 0)?;
+
+        let live = icy::is_radio(&response);
+        let len = response
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok())
+            .filter(|_| !live);
+        let seekable = len.is_some() && response.header("Accept-Ranges") == Some("bytes");
+        let content_type = response
+            .header("Content-Type")
+            .map(|mime| mime.split(';').next().unwrap_or(mime).trim().to_string());
+        if live {
+            info!(
+                "Streaming radio {} ({}, {})",
+                response.header("icy-name").unwrap_or(url),
+                content_type.as_deref().unwrap_or("unknown type"),
+                response
+                    .header("icy-br")
+                    .map_or("unknown bitrate".to_string(), |br| format!("{} kbps", br))
+            );
+        } else {
+            info!(
+                "Streaming {} ({}, {})",
+                url,
+                content_type.as_deref().unwrap_or("unknown type"),
+                len.map_or("unknown length".to_string(), |len| format!("{} bytes", len))
+            );
+        }
+
+        let shared = Arc::new((
+            Mutex::new(Shared {
+                buffer: VecDeque::new(),
+                start: 0,
+                restart_at: None,
+                eof: false,
+                error: None,
+                closed: false,
+            }),
+            Condvar::new(),
+        ));
+        let downloader = Downloader {
+            agent,
+            url: url.to_string(),
+            shared: shared.clone(),
+            resumable: seekable,
+            live,
+        };
+        std::thread::Builder::new()
+            .name("http".into())
+            .spawn(move || downloader.run(response))?;
+
+        Ok(Self {
+            shared,
+            position: 0,
+            len,
+            seekable,
+            content_type,
+        })
+    }
+
+    /// The mime type reported by the server, without parameters.
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+}
+
+impl Read for HttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (lock, wake) = &*self.shared;
+        let mut shared = lock.lock().unwrap();
+        while shared.buffer.is_empty() && !shared.eof && shared.error.is_none() {
+            shared = wake.wait(shared).unwrap();
+        }
+        if shared.buffer.is_empty() {
+            return match &shared.error {
+                Some(e) => Err(io::Error::other(e.clone())),
+                None => Ok(0),
+            };
+        }
+
+        let n = buf.len().min(shared.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(shared.buffer.drain(..n)) {
+            *dst = src;
+        }
+        shared.start += n as u64;
+        self.position += n as u64;
+        wake.notify_all();
+        Ok(n)
+    }
+}
+
+impl Seek for HttpStream {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.len.and_then(|len| len.checked_add_signed(delta)),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))?;
+        if target == self.position {
+            return Ok(target);
+        }
+
+        let (lock, wake) = &*self.shared;
+        let mut shared = lock.lock().unwrap();
+        let buffered_end = shared.start + shared.buffer.len() as u64;
+        if target > shared.start && target <= buffered_end {
+            // Already downloaded: just skip ahead
+            let skip = (target - shared.start) as usize;
+            shared.buffer.drain(..skip);
+            shared.start = target;
+        } else if self.seekable {
+            debug!("HTTP seek to byte {}", target);
+            shared.buffer.clear();
+            shared.start = target;
+            shared.restart_at = Some(target);
+            shared.eof = false;
+            shared.error = None;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "server does not support seeking",
+            ));
+        }
+        wake.notify_all();
+        self.position = target;
+        Ok(target)
+    }
+}
+
+impl MediaSource for HttpStream {
+    fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.len
+    }
+}
+
+impl Drop for HttpStream {
+    fn drop(&mut self) {
+        let (lock, wake) = &*self.shared;
+        lock.lock().unwrap().closed = true;
+        wake.notify_all();
+    }
+}
+
+struct Downloader {
+    agent: ureq::Agent,
+    url: String,
+    shared: Arc<(Mutex<Shared>, Condvar)>,
+    /// Whether a dropped connection can be picked up where it left off.
+    resumable: bool,
+    /// An endless radio stream, reconnected whenever it drops.
+    live: bool,
+}
+
+impl Downloader {
+    fn run(self, response: ureq::Response) {
+        let mut reader = body_reader(response);
+        let mut offset = 0;
+        let mut chunk = vec![0; DOWNLOAD_CHUNK];
+
+        loop {
+            // Wait for room in the buffer, a seek, or the reader going away
+            {
+                let (lock, wake) = &*self.shared;
+                let mut shared = lock.lock().unwrap();
+                while !shared.closed
+                    && shared.restart_at.is_none()
+                    && (shared.eof || shared.buffer.len() >= READ_AHEAD)
+                {
+                    shared = wake.wait(shared).unwrap();
+                }
+                if shared.closed {
+                    return;
+                }
+                if let Some(restart) = shared.restart_at.take() {
+                    offset = restart;
+                    drop(shared);
+                    match self.reconnect(offset) {
+                        Some(new_reader) => reader = new_reader,
+                        None => continue,
+                    }
+                }
+            }
+
+            match reader.read(&mut chunk) {
+                Ok(0) | Err(_) if self.live => {
+                    warn!("Radio stream dropped; reconnecting");
+                    match self.reconnect_live() {
+                        Some(new_reader) => reader = new_reader,
+                        None => return,
+                    }
+                }
+                Ok(0) => self.finish(None),
+                Ok(n) => {
+                    let (lock, wake) = &*self.shared;
+                    let mut shared = lock.lock().unwrap();
+                    // A seek while reading makes this data stale
+                    if shared.restart_at.is_none() {
+                        shared.buffer.extend(&chunk[..n]);
+                        offset += n as u64;
+                        wake.notify_all();
+                    }
+                }
+                Err(e) if self.resumable => {
+                    warn!("HTTP stream interrupted at byte {}: {}", offset, e);
+                    match self.reconnect(offset) {
+                        Some(new_reader) => reader = new_reader,
+                        None => continue,
+                    }
+                }
+                Err(e) => self.finish(Some(e.to_string())),
+            }
+        }
+    }
+
+    /// Reconnect at `offset`, retrying with backoff; records the error and
+    /// returns `None` if every attempt fails.
+    fn reconnect(&self, offset: u64) -> Option<BodyReader> {
+        let mut delay = RECONNECT_DELAY;
+        let mut last_error = String::new();
+        for attempt in 1..=RECONNECT_ATTEMPTS {
+            match connect(&self.agent, &self.url, offset) {
+                Ok(response) => return Some(body_reader(response)),
+                Err(e) => {
+                    warn!(
+                        "Reconnect attempt {}/{} failed: {}",
+                        attempt, RECONNECT_ATTEMPTS, e
+                    );
+                    last_error = e.to_string();
+                }
+            }
+            std::thread::sleep(delay);
+            delay *= 2;
+        }
+        self.finish(Some(last_error));
+        None
+    }
+
+    /// Reconnect to a radio stream until it answers; returns `None` only once
+    /// the stream is no longer being played.
+    fn reconnect_live(&self) -> Option<BodyReader> {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            if self.shared.0.lock().unwrap().closed {
+                return None;
+            }
+            match connect(&self.agent, &self.url, 0) {
+                Ok(response) => {
+                    info!("Reconnected to {}", self.url);
+                    return Some(body_reader(response));
+                }
+                Err(e) => warn!("Reconnect failed, retrying in {:?}: {}", delay, e),
+            }
+            std::thread::sleep(delay);
+            delay = (delay * 2).min(MAX_RADIO_RECONNECT_DELAY);
+        }
+    }
+
+    /// Mark the end of the download, cleanly or with an error.
+    fn finish(&self, error: Option<String>) {
+        let (lock, wake) = &*self.shared;
+        let mut shared = lock.lock().unwrap();
+        shared.eof = true;
+        shared.error = error;
+        wake.notify_all();
+    }
+}
@@ -0,0 +1,81 @@
+//! ICY (Icecast/Shoutcast) in-band metadata.
+//!
+//! When a client sends `Icy-MetaData: 1`, radio servers interleave a metadata
+//! block every `icy-metaint` bytes of audio: one length byte (in units of 16
+//! bytes) followed by text such as `StreamTitle='Artist - Song';`. The blocks
+//! have to be removed before the audio reaches the decoder.
+
+use std::io::{self, Read};
+use tracing::info;
+
+/// Audio bytes between metadata blocks, when the server interleaves them.
+pub fn metaint(response: &ureq::Response) -> Option<usize> {
+    response
+        .header("icy-metaint")
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|&metaint| metaint > 0)
+}
+
+/// Whether the response is an internet radio stream rather than a file.
+pub fn is_radio(response: &ureq::Response) -> bool {
+    metaint(response).is_some() || response.header("icy-name").is_some()
+}
+
+/// The `StreamTitle` value of a metadata block, if it has one.
+fn stream_title(block: &str) -> Option<&str> {
+    let start = block.find("StreamTitle='")? + "StreamTitle='".len();
+    let len = block[start..].find("';")?;
+    Some(&block[start..start + len])
+}
+
+/// Passes the audio through, logging and dropping the metadata blocks.
+pub struct IcyReader<R> {
+    inner: R,
+    metaint: usize,
+    /// Audio bytes left before the next metadata block.
+    until_metadata: usize,
+    title: String,
+}
+
+impl<R: Read> IcyReader<R> {
+    pub fn new(inner: R, metaint: usize) -> Self {
+        Self {
+            inner,
+            metaint,
+            until_metadata: metaint,
+            title: String::new(),
+        }
+    }
+
+    fn read_metadata(&mut self) -> io::Result<()> {
+        let mut len = [0u8; 1];
+        self.inner.read_exact(&mut len)?;
+        let mut block = vec![0u8; len[0] as usize * 16];
+        self.inner.read_exact(&mut block)?;
+
+        // Empty blocks mean "unchanged"
+        let block = String::from_utf8_lossy(&block);
+        if let Some(title) = stream_title(&block) {
+            if title != self.title {
+                if !title.is_empty() {
+                    info!("Now playing: {}", title);
+                }
+                self.title = title.to_string();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for IcyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.until_metadata == 0 {
+            self.read_metadata()?;
+            self.until_metadata = self.metaint;
+        }
+        let len = buf.len().min(self.until_metadata);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.until_metadata -= n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,497 @@
+//! Per-instance state files, so a running virtual mic can be stopped by name.
+//!
+//! Each instance records its PID and the pactl modules it loaded in
+//! `$XDG_RUNTIME_DIR/virtual-mic/<name>.state`. `virtual-mic stop <name>` reads
+//! that file, asks the process to shut down, and unloads the modules itself if
+//! the process is already gone.
+//!
+//! Before loading anything, an instance takes a lock on `<name>.pid`, which
+//! holds its PID, so two instances can't create the same microphone even if
+//! started at the same moment. The lock goes with the process, however it
+//! exits.
+
+use crate::Pactl;
+use anyhow::{anyhow, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How long `stop` waits for the process to exit after signalling it.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Delay between checks while waiting for the process to exit.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What a running instance records about itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstanceState {
+    pub name: String,
+    pub pid: u32,
+    /// Module IDs in the order they should be unloaded.
+    pub modules: Vec<u32>,
+}
+
+impl InstanceState {
+    /// Write the state file, returning a guard that removes it when dropped.
+    pub fn write(&self) -> Result<StateFile> {
+        let path = state_path(&self.name);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let modules: Vec<String> = self.modules.iter().map(u32::to_string).collect();
+        let contents = format!(
+            "name={}\npid={}\nmodules={}\n",
+            self.name,
+            self.pid,
+            modules.join(" ")
+        );
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(StateFile { path })
+    }
+
+    /// Read a state file written by [`InstanceState::write`].
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::parse(&contents).ok_or_else(|| anyhow!("Malformed state file {:?}", path))
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let (mut name, mut pid, mut modules) = (None, None, None);
+        for line in contents.lines() {
+            match line.split_once('=') {
+                Some(("name", value)) => name = Some(value.to_string()),
+                Some(("pid", value)) => pid = value.trim().parse().ok(),
+                Some(("modules", value)) => {
+                    modules = value
+                        .split_whitespace()
+                        .map(str::parse)
+                        .collect::<Result<Vec<u32>, _>>()
+                        .ok()
+                }
+                _ => {}
+            }
+        }
+        Some(Self {
+            name: name?,
+            pid: pid?,
+            modules: modules?,
+        })
+    }
+}
+
+/// Removes the state file when the owning instance shuts down.
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl Drop for StateFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Check an instance name for use in file names: no directories, and not
+/// hidden.
+pub fn parse_name(name: &str) -> Result<String, String> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        return Err(format!(
+            "invalid name '{}': it can't be empty, contain / or start with .",
+            name
+        ));
+    }
+    Ok(name.to_string())
+}
+
+fn state_dir() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("virtual-mic")
+}
+
+fn state_path(name: &str) -> PathBuf {
+    state_dir().join(format!("{}.state", name))
+}
+
+fn pid_path(name: &str) -> PathBuf {
+    state_dir().join(format!("{}.pid", name))
+}
+
+/// Names of all instances that have a state file.
+fn known_instances() -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(state_dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            match path.extension() {
+                Some(ext) if ext == "state" => {
+                    Some(path.file_stem()?.to_string_lossy().into_owned())
+                }
+                _ => None,
+            }
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+/// Whether the instance called `name` is alive. It holds the lock on
+/// `<name>.pid` for as long as it runs, which a process that was handed its
+/// PID later doesn't.
+fn is_running(name: &str) -> bool {
+    let Ok(file) = File::open(pid_path(name)) else {
+        return false;
+    };
+    // SAFETY: flock on a file this function owns; closing it drops the lock
+    let locked = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH | libc::LOCK_NB) } != 0;
+    locked && std::io::Error::last_os_error().raw_os_error() == Some(libc::EWOULDBLOCK)
+}
+
+/// Holds the lock on `<name>.pid`; empties the file when dropped.
+///
+/// The file itself stays: removing it would let the next instance lock a new
+/// file of that name while another still waits on the old one.
+pub struct PidFile {
+    file: File,
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+    }
+}
+
+/// Claim `name` for this process, refusing if another instance holds it.
+///
+/// What an instance that died left behind is adopted: its modules are
+/// unloaded, so the microphone isn't listed twice. A source of that name
+/// that no instance made is refused as well.
+pub fn lock(name: &str, pactl: Pactl) -> Result<PidFile> {
+    let path = pid_path(name);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .map_err(|e| anyhow!("Failed to open {:?}: {}", path, e))?;
+    // SAFETY: flock on a file this function owns
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let mut pid = String::new();
+        let _ = file.read_to_string(&mut pid);
+        return Err(anyhow!(
+            "'{}' is already running (PID {}); stop it first with `virtual-mic stop {}`",
+            name,
+            pid.trim(),
+            name
+        ));
+    }
+
+    // From here on, returning an error empties the file again
+    let mut pid_file = PidFile { file };
+    adopt_leftovers(name, pactl)?;
+    check_source_free(name, pactl)?;
+
+    pid_file.file.set_len(0)?;
+    pid_file.file.rewind()?;
+    writeln!(pid_file.file, "{}", std::process::id())?;
+    Ok(pid_file)
+}
+
+/// Clean up after an instance under `name` that died. Holding the lock
+/// proves it did, whatever the process with its recorded PID is now.
+fn adopt_leftovers(name: &str, pactl: Pactl) -> Result<()> {
+    let path = state_path(name);
+    if !path.exists() {
+        return Ok(());
+    }
+
+    match InstanceState::read(&path) {
+        Ok(state) => {
+            info!(
+                "Cleaning up after '{}' (PID {}), which is no longer running",
+                name, state.pid
+            );
+            unload_leftover_modules(&state, pactl)?;
+            std::fs::remove_file(&path)?;
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Ignoring unreadable state file: {}", e);
+            Ok(())
+        }
+    }
+}
+
+/// Refuse a name some other program's source already has, since
+/// applications couldn't tell the two microphones apart.
+fn check_source_free(name: &str, pactl: Pactl) -> Result<()> {
+    let output = pactl.run(&["list", "sources", "short"])?;
+    if output.status.success()
+        && crate::parse_source_names(&output.stdout)
+            .iter()
+            .any(|s| s == name)
+    {
+        return Err(anyhow!(
+            "A source named '{}' already exists; choose another --name",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Stop the instance called `name`, cleaning up after it if it already died.
+pub fn stop(name: &str, pactl: Pactl) -> Result<()> {
+    // State files are keyed by the exact name, so "Mic" never matches "Mic2"
+    let path = state_path(name);
+    if !path.exists() {
+        let running = known_instances();
+        return Err(if running.is_empty() {
+            anyhow!("No instance named '{}' is running", name)
+        } else {
+            anyhow!(
+                "No instance named '{}' is running (known instances: {})",
+                name,
+                running.join(", ")
+            )
+        });
+    }
+
+    let state = InstanceState::read(&path)?;
+
+    if is_running(name) {
+        info!("Stopping '{}' (PID {})...", name, state.pid);
+        // SAFETY: sending a signal has no memory effects here
+        if unsafe { libc::kill(state.pid as libc::pid_t, libc::SIGTERM) } != 0 {
+            return Err(anyhow!(
+                "Failed to signal PID {}: {}",
+                state.pid,
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while is_running(name) {
+            if Instant::now() >= deadline {
+                return Err(anyhow!(
+                    "'{}' (PID {}) did not exit within {:?}",
+                    name,
+                    state.pid,
+                    STOP_TIMEOUT
+                ));
+            }
+            std::thread::sleep(STOP_POLL_INTERVAL);
+        }
+
+        // A clean shutdown unloads its own modules and removes the state file
+        if !path.exists() {
+            info!("Stopped '{}'", name);
+            return Ok(());
+        }
+    } else {
+        info!("'{}' (PID {}) is no longer running", name, state.pid);
+    }
+
+    unload_leftover_modules(&state, pactl)?;
+    std::fs::remove_file(&path)?;
+    info!("Cleaned up '{}'", name);
+    Ok(())
+}
+
+/// Unload the modules recorded in `state` that are still loaded.
+///
+/// Only modules that still name the instance's sink or source are touched, in
+/// case the audio server restarted and handed the same IDs to someone else.
+fn unload_leftover_modules(state: &InstanceState, pactl: Pactl) -> Result<()> {
+    let output = pactl.run(&["list", "modules", "short"])?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Failed to list modules: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    for id in leftover_modules(state, &parse_modules(&output.stdout)) {
+        info!("Unloading leftover module {}", id);
+        let _ = pactl.run(&["unload-module", &id.to_string()]);
+    }
+    Ok(())
+}
+
+/// The modules recorded in `state` that are still `loaded` and still its own.
+fn leftover_modules(state: &InstanceState, loaded: &[(u32, String)]) -> Vec<u32> {
+    // Whole arguments, so `desk` doesn't claim the modules of `desktop`
+    let own = [
+        format!("sink_name={}_sink", state.name),
+        format!("source_name={}", state.name),
+    ];
+    let is_own = |args: &str| {
+        args.split_whitespace()
+            .any(|arg| own.iter().any(|own| own == arg))
+    };
+    state
+        .modules
+        .iter()
+        .copied()
+        .filter(
+            |id| match loaded.iter().find(|(loaded_id, _)| loaded_id == id) {
+                Some((_, args)) if is_own(args) => true,
+                Some(_) => {
+                    warn!(
+                        "Module {} now belongs to something else; leaving it loaded",
+                        id
+                    );
+                    false
+                }
+                None => false,
+            },
+        )
+        .collect()
+}
+
+/// Parse `pactl list modules short` output into `(id, arguments)` pairs.
+fn parse_modules(stdout: &[u8]) -> Vec<(u32, String)> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let id = fields.next()?.trim().parse().ok()?;
+            let _module = fields.next()?;
+            Some((id, fields.next().unwrap_or("").to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `pactl list modules short` with the modules of an instance called
+    /// `desk`, one of them since handed to another program.
+    const MODULES: &[u8] = b"\
+7\tmodule-null-sink\tsink_name=desk_sink channels=1
+9\tmodule-remap-source\tsource_name=desk master=desk_sink.monitor
+12\tmodule-loopback\tsource=other
+14\tmodule-switch-on-connect\t
+";
+
+    #[test]
+    fn parses_a_state_file() {
+        let state = InstanceState::parse("name=desk\npid=4242\nmodules=9 7\n").unwrap();
+        assert_eq!(
+            state,
+            InstanceState {
+                name: "desk".to_string(),
+                pid: 4242,
+                modules: vec![9, 7],
+            }
+        );
+        assert!(InstanceState::parse("name=desk\nmodules=9 7\n").is_none());
+        assert!(InstanceState::parse("name=desk\npid=4242\nmodules=9 x\n").is_none());
+    }
+
+    #[test]
+    fn parses_modules_with_and_without_arguments() {
+        assert_eq!(
+            parse_modules(MODULES),
+            [
+                (7, "sink_name=desk_sink channels=1".to_string()),
+                (9, "source_name=desk master=desk_sink.monitor".to_string()),
+                (12, "source=other".to_string()),
+                (14, String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn cleans_up_after_a_dead_instance() {
+        let name = format!("test-dead-{}", std::process::id());
+        let written = InstanceState {
+            name: name.clone(),
+            pid: u32::MAX,
+            modules: vec![9, 12, 20],
+        };
+        let state_file = written.write().unwrap();
+        let state = InstanceState::read(&state_path(&name)).unwrap();
+        drop(state_file);
+
+        assert_eq!(state, written);
+        // Nothing holds its lock, even if some process has its PID now
+        assert!(!is_running(&name));
+        let loaded = parse_modules(
+            &String::from_utf8_lossy(MODULES)
+                .replace("desk", &name)
+                .into_bytes(),
+        );
+        // 12 was handed to someone else and 20 is gone
+        assert_eq!(leftover_modules(&state, &loaded), [9]);
+    }
+
+    #[test]
+    fn leaves_the_modules_of_an_instance_sharing_its_prefix() {
+        // After a restart, desk's old IDs went to desktop's modules
+        let loaded = parse_modules(
+            b"7\tmodule-null-sink\tsink_name=desktop_sink channels=1\n\
+              9\tmodule-remap-source\tsource_name=desktop master=desktop_sink.monitor\n\
+              21\tmodule-null-sink\tsink_name=desk_sink channels=1\n\
+              22\tmodule-remap-source\tsource_name=desk master=desk_sink.monitor\n",
+        );
+        let desk = InstanceState {
+            name: "desk".to_string(),
+            pid: u32::MAX,
+            modules: vec![7, 9, 21, 22],
+        };
+        assert_eq!(leftover_modules(&desk, &loaded), [21, 22]);
+        let desktop = InstanceState {
+            name: "desktop".to_string(),
+            pid: u32::MAX,
+            modules: vec![7, 9, 21, 22],
+        };
+        assert_eq!(leftover_modules(&desktop, &loaded), [7, 9]);
+    }
+
+    #[test]
+    fn names_stay_in_the_state_directory() {
+        assert_eq!(parse_name("desk-2").as_deref(), Ok("desk-2"));
+        for name in ["", "../x", "a/b", ".hidden", ".."] {
+            assert!(parse_name(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn an_instance_holding_the_lock_is_running() {
+        let name = format!("test-live-{}", std::process::id());
+        std::fs::create_dir_all(state_dir()).unwrap();
+        let file = File::create(pid_path(&name)).unwrap();
+        // SAFETY: flock on a file this test owns
+        assert_eq!(unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) }, 0);
+        assert!(is_running(&name));
+
+        drop(file);
+        assert!(!is_running(&name));
+        std::fs::remove_file(pid_path(&name)).unwrap();
+    }
+
+    #[test]
+    fn a_released_pid_file_is_emptied_not_removed() {
+        let path = pid_path(&format!("test-release-{}", std::process::id()));
+        std::fs::create_dir_all(state_dir()).unwrap();
+        let mut file = File::create(&path).unwrap();
+        writeln!(file, "4242").unwrap();
+        drop(PidFile { file });
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
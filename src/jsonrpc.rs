@@ -0,0 +1,293 @@
+//! `--json-rpc`: JSON-RPC 2.0 over a Unix socket, for programs that would
+//! rather not parse the control socket's lines.
+//!
+//! Requests and responses are one JSON object per line. Parameters may be
+//! given by name or by position:
+//!
+//! | Method | Parameters | Command |
+//! |--------|------------|---------|
+//! | `play` | none, or `path` | `resume`, or `load <path>` |
+//! | `pause` | | `pause` |
+//! | `seek` | `position`, or `offset`, in seconds | `seek <time>`, `seek ±<time>` |
+//! | `enqueue` | `path` | `enqueue <path>` |
+//! | `volume` | `level`, or `change` | `volume <level>`, `volume ±<step>` |
+//! | `status` | | `status`, its fields as an object |
+//! | `quit` | | `quit` |
+//! | `subscribe` | | events, as `event` notifications |
+//!
+//! After `subscribe`, each playback event arrives as a notification,
+//! `{"jsonrpc":"2.0","method":"event","params":{"event":"started",...}}`,
+//! its parameters laid out as the `--webhook` bodies are.
+
+use crate::control::{self, ControlCommand, Events, Request, SeekTarget, VolumeChange};
+use crate::{time, webhook};
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Socket name under `$XDG_RUNTIME_DIR` used by a bare `--json-rpc`.
+const SOCKET_NAME: &str = "virtual-mic.sock";
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// A valid call whose command failed, e.g. a file that won't open.
+const COMMAND_FAILED: i64 = -32000;
+
+/// Where a bare `--json-rpc` puts the socket.
+pub fn default_path() -> Result<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .ok_or_else(|| anyhow!("XDG_RUNTIME_DIR isn't set; give --json-rpc a path"))?;
+    Ok(Path::new(&runtime_dir).join(SOCKET_NAME))
+}
+
+/// An error response's `code` and `message`.
+struct Error(i64, String);
+
+/// What a call asks for.
+enum Call {
+    Command(ControlCommand),
+    Subscribe,
+}
+
+/// A client connection; notifications and responses share it.
+type Writer = Arc<Mutex<UnixStream>>;
+
+/// Listens on the JSON-RPC socket; removes the socket file when dropped.
+pub struct JsonRpcServer {
+    path: PathBuf,
+}
+
+impl JsonRpcServer {
+    /// Bind the socket and start accepting clients on a background thread,
+    /// passing their commands to `tx`.
+    pub fn start(path: &Path, events: Events, tx: Sender<Request>) -> Result<Self> {
+        let listener = control::bind(path)?;
+        info!("JSON-RPC listening on {:?}", path);
+
+        std::thread::Builder::new()
+            .name("json-rpc".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let tx = tx.clone();
+                            let events = events.clone();
+                            let _ = std::thread::Builder::new()
+                                .name("json-rpc-client".into())
+                                .spawn(move || serve_client(stream, tx, events));
+                        }
+                        Err(e) => warn!("JSON-RPC accept failed: {}", e),
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for JsonRpcServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve_client(stream: UnixStream, requests: Sender<Request>, events: Events) {
+    let writer: Writer = match stream.try_clone() {
+        Ok(writer) => Arc::new(Mutex::new(writer)),
+        Err(e) => {
+            warn!("JSON-RPC client error: {}", e);
+            return;
+        }
+    };
+    let mut subscribed = false;
+
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else { return };
+        if line.trim().is_empty() {
+            continue;
+        }
+        debug!("JSON-RPC request: {}", line.trim());
+        let mut handle =
+            |request: Value| handle(request, &requests, &events, &writer, &mut subscribed);
+        let response = match serde_json::from_str::<Value>(&line) {
+            Err(e) => Some(error(Value::Null, Error(PARSE_ERROR, e.to_string()))),
+            Ok(Value::Array(batch)) if batch.is_empty() => Some(error(
+                Value::Null,
+                Error(INVALID_REQUEST, "empty batch".to_string()),
+            )),
+            Ok(Value::Array(batch)) => {
+                let responses: Vec<Value> = batch.into_iter().filter_map(&mut handle).collect();
+                (!responses.is_empty()).then_some(Value::Array(responses))
+            }
+            Ok(request) => handle(request),
+        };
+        if let Some(response) = response {
+            if send(&writer, &response).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Carry out one request; the response, or `None` for a notification.
+fn handle(
+    request: Value,
+    requests: &Sender<Request>,
+    events: &Events,
+    writer: &Writer,
+    subscribed: &mut bool,
+) -> Option<Value> {
+    let id = request.get("id").cloned();
+    let method = request.get("method").and_then(Value::as_str);
+    let (Some(method), Some("2.0")) = (method, request.get("jsonrpc").and_then(Value::as_str))
+    else {
+        let message = "expected a JSON-RPC 2.0 request with a method".to_string();
+        return Some(error(
+            id.unwrap_or(Value::Null),
+            Error(INVALID_REQUEST, message),
+        ));
+    };
+    let params = request.get("params").unwrap_or(&Value::Null);
+
+    let result = match call(method, params) {
+        Ok(Call::Subscribe) => {
+            if !std::mem::replace(subscribed, true) {
+                subscribe(events, writer.clone());
+            }
+            Ok(Value::Null)
+        }
+        Ok(Call::Command(command)) => run(command, requests),
+        Err(e) => Err(e),
+    };
+    // Notifications get no response, failed or not
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(e) => error(id, e),
+    })
+}
+
+/// The call a method and its parameters make.
+fn call(method: &str, params: &Value) -> Result<Call, Error> {
+    let param = |name: &str, index: usize| match params {
+        Value::Object(params) => params.get(name),
+        Value::Array(params) => params.get(index),
+        _ => None,
+    };
+    let invalid = |message: &str| Error(INVALID_PARAMS, message.to_string());
+    let path = |usage: &str| match param("path", 0) {
+        Some(Value::String(path)) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => Err(invalid(usage)),
+    };
+
+    let command = match method {
+        "play" => match param("path", 0) {
+            None | Some(Value::Null) => ControlCommand::Resume,
+            Some(_) => ControlCommand::Load(path("play takes an optional path")?),
+        },
+        "pause" => ControlCommand::Pause,
+        "seek" => {
+            let usage = "seek needs a position, or an offset, in seconds";
+            let target = match (param("position", 0), param("offset", usize::MAX)) {
+                (Some(position), None) => SeekTarget::To(seconds(position).ok_or(invalid(usage))?),
+                (None, Some(offset)) => match offset.as_f64().filter(|offset| offset.is_finite()) {
+                    Some(offset) if offset < 0.0 => {
+                        SeekTarget::Back(Duration::from_secs_f64(-offset))
+                    }
+                    Some(offset) => SeekTarget::Forward(Duration::from_secs_f64(offset)),
+                    None => return Err(invalid(usage)),
+                },
+                _ => return Err(invalid(usage)),
+            };
+            ControlCommand::Seek(target)
+        }
+        "enqueue" => ControlCommand::Enqueue(path("enqueue needs a path")?),
+        "volume" => {
+            let usage = "volume needs a level from 0.0 to 2.0, or a change";
+            let number = |value: &Value| value.as_f64().filter(|value| value.is_finite());
+            let change = match (param("level", 0), param("change", usize::MAX)) {
+                (Some(level), None) => {
+                    VolumeChange::To(number(level).ok_or(invalid(usage))? as f32)
+                }
+                (None, Some(change)) => {
+                    VolumeChange::By(number(change).ok_or(invalid(usage))? as f32)
+                }
+                _ => return Err(invalid(usage)),
+            };
+            ControlCommand::Volume(change)
+        }
+        "status" => ControlCommand::Status,
+        "quit" => ControlCommand::Quit,
+        "subscribe" => return Ok(Call::Subscribe),
+        _ => {
+            let message = format!("unknown method '{}'", method);
+            return Err(Error(METHOD_NOT_FOUND, message));
+        }
+    };
+    Ok(Call::Command(command))
+}
+
+/// Seconds, as a number or a time such as `"1:30"`.
+fn seconds(value: &Value) -> Option<Duration> {
+    match value {
+        Value::String(time) => time::parse(time),
+        value => value
+            .as_f64()
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)
+            .map(Duration::from_secs_f64),
+    }
+}
+
+/// Hand a command to the main loop and wait for its result.
+fn run(command: ControlCommand, requests: &Sender<Request>) -> Result<Value, Error> {
+    let status = command == ControlCommand::Status;
+    let (request, response) = Request::new(command);
+    let shutting_down = || Error(COMMAND_FAILED, "shutting down".to_string());
+    requests.send(request).map_err(|_| shutting_down())?;
+    let answer = response
+        .recv()
+        .map_err(|_| shutting_down())?
+        .map_err(|e| Error(COMMAND_FAILED, e))?;
+    Ok(match status {
+        true => Value::Object(webhook::fields(&answer)),
+        false => Value::Null,
+    })
+}
+
+/// Send each event from now on to the client as a notification.
+fn subscribe(events: &Events, writer: Writer) {
+    let events = events.subscribe();
+    let _ = std::thread::Builder::new()
+        .name("json-rpc-events".into())
+        .spawn(move || {
+            for event in events {
+                let Some(params) = webhook::event_object(&event) else {
+                    continue;
+                };
+                let notification = json!({"jsonrpc": "2.0", "method": "event", "params": params});
+                if send(&writer, &notification).is_err() {
+                    return;
+                }
+            }
+        });
+}
+
+fn error(id: Value, Error(code, message): Error) -> Value {
+    json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}})
+}
+
+fn send(writer: &Writer, message: &Value) -> std::io::Result<()> {
+    let mut writer = writer.lock().unwrap_or_else(|e| e.into_inner());
+    writeln!(writer, "{}", message)
+}
@@ -0,0 +1,148 @@
+//! Single-key playback control from the terminal.
+//!
+//! When stdin is a terminal in the foreground, it is taken out of line mode so
+//! keys act as soon as they are pressed, without Enter and without echo.
+//! Ctrl+C still interrupts as usual. The terminal's settings are restored when
+//! the [`Keyboard`] is dropped.
+
+use crate::control::{ControlCommand, SeekTarget, VolumeChange};
+use std::io::Read;
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// How far the arrow keys seek.
+const SEEK_STEP: Duration = Duration::from_secs(5);
+
+/// How far `+` and `-` move the volume.
+const VOLUME_STEP: f32 = 0.1;
+
+/// Keys and the commands they send.
+const KEYS: &[(u8, ControlCommand)] = &[
+    (b' ', ControlCommand::TogglePause),
+    (b'p', ControlCommand::TogglePause),
+    (b'm', ControlCommand::ToggleMute),
+    (b'n', ControlCommand::Next),
+    (b'b', ControlCommand::Previous),
+    (b'+', ControlCommand::Volume(VolumeChange::By(VOLUME_STEP))),
+    // The same key without shift on most layouts
+    (b'=', ControlCommand::Volume(VolumeChange::By(VOLUME_STEP))),
+    (b'-', ControlCommand::Volume(VolumeChange::By(-VOLUME_STEP))),
+];
+
+/// Arrow keys, by the last byte of their `ESC [` sequence.
+const ARROWS: &[(u8, ControlCommand)] = &[
+    (b'C', ControlCommand::Seek(SeekTarget::Forward(SEEK_STEP))),
+    (b'D', ControlCommand::Seek(SeekTarget::Back(SEEK_STEP))),
+];
+
+/// What the keys do, logged at startup.
+pub const HELP: &str =
+    "space or p pauses, m mutes, +/- change the volume, left/right seek 5s, n/b skip tracks";
+
+/// Holds the terminal in single-key mode.
+pub struct Keyboard {
+    saved: libc::termios,
+}
+
+impl Keyboard {
+    /// Start reading keys on a background thread, if stdin is a terminal this
+    /// process is in the foreground of. With `pads` soundboard clips, the
+    /// number keys play them: 1 to 9, then 0 for the tenth.
+    pub fn start(pads: usize) -> Option<(Self, Receiver<ControlCommand>)> {
+        // SAFETY: plain libc calls on stdin with a zeroed termios to fill in
+        let saved = unsafe {
+            if libc::isatty(libc::STDIN_FILENO) != 1
+                || libc::tcgetpgrp(libc::STDIN_FILENO) != libc::getpgrp()
+            {
+                return None;
+            }
+            // Put in the background later, reads fail instead of stopping the process
+            libc::signal(libc::SIGTTIN, libc::SIG_IGN);
+            libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+
+            let mut saved: libc::termios = std::mem::zeroed();
+            if libc::tcgetattr(libc::STDIN_FILENO, &mut saved) != 0 {
+                return None;
+            }
+            let mut raw = saved;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &raw) != 0 {
+                return None;
+            }
+            saved
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let spawned = std::thread::Builder::new()
+            .name("keyboard".into())
+            .spawn(move || {
+                let mut keys = std::io::stdin().lock().bytes();
+                while let Some(key) = keys.next() {
+                    let key = match key {
+                        Ok(key) => key,
+                        Err(e) => {
+                            debug!("Stopped reading keys: {}", e);
+                            return;
+                        }
+                    };
+                    let command = match key {
+                        // Escape sequences of the arrow keys
+                        0x1b => match (keys.next(), keys.next()) {
+                            (Some(Ok(b'[')), Some(Ok(last))) => command(last, true, pads),
+                            _ => continue,
+                        },
+                        key => command(key, false, pads),
+                    };
+                    let Some(command) = command else {
+                        continue;
+                    };
+                    if tx.send(command).is_err() {
+                        return;
+                    }
+                }
+            });
+        let keyboard = Self { saved };
+        if let Err(e) = spawned {
+            warn!("Failed to start reading keys: {}", e);
+            return None;
+        }
+
+        info!("Keys: {}", HELP);
+        match pads {
+            0 => {}
+            1 => info!("Key 1 plays the soundboard clip"),
+            10 => info!("Keys 1 to 9 and 0 play the soundboard clips"),
+            pads => info!("Keys 1 to {} play the soundboard clips", pads),
+        }
+        Some((keyboard, rx))
+    }
+}
+
+/// The command a key sends: `key` itself, or the last byte of an arrow key's
+/// escape sequence if `arrow`. With `pads` soundboard clips, the number keys
+/// play them.
+pub fn command(key: u8, arrow: bool, pads: usize) -> Option<ControlCommand> {
+    let table = match key {
+        _ if arrow => ARROWS,
+        b'0'..=b'9' => {
+            let pad = match key {
+                b'0' => 10,
+                digit => (digit - b'0') as usize,
+            };
+            return (pad <= pads).then_some(ControlCommand::Pad(pad));
+        }
+        _ => KEYS,
+    };
+    let (_, command) = table.iter().find(|(k, _)| *k == key)?;
+    Some(command.clone())
+}
+
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        // SAFETY: restores the settings read in `start`
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.saved) };
+    }
+}
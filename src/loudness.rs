@@ -0,0 +1,390 @@
+//! EBU R128 loudness, for `--normalize`.
+//!
+//! Loudness is measured as in ITU-R BS.1770: the audio is K-weighted (a high
+//! shelf for the head's effect, then a high-pass), its power is taken over
+//! 400 ms blocks every 100 ms, and the blocks are gated twice, at -70 LUFS
+//! and then 10 LU below the level of what passed the first gate, so pauses
+//! and quiet passages don't pull the result down.
+//!
+//! Files are measured from start to end before they play (the first pass of
+//! two), on a background thread for all but the first. Inputs that can't be
+//! read twice, and files that start before their measurement is done, are
+//! measured as they play instead, with the gain following the loudness of
+//! what has played so far.
+
+use crate::dsp::Stage;
+use crate::source::{InputSpec, Source, Span};
+use anyhow::Result;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info, warn};
+
+/// Blocks quieter than this are left out altogether.
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+
+/// Blocks this far below the level of the rest are left out.
+const RELATIVE_GATE_LU: f64 = 10.0;
+
+/// Loudest block the histogram keeps apart; louder ones share its top bin.
+const MAX_BLOCK_LUFS: f64 = 10.0;
+
+/// Width of a histogram bin.
+const BIN_LU: f64 = 0.1;
+
+/// Furthest `--normalize` will move the gain, in dB.
+const MAX_GAIN_DB: f64 = 20.0;
+
+/// Time constant of the gain while measuring as it plays.
+const STREAMING_SMOOTHING_MS: u32 = 1000;
+
+/// Sample rate assumed for inputs that don't report one.
+const FALLBACK_RATE: u32 = 48000;
+
+/// One second-order section, in direct form I.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The two K-weighting filters of BS.1770 for `rate`.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = rate as f64;
+
+    // High shelf, about +4 dB above 2 kHz
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    // High-pass at about 38 Hz
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+fn lufs(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+/// Gated integrated loudness of interleaved audio, fed in as it comes.
+///
+/// Block levels go into a histogram rather than a list, so a meter on a live
+/// input runs for days in the same memory.
+pub struct LoudnessMeter {
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    /// Frames in 100 ms, a quarter of a block.
+    step: usize,
+    /// Frames and K-weighted energy so far in the current step.
+    filled: usize,
+    energy: f64,
+    /// Energy of the last complete steps, up to a block's worth.
+    steps: VecDeque<f64>,
+    /// Count and summed power of the blocks, by level.
+    histogram: Vec<(u64, f64)>,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: usize, rate: u32) -> Self {
+        let bins = ((MAX_BLOCK_LUFS - ABSOLUTE_GATE_LUFS) / BIN_LU).ceil() as usize;
+        Self {
+            channels,
+            filters: vec![k_weighting(rate); channels],
+            step: (rate as usize / 10).max(1),
+            filled: 0,
+            energy: 0.0,
+            steps: VecDeque::with_capacity(4),
+            histogram: vec![(0, 0.0); bins],
+        }
+    }
+
+    /// Measure more audio; returns whether a block was completed.
+    pub fn push(&mut self, samples: &[f32]) -> bool {
+        let mut completed = false;
+        for frame in samples.chunks_exact(self.channels) {
+            for (&sample, filters) in frame.iter().zip(&mut self.filters) {
+                let shelved = filters[0].process(sample as f64);
+                let weighted = filters[1].process(shelved);
+                self.energy += weighted * weighted;
+            }
+            self.filled += 1;
+            if self.filled == self.step {
+                completed |= self.end_step();
+            }
+        }
+        completed
+    }
+
+    fn end_step(&mut self) -> bool {
+        if self.steps.len() == 4 {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(self.energy);
+        self.filled = 0;
+        self.energy = 0.0;
+        if self.steps.len() < 4 {
+            return false;
+        }
+
+        let power = self.steps.iter().sum::<f64>() / (4 * self.step) as f64;
+        let level = lufs(power);
+        if level > ABSOLUTE_GATE_LUFS {
+            let bin = self.bin(level);
+            self.histogram[bin].0 += 1;
+            self.histogram[bin].1 += power;
+        }
+        true
+    }
+
+    fn bin(&self, level: f64) -> usize {
+        let bin = ((level - ABSOLUTE_GATE_LUFS) / BIN_LU).max(0.0) as usize;
+        bin.min(self.histogram.len() - 1)
+    }
+
+    /// The integrated loudness in LUFS, once a block has passed the gates.
+    pub fn integrated(&self) -> Option<f64> {
+        let mean = |bins: &[(u64, f64)]| {
+            let (count, power) = bins.iter().fold((0, 0.0), |(count, power), bin| {
+                (count + bin.0, power + bin.1)
+            });
+            (count > 0).then(|| power / count as f64)
+        };
+        let gate = lufs(mean(&self.histogram)?) - RELATIVE_GATE_LU;
+        let first = (((gate - ABSOLUTE_GATE_LUFS) / BIN_LU).ceil().max(0.0) as usize)
+            .min(self.histogram.len() - 1);
+        mean(&self.histogram[first..]).map(lufs)
+    }
+
+    /// Start measuring afresh.
+    pub fn reset(&mut self) {
+        self.filters.iter_mut().for_each(|filters| {
+            *filters = filters.map(|filter| Biquad::new(filter.b, filter.a));
+        });
+        self.filled = 0;
+        self.energy = 0.0;
+        self.steps.clear();
+        self.histogram.fill((0, 0.0));
+    }
+}
+
+/// The linear gain that takes audio at `loudness` to `target`.
+fn gain_to(target: f64, loudness: f64) -> f32 {
+    let db = (target - loudness).clamp(-MAX_GAIN_DB, MAX_GAIN_DB);
+    10f64.powf(db / 20.0) as f32
+}
+
+/// Whether `input` is a file that can be read through once to measure it
+/// and again to play it.
+fn measurable(input: &InputSpec) -> bool {
+    input.exec.is_none()
+        && input.ytdlp.is_none()
+        && input.tts.is_none()
+        && input.generate.is_none()
+        && input.path.is_file()
+}
+
+/// Integrated loudness of all of `input`, mixed to `channels` the way it
+/// plays; `None` if it is silent throughout.
+fn measure(input: &InputSpec, channels: usize) -> Result<Option<f64>> {
+    let mut source = Source::open(input)?;
+    let mut meter = LoudnessMeter::new(channels, source.sample_rate.unwrap_or(FALLBACK_RATE));
+    while let Some((samples, source_channels)) = source.next_chunk()? {
+        meter.push(&crate::remix(&samples, source_channels, channels));
+    }
+    Ok(meter.integrated())
+}
+
+type Key = (PathBuf, Option<Span>);
+
+/// The files measured so far, shared with the thread measuring ahead.
+#[derive(Clone)]
+pub struct Loudness {
+    channels: usize,
+    measured: Arc<Mutex<HashMap<Key, Option<f64>>>>,
+    ahead: Sender<InputSpec>,
+}
+
+impl Loudness {
+    /// Start the thread that measures files queued with [`Loudness::request`].
+    pub fn start(channels: usize) -> Result<Self> {
+        let (ahead, requests) = mpsc::channel::<InputSpec>();
+        let loudness = Self {
+            channels,
+            measured: Arc::default(),
+            ahead,
+        };
+        let worker = loudness.clone();
+        std::thread::Builder::new()
+            .name("loudness".into())
+            .spawn(move || {
+                for input in requests {
+                    worker.measure(&input);
+                }
+            })?;
+        Ok(loudness)
+    }
+
+    /// Measure `input` now, unless it has been already or can't be.
+    pub fn measure(&self, input: &InputSpec) {
+        let key = (input.path.clone(), input.span);
+        if !measurable(input) || self.lock().contains_key(&key) {
+            return;
+        }
+        debug!("Measuring the loudness of {:?}", input.path);
+        let loudness = match measure(input, self.channels) {
+            Ok(loudness) => loudness,
+            Err(e) => {
+                warn!("Failed to measure the loudness of {:?}: {}", input.path, e);
+                None
+            }
+        };
+        match loudness {
+            Some(lufs) => info!("Loudness of {:?}: {:.1} LUFS", input.path, lufs),
+            None => info!("{:?} is silent; nothing to normalize", input.path),
+        }
+        self.lock().insert(key, loudness);
+    }
+
+    /// Measure `input` on the background thread.
+    pub fn request(&self, input: InputSpec) {
+        if measurable(&input) {
+            // The thread only stops if this handle is gone too
+            let _ = self.ahead.send(input);
+        }
+    }
+
+    /// The loudness of `input`, if it has been measured and isn't silent.
+    pub fn get(&self, input: &InputSpec) -> Option<f64> {
+        let key = (input.path.clone(), input.span);
+        self.lock().get(&key).copied().flatten()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Key, Option<f64>>> {
+        self.measured.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The `--normalize` stage: the gain from the playing file's measured
+/// loudness, or else from the loudness of what has played so far.
+pub struct Normalizer {
+    target: f64,
+    /// Measured loudness of the playing file, set as each one starts.
+    measured: Rc<Cell<Option<f64>>>,
+    meter: LoudnessMeter,
+    smoothing: f32,
+    gain: f32,
+    /// The gain the streaming measurement is heading for.
+    desired: f32,
+}
+
+impl Normalizer {
+    pub fn new(
+        target: f64,
+        measured: Rc<Cell<Option<f64>>>,
+        channels: usize,
+        sample_rate: u32,
+    ) -> Self {
+        let frames = STREAMING_SMOOTHING_MS as f32 * sample_rate as f32 / 1000.0;
+        Self {
+            target,
+            measured,
+            meter: LoudnessMeter::new(channels, sample_rate),
+            smoothing: 1.0 - (-1.0 / frames).exp(),
+            gain: 1.0,
+            desired: 1.0,
+        }
+    }
+}
+
+impl Stage for Normalizer {
+    fn process(&mut self, frames: &mut Vec<f32>, channels: usize) {
+        if let Some(loudness) = self.measured.get() {
+            let gain = gain_to(self.target, loudness);
+            frames.iter_mut().for_each(|sample| *sample *= gain);
+            return;
+        }
+
+        if self.meter.push(frames) {
+            if let Some(loudness) = self.meter.integrated() {
+                self.desired = gain_to(self.target, loudness);
+            }
+        }
+        for frame in frames.chunks_mut(channels) {
+            self.gain += (self.desired - self.gain) * self.smoothing;
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        // A measured file plays at its own gain; anything else starts over
+        self.meter.reset();
+        self.gain = 1.0;
+        self.desired = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizer_applies_the_measured_gain() {
+        let measured = Rc::new(Cell::new(Some(-30.0)));
+        let mut normalizer = Normalizer::new(-24.0, measured.clone(), 1, 48_000);
+        let mut frames = vec![0.1, -0.2];
+        normalizer.process(&mut frames, 1);
+        // 6 dB up, just about doubled
+        assert!((frames[0] - 0.1995).abs() < 1e-4);
+        assert!((frames[1] + 0.3991).abs() < 1e-4);
+
+        // Far out of range, the gain stops at +20 dB
+        measured.set(Some(-90.0));
+        let mut frames = vec![0.01];
+        normalizer.process(&mut frames, 1);
+        assert!((frames[0] - 0.1).abs() < 1e-6);
+    }
+}
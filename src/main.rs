@@ -1,417 +1,3442 @@
-use anyhow::{anyhow, Result};
-use clap::Parser;
+mod auth;
+mod capture;
+mod control;
+mod cue;
+#[cfg(feature = "dbus")]
+mod dbus;
+mod dsp;
+mod edl;
+mod evdev;
+mod exec;
+mod exit;
+mod feed;
+mod fifo;
+mod generator;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod hls;
+mod hooks;
+mod hotkeys;
+mod http;
+mod icy;
+mod instance;
+mod jsonrpc;
+mod keys;
+mod loudness;
+mod midi;
+mod midiin;
+mod midimap;
+mod mp4;
+#[cfg(feature = "dbus")]
+mod mpris;
+mod opus;
+mod osc;
+mod playlist;
+mod ptt;
+mod queue;
+mod raw;
+mod ready;
+mod recorder;
+mod repl;
+mod replaygain;
+mod resample;
+mod rest;
+mod resume;
+mod rtp;
+mod signals;
+mod soundboard;
+mod source;
+mod spool;
+mod stretch;
+mod time;
+mod timeline;
+mod tracker;
+mod ts;
+mod tts;
+#[cfg(feature = "tui")]
+mod tui;
+mod vad;
+mod varlink;
+mod webhook;
+mod ws;
+mod ytdlp;
+
+use anyhow::{anyhow, Context, Result};
+use auth::Access;
+use capture::{Capture, MixBuffer};
+use clap::{Parser, Subcommand};
+use control::{ControlCommand, ControlSocket, Events, Request, SeekTarget, VolumeChange};
+use dsp::{
+    Agc, AgcOptions, ClipStats, DcBlocker, Declicker, Gain, Limiter, Meter, ProcessChain, Ramp,
+    SilenceSkipper, SilenceTrimmer, Stage, StageKind,
+};
+use exit::Exit;
+use feed::Feed;
+use generator::{GenerateOptions, Signal};
+use hooks::TrackHooks;
+use instance::InstanceState;
+use jsonrpc::JsonRpcServer;
+use keys::Keyboard;
+use loudness::{Loudness, Normalizer};
+use midi::MidiOptions;
+use midimap::MidiMap;
 use pipewire as pw;
+use playlist::{Entry, Playlist};
 use pw::spa::pod::Pod;
 use pw::spa::utils::Id;
 use pw::stream::{Stream, StreamFlags};
-use std::cell::RefCell;
+use raw::{RawFormat, RawParams};
+use ready::Readiness;
+use recorder::{RecordFormat, Recorder};
+use replaygain::ReplayGainMode;
+use resample::LinearResampler;
+use rest::RestServer;
+use resume::ResumePoint;
+use rtp::{RtpEncoding, RtpParams};
+use soundboard::{Clip, Overlap, Pads, Soundboard};
+use source::{InputSpec, Source, Span};
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
-use std::fs::File;
-use std::path::PathBuf;
-use std::process::Command;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode, Output};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
 use std::sync::Arc;
-use symphonia::core::audio::SampleBuffer;
-use symphonia::core::codecs::DecoderOptions;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
-use symphonia::core::probe::Hint;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use stretch::Wsola;
 use tracing::{debug, error, info, warn};
+use tts::{TtsEngine, TtsOptions};
+use vad::TurnTaking;
+
+/// Default stream format: 48 kHz mono float, which is what WebRTC captures natively.
+const DEFAULT_FORMAT: StreamFormat = StreamFormat {
+    rate: 48000,
+    channels: 1,
+    sample_format: SampleFormat::F32,
+};
 
-const SAMPLE_RATE: u32 = 48000;
-const CHANNELS: u32 = 1; // Mono for microphone
+/// Media roles understood by PipeWire's session manager (`media.role`).
+const KNOWN_MEDIA_ROLES: &[&str] = &[
+    "Movie",
+    "Music",
+    "Camera",
+    "Screen",
+    "Communication",
+    "Game",
+    "Notification",
+    "DSP",
+    "Production",
+    "Accessibility",
+    "Test",
+];
+
+/// Media categories understood by PipeWire (`media.category`).
+const KNOWN_MEDIA_CATEGORIES: &[&str] = &["Playback", "Capture", "Duplex", "Monitor", "Manager"];
 
 #[derive(Parser, Debug)]
 #[command(name = "virtual-mic")]
 #[command(about = "Create a virtual microphone and pipe audio files to it")]
+#[command(subcommand_negates_reqs = true)]
+#[command(group(clap::ArgGroup::new("pcm_input").args(["raw", "stdin_pcm", "listen_ws", "exec"])))]
+#[command(group(clap::ArgGroup::new("looping").args(["loop_audio", "repeat"])))]
 struct Args {
-    /// Audio file to play (supports mp3, wav, flac, ogg, aac)
-    #[arg(short, long)]
-    file: PathBuf,
+    #[command(subcommand)]
+    command: Option<CliCommand>,
 
-    /// Loop the audio file
-    #[arg(short, long, default_value = "false")]
-    loop_audio: bool,
+    /// Audio file or directory to play; repeat to build a playlist
+    /// (supports mp3, wav, flac, ogg, opus, webm, aac, mid with --soundfont, and mod, xm, it
+    /// and s3m with the tracker feature; "-" reads stdin; http(s) URLs stream)
+    #[arg(short, long, required_unless_present_any = [
+        "stdin_pcm",
+        "listen_rtp",
+        "listen_ws",
+        "listen_grpc",
+        "capture_sink_monitor",
+        "exec",
+        "url",
+        "spool",
+        "say",
+        "say_file",
+        "say_stdin",
+        "generate",
+        "dtmf",
+        "timeline"
+    ])]
+    file: Vec<PathBuf>,
+
+    /// Audio track to play from files with several (e.g. a video with dubs), counting
+    /// from 1; the available tracks are logged
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    track: Option<u64>,
+
+    /// SoundFont (.sf2) used to render MIDI files (.mid) in the playlist
+    #[arg(long, value_name = "PATH")]
+    soundfont: Option<PathBuf>,
+
+    /// Force the container hint, as a file extension ("webm") or mime type ("audio/webm")
+    #[arg(long)]
+    format_hint: Option<String>,
+
+    /// Read the input as headerless PCM described by --raw-rate, --raw-channels and --raw-format
+    #[arg(
+        long,
+        default_value = "false",
+        requires_all = ["raw_rate", "raw_channels", "raw_format"],
+        conflicts_with = "format_hint"
+    )]
+    raw: bool,
+
+    /// Read headerless PCM piped on stdin instead of a file (shorthand for --raw -f -)
+    #[arg(
+        long,
+        default_value = "false",
+        requires_all = ["raw_rate", "raw_channels", "raw_format"],
+        conflicts_with_all = ["file", "format_hint"]
+    )]
+    stdin_pcm: bool,
+
+    /// Speak this text through the mic with the --tts-engine
+    #[arg(
+        long,
+        value_name = "TEXT",
+        conflicts_with_all = [
+            "file",
+            "say_file",
+            "pcm_input",
+            "listen_rtp",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "url",
+            "spool"
+        ]
+    )]
+    say: Option<String>,
+
+    /// Speak the contents of this text file through the mic with the --tts-engine
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "file",
+            "pcm_input",
+            "listen_rtp",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "url",
+            "spool"
+        ]
+    )]
+    say_file: Option<PathBuf>,
+
+    /// Speak each line read from stdin as it arrives, one after another
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with_all = [
+            "file",
+            "say",
+            "say_file",
+            "pcm_input",
+            "listen_rtp",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "url",
+            "spool"
+        ]
+    )]
+    say_stdin: bool,
+
+    /// Speech engine for --say, --say-file and --say-stdin
+    #[arg(long, value_enum, default_value = "espeak-ng")]
+    tts_engine: TtsEngine,
+
+    /// Voice for the --tts-engine: an espeak-ng voice name, or a Piper .onnx model path
+    #[arg(long, value_name = "VOICE")]
+    tts_voice: Option<String>,
+
+    /// Play a test signal instead of files: sine:HZ, white, pink, speech (speech-shaped
+    /// noise) or sweep:HZ-HZ (e.g. sine:1000 or sweep:20-20000)
+    #[arg(
+        long,
+        value_name = "SIGNAL",
+        value_parser = parse_signal,
+        conflicts_with_all = [
+            "file",
+            "pcm_input",
+            "listen_rtp",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "url",
+            "spool",
+            "say",
+            "say_file",
+            "say_stdin"
+        ]
+    )]
+    generate: Option<Signal>,
+
+    /// RMS level of the --generate signal in dBFS [default: -9 for tones, -18 for noise]
+    #[arg(
+        long,
+        value_name = "DBFS",
+        value_parser = parse_level,
+        allow_hyphen_values = true,
+        requires = "generate"
+    )]
+    generate_level: Option<f32>,
+
+    /// Dial these DTMF digits (0-9, *, #, A-D; a comma pauses for one digit) instead
+    /// of playing files
+    #[arg(
+        long,
+        value_name = "DIGITS",
+        value_parser = generator::parse_dtmf_digits,
+        conflicts_with_all = [
+            "file",
+            "pcm_input",
+            "listen_rtp",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "url",
+            "spool",
+            "say",
+            "say_file",
+            "say_stdin",
+            "generate"
+        ]
+    )]
+    dtmf: Option<String>,
+
+    /// Length of each --dtmf tone in milliseconds (at least 40 to be detected reliably)
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value = "100",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    dtmf_tone: u64,
+
+    /// Silence after each --dtmf tone in milliseconds
+    #[arg(long, value_name = "MS", default_value = "100")]
+    dtmf_gap: u64,
+
+    /// Play audio files dropped into this directory as they arrive, moving each to
+    /// its done/ subdirectory afterwards
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = [
+            "file",
+            "stdin_pcm",
+            "listen_ws",
+            "listen_rtp",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "exec",
+            "url"
+        ]
+    )]
+    spool: Option<PathBuf>,
+
+    /// Play files at set times from the start, as a script of `t=<time> play <file>` cues
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "file",
+            "stdin_pcm",
+            "exec",
+            "listen_rtp",
+            "listen_ws",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "url",
+            "spool",
+            "say",
+            "say_file",
+            "say_stdin",
+            "generate",
+            "dtmf",
+            "looping",
+            "shuffle",
+            "on_end",
+            "seek"
+        ]
+    )]
+    timeline: Option<PathBuf>,
+
+    /// Video or audio page to play the best audio of, resolved with yt-dlp (e.g. a
+    /// YouTube link); repeat to build a playlist
+    #[arg(long, value_name = "URL", conflicts_with_all = ["file", "pcm_input"])]
+    url: Vec<String>,
+
+    /// yt-dlp binary used to resolve --url
+    #[arg(long, value_name = "PATH", default_value = "yt-dlp")]
+    yt_dlp: String,
+
+    /// Run a shell command and play its stdout instead of files; encoded output is
+    /// detected, anything else is read as raw PCM in the --pcm-* format
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with_all = ["file", "listen_rtp", "listen_grpc", "capture_sink_monitor"]
+    )]
+    exec: Option<String>,
+
+    /// Accept raw PCM from WebSocket clients on this address instead of playing files;
+    /// the format comes from --pcm-rate, --pcm-channels and --pcm-format
+    #[arg(
+        long,
+        value_name = "ADDR:PORT",
+        requires_all = ["raw_rate", "raw_channels", "raw_format"],
+        conflicts_with_all = ["file", "format_hint", "listen_rtp", "listen_grpc"]
+    )]
+    listen_ws: Option<SocketAddr>,
+
+    /// Serve the AudioIngest gRPC service (PushAudio) on this address instead of playing
+    /// files; needs a build with `--features grpc`
+    #[arg(
+        long,
+        value_name = "ADDR:PORT",
+        conflicts_with_all = ["file", "pcm_input", "format_hint", "listen_rtp"]
+    )]
+    listen_grpc: Option<SocketAddr>,
+
+    /// Play what another application outputs to this sink (its monitor) instead of files
+    #[arg(
+        long,
+        value_name = "SINK",
+        conflicts_with_all = ["file", "pcm_input", "format_hint", "listen_rtp", "listen_grpc"]
+    )]
+    capture_sink_monitor: Option<String>,
+
+    /// Receive RTP audio on this UDP address (e.g. 0.0.0.0:5004) instead of playing files
+    #[arg(long, value_name = "ADDR:PORT", conflicts_with_all = ["file", "pcm_input", "format_hint"])]
+    listen_rtp: Option<SocketAddr>,
+
+    /// Encoding of --listen-rtp packets with a dynamic payload type (10 and 11 are always L16)
+    #[arg(long, value_enum, default_value = "opus", requires = "listen_rtp")]
+    rtp_encoding: RtpEncoding,
+
+    /// Clock rate of dynamic-payload L16 RTP audio in Hz
+    #[arg(
+        long,
+        default_value = "48000",
+        requires = "listen_rtp",
+        value_parser = clap::value_parser!(u32).range(1000..=384000)
+    )]
+    rtp_rate: u32,
+
+    /// Channel count of dynamic-payload RTP audio
+    #[arg(
+        long,
+        default_value = "1",
+        requires = "listen_rtp",
+        value_parser = clap::value_parser!(u32).range(1..=2)
+    )]
+    rtp_channels: u32,
+
+    /// Audio buffered before RTP playout starts, in milliseconds
+    #[arg(long, default_value = "60", requires = "listen_rtp")]
+    rtp_jitter: u64,
+
+    /// Sample rate of --raw input and .raw/.pcm files in Hz
+    #[arg(
+        long,
+        visible_alias = "pcm-rate",
+        requires_all = ["raw_channels", "raw_format"],
+        value_parser = clap::value_parser!(u32).range(1000..=384000)
+    )]
+    raw_rate: Option<u32>,
+
+    /// Channel count of --raw input and .raw/.pcm files
+    #[arg(
+        long,
+        visible_alias = "pcm-channels",
+        requires_all = ["raw_rate", "raw_format"],
+        value_parser = clap::value_parser!(u32).range(1..=32)
+    )]
+    raw_channels: Option<u32>,
+
+    /// Sample encoding of --raw input and .raw/.pcm files
+    #[arg(
+        long,
+        visible_alias = "pcm-format",
+        requires_all = ["raw_rate", "raw_channels"],
+        value_enum
+    )]
+    raw_format: Option<RawFormat>,
+
+    /// Loop the audio file, forever or until it has played COUNT times
+    #[arg(
+        short,
+        long,
+        visible_alias = "loop",
+        value_name = "COUNT",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    loop_audio: Option<Option<u32>>,
+
+    /// What repeats when the playlist reaches its end
+    #[arg(long, value_enum)]
+    repeat: Option<Repeat>,
+
+    /// Play the playlist in a random order, reshuffled each time it starts over
+    #[arg(long, default_value = "false")]
+    shuffle: bool,
+
+    /// Seed for --shuffle, to repeat the same order [default: random]
+    #[arg(long, value_name = "SEED", requires = "shuffle")]
+    shuffle_seed: Option<u64>,
+
+    /// Silence between repetitions when looping: seconds, m:ss or e.g. 500ms
+    #[arg(long, value_name = "TIME", value_parser = parse_time, requires = "looping")]
+    loop_gap: Option<Duration>,
+
+    /// Overlap the end of each playlist entry with the start of the next: seconds,
+    /// m:ss or e.g. 500ms
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    crossfade: Option<Duration>,
+
+    /// Crossfade from the end of each repetition into the next, in milliseconds
+    #[arg(
+        long,
+        default_value = "0",
+        requires = "looping",
+        conflicts_with = "loop_gap"
+    )]
+    loop_crossfade: u64,
+
+    /// Start the first file this far in: seconds, m:ss or e.g. 1m30s
+    #[arg(
+        long,
+        value_name = "TIME",
+        value_parser = parse_time,
+        conflicts_with_all = [
+            "listen_rtp",
+            "listen_ws",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "spool",
+            "say_stdin"
+        ]
+    )]
+    seek: Option<Duration>,
+
+    /// Start playback when the local clock shows this time, hh:mm[:ss[.fff]]
+    /// (tomorrow if it has passed); the mic carries silence until then
+    #[arg(
+        long,
+        value_name = "TIME",
+        value_parser = parse_time_of_day,
+        conflicts_with_all = [
+            "sync_epoch",
+            "listen_rtp",
+            "listen_ws",
+            "listen_grpc",
+            "capture_sink_monitor"
+        ]
+    )]
+    start_at: Option<Duration>,
+
+    /// Start playback at this Unix time, in seconds with an optional fraction
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        value_parser = parse_epoch,
+        conflicts_with_all = [
+            "listen_rtp",
+            "listen_ws",
+            "listen_grpc",
+            "capture_sink_monitor"
+        ]
+    )]
+    sync_epoch: Option<SystemTime>,
+
+    /// Carry on from where the last run of this instance (by --name) stopped
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "timeline",
+            "stdin_pcm",
+            "listen_rtp",
+            "listen_ws",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "exec",
+            "spool",
+            "say_stdin",
+            "generate",
+            "dtmf"
+        ]
+    )]
+    resume: bool,
+
+    /// Play each file from this point on: seconds, m:ss or e.g. 1m30s
+    #[arg(
+        long,
+        value_name = "TIME",
+        value_parser = parse_time,
+        conflicts_with_all = [
+            "listen_rtp",
+            "listen_ws",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "spool",
+            "say_stdin"
+        ]
+    )]
+    start: Option<Duration>,
+
+    /// Stop each file at this point, counted from the start of the file
+    #[arg(
+        long,
+        value_name = "TIME",
+        value_parser = parse_time,
+        conflicts_with_all = [
+            "duration",
+            "listen_rtp",
+            "listen_ws",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "spool",
+            "say_stdin"
+        ]
+    )]
+    end: Option<Duration>,
+
+    /// Play this much of each file, from --start; also the length of the --generate
+    /// signal (endless by default; a sweep then repeats every 10 seconds)
+    #[arg(
+        long,
+        value_name = "TIME",
+        value_parser = parse_length,
+        conflicts_with_all = [
+            "listen_rtp",
+            "listen_ws",
+            "listen_grpc",
+            "capture_sink_monitor",
+            "spool",
+            "say_stdin"
+        ]
+    )]
+    duration: Option<Duration>,
 
     /// Virtual microphone name
-    #[arg(short, long, default_value = "VirtualMic")]
+    #[arg(short, long, default_value = "VirtualMic", value_parser = instance::parse_name)]
     name: String,
 
     /// Volume multiplier (0.0 - 2.0)
     #[arg(short, long, default_value = "1.0")]
     volume: f32,
 
+    /// Apply the gain stored in each file's ReplayGain or R128 tags
+    #[arg(long, value_enum, value_name = "MODE", conflicts_with = "normalize")]
+    replay_gain: Option<ReplayGainMode>,
+
+    /// Bring the loudness to this EBU R128 target, e.g. -16LUFS
+    #[arg(long, value_name = "LUFS", value_parser = parse_lufs, allow_hyphen_values = true)]
+    normalize: Option<f64>,
+
+    /// Extra gain in dB for files with --replay-gain tags
+    #[arg(
+        long,
+        value_name = "DB",
+        default_value = "0",
+        allow_hyphen_values = true
+    )]
+    replay_gain_preamp: f32,
+
+    /// Fade in over this long when playback starts or resumes: seconds or e.g. 500ms
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    fade_in: Option<Duration>,
+
+    /// Fade out over this long when playback ends, pauses or is stopped
+    #[arg(long, value_name = "TIME", value_parser = parse_time)]
+    fade_out: Option<Duration>,
+
+    /// Playback speed (0.5 - 2.0); the pitch changes with it
+    #[arg(
+        long,
+        default_value = "1.0",
+        value_parser = parse_speed,
+        conflicts_with_all = ["listen_rtp", "listen_ws", "listen_grpc", "capture_sink_monitor"]
+    )]
+    speed: f64,
+
+    /// Playback tempo (0.5 - 2.0), keeping the pitch; e.g. 1.3 for faster speech
+    #[arg(
+        long,
+        default_value = "1.0",
+        value_parser = parse_tempo,
+        conflicts_with_all = ["listen_rtp", "listen_ws", "listen_grpc", "capture_sink_monitor"]
+    )]
+    tempo: f64,
+
+    /// Capture this PipeWire source (e.g. a real microphone) and mix it into the output
+    #[arg(long, value_name = "SOURCE")]
+    mix_source: Option<String>,
+
+    /// Gain for --mix-source (0.0 - 2.0); --volume sets the gain of the played audio
+    #[arg(long, default_value = "1.0", requires = "mix_source")]
+    mix_gain: f32,
+
+    /// Take turns with this PipeWire source (e.g. a voice assistant's output,
+    /// or <sink>.monitor): play an entry, then wait for it to speak and go quiet
+    /// before playing the next
+    #[arg(
+        long,
+        value_name = "SOURCE",
+        conflicts_with_all = [
+            "timeline", "listen_rtp", "listen_ws", "listen_grpc", "capture_sink_monitor",
+            "spool", "say_stdin", "stdin_pcm"
+        ]
+    )]
+    reply_to: Option<String>,
+
+    /// Quiet time after --reply-to has spoken that ends its turn: seconds or e.g. 800ms
+    #[arg(long, value_name = "TIME", default_value = "800ms", value_parser = parse_time)]
+    reply_silence: Duration,
+
+    /// Level at which --reply-to counts as speaking, in dBFS
+    #[arg(
+        long,
+        value_name = "DBFS",
+        default_value = "-45",
+        value_parser = parse_level,
+        allow_hyphen_values = true
+    )]
+    reply_threshold: f32,
+
     /// Also play audio through speakers (monitor mode)
     #[arg(short, long, default_value = "false")]
     monitor: bool,
-}
 
-struct AudioDecoder {
-    path: PathBuf,
-    loop_audio: bool,
-    volume: f32,
-    buffer: VecDeque<f32>,
-    decoder: Option<Box<dyn symphonia::core::codecs::Decoder>>,
-    format: Option<Box<dyn symphonia::core::formats::FormatReader>>,
-    track_id: Option<u32>,
-    source_sample_rate: Option<u32>,
-}
+    /// Configure rate, channels and sample format together for a common use case
+    #[arg(long, value_enum)]
+    preset: Option<Preset>,
 
-impl AudioDecoder {
-    fn new(path: PathBuf, loop_audio: bool, volume: f32) -> Self {
-        Self {
-            path,
-            loop_audio,
-            volume,
-            buffer: VecDeque::with_capacity(SAMPLE_RATE as usize * 2),
-            decoder: None,
-            format: None,
-            track_id: None,
-            source_sample_rate: None,
-        }
-    }
+    /// Output sample rate in Hz [default: 48000, or from --preset]
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(8000..=192000))]
+    rate: Option<u32>,
 
-    fn open(&mut self) -> Result<()> {
-        let file = File::open(&self.path)?;
-        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    /// Output channels: 1 (mono microphone) or 2 (stereo) [default: 1, or from --preset]
+    #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..=2))]
+    channels: Option<u32>,
 
-        let mut hint = Hint::new();
-        if let Some(ext) = self.path.extension().and_then(|e| e.to_str()) {
-            hint.with_extension(ext);
-        }
+    /// Output sample format [default: f32, or from --preset]
+    #[arg(long, value_enum)]
+    sample_format: Option<SampleFormat>,
 
-        let probed = symphonia::default::get_probe().format(
-            &hint,
-            mss,
-            &FormatOptions::default(),
-            &MetadataOptions::default(),
-        )?;
+    /// Equal-power pan position (-1.0 = hard left, 1.0 = hard right); stereo only
+    #[arg(long, value_parser = parse_pan, allow_hyphen_values = true)]
+    pan: Option<f32>,
 
-        let format = probed.format;
-        let track = format
-            .tracks()
-            .iter()
-            .find(|t| t.codec_params.codec != symphonia::core::codecs::CODEC_TYPE_NULL)
-            .ok_or_else(|| anyhow!("No audio track found"))?;
+    /// Remove DC offset with a high-pass filter and ramp into files that start abruptly
+    #[arg(long, default_value = "false")]
+    dc_block: bool,
 
-        let track_id = track.id;
-        let codec_params = &track.codec_params;
+    /// Skip leading silence and drop trailing silence at the end of each play-through
+    #[arg(long, default_value = "false")]
+    trim_silence: bool,
 
-        self.source_sample_rate = codec_params.sample_rate;
-        info!(
-            "Audio: {} Hz, {} channels",
-            self.source_sample_rate.unwrap_or(0),
-            codec_params.channels.map(|c| c.count()).unwrap_or(0)
-        );
+    /// Level below which audio counts as silence for --trim-silence, in dBFS
+    #[arg(long, default_value = "-50", allow_hyphen_values = true)]
+    silence_threshold: f32,
 
-        let decoder =
-            symphonia::default::get_codecs().make(codec_params, &DecoderOptions::default())?;
+    /// Shorten silences longer than TIME to TIME, e.g. "-45,1s"; audio under DBFS is silent
+    #[arg(long, value_name = "DBFS,TIME", value_parser = parse_skip_silence, allow_hyphen_values = true)]
+    skip_silence: Option<(f32, Duration)>,
 
-        self.decoder = Some(decoder);
-        self.format = Some(format);
-        self.track_id = Some(track_id);
+    /// Per-channel gains applied after panning, e.g. "l=0.8,r=1.0"; stereo only
+    #[arg(long, value_parser = parse_channel_gains)]
+    channel_gains: Option<ChannelGains>,
 
-        Ok(())
-    }
+    /// Output ceiling as a fraction of full scale; louder samples are clipped and counted
+    #[arg(long, default_value = "1.0", value_parser = parse_max_volume)]
+    max_volume: f32,
 
-    fn decode_more(&mut self) -> Result<bool> {
-        let format = self.format.as_mut().ok_or_else(|| anyhow!("Not opened"))?;
-        let decoder = self.decoder.as_mut().ok_or_else(|| anyhow!("No decoder"))?;
-        let track_id = self.track_id.ok_or_else(|| anyhow!("No track"))?;
+    /// Automatically lower the gain when sustained clipping is detected
+    #[arg(long, default_value = "false")]
+    auto_gain: bool,
 
-        loop {
-            match format.next_packet() {
-                Ok(packet) => {
-                    if packet.track_id() != track_id {
-                        continue;
-                    }
+    /// Order of the processing stages, e.g. "normalize,agc,gain"; unlisted stages follow in default order
+    #[arg(long, value_enum, value_delimiter = ',')]
+    chain_order: Vec<StageKind>,
 
-                    match decoder.decode(&packet) {
-                        Ok(decoded) => {
-                            let spec = *decoded.spec();
-                            let duration = decoded.capacity() as u64;
+    /// Continuously adjust the gain to keep the level steady on inconsistent material
+    #[arg(long, default_value = "false")]
+    agc: bool,
 
-                            let mut sample_buf = SampleBuffer::<f32>::new(duration, spec);
-                            sample_buf.copy_interleaved_ref(decoded);
+    /// Level --agc steers towards, as RMS in dBFS
+    #[arg(long, default_value = "-20", allow_hyphen_values = true)]
+    agc_target: f32,
 
-                            let samples = sample_buf.samples();
-                            let source_channels = spec.channels.count();
-                            let source_rate = self.source_sample_rate.unwrap_or(SAMPLE_RATE);
+    /// How quickly --agc turns the gain down when audio gets louder, in milliseconds
+    #[arg(long, default_value = "200")]
+    agc_attack: u32,
 
-                            // Convert to mono and resample if needed
-                            for i in (0..samples.len()).step_by(source_channels) {
-                                // Mix to mono
-                                let mono: f32 = (0..source_channels)
-                                    .filter_map(|ch| samples.get(i + ch))
-                                    .sum::<f32>()
-                                    / source_channels as f32;
+    /// How quickly --agc turns the gain up when audio gets quieter, in milliseconds
+    #[arg(long, default_value = "3000")]
+    agc_release: u32,
 
-                                self.buffer.push_back(mono * self.volume);
-                            }
+    /// Record the live output to a WAV file
+    #[arg(long)]
+    record: Option<PathBuf>,
 
-                            // Simple linear resampling if rates don't match
-                            if source_rate != SAMPLE_RATE {
-                                let ratio = SAMPLE_RATE as f64 / source_rate as f64;
-                                let old_len = self.buffer.len();
-                                let new_len = (old_len as f64 * ratio) as usize;
-
-                                let old_samples: Vec<f32> = self.buffer.drain(..).collect();
-                                for i in 0..new_len {
-                                    let src_idx = i as f64 / ratio;
-                                    let idx0 = src_idx.floor() as usize;
-                                    let idx1 = (idx0 + 1).min(old_samples.len() - 1);
-                                    let frac = src_idx - idx0 as f64;
-
-                                    let sample = if idx0 < old_samples.len() {
-                                        old_samples[idx0] * (1.0 - frac as f32)
-                                            + old_samples.get(idx1).unwrap_or(&0.0) * frac as f32
-                                    } else {
-                                        0.0
-                                    };
-                                    self.buffer.push_back(sample);
-                                }
-                            }
+    /// Sample format of the recording
+    #[arg(long, value_enum, default_value = "f32", requires = "record")]
+    record_format: RecordFormat,
 
-                            return Ok(true);
-                        }
-                        Err(e) => {
-                            warn!("Decode error: {}", e);
-                            continue;
-                        }
-                    }
-                }
-                Err(symphonia::core::errors::Error::IoError(e))
-                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
-                {
-                    // End of file
-                    if self.loop_audio {
-                        info!("Looping audio...");
-                        self.open()?;
-                        return Ok(true);
-                    }
-                    return Ok(false);
-                }
-                Err(e) => {
-                    error!("Format error: {}", e);
-                    return Err(e.into());
-                }
-            }
-        }
-    }
+    /// Sample rate of the recording (defaults to the live stream rate)
+    #[arg(
+        long,
+        requires = "record",
+        value_parser = clap::value_parser!(u32).range(8000..=192000)
+    )]
+    record_rate: Option<u32>,
 
-    fn fill_buffer(&mut self, output: &mut [f32]) -> Result<usize> {
-        let mut filled = 0;
+    /// Log playback progress every N seconds (0 disables)
+    #[arg(long, default_value = "0")]
+    stats_interval: u64,
 
-        while filled < output.len() {
-            if self.buffer.is_empty() {
-                if !self.decode_more()? {
-                    // End of audio, fill rest with silence
-                    for sample in &mut output[filled..] {
-                        *sample = 0.0;
-                    }
-                    return Ok(output.len());
-                }
-            }
+    /// Periodically write playback status (position, duration, progress) to this file
+    #[arg(long)]
+    status_file: Option<PathBuf>,
 
-            while filled < output.len() && !self.buffer.is_empty() {
-                output[filled] = self.buffer.pop_front().unwrap_or(0.0);
-                filled += 1;
-            }
-        }
+    /// Request a buffer size in frames (node.latency); smaller is lower latency but risks xruns
+    #[arg(long)]
+    quantum: Option<u32>,
 
-        Ok(filled)
-    }
-}
+    /// Also ask the graph to run at the stream rate (node.rate) so --quantum is exact
+    #[arg(long, default_value = "false", requires = "quantum")]
+    rate_quantum: bool,
 
-struct VirtualDevice {
-    module_id: Option<u32>,
-    remap_module_id: Option<u32>,
-    loopback_module_id: Option<u32>,
-    sink_name: String,
-    source_name: String,
-}
+    /// Make the whole graph run at --quantum (node.force-quantum), not just ask for it
+    #[arg(long, default_value = "false", requires = "quantum")]
+    force_quantum: bool,
 
-impl VirtualDevice {
-    fn new(name: &str, monitor: bool) -> Result<Self> {
-        let sink_name = format!("{}_sink", name);
-        let source_name = name.to_string();
+    /// Accept runtime commands such as "load <path>" on this Unix socket
+    #[arg(long)]
+    control_socket: Option<PathBuf>,
 
-        // Step 1: Create a null-sink to receive audio
-        let output = Command::new("pactl")
-            .args([
-                "load-module",
-                "module-null-sink",
-                &format!("sink_name={}", sink_name),
-                &format!(
-                    "sink_properties=device.description=\"{}_Output\"",
-                    name
-                ),
-                &format!("rate={}", SAMPLE_RATE),
-                &format!("channels={}", CHANNELS),
-            ])
-            .output()?;
+    /// Take JSON-RPC 2.0 calls on this Unix socket; alone, $XDG_RUNTIME_DIR/virtual-mic.sock
+    #[arg(long, value_name = "PATH", num_args = 0..=1)]
+    json_rpc: Option<Option<PathBuf>>,
 
-        if !output.status.success() {
-            return Err(anyhow!(
-                "Failed to create null sink: {}",
-                String::from_utf8_lossy(&output.stderr)
-            ));
-        }
+    /// Serve the io.virtualmic varlink interface on this Unix socket; alone,
+    /// $XDG_RUNTIME_DIR/io.virtualmic
+    #[arg(long, value_name = "PATH", num_args = 0..=1)]
+    varlink: Option<Option<PathBuf>>,
 
-        let module_id: u32 = String::from_utf8_lossy(&output.stdout)
-            .trim()
-            .parse()
-            .map_err(|_| anyhow!("Failed to parse module ID"))?;
+    /// Serve a REST API answering in JSON on this address, e.g. 127.0.0.1:8080
+    #[arg(long, value_name = "ADDR:PORT")]
+    http: Option<SocketAddr>,
 
-        info!("Created null sink with module ID: {}", module_id);
+    /// Require this token from clients of --http and --listen-ws, sent as
+    /// "Authorization: Bearer <token>"
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        env = "VIRTUAL_MIC_AUTH_TOKEN",
+        hide_env_values = true,
+        value_parser = clap::builder::NonEmptyStringValueParser::new()
+    )]
+    auth_token: Option<String>,
 
-        // Step 2: Create a remap-source that exposes the monitor as a proper microphone
-        // This makes it appear as a real input device to browsers
-        let monitor_name = format!("{}.monitor", sink_name);
-        let output = Command::new("pactl")
-            .args([
-                "load-module",
-                "module-remap-source",
-                &format!("source_name={}", source_name),
-                &format!("master={}", monitor_name),
-                &format!(
-                    "source_properties=device.description=\"{}\"",
-                    name
-                ),
-            ])
-            .output()?;
+    /// Serve --http and --listen-ws over TLS with this PEM certificate chain
+    /// (needs the tls feature)
+    #[arg(long, value_name = "PEM", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
 
-        if !output.status.success() {
-            // Clean up the sink if remap fails
-            let _ = Command::new("pactl")
-                .args(["unload-module", &module_id.to_string()])
-                .output();
-            return Err(anyhow!(
-                "Failed to create remap source: {}",
-                String::from_utf8_lossy(&output.stderr)
-            ));
-        }
+    /// The private key of --tls-cert, in PEM
+    #[arg(long, value_name = "PEM", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
 
-        let remap_module_id: u32 = String::from_utf8_lossy(&output.stdout)
-            .trim()
-            .parse()
-            .map_err(|_| anyhow!("Failed to parse remap module ID"))?;
+    /// Serve org.virtualmic.Player1 on the D-Bus session bus (needs the dbus feature)
+    #[arg(long, default_value = "false")]
+    dbus: bool,
 
-        info!("Created remap source with module ID: {}", remap_module_id);
+    /// Serve the MPRIS2 media player interface, for desktop widgets, playerctl and
+    /// media keys (needs the dbus feature)
+    #[arg(long, default_value = "false")]
+    mpris: bool,
 
-        // Step 3: Optionally create a loopback to play audio through speakers
-        let loopback_module_id = if monitor {
-            let monitor_name = format!("{}.monitor", sink_name);
-            let output = Command::new("pactl")
-                .args([
-                    "load-module",
-                    "module-loopback",
-                    &format!("source={}", monitor_name),
-                    "latency_msec=1",
-                ])
-                .output()?;
+    /// Duck the playing audio by this many dB under an "interject" clip
+    /// instead of pausing it
+    #[arg(long, value_name = "DB")]
+    interject_duck: Option<f32>,
 
-            if !output.status.success() {
-                warn!(
-                    "Failed to create loopback (audio won't play through speakers): {}",
-                    String::from_utf8_lossy(&output.stderr)
-                );
-                None
-            } else {
-                let loopback_id: Option<u32> = String::from_utf8_lossy(&output.stdout)
-                    .trim()
-                    .parse()
-                    .ok();
-                if let Some(id) = loopback_id {
-                    info!("Created loopback with module ID: {} (audio will play through speakers)", id);
+    /// How quickly --interject-duck takes the playing audio down when a clip sounds, in milliseconds
+    #[arg(long, default_value = "100")]
+    duck_attack: u32,
+
+    /// How quickly --interject-duck brings the playing audio back up when a clip goes quiet, in milliseconds
+    #[arg(long, default_value = "500")]
+    duck_release: u32,
+
+    /// Queue each path appended to this file at the end of the playlist
+    #[arg(long, value_name = "FILE")]
+    queue_file: Option<PathBuf>,
+
+    /// POST playback events to this URL as JSON
+    #[arg(long, value_name = "URL")]
+    webhook: Option<String>,
+
+    /// Crossfade applied when "load" replaces the playing file, in milliseconds
+    #[arg(long, default_value = "50")]
+    load_crossfade: u64,
+
+    /// Don't take single-key commands (space to pause) from the terminal
+    #[arg(long, default_value = "false")]
+    no_keys: bool,
+
+    /// Show a full-screen dashboard (status, level meter, queue and log) instead
+    /// of the log stream (needs the tui feature)
+    #[arg(long, default_value = "false", conflicts_with = "no_keys")]
+    tui: bool,
+
+    /// Take control commands typed on stdin, one per line ("seek +10", "play <path>",
+    /// "vol 0.8"), in place of single keys
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with_all = ["tui", "stdin_pcm", "say_stdin"]
+    )]
+    repl: bool,
+
+    /// Push-to-talk: send silence unless this is held. key:<name>, joystick:<button>
+    /// or midi:<note> (midi:cc<n> for a pedal), each optionally @<device>
+    #[arg(long, value_name = "TRIGGER", value_parser = ptt::parse_trigger)]
+    ptt: Option<ptt::Trigger>,
+
+    /// System-wide hotkeys read from the input devices, e.g. "ctrl+alt+m=toggle-mute,f13=next";
+    /// alone, Ctrl+Alt with p, m, n and b pause, mute and skip
+    #[arg(
+        long,
+        value_name = "BINDINGS",
+        num_args = 0..=1,
+        default_missing_value = hotkeys::DEFAULT_BINDINGS,
+        value_parser = hotkeys::parse_bindings
+    )]
+    global_keys: Option<hotkeys::Bindings>,
+
+    /// Map the notes and controllers of a MIDI controller to commands, from a TOML file
+    #[arg(long, value_name = "FILE")]
+    midi_map: Option<PathBuf>,
+
+    /// Take OSC messages (/virtualmic/play, /virtualmic/volume, ...) on this UDP address
+    #[arg(long, value_name = "ADDR:PORT")]
+    osc: Option<SocketAddr>,
+
+    /// Quit once playback has finished and played out, instead of streaming silence
+    /// (same as --on-end exit)
+    #[arg(long, default_value = "false")]
+    exit_on_end: bool,
+
+    /// What happens when an entry ends [default: next]
+    #[arg(long, value_enum, conflicts_with = "exit_on_end")]
+    on_end: Option<OnEnd>,
+
+    /// Quit on the first decode error or playlist entry that fails to open,
+    /// instead of skipping it
+    #[arg(long, default_value = "false")]
+    strict: bool,
+
+    /// Shell command to run as each track starts, with the track described in
+    /// VIRTUAL_MIC_* environment variables
+    #[arg(long, value_name = "CMD")]
+    on_track_start: Option<String>,
+
+    /// Shell command to run as each track's input runs out
+    #[arg(long, value_name = "CMD")]
+    on_track_end: Option<String>,
+
+    /// Stop, remove the devices and quit after this long: seconds, m:ss or e.g. 10m
+    #[arg(long, value_name = "TIME", value_parser = parse_length)]
+    max_duration: Option<Duration>,
+
+    /// Fade out over this long when --max-duration runs out [default: --fade-out]
+    #[arg(long, value_name = "TIME", value_parser = parse_time, requires = "max_duration")]
+    max_duration_fade: Option<Duration>,
+
+    /// Create this file once the microphone is usable, and remove it on exit
+    #[arg(long)]
+    ready_file: Option<PathBuf>,
+
+    /// Stream media role, e.g. "Communication" for conferencing routing/ducking
+    #[arg(long, default_value = "Music")]
+    media_role: String,
+
+    /// Stream media category
+    #[arg(long, default_value = "Playback")]
+    media_category: String,
+
+    /// Number of times to retry a failing pactl command
+    #[arg(long, default_value = "3")]
+    pactl_retries: u32,
+
+    /// Initial delay between pactl retries in milliseconds (doubles each attempt, up to 5s)
+    #[arg(long, default_value = "250")]
+    pactl_retry_delay: u64,
+}
+
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    /// Stop a running instance and unload its modules
+    Stop {
+        /// Name the instance was started with (--name)
+        #[arg(value_parser = instance::parse_name)]
+        name: String,
+    },
+    /// Preload short clips and play them on the number keys; microphone
+    /// options go before the subcommand
+    Soundboard {
+        /// Clips for keys 1 to 9, then 0
+        #[arg(required = true, num_args = 1..=10)]
+        clips: Vec<PathBuf>,
+
+        /// What a key does while a clip still plays
+        #[arg(long, value_enum, default_value = "cut")]
+        overlap: Overlap,
+    },
+}
+
+/// Sample format of the stream and the null sink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum SampleFormat {
+    /// 32-bit float
+    F32,
+    /// 16-bit signed integer
+    S16,
+}
+
+impl SampleFormat {
+    fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleFormat::F32 => std::mem::size_of::<f32>(),
+            SampleFormat::S16 => std::mem::size_of::<i16>(),
+        }
+    }
+
+    fn spa_format(self) -> u32 {
+        match self {
+            SampleFormat::F32 => pw::spa::sys::SPA_AUDIO_FORMAT_F32_LE,
+            SampleFormat::S16 => pw::spa::sys::SPA_AUDIO_FORMAT_S16_LE,
+        }
+    }
+
+    /// Format name understood by PulseAudio module arguments.
+    fn pulse_name(self) -> &'static str {
+        match self {
+            SampleFormat::F32 => "float32le",
+            SampleFormat::S16 => "s16le",
+        }
+    }
+
+    /// Encode float samples into `out`, which must hold `samples.len()` samples.
+    fn encode(self, samples: &[f32], out: &mut [u8]) {
+        let width = self.bytes_per_sample();
+        for (sample, bytes) in samples.iter().zip(out.chunks_exact_mut(width)) {
+            match self {
+                SampleFormat::F32 => bytes.copy_from_slice(&sample.to_le_bytes()),
+                SampleFormat::S16 => {
+                    let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+                    bytes.copy_from_slice(&value.to_le_bytes());
                 }
-                loopback_id
             }
-        } else {
-            None
-        };
+        }
+    }
+}
 
-        info!("Virtual microphone '{}' created - select it in your application", source_name);
+/// What `--repeat` plays again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Repeat {
+    /// Play the playlist once
+    None,
+    /// Play the current entry over and over
+    One,
+    /// Start the playlist over after its last entry, like --loop
+    All,
+}
 
-        Ok(Self {
-            module_id: Some(module_id),
-            remap_module_id: Some(remap_module_id),
-            loopback_module_id,
-            sink_name,
-            source_name,
-        })
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OnEnd {
+    /// Stop and stream silence until a command starts something else
+    Silence,
+    /// Move on like `next`, and quit when nothing is left to play
+    Exit,
+    /// Play the entry again
+    Loop,
+    /// Move on to the next entry (as --loop and --repeat say), then stream silence
+    Next,
+    /// Move on like `next`, then stop streaming but keep the device
+    HoldDevice,
+}
 
-    fn sink_name(&self) -> &str {
-        &self.sink_name
+impl OnEnd {
+    fn from_args(args: &Args) -> Self {
+        match args.on_end {
+            Some(on_end) => on_end,
+            None if args.exit_on_end => Self::Exit,
+            None => Self::Next,
+        }
     }
 }
 
-impl Drop for VirtualDevice {
-    fn drop(&mut self) {
-        // Unload in reverse order: loopback, remap source, then sink
-        if let Some(loopback_id) = self.loopback_module_id {
-            info!("Cleaning up loopback (module {})", loopback_id);
-            let _ = Command::new("pactl")
-                .args(["unload-module", &loopback_id.to_string()])
-                .output();
+/// Common rate/channel/format combinations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Preset {
+    /// 48 kHz mono float, what browsers and WebRTC apps capture natively
+    Webrtc,
+    /// 48 kHz stereo float
+    Music,
+    /// 8 kHz mono 16-bit, narrowband telephony
+    Telephony,
+}
+
+/// Rate, channel count and sample format shared by the null sink, the
+/// PipeWire stream and the decoder's resample target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StreamFormat {
+    rate: u32,
+    channels: u32,
+    sample_format: SampleFormat,
+}
+
+impl StreamFormat {
+    fn preset(preset: Preset) -> Self {
+        match preset {
+            Preset::Webrtc => DEFAULT_FORMAT,
+            Preset::Music => StreamFormat {
+                channels: 2,
+                ..DEFAULT_FORMAT
+            },
+            Preset::Telephony => StreamFormat {
+                rate: 8000,
+                channels: 1,
+                sample_format: SampleFormat::S16,
+            },
         }
-        if let Some(remap_id) = self.remap_module_id {
-            info!("Cleaning up remap source (module {})", remap_id);
-            let _ = Command::new("pactl")
-                .args(["unload-module", &remap_id.to_string()])
-                .output();
+    }
+
+    /// Resolve the format from `--preset`, with explicit flags taking precedence.
+    fn from_args(args: &Args) -> Self {
+        let base = args.preset.map(Self::preset).unwrap_or(DEFAULT_FORMAT);
+        Self {
+            rate: args.rate.unwrap_or(base.rate),
+            channels: args.channels.unwrap_or(base.channels),
+            sample_format: args.sample_format.unwrap_or(base.sample_format),
         }
-        if let Some(module_id) = self.module_id {
-            info!("Cleaning up null sink (module {})", module_id);
-            let _ = Command::new("pactl")
-                .args(["unload-module", &module_id.to_string()])
-                .output();
+    }
+
+    /// Arguments describing this format for `module-null-sink`.
+    fn sink_args(&self) -> [String; 3] {
+        [
+            format!("rate={}", self.rate),
+            format!("channels={}", self.channels),
+            format!("format={}", self.sample_format.pulse_name()),
+        ]
+    }
+}
+
+/// Per-channel gain multipliers for stereo output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ChannelGains {
+    left: f32,
+    right: f32,
+}
+
+impl Default for ChannelGains {
+    fn default() -> Self {
+        Self {
+            left: 1.0,
+            right: 1.0,
         }
     }
 }
 
-fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .with_env_filter(
-            tracing_subscriber::EnvFilter::from_default_env()
-                .add_directive(tracing::Level::INFO.into()),
-        )
-        .init();
+fn parse_pan(s: &str) -> Result<f32, String> {
+    let pan: f32 = s.parse().map_err(|_| format!("invalid pan value: {}", s))?;
+    if !(-1.0..=1.0).contains(&pan) {
+        return Err(format!("pan must be between -1.0 and 1.0, got {}", pan));
+    }
+    Ok(pan)
+}
 
-    let args = Args::parse();
+fn parse_signal(s: &str) -> Result<Signal, String> {
+    s.parse()
+}
 
-    if !args.file.exists() {
-        return Err(anyhow!("Audio file not found: {:?}", args.file));
+fn parse_level(s: &str) -> Result<f32, String> {
+    let level: f32 = s.parse().map_err(|_| format!("invalid level: {}", s))?;
+    if !(level.is_finite() && level <= 0.0) {
+        return Err(format!("level must be at most 0 dBFS, got {}", s));
     }
+    Ok(level)
+}
 
-    // Create the virtual audio device (null sink with monitor)
-    let virtual_device = VirtualDevice::new(&args.name, args.monitor)?;
+fn parse_time(s: &str) -> Result<Duration, String> {
+    time::parse(s).ok_or_else(|| format!("invalid time '{}'; use seconds, m:ss or e.g. 1m30s", s))
+}
 
-    info!("Initializing PipeWire...");
-    pw::init();
+fn parse_skip_silence(s: &str) -> Result<(f32, Duration), String> {
+    let (level, time) = s
+        .split_once(',')
+        .ok_or_else(|| format!("invalid --skip-silence '{}'; use DBFS,TIME, e.g. -45,1s", s))?;
+    Ok((parse_level(level.trim())?, parse_time(time.trim())?))
+}
 
-    let mainloop = pw::main_loop::MainLoop::new(None)?;
-    let context = pw::context::Context::new(&mainloop)?;
-    let core = context.connect(None)?;
+fn parse_length(s: &str) -> Result<Duration, String> {
+    match parse_time(s)? {
+        duration if duration.is_zero() => Err(format!("must be longer than zero, got {}", s)),
+        duration => Ok(duration),
+    }
+}
 
-    let decoder = Rc::new(RefCell::new(AudioDecoder::new(
-        args.file.clone(),
-        args.loop_audio,
-        args.volume.clamp(0.0, 2.0),
-    )));
+fn parse_lufs(s: &str) -> Result<f64, String> {
+    let number = s
+        .trim()
+        .strip_suffix("LUFS")
+        .or_else(|| s.trim().strip_suffix("lufs"))
+        .unwrap_or(s);
+    match number.trim().parse::<f64>() {
+        Ok(lufs) if (-70.0..=0.0).contains(&lufs) => Ok(lufs),
+        _ => Err(format!(
+            "invalid loudness '{}'; use -70 to 0 LUFS, e.g. -16LUFS",
+            s
+        )),
+    }
+}
 
-    // Open the audio file
-    decoder.borrow_mut().open()?;
+fn parse_time_of_day(s: &str) -> Result<Duration, String> {
+    time::parse_time_of_day(s)
+        .ok_or_else(|| format!("invalid time of day '{}'; use hh:mm or hh:mm:ss", s))
+}
 
-    info!("Creating audio stream to virtual device...");
+fn parse_epoch(s: &str) -> Result<SystemTime, String> {
+    let seconds: f64 = s.parse().map_err(|_| format!("invalid Unix time: {}", s))?;
+    Duration::try_from_secs_f64(seconds)
+        .map(|since| UNIX_EPOCH + since)
+        .map_err(|_| format!("invalid Unix time: {}", s))
+}
 
-    let values: Vec<u8> = pw::spa::pod::serialize::PodSerializer::serialize(
-        std::io::Cursor::new(Vec::new()),
-        &pw::spa::pod::Value::Object(pw::spa::pod::Object {
-            type_: pw::spa::sys::SPA_TYPE_OBJECT_Format,
+fn parse_speed(s: &str) -> Result<f64, String> {
+    let speed: f64 = s.parse().map_err(|_| format!("invalid speed: {}", s))?;
+    if !(0.5..=2.0).contains(&speed) {
+        return Err(format!("speed must be between 0.5 and 2.0, got {}", s));
+    }
+    Ok(speed)
+}
+
+fn parse_tempo(s: &str) -> Result<f64, String> {
+    let tempo: f64 = s.parse().map_err(|_| format!("invalid tempo: {}", s))?;
+    if !(0.5..=2.0).contains(&tempo) {
+        return Err(format!("tempo must be between 0.5 and 2.0, got {}", s));
+    }
+    Ok(tempo)
+}
+
+fn parse_max_volume(s: &str) -> Result<f32, String> {
+    let max: f32 = s
+        .parse()
+        .map_err(|_| format!("invalid max volume: {}", s))?;
+    if !(max > 0.0 && max <= 1.0) {
+        return Err(format!("max volume must be in (0.0, 1.0], got {}", max));
+    }
+    Ok(max)
+}
+
+fn parse_channel_gains(s: &str) -> Result<ChannelGains, String> {
+    let mut gains = ChannelGains::default();
+    for part in s.split(',') {
+        let (key, value) = part
+            .split_once('=')
+            .ok_or_else(|| format!("expected <channel>=<gain>, got '{}'", part))?;
+        let gain: f32 = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid gain for '{}': {}", key, value))?;
+        if !(0.0..=2.0).contains(&gain) {
+            return Err(format!("gain for '{}' must be between 0.0 and 2.0", key));
+        }
+        match key.trim() {
+            "l" | "left" => gains.left = gain,
+            "r" | "right" => gains.right = gain,
+            other => return Err(format!("unknown channel '{}' (expected l or r)", other)),
+        }
+    }
+    Ok(gains)
+}
+
+/// Compute the per-output-channel gains from the pan position and channel gains.
+///
+/// Panning uses the equal-power law, so a centered pan puts each channel at -3 dB.
+/// Mono output has nothing to pan across and always gets unity gain.
+fn output_gains(channels: usize, pan: Option<f32>, gains: ChannelGains) -> Vec<f32> {
+    if channels < 2 {
+        return vec![1.0; channels];
+    }
+
+    let (pan_l, pan_r) = match pan {
+        Some(pan) => {
+            let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+            (angle.cos(), angle.sin())
+        }
+        None => (1.0, 1.0),
+    };
+
+    vec![pan_l * gains.left, pan_r * gains.right]
+}
+
+/// Playback position within the current file.
+#[derive(Debug, Clone, Copy)]
+struct Progress {
+    position: Duration,
+    /// `None` for streams and files without a known frame count.
+    duration: Option<Duration>,
+    /// 1-based playlist position and playlist length.
+    track: usize,
+    tracks: usize,
+}
+
+impl Progress {
+    /// Progress through the file as 0-100%, if the duration is known.
+    fn percent(&self) -> Option<f64> {
+        let duration = self.duration?.as_secs_f64();
+        if duration <= 0.0 {
+            return None;
+        }
+        Some((self.position.as_secs_f64() / duration * 100.0).min(100.0))
+    }
+
+    /// Progress through the whole playlist as 0-100%, each entry counting
+    /// equally: the lengths of entries not yet opened aren't known.
+    fn playlist_percent(&self) -> Option<f64> {
+        let done = self.track.checked_sub(1)? as f64 + self.percent()? / 100.0;
+        Some((done / self.tracks as f64 * 100.0).min(100.0))
+    }
+
+    /// The progress as `key=value` fields, as written to `--status-file`.
+    fn fields(&self) -> Vec<String> {
+        let mut fields = vec![
+            format!("track={}", self.track),
+            format!("tracks={}", self.tracks),
+            format!("position_secs={:.3}", self.position.as_secs_f64()),
+        ];
+        match (self.duration, self.percent()) {
+            (Some(duration), Some(percent)) => {
+                fields.push(format!("duration_secs={:.3}", duration.as_secs_f64()));
+                fields.push(format!("progress_percent={:.1}", percent));
+            }
+            _ => {
+                fields.push("duration_secs=unknown".to_string());
+                fields.push("progress_percent=unknown".to_string());
+            }
+        }
+        fields.push(match self.playlist_percent() {
+            Some(percent) => format!("playlist_percent={:.1}", percent),
+            None => "playlist_percent=unknown".to_string(),
+        });
+        fields
+    }
+}
+
+impl std::fmt::Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.tracks > 1 {
+            write!(f, "[{}/{}] ", self.track, self.tracks)?;
+        }
+        match (self.duration, self.percent()) {
+            (Some(duration), Some(percent)) => write!(
+                f,
+                "{} / {} ({:.1}%)",
+                format_duration(self.position),
+                format_duration(duration),
+                percent
+            )?,
+            _ => write!(f, "{} / unknown duration", format_duration(self.position))?,
+        }
+        match self.playlist_percent() {
+            Some(percent) if self.tracks > 1 => write!(f, ", {:.1}% of the playlist", percent),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Format a duration as `m:ss.s`.
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs_f64();
+    format!("{}:{:04.1}", (secs / 60.0) as u64, secs % 60.0)
+}
+
+/// Write the current status as `key=value` lines, replacing the file atomically.
+fn write_status_file(
+    path: &Path,
+    file: &Path,
+    title: Option<&str>,
+    progress: Progress,
+) -> Result<()> {
+    let mut status = format!("file={}\n", file.display());
+    if let Some(title) = title {
+        status.push_str(&format!("title={}\n", title));
+    }
+    for field in progress.fields() {
+        status.push_str(&field);
+        status.push('\n');
+    }
+
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, status)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Headroom left below the `--max-volume` ceiling when `--auto-gain` lowers the gain.
+const AUTO_GAIN_HEADROOM_DB: f32 = 1.0;
+
+/// How often queued control commands are applied.
+const CONTROL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How often clipping is checked when neither stats nor a status file are enabled.
+const CLIP_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Captured `--mix-source` audio buffered at most before the oldest is dropped.
+const MIX_MAX_LATENCY_MS: u32 = 100;
+
+/// Silence streamed after the last of the audio before `--exit-on-end` quits,
+/// so what is still queued in the graph reaches the microphone's readers.
+const END_DRAIN_MS: u32 = 250;
+
+/// How long the input takes to fade out under an `interject` clip, and back
+/// in, when it is held rather than ducked.
+const INTERJECT_FADE: Duration = Duration::from_millis(100);
+
+/// Clip level (about -50 dBFS) above which `--interject-duck` ducks the input.
+const DUCK_THRESHOLD: f32 = 0.003;
+
+/// How long muting and unmuting take, just long enough not to click.
+const MUTE_FADE: Duration = Duration::from_millis(10);
+
+/// How long Ctrl+C fades the audio out over, unless `--fade-out` is set.
+const SHUTDOWN_FADE: Duration = Duration::from_millis(200);
+
+/// How much longer than its fade shutdown waits for the audio to play out
+/// before giving up, e.g. when nothing in the graph is pulling from the stream.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The `--raw-*`/`--pcm-*` parameters, if all three are given.
+fn pcm_params(args: &Args) -> Option<RawParams> {
+    Some(RawParams {
+        format: args.raw_format?,
+        rate: args.raw_rate?,
+        channels: args.raw_channels? as usize,
+    })
+}
+
+/// Raw PCM parameters for files and stdin, when `--raw` or `--stdin-pcm` is given,
+/// and for `--exec` output that isn't encoded.
+fn raw_params(args: &Args) -> Option<RawParams> {
+    // clap makes --raw and --stdin-pcm require all three parameters
+    pcm_params(args).filter(|_| args.raw || args.stdin_pcm || args.exec.is_some())
+}
+
+/// The part of each file that plays, from --start, --end and --duration.
+fn slice(args: &Args) -> Result<Option<Span>> {
+    if args.start.is_none() && args.end.is_none() && args.duration.is_none() {
+        return Ok(None);
+    }
+    let start = args.start.unwrap_or_default();
+    let end = args.end.or(args.duration.map(|duration| start + duration));
+    if end.is_some_and(|end| end <= start) {
+        return Err(anyhow!("--end must come after --start"));
+    }
+    Ok(Some(Span { start, end }))
+}
+
+/// The --generate signal or --dtmf sequence to play, if any.
+fn generated_signal(args: &Args) -> Option<Signal> {
+    let dtmf = args.dtmf.as_ref().map(|digits| Signal::Dtmf {
+        digits: digits.clone(),
+        tone: Duration::from_millis(args.dtmf_tone),
+        gap: Duration::from_millis(args.dtmf_gap),
+    });
+    args.generate.clone().or(dtmf)
+}
+
+/// An input being opened on a background thread, to be swapped in once its
+/// first packet has decoded.
+struct Loading {
+    input: InputSpec,
+    /// The playlist entry it is, for `track`; `None` for `load`.
+    track: Option<usize>,
+    crossfade: Duration,
+    opened: Receiver<Result<Source>>,
+}
+
+/// `--timeline` playback: which playlist entry starts when.
+struct Timeline {
+    /// When each playlist entry starts, in output frames from the start.
+    cues: Vec<u64>,
+    /// Index of the next cue to play.
+    next: usize,
+    /// Output frames since playback started.
+    clock: u64,
+    /// When the first buffer was filled, to compare the cues against.
+    started: Option<Instant>,
+    /// The next cue's input, opening ahead of time.
+    preload: Option<(usize, Receiver<Result<Source>>)>,
+}
+
+/// How late `--start-at` may be noticed before it is reported as missed.
+const START_AT_TOLERANCE: Duration = Duration::from_secs(1);
+
+/// How far ahead of its time a cue's file is opened.
+const TIMELINE_PRELOAD: Duration = Duration::from_secs(2);
+
+/// Open `input` and decode its first packet on a background thread.
+/// With `--normalize`, it is measured first.
+fn open_in_background(
+    input: InputSpec,
+    loudness: Option<Loudness>,
+) -> Result<Receiver<Result<Source>>> {
+    let (tx, opened) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("load".into())
+        .spawn(move || {
+            if let Some(loudness) = loudness {
+                loudness.measure(&input);
+            }
+            let source = Source::open(&input).and_then(|mut source| {
+                source.prime()?;
+                Ok(source)
+            });
+            // Nobody is waiting if it was replaced in the meantime
+            let _ = tx.send(source);
+        })?;
+    Ok(opened)
+}
+
+/// A clip for `interject` being opened on a background thread.
+struct OpeningClip {
+    input: InputSpec,
+    opened: Receiver<Result<Source>>,
+}
+
+/// A clip played over the input by `interject`.
+struct Interjection {
+    path: PathBuf,
+    source: Source,
+    resampler: LinearResampler,
+    /// Processed audio of the clip, ready to mix in.
+    buffer: VecDeque<f32>,
+    /// The clip has run out; what is left in `buffer` still plays.
+    ended: bool,
+}
+
+/// Downmix or upmix interleaved source frames to the output channel count.
+fn remix(samples: &[f32], source_channels: usize, channels: usize) -> Vec<f32> {
+    let mut frames = Vec::with_capacity(samples.len() / source_channels * channels);
+    for frame in samples.chunks(source_channels) {
+        if channels == 1 || source_channels == 1 {
+            // Mix to mono, duplicating across outputs for stereo
+            let mono = frame.iter().sum::<f32>() / source_channels as f32;
+            frames.extend(std::iter::repeat_n(mono, channels));
+        } else {
+            // Keep the front left/right pair of multichannel sources
+            frames.extend_from_slice(&frame[..channels]);
+        }
+    }
+    frames
+}
+
+/// How the playlist carries on and repeats, from the command line.
+struct LoopOptions {
+    /// How many times the playlist plays in all; `None` loops forever.
+    passes: Option<u32>,
+    /// `--repeat one`: play the current entry again instead of moving on.
+    one: bool,
+    /// `--on-end silence`: stop at the end of each entry instead of moving on.
+    stop: bool,
+    /// Silence played before each repetition.
+    gap: Duration,
+    /// How much of the end of each repetition fades into the start of the next.
+    crossfade: Duration,
+}
+
+impl LoopOptions {
+    fn from_args(args: &Args, stdin: bool) -> Self {
+        let on_end = OnEnd::from_args(args);
+        let one = args.repeat == Some(Repeat::One) || on_end == OnEnd::Loop;
+        let passes = match args.repeat {
+            // stdin can't be rewound, so there is nothing to loop
+            _ if stdin => Some(1),
+            _ if one => None,
+            Some(Repeat::None) => Some(1),
+            Some(Repeat::One | Repeat::All) => None,
+            None => args.loop_audio.unwrap_or(Some(1)),
+        };
+        Self {
+            passes,
+            one: one && !stdin,
+            // A timeline starts each file itself
+            stop: on_end == OnEnd::Silence || args.timeline.is_some() || args.reply_to.is_some(),
+            gap: args.loop_gap.unwrap_or_default(),
+            crossfade: Duration::from_millis(args.loop_crossfade),
+        }
+    }
+}
+
+/// Sample processing settings derived from the command line.
+struct ProcessingOptions {
+    volume: f32,
+    /// Playback speed, applied while resampling to `sample_rate`.
+    speed: f64,
+    /// Playback tempo, applied by time stretching after resampling.
+    tempo: f64,
+    fade_in: Duration,
+    fade_out: Duration,
+    /// Gain on the input under an `interject` clip; 0 to hold it instead.
+    interject_duck: f32,
+    /// `--duck-attack` and `--duck-release`.
+    duck_attack: Duration,
+    duck_release: Duration,
+    /// `--replay-gain` and its preamp in dB.
+    replay_gain: Option<(ReplayGainMode, f32)>,
+    /// `--normalize` target in LUFS.
+    normalize: Option<f64>,
+    sample_rate: u32,
+    channels: usize,
+    channel_gains: Vec<f32>,
+    dc_block: bool,
+    /// Threshold in dBFS when `--trim-silence` is enabled.
+    silence_threshold: Option<f32>,
+    /// `--skip-silence` threshold in dBFS and the longest pause kept.
+    skip_silence: Option<(f32, Duration)>,
+    max_volume: f32,
+    auto_gain: bool,
+    agc: Option<AgcOptions>,
+    chain_order: Vec<StageKind>,
+}
+
+impl ProcessingOptions {
+    fn from_args(args: &Args, format: &StreamFormat) -> Result<Self> {
+        let channels = format.channels as usize;
+        Ok(Self {
+            volume: args.volume.clamp(0.0, 2.0),
+            speed: args.speed,
+            tempo: args.tempo,
+            fade_in: args.fade_in.unwrap_or_default(),
+            fade_out: args.fade_out.unwrap_or_default(),
+            interject_duck: args
+                .interject_duck
+                .map_or(0.0, |db| 10f32.powf(-db.abs() / 20.0)),
+            duck_attack: Duration::from_millis(args.duck_attack.into()),
+            duck_release: Duration::from_millis(args.duck_release.into()),
+            replay_gain: args.replay_gain.map(|mode| (mode, args.replay_gain_preamp)),
+            normalize: args.normalize,
+            sample_rate: format.rate,
+            channels,
+            channel_gains: output_gains(channels, args.pan, args.channel_gains.unwrap_or_default()),
+            dc_block: args.dc_block,
+            silence_threshold: args.trim_silence.then_some(args.silence_threshold),
+            skip_silence: args.skip_silence,
+            max_volume: args.max_volume,
+            auto_gain: args.auto_gain,
+            agc: args.agc.then_some(AgcOptions {
+                target_dbfs: args.agc_target,
+                attack_ms: args.agc_attack,
+                release_ms: args.agc_release,
+            }),
+            chain_order: dsp::resolve_order(&args.chain_order)?,
+        })
+    }
+
+    /// Build the processing chain for the enabled stages, in the configured order.
+    fn build_chain(
+        &self,
+        volume: Rc<Cell<f32>>,
+        auto_gain: Rc<Cell<f32>>,
+        file_gain: Rc<Cell<f32>>,
+        file_loudness: Rc<Cell<Option<f64>>>,
+        clip_stats: Rc<Cell<ClipStats>>,
+    ) -> ProcessChain {
+        let (channels, sample_rate) = (self.channels, self.sample_rate);
+        let mut chain = ProcessChain::default();
+        for kind in &self.chain_order {
+            let stage: Box<dyn Stage> = match kind {
+                StageKind::DcBlock if self.dc_block => {
+                    Box::new(DcBlocker::new(channels, sample_rate))
+                }
+                StageKind::TrimSilence => match self.silence_threshold {
+                    Some(threshold) => {
+                        Box::new(SilenceTrimmer::new(threshold, channels, sample_rate))
+                    }
+                    None => continue,
+                },
+                StageKind::SkipSilence => match self.skip_silence {
+                    Some((threshold, keep)) => {
+                        Box::new(SilenceSkipper::new(threshold, keep, sample_rate))
+                    }
+                    None => continue,
+                },
+                StageKind::Normalize => match self.normalize {
+                    Some(target) => Box::new(Normalizer::new(
+                        target,
+                        file_loudness.clone(),
+                        channels,
+                        sample_rate,
+                    )),
+                    None => continue,
+                },
+                StageKind::Agc => match self.agc {
+                    Some(agc) => Box::new(Agc::new(agc, sample_rate)),
+                    None => continue,
+                },
+                StageKind::Declick if self.dc_block => Box::new(Declicker::new(sample_rate)),
+                StageKind::Gain => Box::new(Gain::new(
+                    volume.clone(),
+                    auto_gain.clone(),
+                    file_gain.clone(),
+                    self.channel_gains.clone(),
+                    sample_rate,
+                )),
+                StageKind::Limit => Box::new(Limiter::new(self.max_volume, clip_stats.clone())),
+                _ => continue,
+            };
+            chain.push(stage);
+        }
+        chain
+    }
+}
+
+/// How to open a playlist entry, given how every entry is opened.
+fn entry_input(entry_spec: &InputSpec, entry: &Entry) -> InputSpec {
+    InputSpec {
+        path: entry.path.clone(),
+        span: match (entry.span, entry_spec.span) {
+            (Some(entry), Some(slice)) => Some(slice.within(entry)),
+            (entry, slice) => entry.or(slice),
+        },
+        ..entry_spec.clone()
+    }
+}
+
+struct AudioDecoder {
+    playlist: Playlist,
+    /// How every playlist entry is opened (`--format-hint`, `--raw`, `--exec`,
+    /// `--url`); the path is filled in per entry.
+    entry_spec: InputSpec,
+    /// What is playing now: the current playlist entry, or a file loaded at runtime.
+    input: InputSpec,
+    /// Name of the playing cue sheet track.
+    title: Option<String>,
+    looping: LoopOptions,
+    /// `--crossfade` between playlist entries.
+    crossfade: Duration,
+    /// The play-through of the playlist in progress, from 1.
+    pass: u32,
+    /// Samples of the loop gap still to play before the input carries on.
+    gap_samples: usize,
+    /// Set while a repetition fades in, so it doesn't crossfade again if it ends.
+    fading: bool,
+    /// Output rate that decoded audio is resampled to.
+    sample_rate: u32,
+    channels: usize,
+    /// `--speed`: input time played per second of output, pitch and all.
+    speed: f64,
+    /// `--tempo`: the same, keeping the pitch.
+    tempo: f64,
+    chain: ProcessChain,
+    /// `--volume`, changed at runtime by the `volume` command.
+    volume: Rc<Cell<f32>>,
+    /// Gain reduction applied by `--auto-gain`; `None` when disabled.
+    auto_gain: Option<Rc<Cell<f32>>>,
+    /// `--replay-gain` and its preamp in dB.
+    replay_gain: Option<(ReplayGainMode, f32)>,
+    /// The gain of the playing file, and of the `interject` clip, from their tags.
+    file_gain: Rc<Cell<f32>>,
+    clip_gain: Rc<Cell<f32>>,
+    /// `--normalize`: the files measured so far.
+    loudness: Option<Loudness>,
+    /// Measured loudness of the playing file and of the clip, if known.
+    file_loudness: Rc<Cell<Option<f64>>>,
+    clip_loudness: Rc<Cell<Option<f64>>>,
+    clip_stats: Rc<Cell<ClipStats>>,
+    buffer: VecDeque<f32>,
+    /// Play silence, leaving the input where it is.
+    paused: bool,
+    /// Send silence while the input plays on.
+    muted: bool,
+    /// Whether the `--ptt` trigger is held; `None` without push-to-talk.
+    talking: Option<bool>,
+    /// Fades the whole output, mixed microphone included, for `muted` and
+    /// push-to-talk.
+    mute: Ramp,
+    source: Option<Source>,
+    resampler: LinearResampler,
+    /// Input rate `resampler` was built for.
+    resampler_input_rate: u32,
+    /// `--tempo` time stretching; `None` at the normal tempo.
+    stretch: Option<Wsola>,
+    played_frames: u64,
+    /// Frames of silence streamed since the input ran out; `None` while playing.
+    ended_frames: Option<u64>,
+    /// Fades applied to the outgoing audio.
+    ramp: Ramp,
+    /// `--fade-in` and `--fade-out`.
+    fade_in: Duration,
+    fade_out: Duration,
+    /// Set by [`AudioDecoder::stop`]; nothing plays once `ramp` reaches zero.
+    stopping: bool,
+    /// A `quit` command came in; shut down as on Ctrl+C.
+    quitting: bool,
+    /// A `load` or `track` still opening; the current input plays meanwhile.
+    loading: Option<Loading>,
+    /// `started` and `finished` events for the control socket.
+    events: Events,
+    /// `--on-track-start` and `--on-track-end`.
+    hooks: TrackHooks,
+    /// Each entry waits for the far side's turn, with `--reply-to`.
+    turns: bool,
+    /// Plays the clips of the `soundboard` subcommand.
+    pads: Option<Pads>,
+    /// The clip `interject` is playing, if any.
+    interjection: Option<Interjection>,
+    /// A clip `interject` is still opening; whatever plays meanwhile carries on.
+    opening_clip: Option<OpeningClip>,
+    /// Processing for interjected clips, kept apart from the input's.
+    clip_chain: ProcessChain,
+    /// Gain on the input while a clip plays; 0 holds it in place instead.
+    duck_gain: f32,
+    duck_attack: Duration,
+    duck_release: Duration,
+    /// Whether the input is ducked (or held), or on its way there.
+    ducked: bool,
+    /// Fades the input down and back up around a clip.
+    duck: Ramp,
+    /// Set with `--timeline`, which starts each entry at its time.
+    timeline: Option<Timeline>,
+    /// `--start-at`: silence plays until then.
+    start_at: Option<SystemTime>,
+    /// Where playback was when it was stopped; `None` if it had reached the end.
+    stopped_at: Option<ResumePoint>,
+}
+
+impl AudioDecoder {
+    fn new(
+        playlist: Playlist,
+        entry_spec: InputSpec,
+        looping: LoopOptions,
+        crossfade: Duration,
+        options: ProcessingOptions,
+    ) -> Result<Self> {
+        let channels = options.channels;
+        let sample_rate = options.sample_rate;
+        let volume = Rc::new(Cell::new(options.volume));
+        let auto_gain = Rc::new(Cell::new(1.0));
+        let file_gain = Rc::new(Cell::new(1.0));
+        let clip_gain = Rc::new(Cell::new(1.0));
+        let file_loudness = Rc::new(Cell::new(None));
+        let clip_loudness = Rc::new(Cell::new(None));
+        let clip_stats = Rc::new(Cell::new(ClipStats::default()));
+        let loudness = match options.normalize {
+            Some(_) => Some(Loudness::start(channels)?),
+            None => None,
+        };
+        let input = entry_input(&entry_spec, playlist.current());
+        let mut decoder = Self {
+            title: playlist.current().title.clone(),
+            playlist,
+            entry_spec,
+            input,
+            looping,
+            crossfade,
+            pass: 1,
+            gap_samples: 0,
+            fading: false,
+            sample_rate,
+            channels,
+            speed: options.speed,
+            tempo: options.tempo,
+            chain: options.build_chain(
+                volume.clone(),
+                auto_gain.clone(),
+                file_gain.clone(),
+                file_loudness.clone(),
+                clip_stats.clone(),
+            ),
+            clip_chain: options.build_chain(
+                volume.clone(),
+                auto_gain.clone(),
+                clip_gain.clone(),
+                clip_loudness.clone(),
+                clip_stats.clone(),
+            ),
+            volume,
+            auto_gain: options.auto_gain.then_some(auto_gain),
+            replay_gain: options.replay_gain,
+            file_gain,
+            clip_gain,
+            loudness,
+            file_loudness,
+            clip_loudness,
+            clip_stats,
+            buffer: VecDeque::with_capacity(sample_rate as usize * channels * 2),
+            paused: false,
+            muted: false,
+            talking: None,
+            mute: Ramp::default(),
+            source: None,
+            resampler: LinearResampler::new(channels, sample_rate, sample_rate)
+                .with_speed(options.speed),
+            resampler_input_rate: sample_rate,
+            stretch: (options.tempo != 1.0)
+                .then(|| Wsola::new(channels, sample_rate, options.tempo)),
+            played_frames: 0,
+            ended_frames: None,
+            ramp: Ramp::default(),
+            fade_in: options.fade_in,
+            fade_out: options.fade_out,
+            stopping: false,
+            quitting: false,
+            loading: None,
+            events: Events::default(),
+            hooks: TrackHooks::default(),
+            turns: false,
+            pads: None,
+            interjection: None,
+            opening_clip: None,
+            duck_gain: options.interject_duck,
+            duck_attack: options.duck_attack,
+            duck_release: options.duck_release,
+            ducked: false,
+            duck: Ramp::default(),
+            timeline: None,
+            start_at: None,
+            stopped_at: None,
+        };
+        decoder.start_fade_in();
+        Ok(decoder)
+    }
+
+    /// Frames of output in `duration`.
+    fn frames(&self, duration: Duration) -> u64 {
+        (duration.as_secs_f64() * self.sample_rate as f64) as u64
+    }
+
+    /// Fade in from silence, if `--fade-in` is set.
+    fn start_fade_in(&mut self) {
+        if !self.fade_in.is_zero() && !self.stopping {
+            self.ramp.jump_to(0.0);
+            self.fade(1.0, self.fade_in);
+        }
+    }
+
+    /// Move the output gain to `gain` over `duration`, or at once.
+    fn fade(&mut self, gain: f32, duration: Duration) {
+        if duration.is_zero() {
+            self.ramp.jump_to(gain);
+        } else {
+            self.ramp.fade_to(gain, self.frames(duration));
+        }
+    }
+
+    /// Open the current playlist entry.
+    fn open(&mut self) -> Result<()> {
+        self.input = entry_input(&self.entry_spec, self.playlist.current());
+        self.title = self.playlist.current().title.clone();
+        let source = Source::open(&self.input)?;
+        self.install(source);
+        Ok(())
+    }
+
+    /// Start playing `source` from its beginning.
+    fn install(&mut self, source: Source) {
+        self.events
+            .emit(format!("started {}", self.input.path.display()));
+        self.source = Some(source);
+        self.hooks.started(&self.hook_track());
+        self.gap_samples = 0;
+        // Starting again after the end, e.g. a file loaded once it played out
+        if self.ended_frames.take().is_some() {
+            self.start_fade_in();
+        }
+        self.start_play_through();
+    }
+
+    /// Replace the playing file once it has been opened in the background.
+    ///
+    /// When the loaded file ends, the playlist carries on after the entry it replaced.
+    fn load(&mut self, path: PathBuf, crossfade: Duration) -> Result<()> {
+        let input = self.file_input(path);
+        info!("Loading {:?}", input.path);
+        self.start_loading(input, None, crossfade)
+    }
+
+    /// How to open a file given at runtime.
+    fn file_input(&self, path: PathBuf) -> InputSpec {
+        // --raw describes every input; a --format-hint was for the original file only
+        InputSpec {
+            path,
+            format_hint: None,
+            raw: self.entry_spec.raw,
+            raw_files: self.entry_spec.raw_files,
+            track: self.entry_spec.track,
+            exec: None,
+            ytdlp: None,
+            tts: None,
+            generate: None,
+            midi: self.entry_spec.midi.clone(),
+            span: self.entry_spec.span,
+            strict: self.entry_spec.strict,
+        }
+    }
+
+    /// Play a clip over the current input as soon as it has opened in the
+    /// background. The input fades out and holds its position (or with
+    /// `--interject-duck`, plays on quietly whenever the clip sounds) until
+    /// the clip has played, then carries on. A clip still playing is cut off,
+    /// and one still opening is dropped.
+    fn interject(&mut self, path: PathBuf) -> Result<()> {
+        // --start and --end are for the files being played, not the clip
+        let input = InputSpec {
+            span: None,
+            ..self.file_input(path)
+        };
+        let opened = open_in_background(input.clone(), self.loudness.clone())?;
+        if let Some(dropped) = self.opening_clip.replace(OpeningClip { input, opened }) {
+            info!("Dropping the clip {:?}", dropped.input.path);
+        }
+        Ok(())
+    }
+
+    /// Start the clip [`AudioDecoder::interject`] opened, if it is ready.
+    /// Returns how opening it went once it is done.
+    fn finish_interjecting(&mut self) -> Option<Result<()>> {
+        let opened = match self.opening_clip.as_ref()?.opened.try_recv() {
+            Ok(opened) => opened,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(anyhow!("The loader thread stopped")),
+        };
+        let input = self.opening_clip.take()?.input;
+        Some(opened.map(|source| self.start_interjection(input, source)))
+    }
+
+    /// Play `source`, the opened clip, over the input from the next buffer.
+    fn start_interjection(&mut self, input: InputSpec, source: Source) {
+        let rate = source.sample_rate.unwrap_or(self.sample_rate);
+        if let Some(cut) = self.interjection.take() {
+            info!("Cutting off {:?}", cut.path);
+        }
+
+        info!("Interjecting {:?}", input.path);
+        self.events
+            .emit(format!("interject-started {}", input.path.display()));
+        self.clip_chain.reset();
+        self.clip_gain.set(self.replay_gain(Some(&source)));
+        // Measured on the loader thread, before the clip was opened
+        if let Some(loudness) = &self.loudness {
+            self.clip_loudness.set(loudness.get(&input));
+        }
+        self.interjection = Some(Interjection {
+            path: input.path,
+            source,
+            resampler: LinearResampler::new(self.channels, rate, self.sample_rate),
+            buffer: VecDeque::new(),
+            ended: false,
+        });
+        // A ducked input follows the clip's level instead, from the next buffer
+        if self.duck_gain == 0.0 {
+            self.duck_under_clip(true);
+        }
+    }
+
+    /// Fade the input down under a clip, or back up. Ducking takes
+    /// `--duck-attack` and `--duck-release` for the full change.
+    fn duck_under_clip(&mut self, duck: bool) {
+        if duck == self.ducked {
+            return;
+        }
+        self.ducked = duck;
+        let (target, time) = match (duck, self.duck_gain == 0.0) {
+            (true, true) => (0.0, INTERJECT_FADE),
+            (false, true) => (1.0, INTERJECT_FADE),
+            (true, false) => (self.duck_gain, self.duck_attack),
+            (false, false) => (1.0, self.duck_release),
+        };
+        // A change that starts partway takes the matching part of the time
+        let distance = (target - self.duck.gain()).abs() / (1.0 - self.duck_gain).max(f32::EPSILON);
+        self.duck
+            .fade_to(target, (self.frames(time) as f32 * distance) as u64);
+    }
+
+    /// With `--interject-duck`, duck the input while the clip's next
+    /// `samples` have anything to hear, and bring it back up while they don't.
+    fn follow_interjection(&mut self, samples: usize) {
+        if self.duck_gain == 0.0 {
+            return;
+        }
+        self.buffer_interjection(samples);
+        let Some(clip) = &self.interjection else {
+            return;
+        };
+        let loud = clip
+            .buffer
+            .iter()
+            .take(samples)
+            .any(|sample| sample.abs() >= DUCK_THRESHOLD);
+        self.duck_under_clip(loud);
+    }
+
+    /// Decode all of a `soundboard` clip, at the output rate and channel count.
+    fn preload(&self, path: PathBuf) -> Result<Clip> {
+        let input = InputSpec {
+            span: None,
+            ..self.file_input(path)
+        };
+        let mut source = Source::open(&input)?;
+        let rate = source.sample_rate.unwrap_or(self.sample_rate);
+        let mut resampler = LinearResampler::new(self.channels, rate, self.sample_rate);
+        let mut samples = Vec::new();
+        while let Some((chunk, source_channels)) = source.next_chunk()? {
+            resampler.process(&remix(&chunk, source_channels, self.channels), &mut samples);
+        }
+        let name = input.path.display().to_string();
+        if samples.is_empty() {
+            return Err(anyhow!("{} has no audio", name));
+        }
+        debug!(
+            "Loaded {} ({:.1}s)",
+            name,
+            (samples.len() / self.channels) as f64 / self.sample_rate as f64
+        );
+        Ok(Clip { name, samples })
+    }
+
+    /// Decode and process the interjected clip until at least `samples` are buffered.
+    fn buffer_interjection(&mut self, samples: usize) {
+        let channels = self.channels;
+        let Some(clip) = &mut self.interjection else {
+            return;
+        };
+        while clip.buffer.len() < samples && !clip.ended {
+            match clip.source.next_chunk() {
+                Ok(Some((samples, source_channels))) => {
+                    let mut frames = remix(&samples, source_channels, channels);
+                    if !clip.resampler.is_passthrough() {
+                        let mut resampled = Vec::with_capacity(frames.len() * 2);
+                        clip.resampler.process(&frames, &mut resampled);
+                        frames = resampled;
+                    }
+                    self.clip_chain.process(&mut frames, channels);
+                    clip.buffer.extend(frames);
+                }
+                Ok(None) => clip.ended = true,
+                Err(e) => {
+                    warn!("Failed to decode {:?}: {}", clip.path, e);
+                    clip.ended = true;
+                }
+            }
+        }
+    }
+
+    /// Add the interjected clip to `output`, and once it has played, bring
+    /// the input back up.
+    fn mix_interjection(&mut self, output: &mut [f32]) {
+        self.buffer_interjection(output.len());
+        let Some(clip) = &mut self.interjection else {
+            return;
+        };
+        let played = output.len().min(clip.buffer.len());
+        for (sample, clip_sample) in output.iter_mut().zip(clip.buffer.drain(..played)) {
+            *sample += clip_sample;
+        }
+
+        if clip.ended && clip.buffer.is_empty() {
+            info!("Finished {:?}; carrying on", clip.path);
+            self.events
+                .emit(format!("interject-finished {}", clip.path.display()));
+            self.interjection = None;
+            self.duck_under_clip(false);
+        }
+    }
+
+    /// Add a file, playlist or directory to the end of the playlist. If
+    /// playback had run out, it carries on with what was added.
+    fn enqueue(&mut self, path: &Path) -> Result<()> {
+        let entries = playlist::expand(&[path.to_path_buf()])?;
+        info!("Queued {:?} ({} entries)", path, entries.len());
+        if let Some(loudness) = &self.loudness {
+            for entry in &entries {
+                loudness.request(entry_input(&self.entry_spec, entry));
+            }
+        }
+        self.playlist.extend(entries);
+        if self.ended_frames.is_some() {
+            self.decode_more()?;
+        }
+        Ok(())
+    }
+
+    /// Jump to a playlist entry (counting from 1) once it has been opened,
+    /// fading like [`AudioDecoder::load`]. The playlist carries on from there.
+    fn skip_to(&mut self, track: usize, crossfade: Duration) -> Result<()> {
+        let entry = self.playlist.get(track).ok_or_else(|| {
+            anyhow!(
+                "There is no track {}; the playlist has {}",
+                track,
+                self.playlist.len()
+            )
+        })?;
+        let input = entry_input(&self.entry_spec, entry);
+        self.start_loading(input, Some(track), crossfade)
+    }
+
+    /// Open the playing input again from its start, for a file that was
+    /// replaced on disk. The playlist entry stays selected if it is the one
+    /// playing.
+    fn reopen(&mut self, crossfade: Duration) -> Result<()> {
+        let track =
+            (self.playlist.current().path == self.input.path).then(|| self.playlist.track());
+        info!("Reopening {:?}", self.input.path);
+        self.start_loading(self.input.clone(), track, crossfade)
+    }
+
+    /// Skip `by` entries forward or back through the playlist, wrapping
+    /// around if it repeats.
+    fn skip_by(&mut self, by: isize, crossfade: Duration) -> Result<()> {
+        let len = self.playlist.len() as isize;
+        // From an entry still opening, so pressing next twice skips two
+        let current = self
+            .loading
+            .as_ref()
+            .and_then(|loading| loading.track)
+            .unwrap_or(self.playlist.track()) as isize;
+        let track = match current + by {
+            track if (1..=len).contains(&track) => track,
+            _ if self.repeats() => (current - 1 + by).rem_euclid(len) + 1,
+            track if track < 1 => 1,
+            _ => return Err(anyhow!("This is the last track")),
+        };
+        self.skip_to(track as usize, crossfade)
+    }
+
+    /// Open `input` and decode its first packet on a background thread, so
+    /// slow inputs (a URL, a command, a large file) don't hold up playback.
+    /// A load still opening is dropped in favour of the new one.
+    fn start_loading(
+        &mut self,
+        input: InputSpec,
+        track: Option<usize>,
+        crossfade: Duration,
+    ) -> Result<()> {
+        let opened = open_in_background(input.clone(), self.loudness.clone())?;
+        if let Some(superseded) = self.loading.replace(Loading {
+            input,
+            track,
+            crossfade,
+            opened,
+        }) {
+            info!("Dropping the load of {:?}", superseded.input.path);
+        }
+        Ok(())
+    }
+
+    /// Swap in the input [`AudioDecoder::start_loading`] opened, if it is
+    /// ready. Returns how the load went once it is done; a failed one leaves
+    /// the current input playing.
+    fn finish_loading(&mut self) -> Option<Result<()>> {
+        let opened = match self.loading.as_ref()?.opened.try_recv() {
+            Ok(opened) => opened,
+            Err(TryRecvError::Empty) => return None,
+            Err(TryRecvError::Disconnected) => Err(anyhow!("The loader thread stopped")),
+        };
+        let loading = self.loading.take()?;
+        Some(opened.and_then(|source| {
+            self.switch(loading.input, loading.track, source, loading.crossfade)?;
+            if loading.track.is_some() {
+                self.announce_track();
+            }
+            Ok(())
+        }))
+    }
+
+    /// Switch to an opened input, fading out up to `crossfade` of the old one
+    /// under its start. `track` is its playlist position, if it is an entry.
+    fn switch(
+        &mut self,
+        input: InputSpec,
+        track: Option<usize>,
+        source: Source,
+        crossfade: Duration,
+    ) -> Result<()> {
+        // Keep just enough of the old file to fade out
+        let fade_frames = (crossfade.as_secs_f64() * self.sample_rate as f64) as usize;
+        let fade_samples = fade_frames * self.channels;
+        while self.buffer.len() < fade_samples && self.decode_more()? {}
+        let tail: Vec<f32> = self.buffer.drain(..).take(fade_samples).collect();
+
+        self.input = input;
+        self.title = match track {
+            Some(track) => {
+                self.playlist.select(track);
+                self.playlist.current().title.clone()
+            }
+            None => None,
+        };
+        self.install(source);
+
+        while self.buffer.len() < tail.len() && self.decode_more()? {}
+        for (i, (sample, old)) in self.buffer.iter_mut().zip(&tail).enumerate() {
+            let fade_in = (i / self.channels) as f32 / fade_frames as f32;
+            *sample = *sample * fade_in + old * (1.0 - fade_in);
+        }
+
+        Ok(())
+    }
+
+    /// Pause or resume playback; the device and stream carry on either way.
+    ///
+    /// Pausing fades out over `--fade-out` before the input stops, and
+    /// resuming fades back in over `--fade-in`.
+    fn set_paused(&mut self, paused: bool) {
+        if paused != self.paused {
+            self.paused = paused;
+            if !self.stopping {
+                match paused {
+                    true => self.fade(0.0, self.fade_out),
+                    false => self.fade(1.0, self.fade_in),
+                }
+            }
+            self.events
+                .emit(if paused { "paused" } else { "resumed" }.to_string());
+            info!("{}", if paused { "Paused" } else { "Resumed" });
+        }
+    }
+
+    /// Mute or unmute the microphone. Unlike pausing, the input carries on
+    /// playing underneath.
+    fn set_muted(&mut self, muted: bool) {
+        if muted != self.muted {
+            self.muted = muted;
+            self.update_gate();
+            self.events
+                .emit(if muted { "muted" } else { "unmuted" }.to_string());
+            info!("{}", if muted { "Muted" } else { "Unmuted" });
+        }
+    }
+
+    /// Hold the output silent until the `--ptt` trigger is pressed.
+    fn start_push_to_talk(&mut self) {
+        self.talking = Some(false);
+        self.mute.jump_to(0.0);
+    }
+
+    /// Open the microphone while the `--ptt` trigger is held.
+    fn set_talking(&mut self, talking: bool) -> Result<()> {
+        let was = self
+            .talking
+            .ok_or_else(|| anyhow!("Push-to-talk is off; start with --ptt"))?;
+        if talking != was {
+            self.talking = Some(talking);
+            self.update_gate();
+            self.events
+                .emit(if talking { "ptt-down" } else { "ptt-up" }.to_string());
+            debug!("{}", if talking { "Talking" } else { "Not talking" });
+        }
+        Ok(())
+    }
+
+    /// Fade the output in or out as muting and push-to-talk say.
+    fn update_gate(&mut self) {
+        let open = !self.muted && self.talking != Some(false);
+        let gain = if open { 1.0 } else { 0.0 };
+        self.mute.fade_to(gain, self.frames(MUTE_FADE));
+    }
+
+    /// Change the volume; the gain stage ramps to it.
+    fn set_volume(&mut self, change: VolumeChange) {
+        let volume = change.resolve(self.volume.get());
+        self.volume.set(volume);
+        self.events.emit(format!("volume {:.2}", volume));
+        info!("Volume: {:.2}", volume);
+    }
+
+    /// Silence what goes out while muted or not talking, after everything
+    /// has been mixed in.
+    fn apply_mute(&mut self, output: &mut [f32]) {
+        self.mute.apply(output, self.channels);
+    }
+
+    /// Jump within the playing file, dropping whatever was decoded ahead.
+    fn seek(&mut self, target: SeekTarget) -> Result<()> {
+        let position = self.position();
+        let source = self.source.as_mut().ok_or_else(|| anyhow!("Not opened"))?;
+        let landed = source.seek(target.resolve(position))?;
+        self.buffer.clear();
+        self.resampler.reset();
+        if let Some(stretch) = &mut self.stretch {
+            stretch.reset();
+        }
+        self.played_frames = (landed.as_secs_f64() * self.sample_rate as f64
+            / (self.speed * self.tempo))
+            .round() as u64;
+        self.events
+            .emit(format!("seeked {:.3}", landed.as_secs_f64()));
+        info!("Seeked to {}", format_duration(landed));
+        Ok(())
+    }
+
+    /// Carry out a runtime command from the control socket or the keyboard.
+    fn apply(&mut self, command: &ControlCommand, crossfade: Duration) -> Result<()> {
+        match command {
+            ControlCommand::Load(path) => self.load(path.clone(), crossfade)?,
+            ControlCommand::Enqueue(path) => self.enqueue(path)?,
+            ControlCommand::Interject(path) => self.interject(path.clone())?,
+            ControlCommand::Track(track) => self.skip_to(*track, crossfade)?,
+            ControlCommand::Next => self.skip_by(1, crossfade)?,
+            ControlCommand::Previous => self.skip_by(-1, crossfade)?,
+            ControlCommand::Pause => self.set_paused(true),
+            ControlCommand::Resume => self.set_paused(false),
+            ControlCommand::TogglePause => self.set_paused(!self.paused),
+            ControlCommand::Mute => self.set_muted(true),
+            ControlCommand::Unmute => self.set_muted(false),
+            ControlCommand::ToggleMute => self.set_muted(!self.muted),
+            ControlCommand::PushToTalk(talking) => self.set_talking(*talking)?,
+            ControlCommand::Pad(pad) => self
+                .pads
+                .as_ref()
+                .ok_or_else(|| anyhow!("Not playing a soundboard"))?
+                .press(*pad)?,
+            ControlCommand::Volume(change) => self.set_volume(*change),
+            ControlCommand::Seek(target) => self.seek(*target)?,
+            ControlCommand::Reopen => self.reopen(crossfade)?,
+            ControlCommand::Position => info!("Progress: {}", self.progress()),
+            ControlCommand::Status => info!("Status: {}", self.status()),
+            ControlCommand::Playlist => info!("Playlist: {}", self.playlist_json()),
+            ControlCommand::Quit => {
+                info!("Quitting");
+                self.quitting = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reset per-play-through state after (re)opening the input.
+    fn start_play_through(&mut self) {
+        self.played_frames = 0;
+        self.chain.reset();
+        let gain = self.replay_gain(self.source.as_ref());
+        self.file_gain.set(gain);
+        if let Some(loudness) = &self.loudness {
+            let measured = loudness.get(&self.input);
+            if measured.is_none() {
+                debug!("Loudness not measured yet; normalizing as it plays");
+            }
+            self.file_loudness.set(measured);
+        }
+
+        // Never interpolate across files: rebuild the resampler when the input
+        // rate changes, and otherwise drop the frame carried over from the last file
+        if !self.update_resampler() {
+            self.resampler.reset();
+        }
+        if let Some(stretch) = &mut self.stretch {
+            stretch.reset();
+        }
+    }
+
+    /// The `--replay-gain` gain for `source`: what its tags give, or else unity.
+    fn replay_gain(&self, source: Option<&Source>) -> f32 {
+        let Some((mode, preamp)) = self.replay_gain else {
+            return 1.0;
+        };
+        match source.and_then(|source| source.replay_gain) {
+            Some(tags) => {
+                let gain = tags.gain(mode, preamp).unwrap_or(1.0);
+                info!("ReplayGain: {:+.2} dB", 20.0 * gain.log10());
+                gain
+            }
+            None => {
+                debug!("No ReplayGain tags; playing at unity gain");
+                1.0
+            }
+        }
+    }
+
+    /// Rebuild the resampler if the source's rate changed; returns whether it did.
+    fn update_resampler(&mut self) -> bool {
+        let input_rate = self
+            .source
+            .as_ref()
+            .and_then(|source| source.sample_rate)
+            .unwrap_or(self.sample_rate);
+        if input_rate == self.resampler_input_rate {
+            return false;
+        }
+        debug!("Resampling {} Hz -> {} Hz", input_rate, self.sample_rate);
+        self.resampler = LinearResampler::new(self.channels, input_rate, self.sample_rate)
+            .with_speed(self.speed);
+        self.resampler_input_rate = input_rate;
+        true
+    }
+
+    /// Handle end of input: move on to the next playlist entry, wrapping around
+    /// when looping, or report the end.
+    fn end_of_input(&mut self) -> Result<bool> {
+        if self.looping.stop {
+            return Ok(false);
+        }
+        // Skip entries that fail to open, but give up after a full pass
+        for attempt in 0..self.playlist.len() {
+            // --repeat one moves on only if the entry fails to open again
+            let again = self.looping.one && attempt == 0;
+            let wrapping = again || self.playlist.track() == self.playlist.len();
+            let fade = self.fade_hold();
+            if !again && !self.playlist.advance(self.repeats()) {
+                return Ok(false);
+            }
+            if wrapping {
+                self.pass += 1;
+            }
+
+            if self.playlist.len() == 1 {
+                match self.looping.passes {
+                    Some(passes) => info!("Looping audio ({}/{})...", self.pass, passes),
+                    None => info!("Looping audio..."),
+                }
+            } else {
+                self.announce_track();
+            }
+
+            // The end of the last entry, still buffered to fade out
+            let tail = if !self.fading {
+                let fade = fade.min(self.buffer.len());
+                self.buffer.drain(self.buffer.len() - fade..).collect()
+            } else {
+                Vec::new()
+            };
+
+            match self.open() {
+                Ok(()) => {
+                    if wrapping {
+                        let frames = self.looping.gap.as_secs_f64() * self.sample_rate as f64;
+                        self.gap_samples = frames.round() as usize * self.channels;
+                    }
+                    if !tail.is_empty() {
+                        self.fade_into(tail)?;
+                    }
+                    return Ok(true);
+                }
+                Err(e) if self.playlist.len() > 1 && !self.entry_spec.strict => {
+                    self.buffer.extend(tail);
+                    warn!("Skipping {}: {}", self.playlist.current(), e)
+                }
+                Err(e) => return Err(e.context(Exit::Unreadable)),
+            }
+        }
+        Err(anyhow!("None of the playlist entries could be opened")).context(Exit::Unreadable)
+    }
+
+    /// Whether the playlist starts over after its last entry (or the entry
+    /// plays again, with `--repeat one`).
+    fn repeats(&self) -> bool {
+        self.looping.passes.is_none_or(|passes| self.pass < passes)
+    }
+
+    /// Samples held back from the end of the current entry to fade out under
+    /// whatever follows: `--crossfade`, or `--loop-crossfade` where the
+    /// playlist starts over. Where playback ends, `--fade-out`.
+    fn fade_hold(&self) -> usize {
+        let fade = if self.looping.stop {
+            self.fade_out
+        } else if !self.looping.one && self.playlist.track() < self.playlist.len() {
+            self.crossfade
+        } else if !self.repeats() {
+            self.fade_out
+        } else if !self.looping.gap.is_zero() {
+            Duration::ZERO
+        } else if !self.looping.crossfade.is_zero() {
+            self.looping.crossfade
+        } else {
+            self.crossfade
+        };
+        let frames = fade.as_secs_f64() * self.sample_rate as f64;
+        frames as usize * self.channels
+    }
+
+    /// Decode the start of the input just opened and fade it in under `tail`,
+    /// the end of the entry before.
+    fn fade_into(&mut self, tail: Vec<f32>) -> Result<()> {
+        let kept = self.buffer.len();
+        self.fading = true;
+        let mut decoded = Ok(true);
+        while self.buffer.len() < kept + tail.len() && matches!(decoded, Ok(true)) {
+            decoded = self.decode_more();
+        }
+        self.fading = false;
+        decoded?;
+
+        let fade_frames = (tail.len() / self.channels) as f32;
+        for (i, (sample, old)) in self.buffer.iter_mut().skip(kept).zip(&tail).enumerate() {
+            // Equal power: the two sides are usually unrelated, and a linear
+            // fade would dip in the middle
+            let t = (i / self.channels) as f32 / fade_frames * std::f32::consts::FRAC_PI_2;
+            *sample = *sample * t.sin() + old * t.cos();
+        }
+        Ok(())
+    }
+
+    /// Log the playlist entry that is starting.
+    fn announce_track(&self) {
+        info!(
+            "Track {}/{}: {}",
+            self.playlist.track(),
+            self.playlist.len(),
+            self.playlist.current()
+        );
+        self.events.emit(format!(
+            "track {} {} {}",
+            self.playlist.track(),
+            self.playlist.len(),
+            self.playlist.current().path.display()
+        ));
+    }
+
+    fn decode_more(&mut self) -> Result<bool> {
+        let source = self.source.as_mut().ok_or_else(|| anyhow!("Not opened"))?;
+        match source.next_chunk()? {
+            Some((samples, source_channels)) => {
+                // A FIFO can change rate between writers
+                self.update_resampler();
+                self.push_samples(&samples, source_channels);
+                Ok(true)
+            }
+            None => {
+                // Play out what the time stretcher held back before moving on
+                if let Some(stretch) = &mut self.stretch {
+                    let mut frames = Vec::new();
+                    stretch.flush(&mut frames);
+                    if !frames.is_empty() {
+                        self.chain.process(&mut frames, self.channels);
+                        self.buffer.extend(frames);
+                        return Ok(true);
+                    }
+                }
+                if self.ended_frames.is_none() {
+                    self.events
+                        .emit(format!("finished {}", self.input.path.display()));
+                    self.hooks.ended(&self.hook_track());
+                }
+                let more = self.end_of_input()?;
+                if !more && self.ended_frames.is_none() {
+                    self.events.emit("ended".to_string());
+                    self.ended_frames = Some(0);
+                    self.fade_out_end();
+                }
+                Ok(more)
+            }
+        }
+    }
+
+    /// Fade the last of the buffered audio out to silence over `--fade-out`,
+    /// once nothing is left to follow it.
+    fn fade_out_end(&mut self) {
+        let frames = (self.frames(self.fade_out) as usize).min(self.buffer.len() / self.channels);
+        let start = self.buffer.len() - frames * self.channels;
+        for (i, sample) in self.buffer.range_mut(start..).enumerate() {
+            *sample *= 1.0 - (i / self.channels + 1) as f32 / frames as f32;
+        }
+    }
+
+    /// Run interleaved source samples through the processing chain into the output buffer.
+    fn push_samples(&mut self, samples: &[f32], source_channels: usize) {
+        let channels = self.channels;
+        let mut frames = remix(samples, source_channels, channels);
+
+        // Resample with state carried across packets so chunk boundaries join smoothly
+        if !self.resampler.is_passthrough() {
+            let mut resampled = Vec::with_capacity(frames.len() * 2);
+            self.resampler.process(&frames, &mut resampled);
+            frames = resampled;
+        }
+        if let Some(stretch) = &mut self.stretch {
+            let mut stretched = Vec::with_capacity(frames.len() * 2);
+            stretch.process(&frames, &mut stretched);
+            frames = stretched;
+        }
+
+        self.chain.process(&mut frames, channels);
+        self.buffer.extend(frames);
+    }
+
+    fn fill_buffer(&mut self, output: &mut [f32]) -> Result<usize> {
+        let mut start = self.wait_for_start(output);
+        if self.start_at.is_some() {
+            return Ok(output.len());
+        }
+        // Start timeline cues on the frame they are due
+        while let Some(due) = self.next_cue((output.len() - start) / self.channels) {
+            let end = start + due * self.channels;
+            self.fill_span(&mut output[start..end])?;
+            start = end;
+            if !self.play_cue(due)? {
+                // Still opening; it starts late, with a later buffer
+                break;
+            }
+        }
+        self.fill_span(&mut output[start..])?;
+        Ok(output.len())
+    }
+
+    /// Fill the start of `output` with silence until `--start-at`, returning
+    /// how many samples that took.
+    fn wait_for_start(&mut self, output: &mut [f32]) -> usize {
+        let Some(at) = self.start_at else {
+            return 0;
+        };
+        let frames = match at.duration_since(SystemTime::now()) {
+            Ok(wait) => self.frames(wait) as usize,
+            Err(late) => {
+                if late.duration() >= START_AT_TOLERANCE {
+                    warn!(
+                        "The start time passed {:.3}s ago; starting now",
+                        late.duration().as_secs_f64()
+                    );
+                }
+                0
+            }
+        };
+        let samples = output.len().min(frames * self.channels);
+        output[..samples].fill(0.0);
+        if samples < output.len() {
+            info!("Starting playback");
+            self.start_at = None;
+        }
+        samples
+    }
+
+    fn fill_span(&mut self, output: &mut [f32]) -> Result<()> {
+        if let Some(timeline) = &mut self.timeline {
+            timeline.clock += (output.len() / self.channels) as u64;
+        }
+        if self.stopped() {
+            output.fill(0.0);
+            let silent = (output.len() / self.channels) as u64;
+            self.ended_frames = Some(self.ended_frames.unwrap_or(0) + silent);
+            return Ok(());
+        }
+        self.follow_interjection(output.len());
+        // A clip holds the input in place once it has faded out
+        if self.interjection.is_some() && self.duck.gain() == 0.0 {
+            output.fill(0.0);
+        } else {
+            self.fill(output)?;
+        }
+        self.duck.apply(output, self.channels);
+
+        // Pausing and fades leave a clip alone, but stopping fades it out too
+        if !self.stopping {
+            self.ramp.apply(output, self.channels);
+        }
+        self.mix_interjection(output);
+        if self.stopping {
+            self.ramp.apply(output, self.channels);
+        }
+        Ok(())
+    }
+
+    /// Hold playback back until `at`, with silence on the mic until then.
+    fn start_at(&mut self, at: SystemTime) {
+        let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        if let Ok(wait) = at.duration_since(SystemTime::now()) {
+            info!(
+                "Waiting {:.1}s to start playback (Unix time {:.3})",
+                wait.as_secs_f64(),
+                since_epoch.as_secs_f64()
+            );
+        }
+        self.start_at = Some(at);
+    }
+
+    /// Measure the loudness of the first entry for `--normalize` now, and of
+    /// the rest in the background.
+    fn measure_playlist(&self) {
+        let Some(loudness) = &self.loudness else {
+            return;
+        };
+        loudness.measure(&entry_input(&self.entry_spec, self.playlist.current()));
+        for track in 1..=self.playlist.len() {
+            if let Some(entry) = self.playlist.get(track) {
+                loudness.request(entry_input(&self.entry_spec, entry));
+            }
+        }
+    }
+
+    /// Play the playlist as a `--timeline`, each entry starting at its cue.
+    /// Silence plays until the first one.
+    fn start_timeline(&mut self, cues: Vec<Duration>) {
+        let rate = self.sample_rate as f64;
+        self.timeline = Some(Timeline {
+            cues: cues
+                .iter()
+                .map(|at| (at.as_secs_f64() * rate).round() as u64)
+                .collect(),
+            next: 0,
+            clock: 0,
+            started: None,
+            preload: None,
+        });
+    }
+
+    /// Frames until the next timeline cue, if it comes within `frames`. Its
+    /// file starts opening once it is close.
+    fn next_cue(&mut self, frames: usize) -> Option<usize> {
+        let preload = self.frames(TIMELINE_PRELOAD);
+        let timeline = self.timeline.as_mut()?;
+        timeline.started.get_or_insert_with(Instant::now);
+        let index = timeline.next;
+        let due = timeline.cues.get(index)?.saturating_sub(timeline.clock);
+        if timeline.preload.is_none() && due < preload {
+            match self.playlist.get(index + 1) {
+                Some(entry) => {
+                    let input = entry_input(&self.entry_spec, entry);
+                    match open_in_background(input, self.loudness.clone()) {
+                        Ok(opened) => timeline.preload = Some((index, opened)),
+                        Err(e) => warn!("Failed to open cue {} ahead of time: {}", index + 1, e),
+                    }
+                }
+                // Cues are made from the playlist, so this is only a safeguard
+                None => {
+                    warn!(
+                        "Cue {} has no playlist entry; ending the timeline",
+                        index + 1
+                    );
+                    timeline.next = timeline.cues.len();
+                    return None;
+                }
+            }
+        }
+        (due < frames as u64).then_some(due as usize)
+    }
+
+    /// Start the next timeline cue, `offset` frames into the buffer being
+    /// filled, and log how its start compares with the wall clock. Returns
+    /// false, leaving the cue to be tried again, while its file is still
+    /// opening on the loader thread.
+    fn play_cue(&mut self, offset: usize) -> Result<bool> {
+        let rate = self.sample_rate as f64;
+        let Some(timeline) = &mut self.timeline else {
+            return Ok(true);
+        };
+        let index = timeline.next;
+        let opened = match &timeline.preload {
+            Some((cue, opened)) if *cue == index => match opened.try_recv() {
+                Ok(opened) => opened,
+                Err(TryRecvError::Empty) => return Ok(false),
+                Err(TryRecvError::Disconnected) => Err(anyhow!("The loader thread stopped")),
+            },
+            // Opening it ahead failed, which was logged then
+            _ => Err(anyhow!("It could not be opened")),
+        };
+        timeline.preload = None;
+        timeline.next += 1;
+        let scheduled = timeline.cues[index] as f64 / rate;
+        let started = timeline
+            .started
+            .map_or(0.0, |at| at.elapsed().as_secs_f64());
+        let actual = started + offset as f64 / rate;
+
+        let track = index + 1;
+        let cues = timeline.cues.len();
+        let entry = self
+            .playlist
+            .get(track)
+            .ok_or_else(|| anyhow!("There is no track {}", track))?;
+        let input = entry_input(&self.entry_spec, entry);
+        let source = match opened {
+            Ok(source) => source,
+            Err(e) if self.entry_spec.strict => return Err(e.context(Exit::Unreadable)),
+            Err(e) => {
+                warn!("Skipping cue {}/{}: {}", track, cues, e);
+                return Ok(true);
+            }
+        };
+        info!(
+            "Cue {}/{} scheduled at {:.3}s, started at {:.3}s ({:+.1} ms): {}",
+            track,
+            cues,
+            scheduled,
+            actual,
+            (actual - scheduled) * 1000.0,
+            entry
+        );
+
+        let crossfade = match self.source {
+            Some(_) => self.crossfade,
+            None => Duration::ZERO,
+        };
+        self.switch(input, Some(track), source, crossfade)?;
+        Ok(true)
+    }
+
+    /// Fade out over `fade`, then play nothing more.
+    fn stop(&mut self, fade: Duration) {
+        let frames = (fade.as_secs_f64() * self.sample_rate as f64) as u64;
+        self.ramp.fade_to(0.0, frames);
+        self.stopping = true;
+        if self.ended_frames.is_none() {
+            self.stopped_at = Some(ResumePoint {
+                track: self.playlist.track(),
+                file: self.input.path.clone(),
+                title: self.title.clone(),
+                position: self.position(),
+            });
+        }
+    }
+
+    /// Whether [`AudioDecoder::stop`] was called and its fade has finished.
+    fn stopped(&self) -> bool {
+        self.stopping && self.ramp.gain() == 0.0
+    }
+
+    fn fill(&mut self, output: &mut [f32]) -> Result<()> {
+        // Play on while pausing fades out; a timeline is silent until its first cue
+        if self.paused && self.ramp.gain() == 0.0 || self.source.is_none() {
+            output.fill(0.0);
+            return Ok(());
+        }
+
+        let mut filled = 0;
+        let mut ended = false;
+
+        while filled < output.len() {
+            // The --loop-gap before a repetition, once the last one has played out
+            if self.gap_samples > 0 && self.buffer.is_empty() {
+                let end = output.len().min(filled + self.gap_samples);
+                output[filled..end].fill(0.0);
+                self.gap_samples -= end - filled;
+                filled = end;
+                continue;
+            }
+
+            // Keep the end of each entry back to crossfade into the next
+            let hold = self.fade_hold();
+            if !ended && self.buffer.len() <= hold {
+                ended = !self.decode_more()?;
+            }
+            if self.buffer.is_empty() {
+                if !ended {
+                    continue;
+                }
+                // End of audio, fill rest with silence
+                for sample in &mut output[filled..] {
+                    *sample = 0.0;
+                }
+                let silent = ((output.len() - filled) / self.channels) as u64;
+                self.ended_frames = Some(self.ended_frames.unwrap_or(0) + silent);
+                return Ok(());
+            }
+
+            let keep = if ended { 0 } else { hold };
+            let start = filled;
+            while filled < output.len() && self.buffer.len() > keep {
+                output[filled] = self.buffer.pop_front().unwrap_or(0.0);
+                filled += 1;
+            }
+            self.played_frames += ((filled - start) / self.channels) as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the input has run out and the end of it has had time to play
+    /// out, with nothing loading or cued to follow it and no clip playing.
+    fn drained(&self) -> bool {
+        self.played_out()
+            && (self.timeline.as_ref()).is_none_or(|timeline| timeline.next == timeline.cues.len())
+            && !(self.turns && self.has_next())
+    }
+
+    /// Whether the entry has run out and its end has had time to play out,
+    /// with nothing loading to replace it and no clip playing.
+    fn played_out(&self) -> bool {
+        let drain = (self.sample_rate * END_DRAIN_MS / 1000) as u64;
+        self.loading.is_none()
+            && self.interjection.is_none()
+            && self.ended_frames.is_some_and(|frames| frames >= drain)
+    }
+
+    /// Whether `next` has an entry to go to.
+    fn has_next(&self) -> bool {
+        self.playlist.track() < self.playlist.len() || self.repeats()
+    }
+
+    /// Play one entry per turn of the far side, for `--reply-to`.
+    fn take_turns(&mut self) {
+        self.turns = true;
+    }
+
+    /// Whether an entry played in `--reply-to` mode has played out, and
+    /// another is waiting for the far side's turn to end.
+    fn awaiting_reply(&self) -> bool {
+        self.turns && self.played_out() && self.has_next()
+    }
+
+    /// Report clipping since the last check, lowering the gain on sustained clipping
+    /// when `--auto-gain` is enabled.
+    fn check_clipping(&mut self) {
+        let stats = self.clip_stats.take();
+        if stats.clipped == 0 {
+            return;
+        }
+
+        warn!(
+            "Clipping: {} of {} samples exceeded the --max-volume ceiling (peak {:+.1} dBFS) - consider lowering --volume",
+            stats.clipped,
+            stats.samples,
+            stats.peak_dbfs()
+        );
+
+        if let Some(auto_gain) = &self.auto_gain {
+            if stats.is_sustained() {
+                let reduction = 10f32.powf(-(stats.overshoot_db() + AUTO_GAIN_HEADROOM_DB) / 20.0);
+                auto_gain.set(auto_gain.get() * reduction);
+                warn!(
+                    "Auto-gain: reducing gain by {:.1} dB (now {:.1} dB)",
+                    -20.0 * reduction.log10(),
+                    20.0 * auto_gain.get().log10()
+                );
+            }
+        }
+    }
+
+    /// The file currently playing.
+    fn path(&self) -> &Path {
+        &self.input.path
+    }
+
+    /// The cue sheet track currently playing, if any.
+    fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// How far into the current file playback is.
+    fn position(&self) -> Duration {
+        Duration::from_secs_f64(
+            self.played_frames as f64 * self.speed * self.tempo / self.sample_rate as f64,
+        )
+    }
+
+    /// The playing track, as described to `--on-track-start` and `--on-track-end`.
+    fn hook_track(&self) -> hooks::Track {
+        hooks::Track {
+            path: self.input.path.clone(),
+            index: self.playlist.track(),
+            count: self.playlist.len(),
+            title: self.title.clone(),
+            duration: self.source.as_ref().and_then(|source| source.duration),
+        }
+    }
+
+    fn progress(&self) -> Progress {
+        Progress {
+            position: self.position(),
+            duration: self.source.as_ref().and_then(|source| source.duration),
+            track: self.playlist.track(),
+            tracks: self.playlist.len(),
+        }
+    }
+
+    /// The answer to `status`: the state of playback, the progress fields,
+    /// the volume and mute, and the playing file last, as it may hold spaces.
+    fn status(&self) -> String {
+        let state = if self.stopping || self.drained() {
+            "stopped"
+        } else if self.paused {
+            "paused"
+        } else {
+            "playing"
+        };
+        let mut fields = vec![format!("state={}", state)];
+        fields.extend(self.progress().fields());
+        fields.push(format!("volume={:.2}", self.volume.get()));
+        fields.push(format!("muted={}", self.muted));
+        fields.push(format!("path={}", self.path().display()));
+        fields.join(" ")
+    }
+
+    /// The answer to `playlist`: each entry's path, and title if it has one,
+    /// as a JSON array on one line.
+    fn playlist_json(&self) -> String {
+        let entries: Vec<serde_json::Value> = self
+            .playlist
+            .entries()
+            .iter()
+            .map(|entry| match &entry.title {
+                Some(title) => serde_json::json!({"path": entry.path, "title": title}),
+                None => serde_json::json!({"path": entry.path}),
+            })
+            .collect();
+        serde_json::Value::from(entries).to_string()
+    }
+}
+
+/// Longest wait between pactl retries, however many there are.
+const PACTL_MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Runs `pactl`, retrying non-zero exits with exponential backoff.
+///
+/// The PulseAudio compatibility server can be briefly unavailable while a
+/// session is starting up, so a single failure shouldn't abort the program.
+#[derive(Debug, Clone, Copy)]
+struct Pactl {
+    retries: u32,
+    delay: Duration,
+}
+
+impl Pactl {
+    fn new(retries: u32, delay: Duration) -> Self {
+        Self { retries, delay }
+    }
+
+    /// Run `pactl` with the given arguments, returning the last attempt's output.
+    fn run(&self, args: &[&str]) -> Result<Output> {
+        self.retry(args, |args| Command::new("pactl").args(args).output())
+    }
+
+    /// Run `pactl` once, for callers that poll on their own or can't wait.
+    fn run_once(&self, args: &[&str]) -> Result<Output> {
+        Ok(Command::new("pactl").args(args).output()?)
+    }
+
+    /// The retry loop of [`Pactl::run`], running `pactl` through `command`.
+    fn retry(
+        &self,
+        args: &[&str],
+        mut command: impl FnMut(&[&str]) -> std::io::Result<Output>,
+    ) -> Result<Output> {
+        let mut delay = self.delay;
+        let mut attempt = 0;
+
+        loop {
+            let output = command(args)?;
+            if output.status.success() || attempt >= self.retries {
+                return Ok(output);
+            }
+
+            attempt += 1;
+            warn!(
+                "pactl {} failed (attempt {}/{}): {} - retrying in {:?}",
+                args.first().unwrap_or(&""),
+                attempt,
+                self.retries + 1,
+                String::from_utf8_lossy(&output.stderr).trim(),
+                delay
+            );
+            std::thread::sleep(delay);
+            delay = next_retry_delay(delay);
+        }
+    }
+}
+
+/// The wait after `delay` between pactl retries: twice as long, up to a cap.
+fn next_retry_delay(delay: Duration) -> Duration {
+    delay.saturating_mul(2).min(PACTL_MAX_RETRY_DELAY)
+}
+
+/// Parse the module ID printed by `pactl load-module`.
+///
+/// Only the last token is considered, so surrounding whitespace, trailing
+/// newlines or any warnings printed ahead of the ID are ignored.
+fn parse_module_id(stdout: &[u8]) -> Option<u32> {
+    String::from_utf8_lossy(stdout)
+        .split_whitespace()
+        .last()?
+        .parse()
+        .ok()
+}
+
+struct VirtualDevice {
+    module_id: Option<u32>,
+    remap_module_id: Option<u32>,
+    loopback_module_id: Option<u32>,
+    sink_name: String,
+    source_name: String,
+    pactl: Pactl,
+}
+
+/// How many times to look for a newly created source before giving up.
+const SOURCE_CHECK_ATTEMPTS: u32 = 10;
+
+/// Delay between checks for a newly created source.
+const SOURCE_CHECK_DELAY: Duration = Duration::from_millis(100);
+
+/// Parse source names from `pactl list sources short` output (or sink names
+/// from `pactl list sinks short`, which has the same layout).
+///
+/// Each line is tab separated: `<index>\t<name>\t<driver>\t<spec>\t<state>`.
+fn parse_source_names(stdout: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(stdout)
+        .lines()
+        .filter_map(|line| line.split('\t').nth(1))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+impl VirtualDevice {
+    fn new(name: &str, monitor: bool, format: &StreamFormat, pactl: Pactl) -> Result<Self> {
+        let sink_name = format!("{}_sink", name);
+        let source_name = name.to_string();
+
+        // Step 1: Create a null-sink to receive audio
+        let [rate, channels, sample_format] = format.sink_args();
+        let output = pactl.run(&[
+            "load-module",
+            "module-null-sink",
+            &format!("sink_name={}", sink_name),
+            &format!("sink_properties=device.description=\"{}_Output\"", name),
+            &rate,
+            &channels,
+            &sample_format,
+        ])?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Failed to create null sink: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        let module_id =
+            parse_module_id(&output.stdout).ok_or_else(|| anyhow!("Failed to parse module ID"))?;
+
+        info!("Created null sink with module ID: {}", module_id);
+
+        // From here on, returning an error drops the partially built device,
+        // which unloads whatever modules were already loaded
+        let mut device = Self {
+            module_id: Some(module_id),
+            remap_module_id: None,
+            loopback_module_id: None,
+            sink_name,
+            source_name,
+            pactl,
+        };
+
+        // Step 2: Create a remap-source that exposes the monitor as a proper microphone
+        // This makes it appear as a real input device to browsers
+        let monitor_name = format!("{}.monitor", device.sink_name);
+        if !device.wait_for_source(&monitor_name)? {
+            return Err(anyhow!(
+                "Null sink monitor '{}' did not appear; cannot create the microphone",
+                monitor_name
+            ));
+        }
+
+        let output = pactl.run(&[
+            "load-module",
+            "module-remap-source",
+            &format!("source_name={}", device.source_name),
+            &format!("master={}", monitor_name),
+            &format!("source_properties=device.description=\"{}\"", name),
+        ])?;
+
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Failed to create remap source: {}",
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        let remap_module_id = parse_module_id(&output.stdout)
+            .ok_or_else(|| anyhow!("Failed to parse remap module ID"))?;
+        device.remap_module_id = Some(remap_module_id);
+
+        info!("Created remap source with module ID: {}", remap_module_id);
+
+        // A remap-source can load yet never show up (e.g. a bad master), leaving a silent mic
+        if !device.wait_for_source(&device.source_name)? {
+            return Err(anyhow!(
+                "Remap source '{}' was loaded but does not appear in the source list",
+                device.source_name
+            ));
+        }
+
+        // Step 3: Optionally create a loopback to play audio through speakers
+        if monitor {
+            let output = pactl.run(&[
+                "load-module",
+                "module-loopback",
+                &format!("source={}", monitor_name),
+                "latency_msec=1",
+            ])?;
+
+            if !output.status.success() {
+                warn!(
+                    "Failed to create loopback (audio won't play through speakers): {}",
+                    String::from_utf8_lossy(&output.stderr)
+                );
+            } else {
+                device.loopback_module_id = parse_module_id(&output.stdout);
+                if let Some(id) = device.loopback_module_id {
+                    info!(
+                        "Created loopback with module ID: {} (audio will play through speakers)",
+                        id
+                    );
+                }
+            }
+        }
+
+        info!(
+            "Virtual microphone '{}' created - select it in your application",
+            device.source_name
+        );
+
+        Ok(device)
+    }
+
+    /// Poll `pactl list sources short` until `name` shows up.
+    fn wait_for_source(&self, name: &str) -> Result<bool> {
+        for attempt in 0..SOURCE_CHECK_ATTEMPTS {
+            // This loop does the retrying, so pactl's own retries would only stack up
+            let output = self.pactl.run_once(&["list", "sources", "short"])?;
+            if output.status.success()
+                && parse_source_names(&output.stdout).iter().any(|s| s == name)
+            {
+                return Ok(true);
+            }
+            if attempt + 1 < SOURCE_CHECK_ATTEMPTS {
+                std::thread::sleep(SOURCE_CHECK_DELAY);
+            }
+        }
+        Ok(false)
+    }
+
+    fn sink_name(&self) -> &str {
+        &self.sink_name
+    }
+
+    /// Loaded module IDs in the order they are unloaded.
+    fn module_ids(&self) -> Vec<u32> {
+        [
+            self.loopback_module_id,
+            self.remap_module_id,
+            self.module_id,
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+}
+
+impl Drop for VirtualDevice {
+    fn drop(&mut self) {
+        // Unload in reverse order: loopback, remap source, then sink. Each is
+        // tried once, so shutting down doesn't wait out retries
+        if let Some(loopback_id) = self.loopback_module_id {
+            info!("Cleaning up loopback (module {})", loopback_id);
+            let _ = self
+                .pactl
+                .run_once(&["unload-module", &loopback_id.to_string()]);
+        }
+        if let Some(remap_id) = self.remap_module_id {
+            info!("Cleaning up remap source (module {})", remap_id);
+            let _ = self
+                .pactl
+                .run_once(&["unload-module", &remap_id.to_string()]);
+        }
+        if let Some(module_id) = self.module_id {
+            info!("Cleaning up null sink (module {})", module_id);
+            let _ = self
+                .pactl
+                .run_once(&["unload-module", &module_id.to_string()]);
+        }
+    }
+}
+
+/// Properties of the stream playing into the null sink `sink`: the media
+/// role and category the session manager routes it by, its names, and any
+/// `--quantum` request.
+fn stream_props(args: &Args, sink: &str, rate: u32) -> Vec<(&'static str, String)> {
+    let mut props = vec![
+        (*pw::keys::MEDIA_TYPE, "Audio".to_string()),
+        (*pw::keys::MEDIA_CATEGORY, args.media_category.clone()),
+        (*pw::keys::MEDIA_ROLE, args.media_role.clone()),
+        (*pw::keys::NODE_NAME, format!("{}_player", args.name)),
+        (
+            *pw::keys::NODE_DESCRIPTION,
+            format!("{} Audio Player", args.name),
+        ),
+        ("node.target", sink.to_string()),
+    ];
+    if let Some(quantum) = args.quantum {
+        for (key, value) in latency_props(quantum, rate, args.rate_quantum, args.force_quantum) {
+            info!("Requesting {}={}", key, value);
+            props.push((key, value));
+        }
+    }
+    props
+}
+
+/// Smallest and largest quantum PipeWire accepts by default.
+const QUANTUM_RANGE: std::ops::RangeInclusive<u32> = 32..=8192;
+
+/// Node properties requesting a buffer size of `quantum` frames at `rate`.
+///
+/// `node.latency` is only a request: the graph runs at the smallest quantum any
+/// active node asks for. With `rate_quantum`, `node.rate` asks the graph to run
+/// at the stream rate too, so the quantum isn't rescaled by a different graph rate.
+/// With `force`, `node.force-quantum` holds the graph at the quantum, whatever
+/// other nodes ask for; it isn't the default because it overrides them.
+fn latency_props(
+    quantum: u32,
+    rate: u32,
+    rate_quantum: bool,
+    force: bool,
+) -> Vec<(&'static str, String)> {
+    let clamped = quantum.clamp(*QUANTUM_RANGE.start(), *QUANTUM_RANGE.end());
+    if clamped != quantum {
+        warn!(
+            "--quantum {} is outside {}..={}; using {}",
+            quantum,
+            QUANTUM_RANGE.start(),
+            QUANTUM_RANGE.end(),
+            clamped
+        );
+    }
+
+    let mut props = vec![("node.latency", format!("{}/{}", clamped, rate))];
+    if rate_quantum {
+        props.push(("node.rate", format!("1/{}", rate)));
+    }
+    if force {
+        props.push(("node.force-quantum", clamped.to_string()));
+    }
+    props
+}
+
+/// SPA channel positions for the given output channel count.
+fn channel_positions(channels: u32) -> Vec<Id> {
+    match channels {
+        1 => vec![Id(pw::spa::sys::SPA_AUDIO_CHANNEL_MONO)],
+        _ => vec![
+            Id(pw::spa::sys::SPA_AUDIO_CHANNEL_FL),
+            Id(pw::spa::sys::SPA_AUDIO_CHANNEL_FR),
+        ],
+    }
+}
+
+/// Serialized `EnumFormat` param for a raw audio stream in `format`.
+fn format_pod(format: &StreamFormat) -> Result<Vec<u8>> {
+    Ok(pw::spa::pod::serialize::PodSerializer::serialize(
+        std::io::Cursor::new(Vec::new()),
+        &pw::spa::pod::Value::Object(pw::spa::pod::Object {
+            type_: pw::spa::sys::SPA_TYPE_OBJECT_Format,
             id: pw::spa::sys::SPA_PARAM_EnumFormat,
             properties: vec![
                 pw::spa::pod::Property {
@@ -427,70 +3452,519 @@ fn main() -> Result<()> {
                 pw::spa::pod::Property {
                     key: pw::spa::sys::SPA_FORMAT_AUDIO_format,
                     flags: pw::spa::pod::PropertyFlags::empty(),
-                    value: pw::spa::pod::Value::Id(Id(pw::spa::sys::SPA_AUDIO_FORMAT_F32_LE)),
+                    value: pw::spa::pod::Value::Id(Id(format.sample_format.spa_format())),
                 },
                 pw::spa::pod::Property {
                     key: pw::spa::sys::SPA_FORMAT_AUDIO_rate,
                     flags: pw::spa::pod::PropertyFlags::empty(),
-                    value: pw::spa::pod::Value::Int(SAMPLE_RATE as i32),
+                    value: pw::spa::pod::Value::Int(format.rate as i32),
                 },
                 pw::spa::pod::Property {
                     key: pw::spa::sys::SPA_FORMAT_AUDIO_channels,
                     flags: pw::spa::pod::PropertyFlags::empty(),
-                    value: pw::spa::pod::Value::Int(CHANNELS as i32),
+                    value: pw::spa::pod::Value::Int(format.channels as i32),
+                },
+                pw::spa::pod::Property {
+                    key: pw::spa::sys::SPA_FORMAT_AUDIO_position,
+                    flags: pw::spa::pod::PropertyFlags::empty(),
+                    value: pw::spa::pod::Value::ValueArray(pw::spa::pod::ValueArray::Id(
+                        channel_positions(format.channels),
+                    )),
+                },
+            ],
+        }),
+    )
+    .map_err(|e| anyhow!("Failed to serialize format: {:?}", e))?
+    .0
+    .into_inner())
+}
+
+fn main() -> ExitCode {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::from_default_env()
+                .add_directive(tracing::Level::INFO.into()),
+        )
+        .init();
+
+    // clap exits with 2 for bad arguments, which here means an unreadable input
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            let _ = e.print();
+            return if e.use_stderr() {
+                ExitCode::from(Exit::Usage as u8)
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+    };
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            Exit::code(&e)
+        }
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    let pactl = Pactl::new(
+        args.pactl_retries,
+        Duration::from_millis(args.pactl_retry_delay),
+    );
+
+    if let Some(CliCommand::Stop { name }) = &args.command {
+        return instance::stop(name, pactl);
+    }
+
+    // Before the device is created, so a missing feature leaves nothing behind
+    #[cfg(not(feature = "tui"))]
+    if args.tui {
+        return Err(anyhow!(
+            "--tui needs virtual-mic built with the tui feature"
+        ));
+    }
+    #[cfg(not(feature = "dbus"))]
+    if args.dbus {
+        return Err(anyhow!(
+            "--dbus needs virtual-mic built with the dbus feature"
+        ));
+    }
+    #[cfg(not(feature = "dbus"))]
+    if args.mpris {
+        return Err(anyhow!(
+            "--mpris needs virtual-mic built with the dbus feature"
+        ));
+    }
+
+    let access = Access::new(
+        args.auth_token.as_deref(),
+        args.tls_cert.as_deref().zip(args.tls_key.as_deref()),
+    )?;
+    let rtp = args.listen_rtp.map(|addr| RtpParams {
+        addr,
+        encoding: args.rtp_encoding,
+        rate: args.rtp_rate,
+        channels: args.rtp_channels as usize,
+        jitter: Duration::from_millis(args.rtp_jitter),
+    });
+    let timeline = match &args.timeline {
+        Some(path) => {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read timeline {:?}: {}", path, e))
+                .context(Exit::Unreadable)?;
+            let cues = timeline::parse(&contents).map_err(|e| anyhow!("{:?}: {}", path, e))?;
+            if cues.is_empty() {
+                return Err(anyhow!("No cues in timeline {:?}", path));
+            }
+            Some(cues)
+        }
+        None => None,
+    };
+    let entries: Vec<Entry> = if let Some(CliCommand::Soundboard { .. }) = &args.command {
+        vec![PathBuf::from("soundboard").into()]
+    } else if args.stdin_pcm || args.say_stdin {
+        vec![PathBuf::from("-").into()]
+    } else if let Some(rtp) = &rtp {
+        // Only shown in logs and the status file
+        vec![PathBuf::from(format!("rtp://{}", rtp.addr)).into()]
+    } else if let Some(addr) = args.listen_ws {
+        vec![PathBuf::from(format!("ws://{}", addr)).into()]
+    } else if let Some(addr) = args.listen_grpc {
+        vec![PathBuf::from(format!("grpc://{}", addr)).into()]
+    } else if let Some(sink) = &args.capture_sink_monitor {
+        vec![PathBuf::from(format!("{}.monitor", sink)).into()]
+    } else if let Some(command) = &args.exec {
+        vec![PathBuf::from(command).into()]
+    } else if !args.url.is_empty() {
+        args.url
+            .iter()
+            .map(|url| PathBuf::from(url).into())
+            .collect()
+    } else if let Some(dir) = &args.spool {
+        vec![dir.clone().into()]
+    } else if let Some(text) = &args.say {
+        vec![PathBuf::from(text).into()]
+    } else if let Some(path) = &args.say_file {
+        vec![path.clone().into()]
+    } else if let Some(signal) = &args.generate {
+        vec![PathBuf::from(signal.to_string()).into()]
+    } else if let Some(digits) = &args.dtmf {
+        vec![PathBuf::from(format!("dtmf:{}", digits)).into()]
+    } else if let Some(cues) = &timeline {
+        let base = args.timeline.as_deref().and_then(Path::parent);
+        cues.iter()
+            .map(|cue| match Path::new(&cue.file) {
+                url if http::is_url(url) => url.to_path_buf().into(),
+                file => base.unwrap_or(Path::new("")).join(file).into(),
+            })
+            .collect()
+    } else {
+        playlist::expand(&args.file).context(Exit::Unreadable)?
+    };
+    let stdin = entries.iter().any(|entry| entry.path == Path::new("-"));
+    if args.repl && stdin {
+        return Err(anyhow!(
+            "--repl reads commands from stdin, which is the input"
+        ));
+    }
+
+    if !KNOWN_MEDIA_ROLES.contains(&args.media_role.as_str()) {
+        warn!(
+            "Unknown media role '{}' (known roles: {})",
+            args.media_role,
+            KNOWN_MEDIA_ROLES.join(", ")
+        );
+    }
+    if !KNOWN_MEDIA_CATEGORIES.contains(&args.media_category.as_str()) {
+        warn!(
+            "Unknown media category '{}' (known categories: {})",
+            args.media_category,
+            KNOWN_MEDIA_CATEGORIES.join(", ")
+        );
+    }
+
+    let format = StreamFormat::from_args(&args);
+    info!(
+        "Stream format: {} Hz, {} channel(s), {:?}",
+        format.rate, format.channels, format.sample_format
+    );
+
+    if let Some(hz) = args.generate.as_ref().and_then(Signal::max_frequency) {
+        if hz >= format.rate as f64 / 2.0 {
+            return Err(anyhow!(
+                "--generate frequency {} Hz must be below half the sample rate ({} Hz)",
+                hz,
+                format.rate
+            ));
+        }
+    }
+
+    let endless =
+        args.loop_audio == Some(None) || matches!(args.repeat, Some(Repeat::One | Repeat::All));
+    let on_end = OnEnd::from_args(&args);
+    if on_end == OnEnd::Exit && endless {
+        return Err(anyhow!(
+            "--exit-on-end and --on-end exit need playback that ends; give --loop a count"
+        ));
+    }
+
+    if format.channels == 1 && (args.pan.is_some() || args.channel_gains.is_some()) {
+        warn!("--pan and --channel-gains only apply to stereo output (--channels 2); ignoring");
+    }
+
+    let _pid_file = instance::lock(&args.name, pactl)?;
+
+    if let Some(source) = &args.mix_source {
+        if *source == args.name {
+            return Err(anyhow!(
+                "--mix-source can't be the virtual microphone itself"
+            ));
+        }
+        let output = pactl.run(&["list", "sources", "short"])?;
+        if output.status.success() && !parse_source_names(&output.stdout).contains(source) {
+            return Err(anyhow!(
+                "Source '{}' not found (see `pactl list sources short`)",
+                source
+            ));
+        }
+    }
+    if let Some(source) = &args.reply_to {
+        if *source == args.name || *source == format!("{}_sink.monitor", args.name) {
+            return Err(anyhow!("--reply-to can't be the virtual microphone itself"));
+        }
+        let output = pactl.run(&["list", "sources", "short"])?;
+        if output.status.success() && !parse_source_names(&output.stdout).contains(source) {
+            return Err(anyhow!(
+                "Source '{}' not found (see `pactl list sources short`)",
+                source
+            ));
+        }
+    }
+    if let Some(sink) = &args.capture_sink_monitor {
+        if *sink == format!("{}_sink", args.name) {
+            return Err(anyhow!(
+                "--capture-sink-monitor can't be the virtual microphone's own sink"
+            ));
+        }
+        let output = pactl.run(&["list", "sinks", "short"])?;
+        if output.status.success() && !parse_source_names(&output.stdout).contains(sink) {
+            return Err(anyhow!(
+                "Sink '{}' not found (see `pactl list sinks short`)",
+                sink
+            ));
+        }
+    }
+
+    // Create the virtual audio device (null sink with monitor)
+    let virtual_device =
+        VirtualDevice::new(&args.name, args.monitor, &format, pactl).context(Exit::Device)?;
+
+    // Record what was created so `virtual-mic stop <name>` can find it
+    let state_file = InstanceState {
+        name: args.name.clone(),
+        pid: std::process::id(),
+        modules: virtual_device.module_ids(),
+    }
+    .write()
+    .map_err(|e| warn!("Failed to write instance state file: {}", e))
+    .ok();
+
+    info!("Initializing PipeWire...");
+    pw::init();
+
+    let mainloop = pw::main_loop::MainLoop::new(None).context(Exit::PipeWire)?;
+    let context = pw::context::Context::new(&mainloop).context(Exit::PipeWire)?;
+    let core = context.connect(None).context(Exit::PipeWire)?;
+
+    let entry_spec = entry_spec(&args, &format)?;
+    let (decoder, resume_point) = build_pipeline(&args, &format, &entries, &entry_spec, stdin)?;
+    let first = decoder.borrow().playlist.current().clone();
+    if let Some(url) = &args.webhook {
+        let events = decoder.borrow().events.clone();
+        webhook::start(url.clone(), args.name.clone(), events.subscribe())?;
+        events.emit("device-created".to_string());
+    }
+
+    // Capture streams always deliver f32 at the output rate and channel count
+    let capture_pod = format_pod(&StreamFormat {
+        sample_format: SampleFormat::F32,
+        ..format
+    })?;
+
+    // Open the audio file, or start listening or capturing
+    let mut _monitor_capture = None;
+    let listener = if let Some(CliCommand::Soundboard { clips, overlap }) = &args.command {
+        let mut decoder = decoder.borrow_mut();
+        let clips = clips
+            .iter()
+            .map(|path| decoder.preload(path.clone()))
+            .collect::<Result<Vec<_>>>()
+            .context(Exit::Unreadable)?;
+        info!("Soundboard of {} clips", clips.len());
+        let (channels, rate) = (format.channels as usize, format.rate);
+        let (board, pads) = Soundboard::new(clips, *overlap, rate, channels);
+        decoder.pads = Some(pads);
+        Some(Source::soundboard(board, rate))
+    } else if let Some(params) = rtp {
+        Some(Source::listen_rtp(params)?)
+    } else if let Some((addr, params)) = args.listen_ws.zip(pcm_params(&args)) {
+        Some(Source::listen_ws(addr, params, access.clone())?)
+    } else if let Some(addr) = args.listen_grpc {
+        Some(Source::listen_grpc(addr)?)
+    } else if let Some(sink) = &args.capture_sink_monitor {
+        let (chunks, feed) = Feed::channel();
+        let (channels, rate) = (format.channels as usize, format.rate);
+        _monitor_capture = Some(
+            Capture::connect(
+                &core,
+                &format!("{}_capture", args.name),
+                sink,
+                true,
+                &capture_pod,
+                // Runs on the main loop thread, so drop audio rather than block when full
+                move |samples| {
+                    let _ = chunks.try_send((samples.to_vec(), channels, Some(rate)));
                 },
-            ],
-        }),
-    )
-    .map_err(|e| anyhow!("Failed to serialize format: {:?}", e))?
-    .0
-    .into_inner();
+            )
+            .context(Exit::PipeWire)?,
+        );
+        Some(Source::from_feed(feed, rate))
+    } else if let Some(dir) = &args.spool {
+        Some(Source::spool(InputSpec {
+            path: dir.clone(),
+            ..entry_spec
+        })?)
+    } else if args.say_stdin {
+        Some(Source::speak_stdin(entry_spec)?)
+    } else {
+        None
+    };
+    if listener.is_none() {
+        decoder.borrow().measure_playlist();
+    }
+    match listener {
+        Some(source) => decoder.borrow_mut().install(source),
+        None if timeline.is_some() => {
+            let cues = timeline.iter().flatten().map(|cue| cue.at).collect();
+            decoder.borrow_mut().start_timeline(cues);
+        }
+        None => {
+            let mut decoder = decoder.borrow_mut();
+            decoder.open().context(Exit::Unreadable)?;
+            if let Some(point) = &resume_point {
+                info!("Resuming {} at {}", first, format_duration(point.position));
+                if let Err(e) = decoder.seek(SeekTarget::To(point.position)) {
+                    warn!("Failed to resume where playback stopped: {}", e);
+                }
+            } else if let Some(position) = args.seek {
+                decoder.seek(SeekTarget::To(position))?;
+            }
+        }
+    }
+    if let Some(at) = args.start_at.map(time::next_local).or(args.sync_epoch) {
+        decoder.borrow_mut().start_at(at);
+    }
+
+    info!("Creating audio stream to virtual device...");
+
+    let values = format_pod(&format)?;
 
     let mut params = [Pod::from_bytes(&values).ok_or_else(|| anyhow!("Invalid pod"))?];
 
     // Create stream that outputs to our null sink
-    let props = pw::properties::properties! {
-        *pw::keys::MEDIA_TYPE => "Audio",
-        *pw::keys::MEDIA_CATEGORY => "Playback",
-        *pw::keys::MEDIA_ROLE => "Music",
-        *pw::keys::NODE_NAME => format!("{}_player", args.name),
-        *pw::keys::NODE_DESCRIPTION => format!("{} Audio Player", args.name),
-        // Target our null sink
-        "node.target" => virtual_device.sink_name(),
+    let mut props = pw::properties::Properties::new();
+    for (key, value) in stream_props(&args, virtual_device.sink_name(), format.rate) {
+        props.insert(key, value);
+    }
+
+    let stream = Rc::new(
+        Stream::new(&core, &format!("{}_player", args.name), props).context(Exit::PipeWire)?,
+    );
+
+    let recorder = match &args.record {
+        Some(path) => Some(Rc::new(Recorder::start(
+            path.clone(),
+            args.record_format,
+            format.channels as usize,
+            format.rate,
+            args.record_rate.unwrap_or(format.rate),
+        )?)),
+        None => None,
+    };
+
+    // Captured audio is mixed in after the played audio's processing chain
+    let mix = args.mix_source.as_ref().map(|_| {
+        Rc::new(RefCell::new(MixBuffer::new(
+            args.mix_gain.clamp(0.0, 2.0),
+            args.max_volume,
+            format.channels as usize,
+            (format.rate * MIX_MAX_LATENCY_MS / 1000) as usize,
+        )))
+    });
+    let _capture = match (&args.mix_source, &mix) {
+        (Some(source), Some(mix)) => {
+            let mix = mix.clone();
+            Some(
+                Capture::connect(
+                    &core,
+                    &format!("{}_mix", args.name),
+                    source,
+                    false,
+                    &capture_pod,
+                    move |samples| mix.borrow_mut().push(samples),
+                )
+                .context(Exit::PipeWire)?,
+            )
+        }
+        _ => None,
     };
 
-    let stream = Stream::new(&core, &format!("{}_player", args.name), props)?;
+    // The far side of --reply-to is listened to while its turn is awaited
+    let turns = args.reply_to.as_ref().map(|_| {
+        decoder.borrow_mut().take_turns();
+        Rc::new(RefCell::new(TurnTaking::new(
+            args.reply_threshold,
+            args.reply_silence,
+            format.rate,
+            format.channels as usize,
+        )))
+    });
+    let _reply_capture = match (&args.reply_to, &turns) {
+        (Some(source), Some(turns)) => {
+            let turns = turns.clone();
+            // A sink's monitor is captured from the sink
+            let (target, sink_monitor) = match source.strip_suffix(".monitor") {
+                Some(sink) => (sink, true),
+                None => (source.as_str(), false),
+            };
+            Some(
+                Capture::connect(
+                    &core,
+                    &format!("{}_reply", args.name),
+                    target,
+                    sink_monitor,
+                    &capture_pod,
+                    move |samples| turns.borrow_mut().push(samples),
+                )
+                .context(Exit::PipeWire)?,
+            )
+        }
+        _ => None,
+    };
 
     let decoder_clone = decoder.clone();
+    let recorder_clone = recorder.clone();
+    let mix_clone = mix.clone();
+    let channels = format.channels as usize;
+    let meter = args.tui.then(|| Meter::new(channels));
+    let meter_clone = meter.clone();
+    let sample_format = format.sample_format;
+    let rate = format.rate;
+    let mut scratch: Vec<f32> = Vec::new();
     let mainloop_weak = mainloop.downgrade();
+    // Why playback stopped early, returned once everything is cleaned up
+    let failure: Rc<RefCell<Option<anyhow::Error>>> = Rc::new(RefCell::new(None));
+    let failure_clone = failure.clone();
+
+    // The remap source was verified when the device was created, so the mic is
+    // usable as soon as the stream is connected
+    let readiness = Rc::new(RefCell::new(Readiness::new(args.ready_file.clone())));
+    let readiness_clone = readiness.clone();
+    let mic_name = args.name.clone();
 
     let _listener = stream
         .add_local_listener_with_user_data(())
         .state_changed(move |_, _, old, new| {
             info!("Stream state: {:?} -> {:?}", old, new);
+            let mut readiness = readiness_clone.borrow_mut();
+            match new {
+                pw::stream::StreamState::Paused | pw::stream::StreamState::Streaming => {
+                    readiness.mark_ready(&mic_name)
+                }
+                _ => readiness.status(&format!("Stream {:?}", new)),
+            }
         })
         .process(move |stream, _| {
             if let Some(mut buffer) = stream.dequeue_buffer() {
                 let datas = buffer.datas_mut();
                 if let Some(data) = datas.first_mut() {
-                    let stride = std::mem::size_of::<f32>() * CHANNELS as usize;
+                    let width = sample_format.bytes_per_sample();
+                    let stride = width * channels;
 
                     let filled = if let Some(slice) = data.data() {
-                        let samples: &mut [f32] = unsafe {
-                            std::slice::from_raw_parts_mut(
-                                slice.as_mut_ptr() as *mut f32,
-                                slice.len() / std::mem::size_of::<f32>(),
-                            )
-                        };
+                        // Decode into float scratch space, then encode in the stream format
+                        scratch.resize(slice.len() / width, 0.0);
 
                         let mut dec = decoder_clone.borrow_mut();
-                        match dec.fill_buffer(samples) {
+                        let started = Instant::now();
+                        match dec.fill_buffer(&mut scratch) {
                             Ok(filled) => {
                                 debug!("Filled {} samples", filled);
+                                // Taking longer than the buffer lasts may leave the graph
+                                // short; PipeWire's own xruns aren't seen from here
+                                let lasts = (filled / channels) as f64 / rate as f64;
+                                let late = started.elapsed().as_secs_f64() - lasts;
+                                if late > 0.0 {
+                                    dec.events.emit(format!("slow-fill {:.1}", late * 1000.0));
+                                }
+                                if let Some(mix) = &mix_clone {
+                                    mix.borrow_mut().mix_into(&mut scratch[..filled]);
+                                }
+                                dec.apply_mute(&mut scratch[..filled]);
+                                if let Some(meter) = &meter_clone {
+                                    meter.measure(&scratch[..filled]);
+                                }
+                                sample_format.encode(&scratch[..filled], slice);
+                                if let Some(recorder) = &recorder_clone {
+                                    recorder.push(&scratch[..filled]);
+                                }
                                 Some(filled)
                             }
                             Err(e) => {
-                                error!("Failed to fill buffer: {}", e);
+                                error!("Failed to fill buffer: {:#}", e);
+                                failure_clone.borrow_mut().get_or_insert(e);
                                 if let Some(ml) = mainloop_weak.upgrade() {
                                     ml.quit();
                                 }
@@ -503,25 +3977,34 @@ fn main() -> Result<()> {
 
                     if let Some(filled) = filled {
                         let chunk = data.chunk_mut();
-                        *chunk.size_mut() = (filled * std::mem::size_of::<f32>()) as u32;
+                        *chunk.size_mut() = (filled * width) as u32;
                         *chunk.stride_mut() = stride as i32;
                         *chunk.offset_mut() = 0;
                     }
                 }
             }
         })
-        .register()?;
+        .register()
+        .context(Exit::PipeWire)?;
 
-    stream.connect(
-        pw::spa::utils::Direction::Output,
-        None,
-        StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS | StreamFlags::RT_PROCESS,
-        &mut params,
-    )?;
+    // No RT_PROCESS: the process callback shares the decoder with main loop timers,
+    // so it must run on the main loop thread rather than the realtime data thread
+    stream
+        .connect(
+            pw::spa::utils::Direction::Output,
+            None,
+            StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS,
+            &mut params,
+        )
+        .context(Exit::PipeWire)?;
 
     info!("Virtual microphone '{}' is now active!", args.name);
     info!("Select '{}' as your microphone in applications", args.name);
-    info!("Playing: {:?}", args.file);
+    match entries.as_slice() {
+        [file] => info!("Playing: {}", file),
+        [_, ..] => info!("Playing {} files, starting with {}", entries.len(), first),
+        [] => {}
+    }
     info!("Press Ctrl+C to stop");
 
     // Handle Ctrl+C
@@ -532,24 +4015,1032 @@ fn main() -> Result<()> {
         running_clone.store(false, Ordering::SeqCst);
     })
     .ok();
+    // After the Ctrl+C handler, so SIGHUP reopens instead of shutting down
+    let signal_commands = match signals::watch() {
+        Ok(commands) => Some(commands),
+        Err(e) => {
+            warn!("Control by signal is off: {}", e);
+            None
+        }
+    };
+
+    // The control socket, JSON-RPC, varlink, HTTP and D-Bus hand their requests to the same queue
+    let (request_tx, requests) = mpsc::channel();
+    let events = decoder.borrow().events.clone();
+    let control_socket = args
+        .control_socket
+        .as_deref()
+        .map(|path| ControlSocket::start(path, events.clone(), request_tx.clone()))
+        .transpose()?;
+    let json_rpc = match &args.json_rpc {
+        Some(path) => {
+            let path = match path {
+                Some(path) => path.clone(),
+                None => jsonrpc::default_path()?,
+            };
+            Some(JsonRpcServer::start(
+                &path,
+                events.clone(),
+                request_tx.clone(),
+            )?)
+        }
+        None => None,
+    };
+    let device = rest::Device {
+        name: args.name.clone(),
+        source: virtual_device.source_name.clone(),
+        sink: virtual_device.sink_name.clone(),
+        rate: format.rate,
+        channels: format.channels,
+        format: format.sample_format.pulse_name().to_string(),
+        pid: std::process::id(),
+    };
+    let varlink = match &args.varlink {
+        Some(path) => {
+            let path = match path {
+                Some(path) => path.clone(),
+                None => varlink::default_path()?,
+            };
+            Some(varlink::VarlinkService::start(
+                &path,
+                device.clone(),
+                events.clone(),
+                request_tx.clone(),
+            )?)
+        }
+        None => None,
+    };
+    let rest_server = match args.http {
+        Some(addr) => Some(RestServer::start(
+            addr,
+            device.clone(),
+            access.clone(),
+            events.clone(),
+            request_tx.clone(),
+        )?),
+        None => None,
+    };
+    #[cfg(feature = "dbus")]
+    let dbus_service = args
+        .dbus
+        .then(|| dbus::DbusService::start(events.clone(), request_tx.clone()))
+        .transpose()?;
+    #[cfg(feature = "dbus")]
+    let mpris = args
+        .mpris
+        .then(|| mpris::MprisService::start(&args.name, events, request_tx.clone()))
+        .transpose()?;
+    #[cfg(not(feature = "dbus"))]
+    let dbus_service: Option<()> = None;
+    #[cfg(not(feature = "dbus"))]
+    let mpris: Option<()> = None;
+    let pads = match &args.command {
+        Some(CliCommand::Soundboard { clips, .. }) => clips.len(),
+        _ => 0,
+    };
+    #[cfg(feature = "tui")]
+    let tui = match meter {
+        Some(meter) => Some(tui::Tui::start(device, meter, pads, request_tx.clone())?),
+        None => None,
+    };
+    #[cfg(not(feature = "tui"))]
+    let tui: Option<()> = None;
+    let repl = args.repl.then(|| repl::start(request_tx)).transpose()?;
+    let control_requests = (control_socket.is_some()
+        || json_rpc.is_some()
+        || varlink.is_some()
+        || rest_server.is_some()
+        || dbus_service.is_some()
+        || mpris.is_some()
+        || tui.is_some()
+        || repl.is_some())
+    .then_some(requests);
 
-    // Keep virtual_device alive until shutdown
-    let _virtual_device = virtual_device;
+    // stdin can't be both an input and the keyboard; the dashboard and --repl read it themselves
+    let keys = if args.no_keys || stdin || args.tui || args.repl {
+        None
+    } else {
+        Keyboard::start(pads)
+    };
+    let (keyboard, key_commands) = match keys {
+        Some((keyboard, commands)) => (Some(keyboard), Some(commands)),
+        None => (None, None),
+    };
+    let queued = args.queue_file.as_deref().map(queue::watch).transpose()?;
+    let ptt_commands = args.ptt.as_ref().map(ptt::watch).transpose()?;
+    let hotkey_commands = args.global_keys.as_ref().map(hotkeys::watch).transpose()?;
+    let midi_commands = match &args.midi_map {
+        Some(path) => Some(midimap::watch(
+            &MidiMap::load(path).context(Exit::Unreadable)?,
+        )?),
+        None => None,
+    };
+    let osc_commands = args.osc.map(osc::listen).transpose()?;
+    if ptt_commands.is_some() {
+        decoder.borrow_mut().start_push_to_talk();
+    }
+    let take_commands = control_requests.is_some()
+        || key_commands.is_some()
+        || queued.is_some()
+        || ptt_commands.is_some()
+        || hotkey_commands.is_some()
+        || midi_commands.is_some()
+        || osc_commands.is_some()
+        || signal_commands.is_some()
+        || turns.is_some();
 
+    // Shutting down fades the audio out, plays out what the graph still holds
+    // and quits the main loop; the stream is then disconnected and the devices
+    // removed as everything is dropped
+    let end_decoder = decoder.clone();
+    let end_stream = stream.clone();
+    let end_mainloop = mainloop.downgrade();
+    let held = Cell::new(false);
+    let deadline = args.max_duration.map(|duration| Instant::now() + duration);
+    let deadline_fade = args.max_duration_fade.or(args.fade_out).unwrap_or_default();
+    let shutdown_fade = args.fade_out.unwrap_or(SHUTDOWN_FADE);
+    let give_up = Cell::new(None);
     let timer = mainloop.loop_().add_timer({
         move |_| {
-            if !running.load(Ordering::SeqCst) {
-                std::process::exit(0);
+            let mut dec = end_decoder.borrow_mut();
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) && !dec.stopping {
+                info!("Reached --max-duration; stopping");
+                dec.stop(deadline_fade);
+                give_up.set(Some(Instant::now() + deadline_fade + SHUTDOWN_TIMEOUT));
+            }
+            let drained = dec.drained();
+            drop(dec);
+            match on_end {
+                OnEnd::Exit if drained => {
+                    info!("Playback finished");
+                    running.store(false, Ordering::SeqCst);
+                }
+                // Nothing is processed while inactive; a command that starts
+                // playing again clears `drained` and brings the stream back
+                OnEnd::HoldDevice if drained != held.get() => {
+                    match end_stream.set_active(!drained) {
+                        Ok(()) if drained => info!("Playback finished; holding the device"),
+                        Ok(()) => info!("Playback resumed"),
+                        Err(e) => warn!("Failed to set the stream active: {}", e),
+                    }
+                    held.set(drained);
+                }
+                _ => {}
+            }
+
+            let mut dec = end_decoder.borrow_mut();
+            if (!running.load(Ordering::SeqCst) || dec.quitting) && !dec.stopping {
+                // Audio that already played out has nothing left to fade
+                let fade = if drained {
+                    Duration::ZERO
+                } else {
+                    shutdown_fade
+                };
+                dec.stop(fade);
+                give_up.set(Some(Instant::now() + fade + SHUTDOWN_TIMEOUT));
+            }
+            let Some(give_up) = give_up.get() else {
+                return;
+            };
+            // A held stream isn't processed, so there is nothing to fade
+            let finished = held.get() || dec.stopped() && dec.drained();
+            if !finished && Instant::now() < give_up {
+                return;
+            }
+            if !finished {
+                warn!("The stream didn't play out in time; stopping anyway");
+            }
+            if let Some(mainloop) = end_mainloop.upgrade() {
+                mainloop.quit();
             }
         }
     });
     timer.update_timer(
-        Some(std::time::Duration::from_millis(100)),
-        Some(std::time::Duration::from_millis(100)),
+        Some(Duration::from_millis(100)),
+        Some(Duration::from_millis(100)),
     );
 
+    // Apply control commands between buffers, on the thread that owns the decoder
+    let control_decoder = decoder.clone();
+    let load_crossfade = Duration::from_millis(args.load_crossfade);
+    // The `load` or `track` request still opening, answered once it is swapped in
+    let loading_request: RefCell<Option<Request>> = RefCell::new(None);
+    let interject_request: RefCell<Option<Request>> = RefCell::new(None);
+    let control_timer = mainloop.loop_().add_timer(move |_| {
+        let mut dec = control_decoder.borrow_mut();
+        if let Some(queued) = &queued {
+            while let Ok(path) = queued.try_recv() {
+                if let Err(e) = dec.enqueue(&path) {
+                    warn!("Failed to queue {:?}: {}", path, e);
+                }
+            }
+        }
+        if let Some(commands) = &key_commands {
+            while let Ok(command) = commands.try_recv() {
+                if let Err(e) = dec.apply(&command, load_crossfade) {
+                    warn!("Key command {:?} failed: {}", command, e);
+                }
+            }
+        }
+        if let Some(commands) = &ptt_commands {
+            while let Ok(command) = commands.try_recv() {
+                if let Err(e) = dec.apply(&command, load_crossfade) {
+                    warn!("Push-to-talk failed: {}", e);
+                }
+            }
+        }
+        if let Some(commands) = &hotkey_commands {
+            while let Ok(command) = commands.try_recv() {
+                if let Err(e) = dec.apply(&command, load_crossfade) {
+                    warn!("Hotkey command {:?} failed: {}", command, e);
+                }
+            }
+        }
+        if let Some(commands) = &midi_commands {
+            while let Ok(command) = commands.try_recv() {
+                if let Err(e) = dec.apply(&command, load_crossfade) {
+                    warn!("MIDI command {:?} failed: {}", command, e);
+                }
+            }
+        }
+        if let Some(commands) = &osc_commands {
+            while let Ok(command) = commands.try_recv() {
+                if let Err(e) = dec.apply(&command, load_crossfade) {
+                    warn!("OSC command {:?} failed: {}", command, e);
+                }
+            }
+        }
+        if let Some(commands) = &signal_commands {
+            while let Ok(command) = commands.try_recv() {
+                if let Err(e) = dec.apply(&command, load_crossfade) {
+                    warn!("Signal command {:?} failed: {}", command, e);
+                }
+            }
+        }
+        if let Some(turns) = &turns {
+            let mut turns = turns.borrow_mut();
+            if turns.take_turn() {
+                if let Err(e) = dec.skip_by(1, Duration::ZERO) {
+                    warn!("Failed to play the reply: {}", e);
+                }
+            } else if !turns.is_listening() && dec.awaiting_reply() {
+                info!("Waiting for a reply");
+                turns.listen();
+            }
+        }
+        let Some(requests) = &control_requests else {
+            return;
+        };
+        while let Ok(request) = requests.try_recv() {
+            if request.command == ControlCommand::Position {
+                request.answer(dec.progress().fields().join(" "));
+                continue;
+            }
+            if request.command == ControlCommand::Status {
+                request.answer(dec.status());
+                continue;
+            }
+            if request.command == ControlCommand::Playlist {
+                request.answer(dec.playlist_json());
+                continue;
+            }
+            let result = dec.apply(&request.command, load_crossfade);
+            if let Err(e) = &result {
+                warn!("Control command {:?} failed: {}", request.command, e);
+            }
+            let loads = matches!(
+                request.command,
+                ControlCommand::Load(_)
+                    | ControlCommand::Track(_)
+                    | ControlCommand::Next
+                    | ControlCommand::Previous
+            );
+            let pending = match request.command {
+                _ if result.is_err() => None,
+                ControlCommand::Interject(_) => Some(&interject_request),
+                _ if loads => Some(&loading_request),
+                _ => None,
+            };
+            if let Some(pending) = pending {
+                if let Some(superseded) = pending.replace(Some(request)) {
+                    superseded.respond(Err("replaced by a later command".to_string()));
+                }
+                continue;
+            }
+            request.respond(result.map_err(|e| e.to_string()));
+        }
+        if let Some(result) = dec.finish_loading() {
+            if let Err(e) = &result {
+                warn!("Loading failed: {}", e);
+            }
+            if let Some(request) = loading_request.take() {
+                request.respond(result.map_err(|e| e.to_string()));
+            }
+        }
+        if let Some(result) = dec.finish_interjecting() {
+            if let Err(e) = &result {
+                warn!("Interjecting failed: {}", e);
+            }
+            if let Some(request) = interject_request.take() {
+                request.respond(result.map_err(|e| e.to_string()));
+            }
+        }
+    });
+    if take_commands {
+        control_timer.update_timer(Some(CONTROL_POLL_INTERVAL), Some(CONTROL_POLL_INTERVAL));
+    }
+
+    // Periodic progress logging, status file updates, progress events and clipping checks
+    let stats_interval = match args.stats_interval {
+        0 if args.status_file.is_some()
+            || args.control_socket.is_some()
+            || args.json_rpc.is_some()
+            || args.varlink.is_some() =>
+        {
+            Duration::from_secs(1)
+        }
+        0 => CLIP_CHECK_INTERVAL,
+        secs => Duration::from_secs(secs),
+    };
+    let log_stats = args.stats_interval > 0;
+    let status_file = args.status_file.clone();
+    let stats_decoder = decoder.clone();
+    let stats_timer = mainloop.loop_().add_timer(move |_| {
+        let mut dec = stats_decoder.borrow_mut();
+        dec.check_clipping();
+        let progress = dec.progress();
+        if log_stats {
+            info!("Progress: {}", progress);
+        }
+        dec.events
+            .emit(format!("progress {}", progress.fields().join(" ")));
+        if let Some(path) = &status_file {
+            if let Err(e) = write_status_file(path, dec.path(), dec.title(), progress) {
+                warn!("Failed to write status file {:?}: {}", path, e);
+            }
+        }
+    });
+    stats_timer.update_timer(Some(stats_interval), Some(stats_interval));
+
     mainloop.run();
 
+    // Let go of the null sink before it is unloaded
+    if let Err(e) = stream.disconnect() {
+        warn!("Failed to disconnect the stream: {}", e);
+    }
+    if let Some(recorder) = &recorder {
+        recorder.finish();
+    }
+    if args.resume {
+        match &decoder.borrow().stopped_at {
+            Some(point) => match point.save(&args.name) {
+                Ok(()) => info!("Saved the position to resume from next time"),
+                Err(e) => warn!("Failed to save the position to resume from: {}", e),
+            },
+            None => ResumePoint::clear(&args.name),
+        }
+    }
+    readiness.borrow_mut().stopping();
+    // In order: no more commands can arrive once the device is gone
+    drop((
+        control_socket,
+        json_rpc,
+        varlink,
+        rest_server,
+        dbus_service,
+        mpris,
+        keyboard,
+        tui,
+        state_file,
+        virtual_device,
+    ));
+
     info!("Goodbye!");
-    Ok(())
+    match failure.take() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// How every input is opened, from the command line; the path is filled in
+/// per entry.
+fn entry_spec(args: &Args, format: &StreamFormat) -> Result<InputSpec> {
+    let slice = slice(args)?;
+    Ok(InputSpec {
+        path: PathBuf::new(),
+        format_hint: args.format_hint.clone(),
+        raw: raw_params(args),
+        raw_files: pcm_params(args),
+        track: args.track.map(|track| track as usize),
+        exec: args.exec.clone(),
+        ytdlp: (!args.url.is_empty()).then(|| args.yt_dlp.clone()),
+        tts: (args.say.is_some() || args.say_file.is_some() || args.say_stdin).then(|| {
+            TtsOptions {
+                engine: args.tts_engine,
+                voice: args.tts_voice.clone(),
+                from_file: args.say_file.is_some(),
+            }
+        }),
+        generate: generated_signal(args).map(|signal| GenerateOptions {
+            signal,
+            rate: format.rate,
+            // The signal runs to the end of the slice, and --start skips into it
+            duration: slice.and_then(|slice| slice.end),
+            level: args.generate_level,
+        }),
+        midi: args.soundfont.clone().map(|soundfont| MidiOptions {
+            soundfont,
+            rate: format.rate,
+        }),
+        span: slice,
+        strict: args.strict,
+    })
+}
+
+/// The decoder with its playlist and processing chain, set up from the
+/// command line, and where `--resume` picks up if its file is still in the
+/// playlist.
+fn build_pipeline(
+    args: &Args,
+    format: &StreamFormat,
+    entries: &[Entry],
+    entry_spec: &InputSpec,
+    stdin: bool,
+) -> Result<(Rc<RefCell<AudioDecoder>>, Option<ResumePoint>)> {
+    let mut playlist = Playlist::new(entries.to_vec());
+    if args.shuffle {
+        let seed = args.shuffle_seed.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_nanos() as u64)
+        });
+        info!("Shuffling the playlist (--shuffle-seed {})", seed);
+        playlist.shuffle(seed);
+    }
+    let resume_point = args.resume.then(|| ResumePoint::load(&args.name)).flatten();
+    let resume_point = resume_point.and_then(|point| match point.find(&playlist) {
+        Some(track) => {
+            playlist.select(track);
+            Some(point)
+        }
+        None => {
+            warn!(
+                "{:?} is no longer in the playlist; starting from the beginning",
+                point.file
+            );
+            None
+        }
+    });
+    let looping = LoopOptions::from_args(args, stdin);
+    let decoder = Rc::new(RefCell::new(AudioDecoder::new(
+        playlist,
+        entry_spec.clone(),
+        looping,
+        args.crossfade.unwrap_or_default(),
+        ProcessingOptions::from_args(args, format)?,
+    )?));
+    decoder.borrow_mut().hooks = TrackHooks::new(
+        &args.name,
+        args.on_track_start.clone(),
+        args.on_track_end.clone(),
+    );
+    Ok((decoder, resume_point))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    /// What a `pactl` run that exited with `code` returns.
+    fn exited(code: i32) -> std::io::Result<Output> {
+        Ok(Output {
+            status: std::process::ExitStatus::from_raw(code << 8),
+            stdout: code.to_string().into_bytes(),
+            stderr: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn pactl_retries_a_failure_until_it_succeeds() {
+        let pactl = Pactl::new(3, Duration::from_millis(1));
+        let mut runs = 0;
+        let output = pactl
+            .retry(&["info"], |_| {
+                runs += 1;
+                exited(if runs == 1 { 1 } else { 0 })
+            })
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(runs, 2);
+    }
+
+    #[test]
+    fn pactl_gives_up_with_the_last_failure() {
+        let pactl = Pactl::new(2, Duration::ZERO);
+        let mut runs = 0;
+        let output = pactl
+            .retry(&["info"], |_| {
+                runs += 1;
+                exited(runs)
+            })
+            .unwrap();
+        assert_eq!(runs, 3);
+        assert_eq!(output.stdout, b"3");
+    }
+
+    #[test]
+    fn pactl_retry_delay_doubles_up_to_a_cap() {
+        assert_eq!(
+            next_retry_delay(Duration::from_millis(250)),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            next_retry_delay(Duration::from_secs(4)),
+            PACTL_MAX_RETRY_DELAY
+        );
+        assert_eq!(next_retry_delay(Duration::MAX), PACTL_MAX_RETRY_DELAY);
+    }
+
+    /// A file under `tests/fixtures`.
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// How the fixtures are opened: as they are, with nothing overridden.
+    fn plain_spec(path: PathBuf) -> InputSpec {
+        InputSpec {
+            path,
+            format_hint: None,
+            raw: None,
+            raw_files: None,
+            track: None,
+            exec: None,
+            ytdlp: None,
+            tts: None,
+            generate: None,
+            midi: None,
+            span: None,
+            strict: true,
+        }
+    }
+
+    /// Processing that leaves the audio alone, at `rate` and in stereo.
+    fn unprocessed(rate: u32) -> ProcessingOptions {
+        ProcessingOptions {
+            volume: 1.0,
+            speed: 1.0,
+            tempo: 1.0,
+            fade_in: Duration::ZERO,
+            fade_out: Duration::ZERO,
+            interject_duck: 0.0,
+            duck_attack: Duration::ZERO,
+            duck_release: Duration::ZERO,
+            replay_gain: None,
+            normalize: None,
+            sample_rate: rate,
+            channels: 2,
+            channel_gains: vec![1.0, 1.0],
+            dc_block: false,
+            silence_threshold: None,
+            skip_silence: None,
+            max_volume: 1.0,
+            auto_gain: false,
+            agc: None,
+            chain_order: dsp::DEFAULT_ORDER.to_vec(),
+        }
+    }
+
+    /// A decoder playing `entries`, opened as `entry_spec` says, through
+    /// once at `rate`.
+    fn decoder_for(entries: Vec<Entry>, entry_spec: InputSpec, rate: u32) -> AudioDecoder {
+        let looping = LoopOptions {
+            passes: Some(1),
+            one: false,
+            stop: false,
+            gap: Duration::ZERO,
+            crossfade: Duration::ZERO,
+        };
+        let mut decoder = AudioDecoder::new(
+            Playlist::new(entries),
+            entry_spec,
+            looping,
+            Duration::ZERO,
+            unprocessed(rate),
+        )
+        .unwrap();
+        decoder.open().unwrap();
+        decoder
+    }
+
+    /// A decoder playing the fixtures `names` through once, at `rate`.
+    fn decoder(names: &[&str], rate: u32) -> AudioDecoder {
+        let entries = names.iter().map(|name| fixture(name).into()).collect();
+        decoder_for(entries, plain_spec(PathBuf::new()), rate)
+    }
+
+    /// An endless sine at `frequency`, generated at 48 kHz.
+    fn sine(frequency: f64) -> InputSpec {
+        InputSpec {
+            generate: Some(GenerateOptions {
+                signal: Signal::Sine(frequency),
+                rate: 48_000,
+                duration: None,
+                level: None,
+            }),
+            ..plain_spec(PathBuf::from(format!("sine {}", frequency)))
+        }
+    }
+
+    #[test]
+    fn progress_reaches_the_end_of_the_playlist() {
+        let mut dec = decoder(&["tone-44100.wav", "tone-48000.wav"], 48_000);
+        let mut output = vec![0.0; 2 * 48];
+        let mut halfway: f64 = 0.0;
+        while dec.ended_frames.is_none() {
+            let progress = dec.progress();
+            if progress.track == 1 {
+                halfway = halfway.max(progress.playlist_percent().unwrap());
+            }
+            dec.fill_buffer(&mut output).unwrap();
+        }
+
+        let progress = dec.progress();
+        assert_eq!((progress.track, progress.tracks), (2, 2));
+        assert_eq!(progress.duration, Some(Duration::from_millis(250)));
+        assert!(progress.percent().unwrap() > 99.0, "{}", progress);
+        assert!(progress.playlist_percent().unwrap() > 99.0, "{}", progress);
+        assert!((49.0..=50.0).contains(&halfway), "{}", halfway);
+    }
+
+    /// Parse a command line, as given after the program name.
+    fn args(line: &str) -> Args {
+        Args::try_parse_from(std::iter::once("virtual-mic").chain(line.split_whitespace())).unwrap()
+    }
+
+    #[test]
+    fn stream_props_carry_the_media_role_and_category() {
+        let args = args("-f a.wav --name desk --media-role Communication --media-category Duplex");
+        let props = stream_props(&args, "desk_sink", 48_000);
+        let get = |key| {
+            props
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, v)| v.as_str())
+        };
+
+        assert_eq!(get("media.type"), Some("Audio"));
+        assert_eq!(get("media.role"), Some("Communication"));
+        assert_eq!(get("media.category"), Some("Duplex"));
+        assert_eq!(get("node.name"), Some("desk_player"));
+        assert_eq!(get("node.description"), Some("desk Audio Player"));
+        assert_eq!(get("node.target"), Some("desk_sink"));
+        assert_eq!(get("node.latency"), None);
+    }
+
+    #[test]
+    fn stream_props_default_to_music_playback() {
+        let props = stream_props(&args("-f a.wav"), "sink", 48_000);
+        assert!(props.contains(&("media.role", "Music".to_string())));
+        assert!(props.contains(&("media.category", "Playback".to_string())));
+    }
+
+    #[test]
+    fn presets_set_the_sink_format() {
+        let sink_args = |line| StreamFormat::from_args(&args(line)).sink_args();
+        assert_eq!(
+            sink_args("-f a.wav"),
+            ["rate=48000", "channels=1", "format=float32le"]
+        );
+        assert_eq!(
+            sink_args("-f a.wav --preset webrtc"),
+            ["rate=48000", "channels=1", "format=float32le"]
+        );
+        assert_eq!(
+            sink_args("-f a.wav --preset music"),
+            ["rate=48000", "channels=2", "format=float32le"]
+        );
+        assert_eq!(
+            sink_args("-f a.wav --preset telephony"),
+            ["rate=8000", "channels=1", "format=s16le"]
+        );
+    }
+
+    #[test]
+    fn explicit_format_flags_override_the_preset() {
+        let format = StreamFormat::from_args(&args(
+            "-f a.wav --preset telephony --rate 16000 --sample-format f32",
+        ));
+        assert_eq!(
+            format.sink_args(),
+            ["rate=16000", "channels=1", "format=float32le"]
+        );
+    }
+
+    #[test]
+    fn source_names_survive_blank_lines_and_stray_whitespace() {
+        let stdout = [
+            "52\talsa_input.usb-mic\tPipeWire\ts16le 2ch 48000Hz\tSUSPENDED",
+            "",
+            "53\tdesk \tPipeWire\tfloat32le 1ch 48000Hz\tRUNNING  \r",
+            "   ",
+            "54\t\tPipeWire\ts16le 1ch 8000Hz\tIDLE",
+            "55",
+            "56\tdesk.monitor\tPipeWire\tfloat32le 1ch 48000Hz\tIDLE\n",
+        ]
+        .join("\n");
+        assert_eq!(
+            parse_source_names(stdout.as_bytes()),
+            ["alsa_input.usb-mic", "desk", "desk.monitor"]
+        );
+        assert!(parse_source_names(b"").is_empty());
+    }
+
+    /// Run `frames` of mono through the chain `options` build, at volume 2,
+    /// returning the output and the number of samples clipped.
+    fn run_chain(options: &ProcessingOptions, mut frames: Vec<f32>) -> (Vec<f32>, u64) {
+        let unity = || Rc::new(Cell::new(1.0));
+        let stats = Rc::new(Cell::new(ClipStats::default()));
+        let mut chain = options.build_chain(
+            Rc::new(Cell::new(2.0)),
+            unity(),
+            unity(),
+            Rc::new(Cell::new(None)),
+            stats.clone(),
+        );
+        chain.process(&mut frames, 1);
+        (frames, stats.get().clipped)
+    }
+
+    #[test]
+    fn assembled_chain_trims_then_gains_then_limits() {
+        let options = ProcessingOptions {
+            channels: 1,
+            channel_gains: vec![1.0],
+            silence_threshold: Some(-40.0),
+            max_volume: 0.5,
+            ..unprocessed(48_000)
+        };
+        let input = vec![0.0, 0.001, 0.1, 0.4];
+        assert_eq!(run_chain(&options, input.clone()), (vec![0.2, 0.5], 1));
+
+        // Limiting first lets the gain push past the ceiling
+        let options = ProcessingOptions {
+            chain_order: dsp::resolve_order(&[StageKind::Limit]).unwrap(),
+            ..options
+        };
+        assert_eq!(run_chain(&options, input), (vec![0.2, 0.8], 0));
+    }
+
+    #[test]
+    fn quantum_sets_the_latency_props() {
+        let props = stream_props(
+            &args("-f a.wav --quantum 256 --rate-quantum --force-quantum"),
+            "sink",
+            48_000,
+        );
+        assert_eq!(
+            props[props.len() - 3..],
+            [
+                ("node.latency", "256/48000".to_string()),
+                ("node.rate", "1/48000".to_string()),
+                ("node.force-quantum", "256".to_string()),
+            ]
+        );
+
+        // Only a request, and a sane one
+        assert_eq!(
+            latency_props(4, 44_100, false, false),
+            [("node.latency", "32/44100".to_string())]
+        );
+        assert_eq!(
+            latency_props(100_000, 48_000, false, true),
+            [
+                ("node.latency", "8192/48000".to_string()),
+                ("node.force-quantum", "8192".to_string()),
+            ]
+        );
+    }
+
+    /// Sign changes in the left channel of stereo `frames`.
+    fn zero_crossings(frames: &[f32]) -> usize {
+        let left: Vec<f32> = frames.iter().step_by(2).copied().collect();
+        left.windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count()
+    }
+
+    #[test]
+    fn alternating_rates_play_at_pitch_without_gaps() {
+        let mut dec = decoder(&["tone-44100.wav", "tone-48000.wav"], 48_000);
+        dec.looping.passes = Some(2);
+        let mut played = Vec::new();
+        let mut rates = Vec::new();
+        let mut output = vec![0.0; 2 * 480];
+        while dec.ended_frames.is_none() {
+            dec.fill_buffer(&mut output).unwrap();
+            played.extend_from_slice(&output);
+            let rate = dec.resampler_input_rate;
+            if rates.last() != Some(&rate) {
+                rates.push(rate);
+            }
+        }
+        // The last buffer is padded out with silence
+        while played.last() == Some(&0.0) {
+            played.pop();
+        }
+
+        // The resampler followed each file's rate
+        assert_eq!(rates, [44_100, 48_000, 44_100, 48_000]);
+        // Four quarter seconds of 440 Hz, each as long at 48 kHz as it was
+        // originally and with no silence between them
+        let frames = played.len() / 2;
+        assert!(frames.abs_diff(48_000) <= 4, "{} frames", frames);
+        for (i, quarter) in played.chunks(2 * 12_000).enumerate() {
+            let crossings = zero_crossings(quarter);
+            assert!(
+                crossings.abs_diff(220) <= 3,
+                "{} crossings in file {}",
+                crossings,
+                i + 1
+            );
+        }
+        let longest_gap = played
+            .iter()
+            .step_by(2)
+            .fold((0, 0), |(run, longest), sample| {
+                let run = if sample.abs() < 1e-3 { run + 1 } else { 0 };
+                (run, longest.max(run))
+            })
+            .1;
+        assert!(longest_gap < 4, "{} silent frames in a row", longest_gap);
+    }
+
+    #[test]
+    fn load_crossfades_into_the_new_input() {
+        let mut dec = decoder_for(vec![PathBuf::from("sine").into()], sine(440.0), 48_000);
+        let mut before = vec![0.0; 2 * 4800];
+        dec.fill_buffer(&mut before).unwrap();
+        assert!(zero_crossings(&before).abs_diff(88) <= 1);
+
+        // 20 ms of crossfade, once the loader thread has opened the new input
+        dec.start_loading(sine(1000.0), None, Duration::from_millis(20))
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let loaded = loop {
+            if let Some(loaded) = dec.finish_loading() {
+                break loaded;
+            }
+            assert!(Instant::now() < deadline, "the load never finished");
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        loaded.unwrap();
+        assert_eq!(dec.path(), Path::new("sine 1000"));
+
+        let mut fading = vec![0.0; 2 * 960];
+        dec.fill_buffer(&mut fading).unwrap();
+        let mut after = vec![0.0; 2 * 4800];
+        dec.fill_buffer(&mut after).unwrap();
+
+        // The old input carries on where it was, then only the new one plays
+        let step = (fading[0] - before[before.len() - 2]).abs();
+        assert!(step < 0.1, "jumped by {}", step);
+        assert!(zero_crossings(&after).abs_diff(200) <= 1);
+        // and the new one has all but taken over by the end of the crossfade
+        let amplitude = before.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        let new = amplitude * (2.0 * std::f32::consts::PI * 1000.0 * 959.0 / 48_000.0).sin();
+        assert!((fading[2 * 959] - new).abs() < 0.01);
+    }
+
+    /// Wait for the clip `interject` is opening.
+    fn finish_interjecting(dec: &mut AudioDecoder) -> Result<()> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(result) = dec.finish_interjecting() {
+                return result;
+            }
+            assert!(Instant::now() < deadline, "the clip never opened");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn interject_opens_the_clip_in_the_background() {
+        let mut dec = decoder(&["tone-44100.wav"], 48_000);
+        dec.interject(fixture("tone-48000.wav")).unwrap();
+        assert!(dec.interjection.is_none());
+        finish_interjecting(&mut dec).unwrap();
+        assert_eq!(
+            dec.interjection.as_ref().map(|clip| clip.path.clone()),
+            Some(fixture("tone-48000.wav"))
+        );
+
+        // A clip that won't open leaves the one playing alone
+        dec.interject(fixture("missing.wav")).unwrap();
+        assert!(finish_interjecting(&mut dec).is_err());
+        assert!(dec.interjection.is_some());
+    }
+
+    #[test]
+    fn interject_measures_the_clip_before_it_starts() {
+        let mut dec = decoder(&["tone-44100.wav"], 48_000);
+        dec.loudness = Some(Loudness::start(2).unwrap());
+        // A second long, as loudness is measured in 400 ms blocks
+        dec.interject(fixture("tone-8000.wav")).unwrap();
+        finish_interjecting(&mut dec).unwrap();
+        assert!(dec.clip_loudness.get().is_some());
+    }
+
+    #[test]
+    fn timeline_cues_start_once_their_file_is_open() {
+        let entries = ["tone-44100.wav", "missing.wav", "tone-48000.wav"]
+            .iter()
+            .map(|name| fixture(name).into())
+            .collect();
+        let looping = LoopOptions {
+            passes: Some(1),
+            one: false,
+            stop: true,
+            gap: Duration::ZERO,
+            crossfade: Duration::ZERO,
+        };
+        let entry_spec = InputSpec {
+            strict: false,
+            ..plain_spec(PathBuf::new())
+        };
+        let mut dec = AudioDecoder::new(
+            Playlist::new(entries),
+            entry_spec,
+            looping,
+            Duration::ZERO,
+            unprocessed(48_000),
+        )
+        .unwrap();
+        let cues = [0, 50, 100].map(Duration::from_millis);
+        dec.start_timeline(cues.to_vec());
+
+        // Buffers keep coming while each file opens; the missing one is skipped
+        let mut output = vec![0.0; 2 * 480];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while dec.timeline.as_ref().unwrap().next < cues.len() {
+            assert!(Instant::now() < deadline, "the cues never started");
+            dec.fill_buffer(&mut output).unwrap();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(dec.progress().track, 3);
+        assert!(dec.timeline.as_ref().unwrap().preload.is_none());
+    }
+
+    #[cfg(not(feature = "tui"))]
+    #[test]
+    fn tui_without_the_feature_fails_before_the_device() {
+        // Failing any later would need pactl, and leave a device behind
+        let error = run(args("-f missing.wav --tui")).unwrap_err();
+        assert!(error.to_string().contains("tui feature"), "{}", error);
+    }
+
+    #[cfg(not(feature = "dbus"))]
+    #[test]
+    fn dbus_without_the_feature_fails_before_the_device() {
+        let error = run(args("-f missing.wav --dbus")).unwrap_err();
+        assert!(error.to_string().contains("dbus feature"), "{}", error);
+    }
+
+    #[cfg(not(feature = "dbus"))]
+    #[test]
+    fn mpris_without_the_feature_fails_before_the_device() {
+        let error = run(args("-f missing.wav --mpris")).unwrap_err();
+        assert!(error.to_string().contains("--mpris"), "{}", error);
+    }
+
+    #[test]
+    fn record_rate_is_in_the_stream_rate_range() {
+        let parse = |line: &str| {
+            Args::try_parse_from(std::iter::once("virtual-mic").chain(line.split_whitespace()))
+        };
+        assert_eq!(
+            args("-f a.wav --record out.wav --record-rate 44100").record_rate,
+            Some(44_100)
+        );
+        assert!(parse("-f a.wav --record out.wav --record-rate 0").is_err());
+        assert!(parse("-f a.wav --record out.wav --record-rate 384000").is_err());
+    }
+
+    #[test]
+    fn repl_conflicts_with_stdin_as_the_input() {
+        let parse = |line: &str| {
+            Args::try_parse_from(std::iter::once("virtual-mic").chain(line.split_whitespace()))
+        };
+        assert!(parse("--say-stdin").is_ok());
+        assert!(parse("--repl --say-stdin").is_err());
+        assert!(
+            parse("--repl --stdin-pcm --raw-rate 48000 --raw-channels 1 --raw-format s16le")
+                .is_err()
+        );
+        // A file named - is only known once the playlist is read
+        let error = run(args("-f - --repl")).unwrap_err();
+        assert!(error.to_string().contains("--repl"), "{}", error);
+    }
 }
@@ -0,0 +1,123 @@
+//! MIDI file playback, rendered through a SoundFont with rustysynth.
+//!
+//! `.mid` files play like any other audio file once `--soundfont` names an
+//! `.sf2` bank. They are synthesized in stereo at the output rate as playback
+//! goes, so nothing is rendered ahead. The SoundFont is loaded once and shared
+//! by every MIDI file in the playlist.
+
+use anyhow::{anyhow, Result};
+use rustysynth::{MidiFile, MidiFileSequencer, SoundFont, Synthesizer, SynthesizerSettings};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+/// Extensions of Standard MIDI Files.
+const MIDI_EXTENSIONS: &[&str] = &["mid", "midi", "smf"];
+/// Rates rustysynth can render at; others are rendered at the nearest and resampled.
+const MIN_RATE: u32 = 16000;
+const MAX_RATE: u32 = 192000;
+/// Rendered after the last event so released notes can ring out.
+const RELEASE_TAIL: Duration = Duration::from_secs(2);
+/// Frames rendered per chunk.
+const CHUNK_FRAMES: usize = 1024;
+
+/// The SoundFont most recently loaded, and where it came from.
+static SOUNDFONT: Mutex<Option<(PathBuf, Arc<SoundFont>)>> = Mutex::new(None);
+
+/// How to render MIDI files, from the command line.
+#[derive(Debug, Clone)]
+pub struct MidiOptions {
+    pub soundfont: PathBuf,
+    pub rate: u32,
+}
+
+pub fn is_midi_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| MIDI_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+fn load_soundfont(path: &Path) -> Result<Arc<SoundFont>> {
+    let mut cached = SOUNDFONT.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((cached_path, soundfont)) = cached.as_ref() {
+        if cached_path == path {
+            return Ok(soundfont.clone());
+        }
+    }
+    let mut reader = BufReader::new(
+        File::open(path).map_err(|e| anyhow!("Failed to open SoundFont {:?}: {}", path, e))?,
+    );
+    let soundfont = Arc::new(
+        SoundFont::new(&mut reader).map_err(|e| anyhow!("Invalid SoundFont {:?}: {}", path, e))?,
+    );
+    info!("Loaded SoundFont {:?}", path);
+    *cached = Some((path.to_path_buf(), soundfont.clone()));
+    Ok(soundfont)
+}
+
+/// A MIDI file being rendered, yielding interleaved stereo chunks.
+pub struct MidiRenderer {
+    sequencer: MidiFileSequencer,
+    pub rate: u32,
+    /// Length of the song itself, without the release tail.
+    pub duration: Duration,
+    /// Frames left to render.
+    remaining: u64,
+    left: Vec<f32>,
+    right: Vec<f32>,
+}
+
+impl MidiRenderer {
+    pub fn open(path: &Path, options: &MidiOptions) -> Result<Self> {
+        let soundfont = load_soundfont(&options.soundfont)?;
+        let mut reader = BufReader::new(File::open(path)?);
+        let midi = Arc::new(
+            MidiFile::new(&mut reader)
+                .map_err(|e| anyhow!("Invalid MIDI file {:?}: {}", path, e))?,
+        );
+
+        let rate = options.rate.clamp(MIN_RATE, MAX_RATE);
+        let settings = SynthesizerSettings::new(rate as i32);
+        let synthesizer = Synthesizer::new(&soundfont, &settings)
+            .map_err(|e| anyhow!("Failed to set up the synthesizer: {}", e))?;
+        let mut sequencer = MidiFileSequencer::new(synthesizer);
+        sequencer.play(&midi, false);
+
+        let duration = Duration::from_secs_f64(midi.get_length());
+        let remaining = ((duration + RELEASE_TAIL).as_secs_f64() * rate as f64) as u64;
+        info!(
+            "MIDI: {:.1}s, rendered at {} Hz",
+            duration.as_secs_f64(),
+            rate
+        );
+        Ok(Self {
+            sequencer,
+            rate,
+            duration,
+            remaining,
+            left: vec![0.0; CHUNK_FRAMES],
+            right: vec![0.0; CHUNK_FRAMES],
+        })
+    }
+
+    /// The next chunk of interleaved stereo samples, or `None` once the song
+    /// and its release tail have been rendered.
+    pub fn next_chunk(&mut self) -> Option<Vec<f32>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let frames = self.remaining.min(CHUNK_FRAMES as u64) as usize;
+        self.remaining -= frames as u64;
+        let (left, right) = (&mut self.left[..frames], &mut self.right[..frames]);
+        self.sequencer.render(left, right);
+        Some(
+            left.iter()
+                .zip(right.iter())
+                .flat_map(|(l, r)| [*l, *r])
+                .collect(),
+        )
+    }
+}
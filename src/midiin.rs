@@ -0,0 +1,139 @@
+//! MIDI controllers, read through the ALSA sequencer with midir, so other
+//! programs can listen to the same port.
+
+use anyhow::{anyhow, Result};
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use std::sync::mpsc;
+
+/// Name of the sequencer client virtual-mic shows up as.
+const CLIENT: &str = "virtual-mic";
+
+/// A channel message, with its channel dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    /// A note pressed (with a velocity above 0) or released.
+    Note { note: u8, on: bool },
+    /// A controller (knob, fader, pedal) moved to `value`.
+    Controller { controller: u8, value: u8 },
+}
+
+impl Message {
+    /// Read one whole message as midir delivers it; anything but notes and
+    /// controllers is `None`.
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        match *bytes {
+            [status, note, velocity] if status & 0xF0 == 0x90 => Some(Message::Note {
+                note,
+                on: velocity > 0,
+            }),
+            [status, note, _] if status & 0xF0 == 0x80 => Some(Message::Note { note, on: false }),
+            [status, controller, value] if status & 0xF0 == 0xB0 => {
+                Some(Message::Controller { controller, value })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Read the messages of the first input port whose name contains `port`, or
+/// of the first port there is, on a background thread, passing them to
+/// `on_message` until it returns `false`. Returns the port's name.
+pub fn watch(
+    port: Option<&str>,
+    mut on_message: impl FnMut(Message) -> bool + Send + 'static,
+) -> Result<String> {
+    let port = port.map(str::to_string);
+    let (connected_tx, connected) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("midi-in".into())
+        .spawn(move || {
+            let (stop, stopped) = mpsc::channel();
+            let connection = connect(port.as_deref(), move |message| {
+                if !on_message(message) {
+                    let _ = stop.send(());
+                }
+            });
+            match connection {
+                Ok((name, connection)) => {
+                    let _ = connected_tx.send(Ok(name));
+                    // Closing the connection ends the callbacks
+                    let _ = stopped.recv();
+                    connection.close();
+                }
+                Err(e) => {
+                    let _ = connected_tx.send(Err(e));
+                }
+            }
+        })?;
+    connected
+        .recv()
+        .map_err(|_| anyhow!("The MIDI thread stopped"))?
+}
+
+fn connect(
+    port: Option<&str>,
+    mut on_message: impl FnMut(Message) + Send + 'static,
+) -> Result<(String, MidiInputConnection<()>)> {
+    let mut input =
+        MidiInput::new(CLIENT).map_err(|e| anyhow!("Failed to open the ALSA sequencer: {}", e))?;
+    input.ignore(Ignore::All);
+    let ports = input.ports();
+    let (name, found) = ports
+        .iter()
+        .filter_map(|found| Some((input.port_name(found).ok()?, found)))
+        .find(|(name, _)| port.is_none_or(|port| name.contains(port)))
+        .ok_or_else(|| match port {
+            Some(port) => anyhow!("No MIDI input port matches {:?}", port),
+            None => anyhow!("No MIDI input found"),
+        })?;
+    let connection = input
+        .connect(
+            found,
+            "input",
+            move |_, bytes, _| {
+                if let Some(message) = Message::parse(bytes) {
+                    on_message(message);
+                }
+            },
+            (),
+        )
+        .map_err(|e| anyhow!("Failed to connect to MIDI port {:?}: {}", name, e))?;
+    Ok((name, connection))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_notes_and_controllers_on_any_channel() {
+        assert_eq!(
+            Message::parse(&[0x93, 36, 100]),
+            Some(Message::Note { note: 36, on: true })
+        );
+        // Note on at velocity 0 is a release, as is note off
+        assert_eq!(
+            Message::parse(&[0x90, 36, 0]),
+            Some(Message::Note {
+                note: 36,
+                on: false
+            })
+        );
+        assert_eq!(
+            Message::parse(&[0x8F, 36, 64]),
+            Some(Message::Note {
+                note: 36,
+                on: false
+            })
+        );
+        assert_eq!(
+            Message::parse(&[0xB0, 7, 127]),
+            Some(Message::Controller {
+                controller: 7,
+                value: 127
+            })
+        );
+        assert_eq!(Message::parse(&[0xC0, 5]), None);
+        assert_eq!(Message::parse(&[0xE0, 0, 64]), None);
+    }
+}
@@ -0,0 +1,230 @@
+//! `--midi-map`: a MIDI controller, such as a pad controller, playing clips
+//! and working the mic through a TOML mapping file.
+//!
+//! ```toml
+//! # Part of a MIDI input port's name; the first port if left out
+//! port = "nanoPAD2"
+//!
+//! [notes]
+//! 36 = "interject /srv/audio/airhorn.wav"
+//! 37 = "pad 2"
+//! 38 = "toggle-mute"
+//!
+//! [controllers]
+//! 7 = "volume"
+//! 64 = "next"
+//! ```
+//!
+//! Notes send their control command when pressed. A controller mapped to
+//! `volume` sets the volume from its position; any other controller sends its
+//! command each time it goes from under 64 to 64 or over, like a button.
+
+use crate::control::{ControlCommand, VolumeChange};
+use crate::midiin::{self, Message};
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use tracing::{debug, info};
+
+/// The mapping file as written.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MapFile {
+    port: Option<String>,
+    #[serde(default)]
+    notes: BTreeMap<String, String>,
+    #[serde(default)]
+    controllers: BTreeMap<String, String>,
+}
+
+/// What a controller does.
+#[derive(Debug, Clone)]
+enum ControllerAction {
+    /// Set the volume, 0 to 127 giving 0.0 to 1.0.
+    Volume,
+    /// Send a command when pushed past the middle.
+    Command(ControlCommand),
+}
+
+/// A loaded mapping file.
+#[derive(Debug, Clone)]
+pub struct MidiMap {
+    port: Option<String>,
+    notes: Vec<(u8, ControlCommand)>,
+    controllers: Vec<(u8, ControllerAction)>,
+}
+
+impl MidiMap {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read MIDI map {:?}: {}", path, e))?;
+        let file: MapFile =
+            toml::from_str(&contents).map_err(|e| anyhow!("Invalid MIDI map {:?}: {}", path, e))?;
+        let invalid = |what: &str, number: &str, e: String| {
+            anyhow!("Invalid MIDI map {:?}: {} {}: {}", path, what, number, e)
+        };
+
+        let mut notes = Vec::new();
+        for (note, command) in &file.notes {
+            let number = midi_number(note).map_err(|e| invalid("note", note, e))?;
+            let command = ControlCommand::parse(command).map_err(|e| invalid("note", note, e))?;
+            notes.push((number, command));
+        }
+        let mut controllers = Vec::new();
+        for (controller, command) in &file.controllers {
+            let number =
+                midi_number(controller).map_err(|e| invalid("controller", controller, e))?;
+            let action = match command.trim() {
+                "volume" => ControllerAction::Volume,
+                command => ControllerAction::Command(
+                    ControlCommand::parse(command)
+                        .map_err(|e| invalid("controller", controller, e))?,
+                ),
+            };
+            controllers.push((number, action));
+        }
+        if notes.is_empty() && controllers.is_empty() {
+            return Err(anyhow!("MIDI map {:?} maps no notes or controllers", path));
+        }
+        Ok(Self {
+            port: file.port,
+            notes,
+            controllers,
+        })
+    }
+}
+
+fn midi_number(s: &str) -> Result<u8, String> {
+    s.trim()
+        .parse()
+        .ok()
+        .filter(|&number: &u8| number < 128)
+        .ok_or_else(|| "expected a number from 0 to 127".to_string())
+}
+
+/// Read the controller on a background thread, sending the mapped commands.
+pub fn watch(map: &MidiMap) -> Result<Receiver<ControlCommand>> {
+    let (tx, rx) = mpsc::channel();
+    let (notes, controllers) = (map.notes.len(), map.controllers.len());
+    let port = map.port.clone();
+    let map = map.clone();
+    // Last value of each controller, to catch a button going down
+    let mut values = [0u8; 128];
+    let port = midiin::watch(port.as_deref(), move |message| {
+        let command = match message {
+            Message::Note { note, on: true } => map
+                .notes
+                .iter()
+                .find(|(mapped, _)| *mapped == note)
+                .map(|(_, command)| command.clone()),
+            Message::Note { on: false, .. } => None,
+            Message::Controller { controller, value } => {
+                let previous = std::mem::replace(&mut values[controller as usize], value);
+                match map
+                    .controllers
+                    .iter()
+                    .find(|(mapped, _)| *mapped == controller)
+                {
+                    Some((_, ControllerAction::Volume)) => Some(ControlCommand::Volume(
+                        VolumeChange::To(value as f32 / 127.0),
+                    )),
+                    Some((_, ControllerAction::Command(command)))
+                        if previous < 64 && value >= 64 =>
+                    {
+                        Some(command.clone())
+                    }
+                    _ => None,
+                }
+            }
+        };
+        match command {
+            Some(command) => {
+                debug!("MIDI {:?}: {:?}", message, command);
+                tx.send(command).is_ok()
+            }
+            None => true,
+        }
+    })?;
+    info!(
+        "MIDI control from {:?}: {} note(s), {} controller(s)",
+        port, notes, controllers
+    );
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Load a mapping file holding `contents`.
+    fn load(contents: &str) -> Result<MidiMap> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "virtual-mic-midimap-{}-{}.toml",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, contents).unwrap();
+        let map = MidiMap::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        map
+    }
+
+    #[test]
+    fn loads_notes_controllers_and_port() {
+        let map = load(
+            r#"
+port = "nanoPAD2"
+
+[notes]
+36 = "interject /srv/audio/airhorn.wav"
+37 = "pad 2"
+
+[controllers]
+7 = "volume"
+64 = " next "
+"#,
+        )
+        .unwrap();
+        assert_eq!(map.port.as_deref(), Some("nanoPAD2"));
+        assert_eq!(
+            map.notes,
+            [
+                (
+                    36,
+                    ControlCommand::Interject(PathBuf::from("/srv/audio/airhorn.wav"))
+                ),
+                (37, ControlCommand::Pad(2)),
+            ]
+        );
+        // Numbered keys come in string order
+        assert!(matches!(
+            map.controllers.as_slice(),
+            [
+                (64, ControllerAction::Command(ControlCommand::Next)),
+                (7, ControllerAction::Volume)
+            ]
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_numbers_commands_and_keys() {
+        for (contents, expected) in [
+            ("[notes]\n128 = \"next\"", "note 128: expected a number"),
+            ("[notes]\nC4 = \"next\"", "note C4: expected a number"),
+            ("[controllers]\n7 = \"louder\"", "controller 7:"),
+            (
+                "[notes]\n36 = \"next\"\n[pads]\n1 = \"next\"",
+                "unknown field",
+            ),
+            ("port = \"nanoPAD2\"", "maps no notes or controllers"),
+        ] {
+            let error = load(contents).unwrap_err().to_string();
+            assert!(error.contains(expected), "{}: {}", contents, error);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! MP4/M4A from unseekable inputs.
+//!
+//! Symphonia reads AAC and ALAC out of MP4 (including iPhone voice memos), but
+//! it needs the `moov` index before it can play anything. Recorders that write
+//! as they go, Apple's among them, put `moov` after the audio, which the
+//! demuxer can only reach by seeking. So when stdin or a command's output turns
+//! out to be such a file, it is read into memory first; MP4s with the index up
+//! front, such as fragmented streams, still play as they arrive.
+
+use std::io::{self, Cursor, Read};
+use symphonia::core::io::{MediaSource, ReadOnlySource};
+use tracing::info;
+
+/// Most leading bytes read while looking for `moov` or `mdat`.
+const MAX_HEAD: usize = 1 << 20;
+
+/// An unseekable input as a media source, buffered whole if it is an MP4 with
+/// its index at the end.
+pub fn media_source<R: Read + Send + Sync + 'static>(
+    mut reader: R,
+) -> io::Result<Box<dyn MediaSource>> {
+    let mut head = Vec::new();
+    let index_at_end = index_at_end(&mut reader, &mut head)?;
+    let mut reader = Cursor::new(head).chain(reader);
+    if !index_at_end {
+        return Ok(Box::new(ReadOnlySource::new(reader)));
+    }
+    info!("MP4 index is at the end; reading the whole input first");
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data)?;
+    Ok(Box::new(Cursor::new(data)))
+}
+
+/// Read top-level atoms into `head` up to the first `moov` or `mdat`, and
+/// report whether `mdat` came first. Anything that isn't MP4 stops at once.
+fn index_at_end(reader: &mut impl Read, head: &mut Vec<u8>) -> io::Result<bool> {
+    if !fill(reader, head, 8)? || &head[4..8] != b"ftyp" {
+        return Ok(false);
+    }
+    let mut pos = 0;
+    loop {
+        if !fill(reader, head, pos + 8)? {
+            return Ok(false);
+        }
+        match &head[pos + 4..pos + 8] {
+            b"moov" => return Ok(false),
+            b"mdat" => return Ok(true),
+            _ => {}
+        }
+        let size = match u32::from_be_bytes(head[pos..pos + 4].try_into().unwrap()) {
+            // 64-bit size after the type
+            1 => {
+                if !fill(reader, head, pos + 16)? {
+                    return Ok(false);
+                }
+                u64::from_be_bytes(head[pos + 8..pos + 16].try_into().unwrap())
+            }
+            size => size as u64,
+        };
+        // Size 0 runs to the end of the file
+        if size < 8 || pos as u64 + size > MAX_HEAD as u64 {
+            return Ok(false);
+        }
+        pos += size as usize;
+    }
+}
+
+/// Read until `head` holds `len` bytes, returning false if the input ends first.
+fn fill(reader: &mut impl Read, head: &mut Vec<u8>, len: usize) -> io::Result<bool> {
+    if head.len() < len {
+        reader
+            .by_ref()
+            .take((len - head.len()) as u64)
+            .read_to_end(head)?;
+    }
+    Ok(head.len() >= len)
+}
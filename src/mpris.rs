@@ -0,0 +1,359 @@
+//! `--mpris`: the MPRIS2 media player interface on the session bus, so
+//! desktop widgets, `playerctl` and media keys can pause, skip and seek the
+//! audio going into the mic.
+//!
+//! The player takes `org.mpris.MediaPlayer2.virtualmic.instance<pid>`, so
+//! several instances can run side by side, and answers at
+//! `/org/mpris/MediaPlayer2` with the `org.mpris.MediaPlayer2` and
+//! `org.mpris.MediaPlayer2.Player` interfaces. Calls are carried out by the
+//! main loop like control socket commands, through [`Commands`].
+
+use crate::control::{ControlCommand, Events, Request, SeekTarget, VolumeChange};
+use crate::dbus::Commands;
+use crate::http;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use zbus::blocking::object_server::InterfaceRef;
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{ObjectPath, OwnedValue, Value as Variant};
+use zbus::{fdo, interface};
+
+/// Path of the player object, fixed by the spec.
+const PATH: &str = "/org/mpris/MediaPlayer2";
+
+/// Prefix of the `mpris:trackid` of each playlist entry.
+const TRACK_ID_PREFIX: &str = "/org/virtualmic/Track/";
+
+/// `org.mpris.MediaPlayer2`: the application.
+struct Root {
+    commands: Commands,
+    identity: String,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    /// There is no window to raise.
+    async fn raise(&self) {}
+
+    async fn quit(&self) -> fdo::Result<()> {
+        self.commands.run(ControlCommand::Quit).await.map(drop)
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn can_quit(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn identity(&self) -> String {
+        self.identity.clone()
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["file".to_string(), "http".to_string(), "https".to_string()]
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// `org.mpris.MediaPlayer2.Player`: playback.
+struct Player {
+    commands: Commands,
+}
+
+impl Player {
+    async fn run(&self, command: ControlCommand) -> fdo::Result<()> {
+        self.commands.run(command).await.map(drop)
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Player {
+    async fn next(&self) -> fdo::Result<()> {
+        self.run(ControlCommand::Next).await
+    }
+
+    async fn previous(&self) -> fdo::Result<()> {
+        self.run(ControlCommand::Previous).await
+    }
+
+    async fn pause(&self) -> fdo::Result<()> {
+        self.run(ControlCommand::Pause).await
+    }
+
+    async fn play_pause(&self) -> fdo::Result<()> {
+        self.run(ControlCommand::TogglePause).await
+    }
+
+    /// Pause and go back to the start of the file, where it can seek.
+    async fn stop(&self) -> fdo::Result<()> {
+        self.run(ControlCommand::Pause).await?;
+        let rewind = ControlCommand::Seek(SeekTarget::To(Duration::ZERO));
+        if let Err(e) = self.run(rewind).await {
+            debug!("Stopped without rewinding: {}", e);
+        }
+        Ok(())
+    }
+
+    async fn play(&self) -> fdo::Result<()> {
+        self.run(ControlCommand::Resume).await
+    }
+
+    /// Jump forward, or back if negative, by `offset` microseconds.
+    async fn seek(&self, offset: i64) -> fdo::Result<()> {
+        let by = Duration::from_micros(offset.unsigned_abs());
+        let target = match offset < 0 {
+            true => SeekTarget::Back(by),
+            false => SeekTarget::Forward(by),
+        };
+        self.run(ControlCommand::Seek(target)).await
+    }
+
+    /// Jump to `position` microseconds into `track_id`, if it still plays.
+    async fn set_position(&self, track_id: ObjectPath<'_>, position: i64) -> fdo::Result<()> {
+        if track_id.as_str() != track_id_of(self.commands.number("track", 0.0).await? as u64) {
+            debug!("Ignoring SetPosition for {}, which isn't playing", track_id);
+            return Ok(());
+        }
+        let Ok(position) = u64::try_from(position) else {
+            return Ok(());
+        };
+        let target = SeekTarget::To(Duration::from_micros(position));
+        self.run(ControlCommand::Seek(target)).await
+    }
+
+    /// Play a `file://`, `http://` or `https://` URI in place of the playing file.
+    async fn open_uri(&self, uri: String) -> fdo::Result<()> {
+        let path = match uri.strip_prefix("file://") {
+            Some(path) => PathBuf::from(http::percent_decode(path)),
+            None if uri.starts_with("http://") || uri.starts_with("https://") => PathBuf::from(uri),
+            None => return Err(fdo::Error::NotSupported(format!("Can't open {}", uri))),
+        };
+        self.run(ControlCommand::Load(path)).await
+    }
+
+    /// `Playing`, `Paused` or `Stopped`.
+    #[zbus(property)]
+    async fn playback_status(&self) -> fdo::Result<String> {
+        let status = match self.commands.text("state").await?.as_str() {
+            "playing" => "Playing",
+            "paused" => "Paused",
+            _ => "Stopped",
+        };
+        Ok(status.to_string())
+    }
+
+    /// Only the normal rate is offered; `--speed` and `--tempo` are set at startup.
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    async fn metadata(&self) -> fdo::Result<HashMap<String, OwnedValue>> {
+        let status = self.commands.status().await?;
+        let track = status.get("track").and_then(Value::as_u64).unwrap_or(0);
+        let mut metadata = HashMap::new();
+        let mut insert = |key: &str, value: Variant| -> fdo::Result<()> {
+            let value = value
+                .try_to_owned()
+                .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+            metadata.insert(key.to_string(), value);
+            Ok(())
+        };
+        let track_id = ObjectPath::try_from(track_id_of(track))
+            .map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        insert("mpris:trackid", track_id.into())?;
+        if let Some(duration) = status.get("duration_secs").and_then(Value::as_f64) {
+            insert("mpris:length", ((duration * 1e6).round() as i64).into())?;
+        }
+        if let Some(path) = status.get("path").and_then(Value::as_str) {
+            let title = Path::new(path)
+                .file_name()
+                .map_or(path.into(), |name| name.to_string_lossy());
+            insert("xesam:title", title.into_owned().into())?;
+            insert("xesam:url", url(path).into())?;
+        }
+        Ok(metadata)
+    }
+
+    #[zbus(property)]
+    async fn volume(&self) -> fdo::Result<f64> {
+        self.commands.number("volume", 1.0).await
+    }
+
+    #[zbus(property)]
+    async fn set_volume(&self, volume: f64) -> zbus::Result<()> {
+        // The spec treats negative volumes as 0
+        let volume = if volume.is_nan() {
+            1.0
+        } else {
+            volume.max(0.0)
+        };
+        let command = ControlCommand::Volume(VolumeChange::To(volume as f32));
+        Ok(self.run(command).await?)
+    }
+
+    /// Microseconds into the playing file.
+    #[zbus(property(emits_changed_signal = "false"))]
+    async fn position(&self) -> fdo::Result<i64> {
+        let position = self.commands.number("position_secs", 0.0).await?;
+        Ok((position * 1e6).round() as i64)
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn can_seek(&self) -> bool {
+        true
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    async fn can_control(&self) -> bool {
+        true
+    }
+
+    /// The position jumped, to this many microseconds in.
+    #[zbus(signal)]
+    async fn seeked(emitter: &SignalEmitter<'_>, position: i64) -> zbus::Result<()>;
+}
+
+/// The `mpris:trackid` of playlist entry `track`.
+fn track_id_of(track: u64) -> String {
+    format!("{}{}", TRACK_ID_PREFIX, track)
+}
+
+/// `path` as a URL: URLs as they are, and files as `file://` URLs.
+fn url(path: &str) -> String {
+    if path.contains("://") {
+        return path.to_string();
+    }
+    let path = std::env::current_dir()
+        .map(|dir| dir.join(path))
+        .unwrap_or_else(|_| PathBuf::from(path));
+    let mut url = "file://".to_string();
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => {
+                url.push(byte as char)
+            }
+            _ => url.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    url
+}
+
+/// Owns the player's bus name; gives it up when dropped.
+pub struct MprisService {
+    _connection: zbus::blocking::Connection,
+}
+
+impl MprisService {
+    /// Take the player's name on the session bus and serve it, passing calls
+    /// to `tx` and signalling `events`. `mic` is the microphone's `--name`.
+    pub fn start(mic: &str, events: Events, tx: Sender<Request>) -> Result<Self> {
+        let name = format!(
+            "org.mpris.MediaPlayer2.virtualmic.instance{}",
+            std::process::id()
+        );
+        let root = Root {
+            commands: Commands::new(tx.clone()),
+            identity: format!("Virtual Mic ({})", mic),
+        };
+        let player = Player {
+            commands: Commands::new(tx),
+        };
+        let connection = zbus::blocking::connection::Builder::session()
+            .and_then(|builder| builder.name(name.as_str()))
+            .and_then(|builder| builder.serve_at(PATH, root))
+            .and_then(|builder| builder.serve_at(PATH, player))
+            .and_then(|builder| builder.build())
+            .map_err(|e| anyhow!("Failed to register {} on the session bus: {}", name, e))?;
+        info!("MPRIS player {} is up", name);
+
+        let player = connection.object_server().interface::<_, Player>(PATH)?;
+        let events = events.subscribe();
+        std::thread::Builder::new()
+            .name("mpris-signals".into())
+            .spawn(move || {
+                for event in events {
+                    if let Err(e) = signal(&player, &event) {
+                        warn!("Failed to signal {:?} over MPRIS: {}", event, e);
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            _connection: connection,
+        })
+    }
+}
+
+/// Send the signals for an event line.
+fn signal(player: &InterfaceRef<Player>, event: &str) -> zbus::Result<()> {
+    let (name, rest) = event.split_once(' ').unwrap_or((event, ""));
+    let emitter = player.signal_emitter();
+    let player = player.get();
+    zbus::block_on(async {
+        match name {
+            "started" => {
+                player.metadata_changed(emitter).await?;
+                player.playback_status_changed(emitter).await
+            }
+            "ended" | "paused" | "resumed" => player.playback_status_changed(emitter).await,
+            "volume" => player.volume_changed(emitter).await,
+            "seeked" => match rest.parse::<f64>() {
+                Ok(position) => Player::seeked(emitter, (position * 1e6).round() as i64).await,
+                Err(_) => Ok(()),
+            },
+            _ => Ok(()),
+        }
+    })
+}
@@ -0,0 +1,353 @@
+//! Opus decoding for symphonia, backed by libopus.
+//!
+//! Symphonia's Ogg and Matroska/WebM demuxers recognise Opus tracks but it
+//! ships no decoder for them, so this plugs libopus into the codec registry.
+//!
+//! Streams go through libopus' multistream decoder, which covers mono, stereo
+//! and surround (channel mapping families 0, 1 and 255) alike. The `OpusHead`
+//! pre-skip is dropped from the start and its output gain applied, as the Ogg
+//! Opus spec asks of players.
+
+use audiopus::ffi;
+use std::ffi::c_int;
+use std::ptr::NonNull;
+use std::sync::Mutex;
+use symphonia::core::audio::{
+    AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec,
+};
+use symphonia::core::codecs::{
+    CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
+};
+use symphonia::core::errors::{decode_error, unsupported_error, Result};
+use symphonia::core::formats::Packet;
+use symphonia::core::support_codec;
+
+/// Opus always decodes at 48 kHz regardless of the original input rate.
+const OPUS_SAMPLE_RATE: u32 = 48000;
+
+/// The longest Opus packet is 120 ms, i.e. 5760 frames at 48 kHz.
+const MAX_FRAMES_PER_PACKET: usize = 5760;
+
+/// The parts of an `OpusHead` identification header the decoder needs.
+#[derive(Debug, Clone)]
+struct OpusHead {
+    channels: usize,
+    /// Frames at the start that are encoder warm-up, not audio.
+    pre_skip: usize,
+    /// Output gain in dB.
+    gain_db: f32,
+    family: u8,
+    streams: u8,
+    coupled: u8,
+    /// Decoded channel for each output channel.
+    mapping: Vec<u8>,
+}
+
+impl OpusHead {
+    fn parse(extra_data: &[u8]) -> Option<Self> {
+        if extra_data.len() < 19 || !extra_data.starts_with(b"OpusHead") {
+            return None;
+        }
+        let channels = extra_data[9] as usize;
+        let family = extra_data[18];
+        let (streams, coupled, mapping) = if family == 0 {
+            Self::family_zero(channels)?
+        } else {
+            let table = extra_data.get(21..21 + channels)?;
+            (extra_data[19], extra_data[20], table.to_vec())
+        };
+        Some(Self {
+            channels,
+            pre_skip: u16::from_le_bytes([extra_data[10], extra_data[11]]) as usize,
+            // Q7.8 fixed point
+            gain_db: i16::from_le_bytes([extra_data[16], extra_data[17]]) as f32 / 256.0,
+            family,
+            streams,
+            coupled,
+            mapping,
+        })
+    }
+
+    /// For streams without a header: a single mono or stereo stream.
+    fn plain(channels: usize, pre_skip: usize) -> Option<Self> {
+        let (streams, coupled, mapping) = Self::family_zero(channels)?;
+        Some(Self {
+            channels,
+            pre_skip,
+            gain_db: 0.0,
+            family: 0,
+            streams,
+            coupled,
+            mapping,
+        })
+    }
+
+    fn family_zero(channels: usize) -> Option<(u8, u8, Vec<u8>)> {
+        match channels {
+            1 => Some((1, 0, vec![0])),
+            2 => Some((1, 1, vec![0, 1])),
+            _ => None,
+        }
+    }
+
+    /// Speaker positions of the output channels, in the order libopus
+    /// produces them, or `None` if they have no defined layout.
+    fn positions(&self) -> Option<Vec<Channels>> {
+        use Channels as C;
+        let positions = match (self.family, self.channels) {
+            (0 | 1, 1) => vec![C::FRONT_LEFT],
+            (0 | 1, 2) => vec![C::FRONT_LEFT, C::FRONT_RIGHT],
+            // Vorbis channel order
+            (1, 3) => vec![C::FRONT_LEFT, C::FRONT_CENTRE, C::FRONT_RIGHT],
+            (1, 4) => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::REAR_LEFT, C::REAR_RIGHT],
+            (1, 5) => vec![
+                C::FRONT_LEFT,
+                C::FRONT_CENTRE,
+                C::FRONT_RIGHT,
+                C::REAR_LEFT,
+                C::REAR_RIGHT,
+            ],
+            (1, 6) => vec![
+                C::FRONT_LEFT,
+                C::FRONT_CENTRE,
+                C::FRONT_RIGHT,
+                C::REAR_LEFT,
+                C::REAR_RIGHT,
+                C::LFE1,
+            ],
+            (1, 7) => vec![
+                C::FRONT_LEFT,
+                C::FRONT_CENTRE,
+                C::FRONT_RIGHT,
+                C::SIDE_LEFT,
+                C::SIDE_RIGHT,
+                C::REAR_CENTRE,
+                C::LFE1,
+            ],
+            (1, 8) => vec![
+                C::FRONT_LEFT,
+                C::FRONT_CENTRE,
+                C::FRONT_RIGHT,
+                C::SIDE_LEFT,
+                C::SIDE_RIGHT,
+                C::REAR_LEFT,
+                C::REAR_RIGHT,
+                C::LFE1,
+            ],
+            _ => return None,
+        };
+        Some(positions)
+    }
+}
+
+/// A libopus multistream decoder; dropping it frees it.
+struct MultistreamDecoder(NonNull<ffi::OpusMSDecoder>);
+
+// SAFETY: the decoder state is plain memory with no thread affinity
+unsafe impl Send for MultistreamDecoder {}
+
+impl MultistreamDecoder {
+    fn new(head: &OpusHead) -> Option<Self> {
+        let mut error: c_int = 0;
+        // SAFETY: the mapping table holds one entry per channel
+        let decoder = unsafe {
+            ffi::opus_multistream_decoder_create(
+                OPUS_SAMPLE_RATE as i32,
+                head.channels as c_int,
+                head.streams as c_int,
+                head.coupled as c_int,
+                head.mapping.as_ptr(),
+                &mut error,
+            )
+        };
+        if error != ffi::OPUS_OK as c_int {
+            return None;
+        }
+        NonNull::new(decoder).map(Self)
+    }
+
+    /// Decode a packet into interleaved samples, returning the frame count.
+    fn decode(&mut self, packet: &[u8], pcm: &mut [f32], channels: usize) -> Option<usize> {
+        // SAFETY: `pcm` has room for the requested frames of every channel
+        let frames = unsafe {
+            ffi::opus_multistream_decode_float(
+                self.0.as_ptr(),
+                packet.as_ptr(),
+                packet.len() as i32,
+                pcm.as_mut_ptr(),
+                (pcm.len() / channels) as c_int,
+                0,
+            )
+        };
+        usize::try_from(frames).ok()
+    }
+}
+
+impl Drop for MultistreamDecoder {
+    fn drop(&mut self) {
+        // SAFETY: created by opus_multistream_decoder_create and not yet freed
+        unsafe { ffi::opus_multistream_decoder_destroy(self.0.as_ptr()) };
+    }
+}
+
+pub struct OpusDecoder {
+    params: CodecParameters,
+    head: OpusHead,
+    // The decoder is `Send` but not `Sync`; symphonia requires both.
+    decoder: Mutex<MultistreamDecoder>,
+    /// Buffer plane for each decoded channel, following symphonia's layout order.
+    planes: Vec<usize>,
+    /// Pre-skip frames still to drop.
+    skip: usize,
+    gain: f32,
+    pcm: Vec<f32>,
+    buf: AudioBuffer<f32>,
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        let head = match params.extra_data.as_deref().and_then(OpusHead::parse) {
+            Some(head) => head,
+            None => {
+                let channels = params.channels.map(|c| c.count()).unwrap_or(2);
+                match OpusHead::plain(channels, params.delay.unwrap_or(0) as usize) {
+                    Some(head) => head,
+                    None => {
+                        return unsupported_error(
+                            "opus: surround streams need an OpusHead channel mapping",
+                        )
+                    }
+                }
+            }
+        };
+
+        // Symphonia orders a buffer's channels by layout bit, so place each
+        // decoded channel by its speaker position. Without a layout the
+        // channels are discrete and keep their order.
+        let (layout, planes) = match head.positions() {
+            Some(positions) => {
+                let layout = positions.iter().fold(Channels::empty(), |all, &c| all | c);
+                let planes = positions
+                    .iter()
+                    .map(|c| (layout.bits() & (c.bits() - 1)).count_ones() as usize)
+                    .collect();
+                (layout, planes)
+            }
+            None => match 1u32
+                .checked_shl(head.channels as u32)
+                .and_then(|bit| Channels::from_bits(bit - 1))
+            {
+                Some(layout) => (layout, (0..head.channels).collect()),
+                None => return unsupported_error("opus: too many channels"),
+            },
+        };
+
+        let decoder = match MultistreamDecoder::new(&head) {
+            Some(decoder) => decoder,
+            None => return unsupported_error("opus: failed to create libopus decoder"),
+        };
+
+        let mut params = params.clone();
+        params
+            .with_sample_rate(OPUS_SAMPLE_RATE)
+            .with_channels(layout);
+
+        let spec = SignalSpec::new(OPUS_SAMPLE_RATE, layout);
+
+        Ok(Self {
+            params,
+            skip: head.pre_skip,
+            gain: 10f32.powf(head.gain_db / 20.0),
+            pcm: vec![0.0; MAX_FRAMES_PER_PACKET * head.channels],
+            head,
+            decoder: Mutex::new(decoder),
+            planes,
+            buf: AudioBuffer::new(MAX_FRAMES_PER_PACKET as u64, spec),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus (libopus)")]
+    }
+
+    fn reset(&mut self) {
+        // A fresh decoder warms up again, so its first frames are skipped too
+        if let Some(decoder) = MultistreamDecoder::new(&self.head) {
+            self.decoder = Mutex::new(decoder);
+            self.skip = self.head.pre_skip;
+        }
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buf.clear();
+
+        let channels = self.head.channels;
+        let decoder = self.decoder.get_mut().unwrap_or_else(|e| e.into_inner());
+        let frames = match decoder.decode(packet.buf(), &mut self.pcm, channels) {
+            Some(frames) => frames,
+            None => return decode_error("opus: failed to decode packet"),
+        };
+
+        let skipped = self.skip.min(frames);
+        self.skip -= skipped;
+        // Padding after the end of the stream; the start is covered by pre-skip
+        let frames = frames - (packet.trim_end() as usize).min(frames - skipped);
+        let pcm = &self.pcm[skipped * channels..frames * channels];
+
+        self.buf.render_reserved(Some(frames - skipped));
+        for (ch, &plane) in self.planes.iter().enumerate() {
+            let plane = self.buf.chan_mut(plane);
+            for (i, sample) in plane.iter_mut().enumerate() {
+                *sample = pcm[i * channels + ch] * self.gain;
+            }
+        }
+
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        Default::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::source::{InputSpec, Source};
+    use std::path::PathBuf;
+
+    #[test]
+    fn decodes_opus_in_webm() {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tone.webm");
+        let mut source = Source::open(&InputSpec {
+            path,
+            format_hint: None,
+            raw: None,
+            raw_files: None,
+            track: None,
+            exec: None,
+            ytdlp: None,
+            tts: None,
+            generate: None,
+            midi: None,
+            span: None,
+            strict: true,
+        })
+        .unwrap();
+        assert_eq!(source.sample_rate, Some(super::OPUS_SAMPLE_RATE));
+
+        let mut frames = 0;
+        while let Some((samples, channels)) = source.next_chunk().unwrap() {
+            assert_eq!(channels, 2);
+            frames += samples.len() / channels;
+        }
+        assert!(frames > 0);
+    }
+}
@@ -0,0 +1,307 @@
+//! `--osc`: an Open Sound Control server, so show-control software and
+//! controller apps such as TouchOSC can drive the mic over UDP.
+//!
+//! | Address | Arguments | Command |
+//! |---------|-----------|---------|
+//! | `/virtualmic/play` | none, or a path | `resume`, or `load <path>` |
+//! | `/virtualmic/pause` | | `pause` |
+//! | `/virtualmic/volume` | a number | `volume <level>` |
+//! | `/virtualmic/mute` | none, or 0/1 | `toggle-mute`, or `unmute`/`mute` |
+//! | `/virtualmic/next`, `/virtualmic/previous` | | `next`, `prev` |
+//! | `/virtualmic/interject` | a path | `interject <path>` |
+//! | `/virtualmic/pad` | a pad number | `pad <n>` |
+//!
+//! Buttons in controller apps send 1 when pressed and 0 when released, so a
+//! 0 (or false) sent to an address that takes no arguments is ignored.
+//! Bundles are unpacked and their messages applied at once, whatever their
+//! time tags say.
+
+use crate::control::{ControlCommand, VolumeChange};
+use anyhow::{anyhow, Result};
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use tracing::{debug, info, warn};
+
+/// Prefix of every address the server answers to.
+const PREFIX: &str = "/virtualmic/";
+
+/// Largest datagram read.
+const MAX_PACKET: usize = 65536;
+
+/// Bundles inside bundles, before the rest is dropped.
+const MAX_DEPTH: usize = 8;
+
+/// An OSC argument.
+#[derive(Debug, Clone, PartialEq)]
+enum Arg {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    Nil,
+}
+
+impl Arg {
+    fn number(&self) -> Option<f64> {
+        match self {
+            Arg::Int(value) => Some(*value as f64),
+            Arg::Float(value) => Some(*value),
+            Arg::Bool(value) => Some(f64::from(u8::from(*value))),
+            _ => None,
+        }
+    }
+}
+
+/// Listen for OSC messages on `addr` on a background thread, sending the
+/// commands they map to.
+pub fn listen(addr: SocketAddr) -> Result<Receiver<ControlCommand>> {
+    let socket =
+        UdpSocket::bind(addr).map_err(|e| anyhow!("Failed to bind OSC on {}: {}", addr, e))?;
+    info!("Listening for OSC on {}", socket.local_addr()?);
+    // OSC has no way to carry --auth-token, so only loopback is safe
+    if !addr.ip().is_loopback() {
+        warn!(
+            "OSC on {} takes commands from anyone who can reach it, without --auth-token; bind it to 127.0.0.1 unless the network is trusted",
+            addr
+        );
+    }
+    let (tx, rx) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("osc".into())
+        .spawn(move || {
+            let mut packet = vec![0u8; MAX_PACKET];
+            loop {
+                let (length, from) = match socket.recv_from(&mut packet) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        warn!("Stopped receiving OSC: {}", e);
+                        return;
+                    }
+                };
+                if !handle(&packet[..length], from, &tx, 0) {
+                    return;
+                }
+            }
+        })?;
+    Ok(rx)
+}
+
+/// Apply a packet, a message or a bundle; `false` once nobody is listening.
+fn handle(packet: &[u8], from: SocketAddr, tx: &Sender<ControlCommand>, depth: usize) -> bool {
+    if let Some(mut elements) = packet.strip_prefix(b"#bundle\0") {
+        if depth == MAX_DEPTH || elements.len() < 8 {
+            return true;
+        }
+        // The time tag is ignored
+        elements = &elements[8..];
+        while let Some(size) = elements.get(..4) {
+            let size = u32::from_be_bytes(size.try_into().unwrap()) as usize;
+            let Some(element) = elements.get(4..4 + size) else {
+                debug!("Truncated OSC bundle from {}", from);
+                return true;
+            };
+            if !handle(element, from, tx, depth + 1) {
+                return false;
+            }
+            elements = &elements[4 + size..];
+        }
+        return true;
+    }
+    let Some((address, args)) = parse_message(packet) else {
+        debug!("Malformed OSC packet from {}", from);
+        return true;
+    };
+    // Controller apps send plenty that isn't for us
+    let Some(name) = address.strip_prefix(PREFIX) else {
+        debug!("Ignoring OSC {} from {}", address, from);
+        return true;
+    };
+    match command(name, &args) {
+        Ok(Some(command)) => {
+            debug!("OSC {} {:?} from {}: {:?}", address, args, from, command);
+            tx.send(command).is_ok()
+        }
+        Ok(None) => true,
+        Err(e) => {
+            warn!("OSC {} from {}: {}", address, from, e);
+            true
+        }
+    }
+}
+
+/// The command for a message to `/virtualmic/<name>`, or `None` for a
+/// button being released.
+fn command(name: &str, args: &[Arg]) -> Result<Option<ControlCommand>, String> {
+    let first = args.first();
+    // A button let go, sent to an address that only needs pressing
+    let released = first.is_some_and(|arg| arg.number() == Some(0.0));
+    let path = || match first {
+        Some(Arg::Str(path)) if !path.is_empty() => Ok(PathBuf::from(path)),
+        _ => Err(format!("{} needs a path", name)),
+    };
+    let command = match name {
+        "play" => match first {
+            Some(Arg::Str(_)) => ControlCommand::Load(path()?),
+            _ if released => return Ok(None),
+            _ => ControlCommand::Resume,
+        },
+        "pause" if released => return Ok(None),
+        "pause" => ControlCommand::Pause,
+        "volume" => {
+            let level = first
+                .and_then(Arg::number)
+                .filter(|level| level.is_finite() && *level >= 0.0)
+                .ok_or("volume needs a level from 0.0 to 2.0")?;
+            ControlCommand::Volume(VolumeChange::To(level as f32))
+        }
+        "mute" => match first.and_then(Arg::number) {
+            Some(value) if value != 0.0 => ControlCommand::Mute,
+            Some(_) => ControlCommand::Unmute,
+            None => ControlCommand::ToggleMute,
+        },
+        "next" | "previous" | "prev" if released => return Ok(None),
+        "next" => ControlCommand::Next,
+        "previous" | "prev" => ControlCommand::Previous,
+        "interject" => ControlCommand::Interject(path()?),
+        "pad" => match first.and_then(Arg::number) {
+            Some(pad) if pad >= 1.0 => ControlCommand::Pad(pad as usize),
+            _ => return Err("pad needs a pad number from 1".to_string()),
+        },
+        _ => return Err("unknown address".to_string()),
+    };
+    Ok(Some(command))
+}
+
+/// Split a message into its address and arguments.
+fn parse_message(packet: &[u8]) -> Option<(String, Vec<Arg>)> {
+    let mut reader = Reader(packet);
+    let address = reader.string()?;
+    if !address.starts_with('/') {
+        return None;
+    }
+    // Very old senders leave the type tags out, and with them any arguments
+    let Some(tags) = reader.string().filter(|tags| tags.starts_with(',')) else {
+        return Some((address, Vec::new()));
+    };
+    let mut args = Vec::new();
+    for tag in tags[1..].chars() {
+        args.push(match tag {
+            'i' => Arg::Int(i32::from_be_bytes(reader.bytes()?) as i64),
+            'h' => Arg::Int(i64::from_be_bytes(reader.bytes()?)),
+            'f' => Arg::Float(f32::from_be_bytes(reader.bytes()?) as f64),
+            'd' => Arg::Float(f64::from_be_bytes(reader.bytes()?)),
+            's' | 'S' => Arg::Str(reader.string()?),
+            'T' => Arg::Bool(true),
+            'F' => Arg::Bool(false),
+            'N' | 'I' => Arg::Nil,
+            // Blobs, time tags and the rest aren't needed; stop at the first
+            _ => break,
+        });
+    }
+    Some((address, args))
+}
+
+/// Reads the 4-byte aligned fields of a message.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.0.get(..N)?.try_into().ok()?;
+        self.0 = &self.0[N..];
+        Some(bytes)
+    }
+
+    /// A string, null-terminated and padded to a multiple of 4 bytes.
+    fn string(&mut self) -> Option<String> {
+        let end = self.0.iter().position(|&b| b == 0)?;
+        let string = String::from_utf8_lossy(&self.0[..end]).into_owned();
+        let padded = (end + 4) & !3;
+        self.0 = self.0.get(padded..).unwrap_or_default();
+        Some(string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A string as OSC pads it: null-terminated, to a multiple of 4 bytes.
+    fn padded(s: &str) -> Vec<u8> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.resize((s.len() + 4) & !3, 0);
+        bytes
+    }
+
+    #[test]
+    fn parses_message_arguments() {
+        let mut packet = padded("/virtualmic/volume");
+        packet.extend(padded(",ifsTN"));
+        packet.extend(7i32.to_be_bytes());
+        packet.extend(0.5f32.to_be_bytes());
+        packet.extend(padded("clip.wav"));
+        assert_eq!(
+            parse_message(&packet),
+            Some((
+                "/virtualmic/volume".to_string(),
+                vec![
+                    Arg::Int(7),
+                    Arg::Float(0.5),
+                    Arg::Str("clip.wav".to_string()),
+                    Arg::Bool(true),
+                    Arg::Nil,
+                ]
+            ))
+        );
+
+        // No type tags at all, from very old senders
+        assert_eq!(
+            parse_message(&padded("/virtualmic/next")),
+            Some(("/virtualmic/next".to_string(), Vec::new()))
+        );
+        // A missing argument, and an address that isn't one
+        let mut short = padded("/virtualmic/volume");
+        short.extend(padded(",f"));
+        assert_eq!(parse_message(&short), None);
+        assert_eq!(parse_message(&padded("virtualmic/next")), None);
+    }
+
+    #[test]
+    fn maps_addresses_to_commands() {
+        let path = || Arg::Str("clip.wav".to_string());
+        assert_eq!(command("play", &[]), Ok(Some(ControlCommand::Resume)));
+        assert_eq!(
+            command("play", &[path()]),
+            Ok(Some(ControlCommand::Load(PathBuf::from("clip.wav"))))
+        );
+        assert_eq!(
+            command("volume", &[Arg::Float(0.25)]),
+            Ok(Some(ControlCommand::Volume(VolumeChange::To(0.25))))
+        );
+        assert_eq!(
+            command("mute", &[Arg::Int(1)]),
+            Ok(Some(ControlCommand::Mute))
+        );
+        assert_eq!(
+            command("mute", &[Arg::Bool(false)]),
+            Ok(Some(ControlCommand::Unmute))
+        );
+        assert_eq!(command("mute", &[]), Ok(Some(ControlCommand::ToggleMute)));
+        assert_eq!(
+            command("interject", &[path()]),
+            Ok(Some(ControlCommand::Interject(PathBuf::from("clip.wav"))))
+        );
+        assert_eq!(
+            command("pad", &[Arg::Float(3.0)]),
+            Ok(Some(ControlCommand::Pad(3)))
+        );
+        // A button released
+        assert_eq!(command("next", &[Arg::Int(0)]), Ok(None));
+        assert_eq!(command("pause", &[Arg::Bool(false)]), Ok(None));
+
+        assert!(command("volume", &[Arg::Float(-1.0)]).is_err());
+        assert!(command("interject", &[]).is_err());
+        assert!(command("pad", &[Arg::Int(0)]).is_err());
+        assert!(command("rewind", &[]).is_err());
+    }
+}
@@ -0,0 +1,416 @@
+//! The ordered list of files to play.
+
+use crate::source::Span;
+use crate::{cue, edl, hls, http, midi, raw, tracker};
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// Extensions picked up when a directory is given as `--file`.
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "aac", "aif", "aifc", "aiff", "caf", "flac", "m4a", "m4v", "mka", "mkv", "mov", "mp3", "mp4",
+    "oga", "ogg", "opus", "wav", "webm",
+];
+
+pub fn is_audio_file(path: &Path) -> bool {
+    path.is_file()
+        && (path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+            || midi::is_midi_file(path)
+            || tracker::is_module_file(path)
+            || raw::is_raw_file(path))
+}
+
+/// How deeply playlist files may include other playlist files.
+const MAX_PLAYLIST_DEPTH: usize = 8;
+
+fn is_playlist_file(path: &Path) -> bool {
+    // Ignore a URL's query string
+    let path = match path.to_str() {
+        Some(url) if http::is_url(path) => Path::new(http::without_query(url)),
+        _ => path,
+    };
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| matches!(ext.to_ascii_lowercase().as_str(), "m3u" | "m3u8" | "pls"))
+}
+
+fn is_cue_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+}
+
+/// Where a cue sheet's `FILE` is. Sheets often still name the file they were
+/// ripped to (say `disc.wav`) after it was encoded to `disc.flac`, so a
+/// missing file falls back to an audio file of the same name beside it.
+fn cue_file(base: &Path, file: &str) -> PathBuf {
+    let path = base.join(file);
+    if path.exists() {
+        return path;
+    }
+    AUDIO_EXTENSIONS
+        .iter()
+        .map(|ext| path.with_extension(ext))
+        .find(|candidate| candidate.is_file())
+        .unwrap_or(path)
+}
+
+/// Entries for the tracks of a cue sheet, each a span of its file.
+fn read_cue(path: &Path) -> Result<Vec<Entry>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read cue sheet {:?}: {}", path, e))?;
+    let tracks = cue::parse(&contents).map_err(|e| anyhow!("{:?}: {}", path, e))?;
+    if tracks.is_empty() {
+        return Err(anyhow!("No audio tracks in cue sheet {:?}", path));
+    }
+    let base = path.parent().unwrap_or(Path::new(""));
+    Ok(tracks
+        .iter()
+        .map(|track| Entry {
+            path: cue_file(base, &track.file),
+            span: Some(Span {
+                start: track.start,
+                end: track.end,
+            }),
+            title: track.name(),
+        })
+        .collect())
+}
+
+fn is_edit_list(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("edl"))
+}
+
+/// Entries for the segments of an edit decision list, with files resolved
+/// against the list's directory.
+fn read_edit_list(path: &Path) -> Result<Vec<Entry>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read edit list {:?}: {}", path, e))?;
+    let segments = edl::parse(&contents).map_err(|e| anyhow!("{:?}: {}", path, e))?;
+    if segments.is_empty() {
+        return Err(anyhow!("No segments in edit list {:?}", path));
+    }
+    let base = path.parent().unwrap_or(Path::new(""));
+    Ok(segments
+        .into_iter()
+        .map(|segment| Entry {
+            path: base.join(segment.file),
+            span: Some(Span {
+                start: segment.start,
+                end: segment.end,
+            }),
+            title: None,
+        })
+        .collect())
+}
+
+/// Entries of an M3U/M3U8 playlist: every non-empty line that isn't a `#` directive.
+fn parse_m3u(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.trim().trim_start_matches('\u{feff}'))
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Entries of a PLS playlist: the `FileN=` keys, ordered by N.
+fn parse_pls(contents: &str) -> Vec<String> {
+    let mut files: Vec<(u32, String)> = contents
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().split_once('=')?;
+            let key = key.trim().to_ascii_lowercase();
+            let number = key.strip_prefix("file")?.parse().ok()?;
+            Some((number, value.trim().to_string()))
+        })
+        .collect();
+    files.sort_by_key(|(number, _)| *number);
+    files.into_iter().map(|(_, file)| file).collect()
+}
+
+/// Read a playlist file or URL, resolving its entries relative to the playlist's location.
+///
+/// Returns `None` for a remote HLS playlist, which is played as one stream.
+fn read_playlist(path: &Path) -> Result<Option<Vec<PathBuf>>> {
+    let url = path.to_str().filter(|_| http::is_url(path));
+    let contents = match url {
+        // Radio stations are usually linked as a remote .pls or .m3u
+        Some(url) => http::fetch_text(url)?,
+        None => std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read playlist {:?}: {}", path, e))?,
+    };
+    if url.is_some() && hls::is_hls(&contents) {
+        return Ok(None);
+    }
+    let is_pls = contents.trim_start().starts_with("[playlist]")
+        || path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("pls"));
+    let lines = if is_pls {
+        parse_pls(&contents)
+    } else {
+        parse_m3u(&contents)
+    };
+
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut entries = Vec::new();
+    for line in lines {
+        let line = line.strip_prefix("file://").unwrap_or(&line);
+        if http::is_url(Path::new(line)) {
+            entries.push(PathBuf::from(line));
+            continue;
+        }
+        if line.contains("://") {
+            warn!(
+                "Skipping unsupported playlist entry {:?} in {:?}",
+                line, path
+            );
+            continue;
+        }
+        match url {
+            Some(url) => entries.push(PathBuf::from(http::resolve(url, line))),
+            None => entries.push(base.join(line)),
+        }
+    }
+    Ok(Some(entries))
+}
+
+/// Expand `--file` arguments into playlist entries.
+///
+/// Files are kept as given; directories contribute their audio files (not
+/// recursively) in name order, M3U/PLS playlists contribute their entries,
+/// cue sheets their tracks and edit lists their segments. "-" (stdin) and
+/// HTTP(S) URLs pass through unchanged.
+pub fn expand(paths: &[PathBuf]) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    expand_into(paths, &mut entries, 0)?;
+    if entries.is_empty() {
+        return Err(anyhow!("The playlist is empty"));
+    }
+    Ok(entries)
+}
+
+fn expand_into(paths: &[PathBuf], entries: &mut Vec<Entry>, depth: usize) -> Result<()> {
+    for path in paths {
+        if path.is_file() && is_cue_file(path) {
+            entries.append(&mut read_cue(path)?);
+        } else if path.is_file() && is_edit_list(path) {
+            entries.append(&mut read_edit_list(path)?);
+        } else if (path.is_file() || http::is_url(path)) && is_playlist_file(path) {
+            if depth >= MAX_PLAYLIST_DEPTH {
+                return Err(anyhow!("Playlists nested too deeply at {:?}", path));
+            }
+            match read_playlist(path)? {
+                Some(nested) => expand_into(&nested, entries, depth + 1)?,
+                None => entries.push(path.clone().into()),
+            }
+        } else if path == Path::new("-") || http::is_url(path) || path.is_file() {
+            entries.push(path.clone().into());
+        } else if path.is_dir() {
+            let mut files: Vec<PathBuf> = std::fs::read_dir(path)?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| is_audio_file(path))
+                .collect();
+            if files.is_empty() {
+                return Err(anyhow!("No audio files found in {:?}", path));
+            }
+            files.sort();
+            entries.extend(files.into_iter().map(Entry::from));
+        } else {
+            return Err(anyhow!("Audio file not found: {:?}", path));
+        }
+    }
+    Ok(())
+}
+
+/// Something to play: a whole file, or a stretch of one such as a cue sheet track.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub path: PathBuf,
+    /// The part of the file to play, if not all of it.
+    pub span: Option<Span>,
+    /// The track's name, from a cue sheet.
+    pub title: Option<String>,
+}
+
+impl From<PathBuf> for Entry {
+    fn from(path: PathBuf) -> Self {
+        Self {
+            path,
+            span: None,
+            title: None,
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.title, self.span) {
+            (Some(title), _) => write!(f, "{:?} in {:?}", title, self.path),
+            (None, Some(span)) => {
+                write!(f, "{:?} from {:.3}s", self.path, span.start.as_secs_f64())?;
+                match span.end {
+                    Some(end) => write!(f, " to {:.3}s", end.as_secs_f64()),
+                    None => write!(f, " to the end"),
+                }
+            }
+            (None, None) => write!(f, "{:?}", self.path),
+        }
+    }
+}
+
+/// Files played in order, remembering which one is current.
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    entries: Vec<Entry>,
+    index: usize,
+    /// Set when shuffling; the entries are reshuffled each time the list starts over.
+    shuffle: Option<Shuffle>,
+}
+
+impl Playlist {
+    pub fn new(entries: Vec<Entry>) -> Self {
+        assert!(!entries.is_empty(), "a playlist needs at least one entry");
+        Self {
+            entries,
+            index: 0,
+            shuffle: None,
+        }
+    }
+
+    /// Play the entries in a random order, the same one every time for the same seed.
+    pub fn shuffle(&mut self, seed: u64) {
+        let mut shuffle = Shuffle::new(seed);
+        shuffle.apply(&mut self.entries);
+        self.shuffle = Some(shuffle);
+        self.index = 0;
+    }
+
+    pub fn current(&self) -> &Entry {
+        &self.entries[self.index]
+    }
+
+    /// The entry at a 1-based position.
+    pub fn get(&self, track: usize) -> Option<&Entry> {
+        self.entries.get(track.checked_sub(1)?)
+    }
+
+    /// Make the entry at a 1-based position current.
+    pub fn select(&mut self, track: usize) {
+        assert!(
+            (1..=self.entries.len()).contains(&track),
+            "track {} is not in the playlist",
+            track
+        );
+        self.index = track - 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The entries, in the order they play.
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Add entries to the end, after any the shuffle put there.
+    pub fn extend(&mut self, entries: Vec<Entry>) {
+        self.entries.extend(entries);
+    }
+
+    /// Move to the next entry, wrapping to the first one if `wrap` is set.
+    ///
+    /// Returns `false` (and stays put) when the end is reached without wrapping.
+    pub fn advance(&mut self, wrap: bool) -> bool {
+        if self.index + 1 < self.entries.len() {
+            self.index += 1;
+            true
+        } else if wrap {
+            self.index = 0;
+            if let Some(shuffle) = &mut self.shuffle {
+                shuffle.apply(&mut self.entries);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 1-based index of the current entry.
+    pub fn track(&self) -> usize {
+        self.index + 1
+    }
+}
+
+/// Seeded xorshift for shuffling.
+#[derive(Debug, Clone)]
+struct Shuffle {
+    state: u64,
+}
+
+impl Shuffle {
+    fn new(seed: u64) -> Self {
+        Self {
+            // Spread small seeds over the bits; xorshift gets stuck at zero
+            state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// Fisher-Yates shuffle.
+    fn apply(&mut self, entries: &mut [Entry]) {
+        for i in (1..entries.len()).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            entries.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_m3u_entries() {
+        let m3u = [
+            "\u{feff}#EXTM3U",
+            "#EXTINF:123,Artist - Title",
+            "  one.mp3  ",
+            "",
+            "sub/two.flac",
+            "http://radio.example/stream",
+        ]
+        .join("\r\n");
+        assert_eq!(
+            parse_m3u(&m3u),
+            ["one.mp3", "sub/two.flac", "http://radio.example/stream"]
+        );
+    }
+
+    #[test]
+    fn reads_pls_entries_in_number_order() {
+        let pls = [
+            "[playlist]",
+            "File2=two.ogg",
+            "Title2=Two",
+            "file1 = one.ogg",
+            "File10=ten.ogg",
+            "NumberOfEntries=3",
+            "Version=2",
+        ]
+        .join("\n");
+        assert_eq!(parse_pls(&pls), ["one.ogg", "two.ogg", "ten.ogg"]);
+    }
+}
@@ -0,0 +1,220 @@
+//! `--ptt`: push-to-talk. The microphone carries silence except while a
+//! trigger is held.
+//!
+//! Triggers are written `<kind>:<which>`, optionally followed by
+//! `@<device>`:
+//!
+//! - `key:space`, `key:f13@/dev/input/event4`: a key or mouse button, on
+//!   every input device unless one is given (see [`crate::evdev`]). A bare
+//!   key name means the same.
+//! - `joystick:0`: a joystick or gamepad button, on `/dev/input/js0` unless
+//!   another device is given.
+//! - `midi:60`, `midi:cc64`: a MIDI note, or a controller held at 64 or over
+//!   (a sustain pedal), on the first MIDI input port unless part of another
+//!   port's name is given.
+
+use crate::control::ControlCommand;
+use crate::{evdev, midiin};
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use tracing::{info, warn};
+
+const DEFAULT_JOYSTICK: &str = "/dev/input/js0";
+
+/// `js_event.type` of a button, and the flag set on the state reported at open.
+const JS_EVENT_BUTTON: u8 = 0x01;
+const JS_EVENT_INIT: u8 = 0x80;
+
+/// What has to be held to talk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    Key {
+        code: u16,
+        device: Option<PathBuf>,
+    },
+    Joystick {
+        button: u8,
+        device: PathBuf,
+    },
+    MidiNote {
+        note: u8,
+        port: Option<String>,
+    },
+    MidiController {
+        controller: u8,
+        port: Option<String>,
+    },
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Key { code, .. } => write!(f, "key {}", code),
+            Trigger::Joystick { button, device } => write!(f, "button {} of {:?}", button, device),
+            Trigger::MidiNote { note, .. } => write!(f, "MIDI note {}", note),
+            Trigger::MidiController { controller, .. } => {
+                write!(f, "MIDI controller {}", controller)
+            }
+        }
+    }
+}
+
+/// Parse a `--ptt` trigger.
+pub fn parse_trigger(s: &str) -> Result<Trigger, String> {
+    let (spec, device) = match s.split_once('@') {
+        Some((spec, device)) => (spec, Some(device)),
+        None => (s, None),
+    };
+    let (kind, which) = spec.split_once(':').unwrap_or(("key", spec));
+    let invalid = |expected: &str| format!("invalid --ptt trigger '{}': {}", s, expected);
+    match kind {
+        "key" => Ok(Trigger::Key {
+            code: evdev::key_code(which)
+                .ok_or_else(|| invalid("unknown key; use a name like space or f13, or a code"))?,
+            device: device.map(PathBuf::from),
+        }),
+        "joystick" => Ok(Trigger::Joystick {
+            button: which
+                .parse()
+                .map_err(|_| invalid("expected a button number"))?,
+            device: PathBuf::from(device.unwrap_or(DEFAULT_JOYSTICK)),
+        }),
+        "midi" => match which.strip_prefix("cc") {
+            Some(controller) => Ok(Trigger::MidiController {
+                controller: controller
+                    .parse()
+                    .ok()
+                    .filter(|&controller| controller < 128)
+                    .ok_or_else(|| invalid("expected a controller number from 0 to 127"))?,
+                port: device.map(str::to_string),
+            }),
+            None => Ok(Trigger::MidiNote {
+                note: which
+                    .parse()
+                    .ok()
+                    .filter(|&note| note < 128)
+                    .ok_or_else(|| invalid("expected a note number from 0 to 127"))?,
+                port: device.map(str::to_string),
+            }),
+        },
+        _ => Err(invalid("use key:<name>, joystick:<button> or midi:<note>")),
+    }
+}
+
+/// Follow `trigger` on background threads, sending a push-to-talk command
+/// each time it is pressed or released.
+pub fn watch(trigger: &Trigger) -> Result<Receiver<ControlCommand>> {
+    let (tx, rx) = mpsc::channel();
+    match trigger {
+        Trigger::Key { code, device } => {
+            let code = *code;
+            let watching = evdev::watch_all(device.as_deref(), move |key| {
+                key.code != code || send(&tx, key.pressed)
+            })?;
+            info!(
+                "Push-to-talk on {}, from {} input device(s)",
+                trigger, watching
+            );
+        }
+        Trigger::Joystick { button, device } => {
+            let button = *button;
+            let mut file =
+                File::open(device).map_err(|e| anyhow!("Failed to open {:?}: {}", device, e))?;
+            let device = device.clone();
+            info!("Push-to-talk on {}", trigger);
+            std::thread::Builder::new()
+                .name("joystick".into())
+                .spawn(move || {
+                    // struct js_event: u32 time, i16 value, u8 type, u8 number
+                    let mut event = [0u8; 8];
+                    loop {
+                        if let Err(e) = file.read_exact(&mut event) {
+                            warn!("Stopped reading {:?}: {}", device, e);
+                            return;
+                        }
+                        let value = i16::from_ne_bytes([event[4], event[5]]);
+                        let kind = event[6] & !JS_EVENT_INIT;
+                        if kind == JS_EVENT_BUTTON && event[7] == button && !send(&tx, value != 0) {
+                            return;
+                        }
+                    }
+                })?;
+        }
+        Trigger::MidiNote { port, .. } | Trigger::MidiController { port, .. } => {
+            let watched = trigger.clone();
+            let port = midiin::watch(port.as_deref(), move |message| match (&watched, message) {
+                (Trigger::MidiNote { note, .. }, midiin::Message::Note { note: got, on })
+                    if got == *note =>
+                {
+                    send(&tx, on)
+                }
+                (
+                    Trigger::MidiController { controller, .. },
+                    midiin::Message::Controller {
+                        controller: got,
+                        value,
+                    },
+                ) if got == *controller => send(&tx, value >= 64),
+                _ => true,
+            })?;
+            info!("Push-to-talk on {} from {:?}", trigger, port);
+        }
+    }
+    Ok(rx)
+}
+
+/// Send the trigger's new state; `false` once nobody is listening.
+fn send(tx: &Sender<ControlCommand>, held: bool) -> bool {
+    tx.send(ControlCommand::PushToTalk(held)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_triggers() {
+        assert_eq!(
+            parse_trigger("space"),
+            Ok(Trigger::Key {
+                code: 57,
+                device: None
+            })
+        );
+        assert_eq!(
+            parse_trigger("key:f13@/dev/input/event4"),
+            Ok(Trigger::Key {
+                code: 183,
+                device: Some(PathBuf::from("/dev/input/event4"))
+            })
+        );
+        assert_eq!(
+            parse_trigger("joystick:4"),
+            Ok(Trigger::Joystick {
+                button: 4,
+                device: PathBuf::from(DEFAULT_JOYSTICK)
+            })
+        );
+        assert_eq!(
+            parse_trigger("midi:60"),
+            Ok(Trigger::MidiNote {
+                note: 60,
+                port: None
+            })
+        );
+        assert_eq!(
+            parse_trigger("midi:cc64@nanoKONTROL2"),
+            Ok(Trigger::MidiController {
+                controller: 64,
+                port: Some("nanoKONTROL2".to_string())
+            })
+        );
+        for trigger in ["key:nope", "joystick:a", "midi:128", "midi:cc", "pedal:1"] {
+            assert!(parse_trigger(trigger).is_err(), "{}", trigger);
+        }
+    }
+}
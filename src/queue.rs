@@ -0,0 +1,90 @@
+//! `--queue-file`: a text file that more files are added to during playback.
+//!
+//! Each line appended to the file is queued at the end of the playlist, like
+//! the control socket's `enqueue`. Lines already in the file at startup are
+//! skipped, so restarting doesn't play them again. Blank lines and `#`
+//! comments are ignored. Relative paths are taken from the file's directory,
+//! and URLs are queued as they are. A truncated file is read again from its
+//! start.
+
+use crate::http;
+use anyhow::{anyhow, Result};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How often the file is checked for new lines.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Follow the queue file at `path` on a background thread, creating it if
+/// needed, and yield the paths added to it.
+pub fn watch(path: &Path) -> Result<Receiver<PathBuf>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| anyhow!("Failed to open queue file {:?}: {}", path, e))?;
+    let offset = file.metadata()?.len();
+    info!("Watching queue file {:?}", path);
+
+    let (tx, rx) = mpsc::channel();
+    let path = path.to_path_buf();
+    std::thread::Builder::new()
+        .name("queue".into())
+        .spawn(move || follow(&path, offset, tx))?;
+    Ok(rx)
+}
+
+fn follow(path: &Path, mut offset: u64, queued: Sender<PathBuf>) {
+    let base = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    loop {
+        std::thread::sleep(POLL_INTERVAL);
+        let lines = match read_lines(path, &mut offset) {
+            Ok(lines) => lines,
+            Err(e) => {
+                warn!("Failed to read queue file {:?}: {}", path, e);
+                continue;
+            }
+        };
+        for line in lines {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let queued_path = match Path::new(line) {
+                url if http::is_url(url) => url.to_path_buf(),
+                file => base.join(file),
+            };
+            if queued.send(queued_path).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Complete lines written after `offset`, moving it past them. A line still
+/// being written is left for the next time.
+fn read_lines(path: &Path, offset: &mut u64) -> std::io::Result<Vec<String>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    if len < *offset {
+        *offset = 0;
+    }
+    if len == *offset {
+        return Ok(Vec::new());
+    }
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut added = Vec::new();
+    file.take(len - *offset).read_to_end(&mut added)?;
+    let Some(end) = added.iter().rposition(|&byte| byte == b'\n') else {
+        return Ok(Vec::new());
+    };
+    *offset += end as u64 + 1;
+    Ok(String::from_utf8_lossy(&added[..end])
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
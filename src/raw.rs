@@ -0,0 +1,157 @@
+//! Headerless PCM input, for tools that emit raw samples instead of a container.
+
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Frames read from the input per chunk.
+const CHUNK_FRAMES: usize = 1024;
+/// Extensions of headerless PCM files, read with the `--raw-*` parameters.
+const RAW_EXTENSIONS: &[&str] = &["pcm", "raw"];
+
+pub fn is_raw_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| RAW_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Sample encoding of a raw PCM input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RawFormat {
+    /// Unsigned 8-bit
+    U8,
+    /// Signed 16-bit little-endian
+    S16le,
+    /// Signed 24-bit little-endian, packed in 3 bytes
+    S24le,
+    /// Signed 32-bit little-endian
+    S32le,
+    /// 32-bit float little-endian
+    F32le,
+}
+
+impl RawFormat {
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            RawFormat::U8 => 1,
+            RawFormat::S16le => 2,
+            RawFormat::S24le => 3,
+            RawFormat::S32le | RawFormat::F32le => 4,
+        }
+    }
+
+    /// Convert one little-endian sample to a float in -1.0..1.0.
+    pub fn decode(self, bytes: &[u8]) -> f32 {
+        match self {
+            RawFormat::U8 => (bytes[0] as f32 - 128.0) / 128.0,
+            RawFormat::S16le => i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0,
+            RawFormat::S24le => {
+                // Shift into the top of an i32 so the sign bit lands in place
+                let value = i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8;
+                value as f32 / 8_388_608.0
+            }
+            RawFormat::S32le => {
+                i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f32
+                    / 2_147_483_648.0
+            }
+            RawFormat::F32le => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+        }
+    }
+}
+
+/// Parameters of a raw PCM input, given on the command line.
+#[derive(Debug, Clone, Copy)]
+pub struct RawParams {
+    pub format: RawFormat,
+    pub rate: u32,
+    pub channels: usize,
+}
+
+impl RawParams {
+    pub fn frame_bytes(&self) -> usize {
+        self.format.bytes_per_sample() * self.channels
+    }
+}
+
+/// Reads interleaved raw PCM a chunk at a time, keeping partial frames between reads.
+pub struct RawReader {
+    reader: Box<dyn Read + Send>,
+    params: RawParams,
+    buf: Vec<u8>,
+    /// Bytes at the start of `buf` left over from a partial frame.
+    pending: usize,
+}
+
+impl RawReader {
+    pub fn new(reader: Box<dyn Read + Send>, params: RawParams) -> Self {
+        Self {
+            reader,
+            params,
+            buf: vec![0; CHUNK_FRAMES * params.frame_bytes()],
+            pending: 0,
+        }
+    }
+
+    /// Read the next chunk of whole frames as interleaved floats.
+    ///
+    /// Returns `Ok(false)` at end of input; a trailing partial frame is dropped.
+    pub fn read(&mut self, output: &mut Vec<f32>) -> io::Result<bool> {
+        let frame_bytes = self.params.frame_bytes();
+        loop {
+            let read = match self.reader.read(&mut self.buf[self.pending..]) {
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if read == 0 {
+                return Ok(false);
+            }
+
+            let available = self.pending + read;
+            let whole = available - available % frame_bytes;
+            if whole == 0 {
+                self.pending = available;
+                continue;
+            }
+
+            let sample_bytes = self.params.format.bytes_per_sample();
+            output.extend(
+                self.buf[..whole]
+                    .chunks_exact(sample_bytes)
+                    .map(|bytes| self.params.format.decode(bytes)),
+            );
+
+            self.buf.copy_within(whole..available, 0);
+            self.pending = available - whole;
+            return Ok(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn s16le_spans_minus_one_to_just_under_one() {
+        let bytes: Vec<u8> = [i16::MIN, 0, i16::MAX, -1]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            // Half a frame at the end, as from a writer killed mid-sample
+            .chain([0x12])
+            .collect();
+        let params = RawParams {
+            format: RawFormat::S16le,
+            rate: 48_000,
+            channels: 2,
+        };
+        let mut reader = RawReader::new(Box::new(io::Cursor::new(bytes)), params);
+
+        let mut samples = Vec::new();
+        while reader.read(&mut samples).unwrap() {}
+        assert_eq!(samples.len(), 4);
+        assert_eq!(samples[0], -1.0);
+        assert_eq!(samples[1], 0.0);
+        assert!(samples[2] < 1.0 && samples[2] > 0.9999);
+        assert_eq!(samples[3], -1.0 / 32768.0);
+    }
+}
@@ -0,0 +1,95 @@
+//! Tell whoever started us when the virtual mic is usable.
+//!
+//! Readiness is signalled once the stream first reaches a connected state
+//! (the remap source has already been verified by then): by writing
+//! `--ready-file`, and with the `systemd` feature, by `sd_notify(READY=1)`.
+
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+pub struct Readiness {
+    ready_file: Option<PathBuf>,
+    ready: bool,
+}
+
+impl Readiness {
+    pub fn new(ready_file: Option<PathBuf>) -> Self {
+        Self {
+            ready_file,
+            ready: false,
+        }
+    }
+
+    /// Signal readiness the first time it's called; later calls only update the status.
+    pub fn mark_ready(&mut self, source_name: &str) {
+        let status = format!("Virtual microphone '{}' is ready", source_name);
+        if self.ready {
+            self.status(&status);
+            return;
+        }
+        self.ready = true;
+
+        if let Some(path) = &self.ready_file {
+            let tmp = path.with_extension("tmp");
+            let result = std::fs::write(&tmp, format!("source={}\n", source_name))
+                .and_then(|()| std::fs::rename(&tmp, path));
+            match result {
+                Ok(()) => info!("Wrote ready file {:?}", path),
+                Err(e) => warn!("Failed to write ready file {:?}: {}", path, e),
+            }
+        }
+
+        #[cfg(feature = "systemd")]
+        notify(&[
+            sd_notify::NotifyState::Ready,
+            sd_notify::NotifyState::Status(&status),
+        ]);
+    }
+
+    /// Report a free-form status to the service manager, if there is one.
+    pub fn status(&self, _status: &str) {
+        #[cfg(feature = "systemd")]
+        notify(&[sd_notify::NotifyState::Status(_status)]);
+    }
+
+    /// Withdraw readiness on shutdown.
+    pub fn stopping(&mut self) {
+        #[cfg(feature = "systemd")]
+        notify(&[sd_notify::NotifyState::Stopping]);
+
+        if let Some(path) = self.ready_file.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(feature = "systemd")]
+fn notify(state: &[sd_notify::NotifyState]) {
+    // Without $NOTIFY_SOCKET (not running under systemd) this does nothing
+    if let Err(e) = sd_notify::notify(false, state) {
+        warn!("sd_notify failed: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_file_lasts_from_ready_to_stopping() {
+        let path = std::env::temp_dir().join(format!("virtual-mic-ready-{}", std::process::id()));
+        let mut readiness = Readiness::new(Some(path.clone()));
+        assert!(!path.exists());
+
+        readiness.mark_ready("desk");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "source=desk\n");
+        // A reconnect only updates the status
+        std::fs::remove_file(&path).unwrap();
+        readiness.mark_ready("desk");
+        assert!(!path.exists());
+
+        std::fs::write(&path, "source=desk\n").unwrap();
+        readiness.stopping();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,179 @@
+//! Tee the live output into a WAV file on a background writer thread.
+
+use crate::resample::LinearResampler;
+use anyhow::{anyhow, Result};
+use std::cell::{Cell, RefCell};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::JoinHandle;
+use tracing::{error, info, warn};
+
+/// Chunks that may be queued for the writer before new audio is dropped.
+const QUEUE_CHUNKS: usize = 256;
+
+/// Sample format of the recorded WAV file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RecordFormat {
+    /// 16-bit signed integer PCM
+    S16,
+    /// 24-bit signed integer PCM
+    S24,
+    /// 32-bit IEEE float
+    F32,
+}
+
+impl RecordFormat {
+    fn spec(self, channels: u16, sample_rate: u32) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            RecordFormat::S16 => (16, hound::SampleFormat::Int),
+            RecordFormat::S24 => (24, hound::SampleFormat::Int),
+            RecordFormat::F32 => (32, hound::SampleFormat::Float),
+        };
+        hound::WavSpec {
+            channels,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+
+pub struct Recorder {
+    tx: RefCell<Option<SyncSender<Vec<f32>>>>,
+    handle: RefCell<Option<JoinHandle<()>>>,
+    dropped: Cell<u64>,
+}
+
+impl Recorder {
+    /// Create the WAV file and start the writer thread.
+    ///
+    /// Samples arrive at `live_rate` and are converted to `record_rate` on the
+    /// writer thread with a resampler of its own, leaving the playback path alone.
+    pub fn start(
+        path: PathBuf,
+        format: RecordFormat,
+        channels: usize,
+        live_rate: u32,
+        record_rate: u32,
+    ) -> Result<Self> {
+        let spec = format.spec(channels as u16, record_rate);
+        let mut writer = hound::WavWriter::create(&path, spec)
+            .map_err(|e| anyhow!("Failed to create recording {:?}: {}", path, e))?;
+
+        info!(
+            "Recording to {:?} ({:?}, {} Hz, {} channels)",
+            path, format, record_rate, channels
+        );
+
+        let (tx, rx) = mpsc::sync_channel::<Vec<f32>>(QUEUE_CHUNKS);
+        let handle = std::thread::Builder::new()
+            .name("recorder".into())
+            .spawn(move || {
+                let mut resampler = LinearResampler::new(channels, live_rate, record_rate);
+                let mut resampled = Vec::new();
+
+                for chunk in rx {
+                    let samples = if resampler.is_passthrough() {
+                        &chunk
+                    } else {
+                        resampled.clear();
+                        resampler.process(&chunk, &mut resampled);
+                        &resampled
+                    };
+                    if let Err(e) = write_samples(&mut writer, format, samples) {
+                        error!("Failed to write recording: {}", e);
+                        return;
+                    }
+                }
+
+                match writer.finalize() {
+                    Ok(()) => info!("Recording saved to {:?}", path),
+                    Err(e) => error!("Failed to finalize recording {:?}: {}", path, e),
+                }
+            })?;
+
+        Ok(Self {
+            tx: RefCell::new(Some(tx)),
+            handle: RefCell::new(Some(handle)),
+            dropped: Cell::new(0),
+        })
+    }
+
+    /// Queue output samples for recording without blocking the caller.
+    pub fn push(&self, samples: &[f32]) {
+        if let Some(tx) = self.tx.borrow().as_ref() {
+            match tx.try_send(samples.to_vec()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    if self.dropped.get() == 0 {
+                        warn!("Recorder can't keep up - dropping audio from the recording");
+                    }
+                    self.dropped.set(self.dropped.get() + samples.len() as u64);
+                }
+                Err(TrySendError::Disconnected(_)) => {}
+            }
+        }
+    }
+
+    /// Flush queued audio and finalize the WAV header.
+    pub fn finish(&self) {
+        // Closing the channel ends the writer loop
+        self.tx.borrow_mut().take();
+        if let Some(handle) = self.handle.borrow_mut().take() {
+            let _ = handle.join();
+        }
+        let dropped = self.dropped.replace(0);
+        if dropped > 0 {
+            warn!("{} samples were dropped from the recording", dropped);
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+fn write_samples<W: std::io::Write + std::io::Seek>(
+    writer: &mut hound::WavWriter<W>,
+    format: RecordFormat,
+    samples: &[f32],
+) -> hound::Result<()> {
+    for &sample in samples {
+        let sample = sample.clamp(-1.0, 1.0);
+        match format {
+            RecordFormat::S16 => writer.write_sample((sample * i16::MAX as f32) as i16)?,
+            RecordFormat::S24 => writer.write_sample((sample * 8_388_607.0) as i32)?,
+            RecordFormat::F32 => writer.write_sample(sample)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_48k_output_at_44_1k() {
+        let path =
+            std::env::temp_dir().join(format!("virtual-mic-record-{}.wav", std::process::id()));
+        let recorder = Recorder::start(path.clone(), RecordFormat::S16, 2, 48_000, 44_100).unwrap();
+        // One second of a 440 Hz tone, in the 10 ms blocks the stream plays
+        let tone: Vec<f32> = (0..48_000)
+            .flat_map(|i| [(2.0 * std::f32::consts::PI * 440.0 * i as f32 / 48_000.0).sin(); 2])
+            .collect();
+        for block in tone.chunks(2 * 480) {
+            recorder.push(block);
+        }
+        recorder.finish();
+
+        let reader = hound::WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        let frames = reader.duration();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((spec.sample_rate, spec.channels), (44_100, 2));
+        assert!(frames.abs_diff(44_100) <= 2, "{} frames", frames);
+    }
+}
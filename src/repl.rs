@@ -0,0 +1,120 @@
+//! `--repl`: control commands typed on stdin, one per line.
+//!
+//! Lines are parsed like the control socket's, with a few shorthands for
+//! typing (`play`, `vol`), and carried out by the main loop. Answers and
+//! errors are printed; commands that just act print nothing. A prompt is
+//! shown when stdin is a terminal. The end of stdin stops reading but leaves
+//! playback running.
+
+use crate::control::{ControlCommand, Request};
+use anyhow::Result;
+use serde_json::Value;
+use std::io::{BufRead, Write};
+use std::sync::mpsc::Sender;
+use tracing::{debug, info};
+
+/// Printed for `help`.
+const HELP: &str = "\
+play [<path>]         resume, or play a file in place of the current one
+pause, toggle-pause   pause or resume
+seek <time>           jump to a time; +<time> and -<time> jump from here
+vol <level>           set the volume (0.0 - 2.0); +<step> and -<step> change it
+next, prev, track <n> move through the playlist
+enqueue <path>        add to the end of the playlist
+mute, unmute          silence the microphone or not
+status, playlist      show what is playing, or the playlist
+quit                  shut down";
+
+/// Start reading commands from stdin on a background thread, passing them to
+/// `tx`.
+pub fn start(tx: Sender<Request>) -> Result<()> {
+    // SAFETY: plain libc calls on stdin
+    let terminal = unsafe {
+        let terminal = libc::isatty(libc::STDIN_FILENO) == 1;
+        if terminal {
+            // Put in the background, reads fail instead of stopping the process
+            libc::signal(libc::SIGTTIN, libc::SIG_IGN);
+        }
+        terminal
+    };
+    std::thread::Builder::new()
+        .name("repl".into())
+        .spawn(move || read(terminal, tx))?;
+    info!("Reading commands from stdin; type help for the list");
+    Ok(())
+}
+
+fn read(prompt: bool, tx: Sender<Request>) {
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        if prompt {
+            print!("> ");
+            let _ = std::io::stdout().flush();
+        }
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                debug!("Stopped reading commands: {}", e);
+                return;
+            }
+            None => {
+                debug!("End of stdin, no more commands");
+                return;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "help" {
+            println!("{}", HELP);
+            continue;
+        }
+        let command = match parse(line) {
+            Ok(command) => command,
+            Err(e) => {
+                println!("error: {}", e);
+                continue;
+            }
+        };
+        let playlist = matches!(command, ControlCommand::Playlist);
+        let (request, response) = Request::new(command);
+        if tx.send(request).is_err() {
+            return;
+        }
+        match response.recv() {
+            Ok(Ok(answer)) if playlist => print_playlist(&answer),
+            Ok(Ok(answer)) if !answer.is_empty() => println!("{}", answer),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => println!("error: {}", e),
+            // The main loop is shutting down
+            Err(_) => return,
+        }
+    }
+}
+
+/// Parse a line, taking `play` and `vol` as shorthands.
+fn parse(line: &str) -> Result<ControlCommand, String> {
+    let (name, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    match name {
+        "play" if arg.trim().is_empty() => Ok(ControlCommand::Resume),
+        "play" => ControlCommand::parse(&format!("load {}", arg)),
+        "vol" => ControlCommand::parse(&format!("volume {}", arg)),
+        _ => ControlCommand::parse(line),
+    }
+}
+
+/// Print the JSON answer to `playlist` as a numbered list.
+fn print_playlist(answer: &str) {
+    let entries = match serde_json::from_str::<Value>(answer) {
+        Ok(Value::Array(entries)) => entries,
+        _ => return println!("{}", answer),
+    };
+    for (i, entry) in entries.iter().enumerate() {
+        let path = entry["path"].as_str().unwrap_or("");
+        match entry["title"].as_str() {
+            Some(title) => println!("{:>3}. {} ({})", i + 1, title, path),
+            None => println!("{:>3}. {}", i + 1, path),
+        }
+    }
+}
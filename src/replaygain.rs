@@ -0,0 +1,106 @@
+//! ReplayGain and R128 loudness tags, read from file metadata.
+//!
+//! ReplayGain tags (`REPLAYGAIN_TRACK_GAIN=-6.48 dB`, with matching album and
+//! peak tags) give the gain that brings a file to the ReplayGain reference
+//! level of -18 LUFS. Opus files carry `R128_TRACK_GAIN` and `R128_ALBUM_GAIN`
+//! instead, in 1/256 dB steps towards -23 LUFS; those are raised by 5 dB so
+//! both kinds of file come out at the same level.
+
+use symphonia::core::meta::{StandardTagKey, Tag};
+
+/// From the R128 reference level (-23 LUFS) to ReplayGain's (-18 LUFS).
+const R128_OFFSET_DB: f32 = 5.0;
+
+/// Which of a file's gains `--replay-gain` applies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReplayGainMode {
+    /// Bring every file to the same loudness.
+    Track,
+    /// Keep the loudness differences between the tracks of an album.
+    Album,
+}
+
+/// The gains and peaks found in a file's tags, in dB and linear amplitude.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ReplayGain {
+    track_gain: Option<f32>,
+    track_peak: Option<f32>,
+    album_gain: Option<f32>,
+    album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    /// Read the gain tags, if there are any.
+    pub fn from_tags<'a>(tags: impl IntoIterator<Item = &'a Tag>) -> Option<Self> {
+        let mut found = Self::default();
+        for tag in tags {
+            let value = tag.value.to_string();
+            let key = tag.key.to_ascii_uppercase();
+            match (tag.std_key, key.as_str()) {
+                (Some(StandardTagKey::ReplayGainTrackGain), _) | (_, "REPLAYGAIN_TRACK_GAIN") => {
+                    found.track_gain = parse_db(&value)
+                }
+                (Some(StandardTagKey::ReplayGainTrackPeak), _) | (_, "REPLAYGAIN_TRACK_PEAK") => {
+                    found.track_peak = parse_peak(&value)
+                }
+                (Some(StandardTagKey::ReplayGainAlbumGain), _) | (_, "REPLAYGAIN_ALBUM_GAIN") => {
+                    found.album_gain = parse_db(&value)
+                }
+                (Some(StandardTagKey::ReplayGainAlbumPeak), _) | (_, "REPLAYGAIN_ALBUM_PEAK") => {
+                    found.album_peak = parse_peak(&value)
+                }
+                (_, "R128_TRACK_GAIN") => found.track_gain = parse_r128(&value),
+                (_, "R128_ALBUM_GAIN") => found.album_gain = parse_r128(&value),
+                _ => {}
+            }
+        }
+        (found.track_gain.is_some() || found.album_gain.is_some()).then_some(found)
+    }
+
+    /// The gain in dB for `mode`, falling back to the other one if that is all
+    /// the file has, with its peak.
+    fn pick(&self, mode: ReplayGainMode) -> Option<(f32, Option<f32>)> {
+        let track = self.track_gain.map(|gain| (gain, self.track_peak));
+        let album = self.album_gain.map(|gain| (gain, self.album_peak));
+        match mode {
+            ReplayGainMode::Track => track.or(album),
+            ReplayGainMode::Album => album.or(track),
+        }
+    }
+
+    /// The linear gain to apply for `mode`, `preamp` dB included, held down
+    /// so the tagged peak doesn't go over full scale.
+    pub fn gain(&self, mode: ReplayGainMode, preamp: f32) -> Option<f32> {
+        let (db, peak) = self.pick(mode)?;
+        let gain = 10f32.powf((db + preamp) / 20.0);
+        Some(match peak {
+            Some(peak) if peak > 0.0 => gain.min(1.0 / peak),
+            _ => gain,
+        })
+    }
+}
+
+/// `-6.48 dB`, `+1.2dB` or a bare number.
+fn parse_db(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let number = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .or_else(|| value.strip_suffix("DB"))
+        .unwrap_or(value);
+    number.trim().parse().ok().filter(|db: &f32| db.is_finite())
+}
+
+fn parse_peak(value: &str) -> Option<f32> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|peak: &f32| peak.is_finite() && *peak >= 0.0)
+}
+
+/// A Q7.8 fixed point gain in dB, relative to -23 LUFS.
+fn parse_r128(value: &str) -> Option<f32> {
+    let steps: i16 = value.trim().parse().ok()?;
+    Some(steps as f32 / 256.0 + R128_OFFSET_DB)
+}
@@ -0,0 +1,81 @@
+//! Streaming linear resampler for interleaved audio.
+
+/// Linear-interpolating sample rate converter that keeps its position and the
+/// last input frame between calls, so consecutive chunks join without gaps.
+pub struct LinearResampler {
+    channels: usize,
+    /// Input frames advanced per output frame (`input_rate / output_rate`).
+    step: f64,
+    /// Position of the next output frame, relative to the first buffered frame.
+    pos: f64,
+    /// Last input frame of the previous chunk, if any.
+    prev: Option<Vec<f32>>,
+}
+
+impl LinearResampler {
+    pub fn new(channels: usize, input_rate: u32, output_rate: u32) -> Self {
+        Self {
+            channels,
+            step: input_rate as f64 / output_rate as f64,
+            pos: 0.0,
+            prev: None,
+        }
+    }
+
+    /// Also change the tempo (and with it the pitch) by `speed`, e.g. 2.0 to play
+    /// twice as fast.
+    pub fn with_speed(mut self, speed: f64) -> Self {
+        self.step *= speed;
+        self
+    }
+
+    /// Forget the carried-over frame and position, e.g. before starting a new file.
+    pub fn reset(&mut self) {
+        self.pos = 0.0;
+        self.prev = None;
+    }
+
+    /// Whether the input and output rates match and samples pass through untouched.
+    pub fn is_passthrough(&self) -> bool {
+        self.step == 1.0
+    }
+
+    /// Resample a chunk of interleaved frames, appending the result to `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if self.is_passthrough() {
+            output.extend_from_slice(input);
+            return;
+        }
+
+        let channels = self.channels;
+        let input_frames = input.len() / channels;
+        if input_frames == 0 {
+            return;
+        }
+
+        // Frame `i` of the working sequence: the carried-over frame (if any) then the input
+        let offset = usize::from(self.prev.is_some());
+        let len = input_frames + offset;
+        let frame = |i: usize| -> &[f32] {
+            match (&self.prev, i) {
+                (Some(prev), 0) => prev,
+                _ => &input[(i - offset) * channels..(i - offset + 1) * channels],
+            }
+        };
+
+        let mut pos = self.pos;
+        while (pos.floor() as usize) + 1 < len {
+            let idx0 = pos.floor() as usize;
+            let frac = (pos - idx0 as f64) as f32;
+            let (a, b) = (frame(idx0), frame(idx0 + 1));
+            for ch in 0..channels {
+                output.push(a[ch] * (1.0 - frac) + b[ch] * frac);
+            }
+            pos += self.step;
+        }
+
+        // The last frame becomes frame 0 of the next chunk
+        self.pos = pos - (len - 1) as f64;
+        self.prev = Some(input[(input_frames - 1) * channels..input_frames * channels].to_vec());
+    }
+}
@@ -0,0 +1,576 @@
+//! `--http`: a REST API answering in JSON, for test harnesses and tools that
+//! speak HTTP more easily than Unix sockets.
+//!
+//! | Endpoint | Body | Command |
+//! |----------|------|---------|
+//! | `GET /` | | the web UI |
+//! | `GET /status` | | `status`, its fields as an object |
+//! | `GET /playlist` | | `playlist`, as `{"entries": [...]}` |
+//! | `GET /device` | | the virtual device, as [`Device`] |
+//! | `POST /play` | none, or `{"path": ...}` | `resume`, or `load <path>` |
+//! | `POST /pause` | | `pause` |
+//! | `POST /next`, `POST /previous` | | `next`, `prev` |
+//! | `POST /enqueue` | `{"path": ...}` | `enqueue <path>` |
+//! | `POST /upload?name=<file>` | the file's bytes | `enqueue` of the stored file |
+//! | `GET /volume` | | the volume |
+//! | `PUT /volume` | `{"level": ...}` or `{"change": ...}` | `volume` |
+//! | `POST /command` | a control command, as text | that command |
+//!
+//! Requests are handled by a small pool of threads, each waiting for the main
+//! loop to carry out its command, so a slow `load` holds up no other request.
+//!
+//! Web pages the user visits can send requests here too. Any request whose
+//! `Origin` isn't the API's own is refused, and so, without `--auth-token`, is
+//! one whose `Host` is a domain name other than `localhost`, which is how a
+//! DNS rebinding attack would reach it.
+//!
+//! With `--auth-token`, every endpoint but the web UI's page needs the token,
+//! as `Authorization: Bearer <token>`; see [`crate::auth`].
+//!
+//! Uploads are stored in a spool directory under the system's temporary
+//! directory, named for their order and `name`, whose extension tells the
+//! decoder their format. The spool is made private to the user when the
+//! server starts, refusing one that already exists, and removed with the
+//! server. An upload is deleted once playback moves on from it, and the
+//! spool holds at most [`MAX_SPOOL`] bytes of uploads waiting to play.
+
+use crate::auth::{self, Access};
+use crate::control::{ControlCommand, Events, Request, VolumeChange};
+use crate::{http, webhook};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::fs::{DirBuilder, File};
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use tiny_http::{Header, Method, Response, Server};
+use tracing::{debug, info, warn};
+
+/// The web UI: one page that drives the endpoints below it.
+const UI: &str = include_str!("../assets/ui.html");
+
+/// Largest request body read.
+const MAX_BODY: u64 = 64 * 1024;
+
+/// Threads serving requests.
+const WORKERS: usize = 4;
+
+/// Largest file `POST /upload` accepts.
+const MAX_UPLOAD: u64 = 256 * 1024 * 1024;
+
+/// Most bytes of uploads the spool holds at once.
+const MAX_SPOOL: u64 = 1024 * 1024 * 1024;
+
+/// The virtual device, as `GET /device` describes it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Device {
+    /// The `--name` of the microphone.
+    pub name: String,
+    /// The source applications record from, and the sink it is fed through.
+    pub source: String,
+    pub sink: String,
+    pub rate: u32,
+    pub channels: u32,
+    /// The sample format, as PulseAudio names it.
+    pub format: String,
+    pub pid: u32,
+}
+
+/// A response: its status code and JSON body.
+type Reply = (u16, Value);
+
+/// Where uploaded files wait to be played.
+struct Spool {
+    dir: PathBuf,
+    /// Uploads so far, numbering their files so equal names don't collide.
+    count: AtomicUsize,
+    /// Bytes stored, plus those set aside for uploads still arriving.
+    used: Mutex<u64>,
+}
+
+impl Spool {
+    /// Set aside `bytes` for an upload, unless the spool would go over
+    /// [`MAX_SPOOL`].
+    fn reserve(&self, bytes: u64) -> bool {
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        if *used + bytes > MAX_SPOOL {
+            return false;
+        }
+        *used += bytes;
+        true
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut used = self.used.lock().unwrap_or_else(|e| e.into_inner());
+        *used = used.saturating_sub(bytes);
+    }
+
+    /// Delete a stored upload and give back its room.
+    fn remove(&self, path: &Path) {
+        let Ok(metadata) = std::fs::metadata(path) else {
+            return;
+        };
+        match std::fs::remove_file(path) {
+            Ok(()) => self.release(metadata.len()),
+            Err(e) => warn!("Failed to remove the upload {:?}: {}", path, e),
+        }
+    }
+
+    /// Create the spool at `dir`, readable only by this user. It must be new:
+    /// one already there, in a shared directory, may be someone else's.
+    fn create(dir: PathBuf) -> Result<Self> {
+        DirBuilder::new()
+            .mode(0o700)
+            .create(&dir)
+            .map_err(|e| anyhow!("Failed to create the upload spool {:?}: {}", dir, e))?;
+        Ok(Self {
+            dir,
+            count: AtomicUsize::new(0),
+            used: Mutex::new(0),
+        })
+    }
+}
+
+/// Serves the API; the listener closes, and the spool is removed, when dropped.
+pub struct RestServer {
+    server: Arc<Server>,
+    spool: Arc<Spool>,
+}
+
+impl RestServer {
+    /// Bind `addr` and serve on a background thread, passing commands to `tx`.
+    pub fn start(
+        addr: SocketAddr,
+        device: Device,
+        access: Access,
+        events: Events,
+        tx: Sender<Request>,
+    ) -> Result<Self> {
+        #[cfg(feature = "tls")]
+        let server = match &access.tls {
+            Some(tls) => Server::https(
+                addr,
+                tiny_http::SslConfig {
+                    certificate: tls.cert.clone(),
+                    private_key: tls.key.clone(),
+                },
+            ),
+            None => Server::http(addr),
+        };
+        #[cfg(not(feature = "tls"))]
+        let server = Server::http(addr);
+        let server =
+            Arc::new(server.map_err(|e| anyhow!("Failed to serve HTTP on {}: {}", addr, e))?);
+        let scheme = if access.secure() { "https" } else { "http" };
+        info!("HTTP API listening on {}://{}", scheme, addr);
+        access.warn_if_open("The HTTP API", addr);
+
+        let spool = Arc::new(Spool::create(
+            std::env::temp_dir().join(format!("virtual-mic-uploads-{}", std::process::id())),
+        )?);
+
+        let uploads = spool.clone();
+        let events = events.subscribe();
+        std::thread::Builder::new()
+            .name("http-api-spool".into())
+            .spawn(move || clean_spool(&uploads, events))?;
+
+        let device = Arc::new(device);
+        for _ in 0..WORKERS {
+            let (listener, device, spool) = (server.clone(), device.clone(), spool.clone());
+            let (access, tx) = (access.clone(), tx.clone());
+            std::thread::Builder::new()
+                .name("http-api".into())
+                .spawn(move || {
+                    for request in listener.incoming_requests() {
+                        serve(request, &device, &access, &spool, &tx);
+                    }
+                })?;
+        }
+
+        Ok(Self { server, spool })
+    }
+}
+
+impl Drop for RestServer {
+    fn drop(&mut self) {
+        // Each call lets one worker go
+        for _ in 0..WORKERS {
+            self.server.unblock();
+        }
+        if self.spool.dir.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&self.spool.dir) {
+                warn!("Failed to remove uploads in {:?}: {}", self.spool.dir, e);
+            }
+        }
+    }
+}
+
+fn serve(
+    mut request: tiny_http::Request,
+    device: &Device,
+    access: &Access,
+    spool: &Spool,
+    requests: &Sender<Request>,
+) {
+    let path = match request.url().split('?').next().unwrap_or_default() {
+        "/" => "/".to_string(),
+        path => path.trim_end_matches('/').to_string(),
+    };
+    let site = same_site(
+        header(&request, "Host"),
+        header(&request, "Origin"),
+        access.needs_token(),
+    );
+    let (status, body, content_type) = if let Err(e) = site {
+        let (status, json) = error(403, e.to_string());
+        (status, json.to_string(), "application/json")
+    } else if request.method() == &Method::Get && matches!(path.as_str(), "/" | "/index.html") {
+        (200, UI.to_string(), "text/html; charset=utf-8")
+    } else if !access.allows(token(&request)) {
+        let (status, json) = error(401, "a valid token is required".to_string());
+        (status, json.to_string(), "application/json")
+    } else if request.method() == &Method::Post && path == "/upload" {
+        let (status, json) = upload(&mut request, spool, requests);
+        (status, json.to_string(), "application/json")
+    } else {
+        let mut body = String::new();
+        let (status, json) = match request.as_reader().take(MAX_BODY).read_to_string(&mut body) {
+            Ok(_) => route(request.method(), &path, &body, device, requests),
+            Err(e) => error(400, format!("unreadable body: {}", e)),
+        };
+        (status, json.to_string(), "application/json")
+    };
+    debug!("HTTP {} {}: {}", request.method(), request.url(), status);
+    let content_type = Header::from_bytes("Content-Type", content_type).unwrap();
+    let mut response = Response::from_string(body)
+        .with_status_code(status)
+        .with_header(content_type);
+    if status == 401 {
+        response.add_header(Header::from_bytes("WWW-Authenticate", "Bearer").unwrap());
+    }
+    if let Err(e) = request.respond(response) {
+        warn!("Failed to answer an HTTP request: {}", e);
+    }
+}
+
+/// The token a request's `Authorization` header presents.
+fn token(request: &tiny_http::Request) -> Option<&str> {
+    header(request, "Authorization").and_then(auth::bearer)
+}
+
+/// The value of the request's header called `name`.
+fn header<'a>(request: &'a tiny_http::Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str())
+}
+
+/// Check that a request comes from the API's own page or a client that isn't
+/// a browser, given its `Host` and `Origin` headers. Without a token, a
+/// `Host` that is a domain name (but `localhost`) is refused as well: a page
+/// can point its own name at 127.0.0.1, and is then its own origin.
+fn same_site(host: Option<&str>, origin: Option<&str>, token: bool) -> Result<(), &'static str> {
+    let host = host.map(str::trim).unwrap_or_default();
+    if let Some(origin) = origin {
+        let authority = origin.split_once("://").map(|(_, authority)| authority);
+        if !authority.is_some_and(|authority| authority.eq_ignore_ascii_case(host)) {
+            return Err("requests from other sites are refused");
+        }
+    }
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    if !token && !name.eq_ignore_ascii_case("localhost") && name.parse::<IpAddr>().is_err() {
+        return Err("use an IP address or localhost, or set --auth-token");
+    }
+    Ok(())
+}
+
+/// Carry out a request for the endpoint at `path`.
+fn route(
+    method: &Method,
+    path: &str,
+    body: &str,
+    device: &Device,
+    requests: &Sender<Request>,
+) -> Reply {
+    let command = match (method, path) {
+        (Method::Get, "/status") => ControlCommand::Status,
+        (Method::Get, "/playlist") => ControlCommand::Playlist,
+        (Method::Get, "/device") => return (200, json!(device)),
+        (Method::Post, "/play") => match body_path(body) {
+            Ok(Some(path)) => ControlCommand::Load(path),
+            Ok(None) => ControlCommand::Resume,
+            Err(e) => return error(400, e),
+        },
+        (Method::Post, "/pause") => ControlCommand::Pause,
+        (Method::Post, "/next") => ControlCommand::Next,
+        (Method::Post, "/previous") => ControlCommand::Previous,
+        (Method::Post, "/enqueue") => match body_path(body) {
+            Ok(Some(path)) => ControlCommand::Enqueue(path),
+            Ok(None) => return error(400, "enqueue needs a path".to_string()),
+            Err(e) => return error(400, e),
+        },
+        (Method::Get, "/volume") => {
+            return match run(ControlCommand::Status, requests) {
+                (200, status) => (200, json!({"volume": status["volume"]})),
+                failed => failed,
+            };
+        }
+        (Method::Put | Method::Post, "/volume") => match volume(body) {
+            Ok(change) => ControlCommand::Volume(change),
+            Err(e) => return error(400, e),
+        },
+        (Method::Post, "/command") => match ControlCommand::parse(body) {
+            Ok(command) => command,
+            Err(e) => return error(400, e),
+        },
+        (
+            _,
+            "/" | "/index.html" | "/status" | "/playlist" | "/device" | "/play" | "/pause"
+            | "/next" | "/previous" | "/enqueue" | "/upload" | "/volume" | "/command",
+        ) => return error(405, format!("{} isn't allowed on {}", method, path)),
+        _ => return error(404, format!("no such endpoint: {}", path)),
+    };
+    run(command, requests)
+}
+
+/// Store the body of a `POST /upload` in the spool and queue it.
+fn upload(request: &mut tiny_http::Request, spool: &Spool, requests: &Sender<Request>) -> Reply {
+    let Some(name) = upload_name(request.url()) else {
+        return error(
+            400,
+            "upload needs a file name, e.g. /upload?name=clip.wav".to_string(),
+        );
+    };
+    if request
+        .body_length()
+        .is_some_and(|length| length as u64 > MAX_UPLOAD)
+    {
+        return error(
+            413,
+            format!("uploads are limited to {} MB", MAX_UPLOAD >> 20),
+        );
+    }
+
+    // Room for the whole body, or the largest upload if its length isn't given
+    let room = request
+        .body_length()
+        .map_or(MAX_UPLOAD, |length| length as u64);
+    if !spool.reserve(room) {
+        return error(
+            507,
+            "the upload spool is full; try again once the queued uploads have played".to_string(),
+        );
+    }
+
+    let number = spool.count.fetch_add(1, Ordering::Relaxed) + 1;
+    let path = spool.dir.join(format!("{}-{}", number, name));
+    let stored = File::options()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .and_then(|mut file| std::io::copy(&mut request.as_reader().take(room + 1), &mut file));
+    let reply = match stored {
+        Ok(length) if length > 0 && length <= room => {
+            info!("Stored an upload of {} bytes as {:?}", length, path);
+            spool.release(room - length);
+            let reply = run(ControlCommand::Enqueue(path.clone()), requests);
+            if reply.0 != 200 {
+                spool.remove(&path);
+                return reply;
+            }
+            return (200, json!({"ok": true, "path": path}));
+        }
+        Ok(0) => error(400, "the upload is empty".to_string()),
+        Ok(_) => error(
+            413,
+            format!("uploads are limited to {} MB", MAX_UPLOAD >> 20),
+        ),
+        Err(e) => error(500, format!("failed to store the upload: {}", e)),
+    };
+    let _ = std::fs::remove_file(&path);
+    spool.release(room);
+    reply
+}
+
+/// Delete each upload once playback moves on from it: another file starts,
+/// or the playlist ends.
+fn clean_spool(spool: &Spool, events: Receiver<String>) {
+    let mut playing: Option<PathBuf> = None;
+    for event in events {
+        let next = match event.split_once(' ') {
+            Some(("started", path)) => Some(PathBuf::from(path)),
+            None if event == "ended" => None,
+            _ => continue,
+        };
+        if let Some(done) = playing.take().filter(|done| Some(done) != next.as_ref()) {
+            if done.parent() == Some(spool.dir.as_path()) {
+                debug!("Done with the upload {:?}", done);
+                spool.remove(&done);
+            }
+        }
+        playing = next;
+    }
+}
+
+/// The `name` an upload's URL gives, without any directories.
+fn upload_name(url: &str) -> Option<String> {
+    let (_, query) = url.split_once('?')?;
+    let name = http::query_param(query, "name")?;
+    let name = Path::new(&name).file_name()?.to_str()?;
+    Some(name.to_string())
+}
+
+/// Hand a command to the main loop and wait for its result.
+fn run(command: ControlCommand, requests: &Sender<Request>) -> Reply {
+    let fields = matches!(command, ControlCommand::Status | ControlCommand::Position);
+    let playlist = command == ControlCommand::Playlist;
+    let (request, response) = Request::new(command);
+    if requests.send(request).is_err() {
+        return error(503, "shutting down".to_string());
+    }
+    match response.recv() {
+        Ok(Ok(answer)) if fields => (200, Value::Object(webhook::fields(&answer))),
+        Ok(Ok(answer)) if playlist => match serde_json::from_str::<Value>(&answer) {
+            Ok(entries) => (200, json!({ "entries": entries })),
+            Err(e) => error(500, e.to_string()),
+        },
+        Ok(Ok(_)) => (200, json!({"ok": true})),
+        // The command was understood but couldn't be carried out
+        Ok(Err(e)) => error(422, e),
+        Err(_) => error(503, "shutting down".to_string()),
+    }
+}
+
+/// The JSON object of a body; an empty body is an empty object.
+fn object(body: &str) -> Result<Map<String, Value>, String> {
+    if body.trim().is_empty() {
+        return Ok(Map::new());
+    }
+    match serde_json::from_str(body) {
+        Ok(Value::Object(object)) => Ok(object),
+        Ok(_) => Err("expected a JSON object".to_string()),
+        Err(e) => Err(format!("invalid JSON: {}", e)),
+    }
+}
+
+/// The `path` of a body, if it has one.
+fn body_path(body: &str) -> Result<Option<PathBuf>, String> {
+    match object(body)?.get("path") {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(path)) if !path.is_empty() => Ok(Some(PathBuf::from(path))),
+        Some(_) => Err("path must be a non-empty string".to_string()),
+    }
+}
+
+/// The change a `PUT /volume` body asks for.
+fn volume(body: &str) -> Result<VolumeChange, String> {
+    let object = object(body)?;
+    let number = |key: &str| {
+        object
+            .get(key)
+            .map(|value| value.as_f64().filter(|value| value.is_finite()))
+    };
+    match (number("level"), number("change")) {
+        (Some(Some(level)), None) => Ok(VolumeChange::To(level as f32)),
+        (None, Some(Some(change))) => Ok(VolumeChange::By(change as f32)),
+        _ => Err("expected {\"level\": <0.0 - 2.0>} or {\"change\": <step>}".to_string()),
+    }
+}
+
+fn error(status: u16, message: String) -> Reply {
+    (status, json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn spool_is_private_and_new() {
+        let dir = std::env::temp_dir().join(format!("virtual-mic-spool-{}", std::process::id()));
+        let spool = Spool::create(dir.clone()).unwrap();
+        let mode = std::fs::metadata(&spool.dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // One already there isn't taken over
+        assert!(Spool::create(dir.clone()).is_err());
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn spool_frees_the_room_of_removed_uploads() {
+        let dir = std::env::temp_dir().join(format!("virtual-mic-room-{}", std::process::id()));
+        let spool = Spool::create(dir.clone()).unwrap();
+        assert!(spool.reserve(MAX_SPOOL - 4));
+        assert!(!spool.reserve(5));
+
+        let upload = dir.join("1-a.wav");
+        std::fs::write(&upload, b"RIFF").unwrap();
+        spool.remove(&upload);
+        assert!(!upload.exists());
+        assert!(spool.reserve(8));
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_other_sites() {
+        // Clients that aren't browsers send no Origin
+        assert!(same_site(Some("127.0.0.1:8080"), None, false).is_ok());
+        assert!(same_site(Some("localhost:8080"), Some("http://localhost:8080"), false).is_ok());
+        assert!(same_site(Some("[::1]:8080"), Some("http://[::1]:8080"), false).is_ok());
+        assert!(same_site(Some("192.168.1.5:8080"), None, false).is_ok());
+
+        assert!(same_site(Some("127.0.0.1:8080"), Some("https://evil.example"), false).is_err());
+        assert!(same_site(Some("127.0.0.1:8080"), Some("null"), false).is_err());
+        // DNS rebinding: the page's own name, pointed at this machine
+        assert!(same_site(
+            Some("evil.example:8080"),
+            Some("http://evil.example:8080"),
+            false
+        )
+        .is_err());
+        assert!(same_site(None, None, false).is_err());
+        // A token keeps the attacker out, so names are fine then
+        assert!(same_site(Some("mic.lan:8080"), Some("http://mic.lan:8080"), true).is_ok());
+        assert!(same_site(Some("mic.lan:8080"), Some("https://evil.example"), true).is_err());
+    }
+
+    #[test]
+    fn reads_the_path_of_a_body() {
+        assert_eq!(body_path(""), Ok(None));
+        assert_eq!(body_path(r#"{"path": null}"#), Ok(None));
+        assert_eq!(
+            body_path(r#"{"path": "/srv/clip.wav"}"#),
+            Ok(Some(PathBuf::from("/srv/clip.wav")))
+        );
+        for body in [r#"{"path": ""}"#, r#"{"path": 3}"#, "[]", "{"] {
+            assert!(body_path(body).is_err(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn reads_a_volume_level_or_change() {
+        assert_eq!(volume(r#"{"level": 0.5}"#), Ok(VolumeChange::To(0.5)));
+        assert_eq!(volume(r#"{"change": -0.1}"#), Ok(VolumeChange::By(-0.1)));
+        for body in [
+            "",
+            r#"{"level": "loud"}"#,
+            r#"{"level": 0.5, "change": 0.1}"#,
+            r#"{"volume": 0.5}"#,
+        ] {
+            assert!(volume(body).is_err(), "{}", body);
+        }
+    }
+}
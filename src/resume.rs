@@ -0,0 +1,116 @@
+//! `--resume`: where the last run of an instance stopped, so the next run can
+//! carry on from there.
+//!
+//! The playing entry and the position in it are written on shutdown to
+//! `$XDG_STATE_HOME/virtual-mic/<name>.resume` (`~/.local/state` by default),
+//! which, unlike the instance state file, survives a reboot. A run that plays
+//! to the end removes the file, so the next one starts over.
+
+use crate::playlist::{Entry, Playlist};
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Where playback stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResumePoint {
+    /// 1-based playlist position of the entry.
+    pub track: usize,
+    pub file: PathBuf,
+    /// The cue sheet track, if the entry was one.
+    pub title: Option<String>,
+    /// From the start of the entry.
+    pub position: Duration,
+}
+
+impl ResumePoint {
+    /// The resume point saved for the instance `name`, if there is one.
+    pub fn load(name: &str) -> Option<Self> {
+        let path = resume_path(name)?;
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                debug!("No resume point in {:?}: {}", path, e);
+                return None;
+            }
+        };
+        let point = Self::parse(&contents);
+        if point.is_none() {
+            warn!("Ignoring malformed resume file {:?}", path);
+        }
+        point
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let (mut track, mut file, mut title, mut position) = (None, None, None, None);
+        for line in contents.lines() {
+            match line.split_once('=') {
+                Some(("track", value)) => track = value.trim().parse().ok(),
+                Some(("file", value)) => file = Some(PathBuf::from(value)),
+                Some(("title", value)) => title = Some(value.to_string()),
+                Some(("position_secs", value)) => {
+                    position = value
+                        .trim()
+                        .parse()
+                        .ok()
+                        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                }
+                _ => {}
+            }
+        }
+        Some(Self {
+            track: track?,
+            file: file?,
+            title,
+            position: position?,
+        })
+    }
+
+    /// Save this point for the instance `name`, replacing the file atomically.
+    pub fn save(&self, name: &str) -> Result<()> {
+        let path = resume_path(name).ok_or_else(|| anyhow!("No home directory to save in"))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let mut contents = format!("track={}\nfile={}\n", self.track, self.file.display());
+        if let Some(title) = &self.title {
+            contents.push_str(&format!("title={}\n", title));
+        }
+        contents.push_str(&format!(
+            "position_secs={:.3}\n",
+            self.position.as_secs_f64()
+        ));
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Forget the saved point of the instance `name`, after playing to the end.
+    pub fn clear(name: &str) {
+        if let Some(path) = resume_path(name) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    /// The playlist position of this point's entry: the saved track if it
+    /// still holds the same entry, or else the first entry that matches.
+    pub fn find(&self, playlist: &Playlist) -> Option<usize> {
+        let matches = |entry: &Entry| entry.path == self.file && entry.title == self.title;
+        if playlist.get(self.track).is_some_and(matches) {
+            return Some(self.track);
+        }
+        (1..=playlist.len()).find(|&track| playlist.get(track).is_some_and(matches))
+    }
+}
+
+fn resume_path(name: &str) -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(dir.join("virtual-mic").join(format!("{}.resume", name)))
+}
@@ -0,0 +1,426 @@
+//! RTP receiver for `--listen-rtp`.
+//!
+//! A background thread receives packets into a jitter buffer ordered by
+//! sequence number. The audio thread plays them back once the buffer holds
+//! `--rtp-jitter` worth of audio, conceals packets that never arrive, and
+//! plays silence while no sender is active.
+
+use crate::feed::RatedChunk;
+use audiopus::coder::Decoder as OpusDecoder;
+use audiopus::packet::Packet as OpusPacket;
+use audiopus::{Channels as OpusChannels, MutSignals, SampleRate};
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Payload encodings understood for dynamic payload types.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RtpEncoding {
+    /// Signed 16-bit big-endian PCM (RFC 3551)
+    L16,
+    /// Opus (RFC 7587), always clocked at 48 kHz
+    Opus,
+}
+
+/// Static payload types for L16 at 44.1 kHz (RFC 3551).
+const PT_L16_STEREO: u8 = 10;
+const PT_L16_MONO: u8 = 11;
+
+/// Opus packets never exceed 120 ms, i.e. 5760 frames at 48 kHz.
+const MAX_OPUS_FRAMES: usize = 5760;
+
+/// Frames of silence returned per poll while nothing is playable (20 ms at 48 kHz).
+const IDLE_FRAMES: usize = 960;
+
+/// Packets the jitter buffer holds at most; the oldest are dropped beyond
+/// that. A sequence number this far past the next one to play starts the
+/// buffer afresh, as a sender restarting with the same SSRC does.
+const MAX_PACKETS: usize = 4096;
+
+/// How long a sender may go quiet before the buffer refills from scratch.
+const SENDER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where to listen and how to interpret dynamic payload types.
+#[derive(Debug, Clone, Copy)]
+pub struct RtpParams {
+    pub addr: SocketAddr,
+    pub encoding: RtpEncoding,
+    /// Clock rate and channel count of L16 with a dynamic payload type.
+    pub rate: u32,
+    pub channels: usize,
+    pub jitter: Duration,
+}
+
+struct RtpPacket {
+    payload_type: u8,
+    timestamp: u32,
+    payload: Vec<u8>,
+}
+
+/// Parse an RTP packet, skipping CSRCs, the header extension and padding.
+fn parse_packet(data: &[u8]) -> Option<(u16, u32, RtpPacket)> {
+    if data.len() < 12 || data[0] >> 6 != 2 {
+        return None;
+    }
+    let padding = data[0] & 0x20 != 0;
+    let extension = data[0] & 0x10 != 0;
+    let csrc_count = (data[0] & 0x0f) as usize;
+    let payload_type = data[1] & 0x7f;
+    let sequence = u16::from_be_bytes([data[2], data[3]]);
+    let timestamp = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    let ssrc = u32::from_be_bytes([data[8], data[9], data[10], data[11]]);
+
+    let mut start = 12 + csrc_count * 4;
+    if extension {
+        let words = u16::from_be_bytes([*data.get(start + 2)?, *data.get(start + 3)?]) as usize;
+        start += 4 + words * 4;
+    }
+    let mut end = data.len();
+    if padding {
+        end = end.checked_sub(*data.last()? as usize)?;
+    }
+    let payload = data.get(start..end)?.to_vec();
+
+    Some((
+        sequence,
+        ssrc,
+        RtpPacket {
+            payload_type,
+            timestamp,
+            payload,
+        },
+    ))
+}
+
+#[derive(Default)]
+struct JitterBuffer {
+    /// Packets by extended (32-bit wrap-free) sequence number.
+    packets: BTreeMap<u64, RtpPacket>,
+    /// Next sequence number to play, once playback has started.
+    next: Option<u64>,
+    highest: Option<u64>,
+    ssrc: Option<u32>,
+}
+
+impl JitterBuffer {
+    fn insert(&mut self, sequence: u16, ssrc: u32, packet: RtpPacket) {
+        if self.ssrc != Some(ssrc) {
+            if self.ssrc.is_some() {
+                info!("New RTP sender (SSRC {:08x})", ssrc);
+            }
+            *self = Self {
+                ssrc: Some(ssrc),
+                ..Self::default()
+            };
+        }
+
+        // Extend the 16-bit sequence number relative to the highest seen
+        let extended = match self.highest {
+            Some(highest) => {
+                let delta = sequence.wrapping_sub(highest as u16) as i16;
+                match highest.checked_add_signed(i64::from(delta)) {
+                    Some(extended) => extended,
+                    None => return,
+                }
+            }
+            // Start high enough that reordered packets before the first one still fit
+            None => (1 << 32) + u64::from(sequence),
+        };
+        if self.next.is_some_and(|next| extended < next) {
+            debug!("Dropping late RTP packet {}", sequence);
+            return;
+        }
+        if self
+            .next
+            .is_some_and(|next| extended - next >= MAX_PACKETS as u64)
+        {
+            info!("RTP sequence jumped to {}; rebuffering", sequence);
+            *self = Self {
+                ssrc: Some(ssrc),
+                ..Self::default()
+            };
+            return self.insert(sequence, ssrc, packet);
+        }
+        self.highest = self.highest.max(Some(extended));
+        self.packets.insert(extended, packet);
+        while self.packets.len() > MAX_PACKETS {
+            if let Some((oldest, _)) = self.packets.pop_first() {
+                debug!("RTP jitter buffer full; dropping packet {}", oldest as u16);
+                self.next = self.next.map(|next| next.max(oldest + 1));
+            }
+        }
+    }
+
+    /// RTP clock ticks between the oldest and newest buffered packets.
+    fn buffered_ticks(&self) -> u32 {
+        match (
+            self.packets.first_key_value(),
+            self.packets.last_key_value(),
+        ) {
+            (Some((_, first)), Some((_, last))) => last.timestamp.wrapping_sub(first.timestamp),
+            _ => 0,
+        }
+    }
+}
+
+/// What the audio thread should play next.
+enum Playout {
+    Packet(RtpPacket),
+    Lost,
+    Idle,
+}
+
+pub struct RtpReceiver {
+    params: RtpParams,
+    buffer: Arc<Mutex<JitterBuffer>>,
+    opus: Option<OpusDecoder>,
+    /// Rate, channels and length of the last packet, for concealment and silence.
+    last_format: (u32, usize, usize),
+}
+
+impl RtpReceiver {
+    /// Bind the socket and start receiving on a background thread.
+    pub fn start(params: RtpParams) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(params.addr)?;
+        socket.set_read_timeout(Some(SENDER_TIMEOUT))?;
+        info!(
+            "Listening for RTP on {} ({:?})",
+            socket.local_addr()?,
+            params.encoding
+        );
+
+        let buffer = Arc::new(Mutex::new(JitterBuffer::default()));
+        let shared = buffer.clone();
+        std::thread::Builder::new()
+            .name("rtp".into())
+            .spawn(move || receive(socket, shared))?;
+
+        Ok(Self {
+            params,
+            buffer,
+            opus: None,
+            last_format: (params.rate, params.channels, IDLE_FRAMES),
+        })
+    }
+
+    /// Clock rate and channel count for a payload type.
+    fn format(&self, payload_type: u8) -> (u32, usize) {
+        match (payload_type, self.params.encoding) {
+            (PT_L16_STEREO, _) => (44100, 2),
+            (PT_L16_MONO, _) => (44100, 1),
+            (_, RtpEncoding::Opus) => (48000, self.params.channels),
+            (_, RtpEncoding::L16) => (self.params.rate, self.params.channels),
+        }
+    }
+
+    fn is_opus(&self, payload_type: u8) -> bool {
+        self.params.encoding == RtpEncoding::Opus
+            && payload_type != PT_L16_STEREO
+            && payload_type != PT_L16_MONO
+    }
+
+    fn playout(&mut self) -> Playout {
+        let mut buffer = self.buffer.lock().unwrap();
+        let (rate, _, _) = self.last_format;
+        let jitter_ticks = (self.params.jitter.as_secs_f64() * rate as f64) as u32;
+
+        let Some(next) = buffer.next else {
+            // Wait until enough audio is buffered to ride out jitter
+            if buffer.packets.is_empty() || buffer.buffered_ticks() < jitter_ticks {
+                return Playout::Idle;
+            }
+            let (&first, _) = buffer.packets.first_key_value().unwrap();
+            debug!("RTP playout starting");
+            buffer.next = Some(first + 1);
+            let packet = buffer.packets.remove(&first).unwrap();
+            return Playout::Packet(packet);
+        };
+
+        if let Some(packet) = buffer.packets.remove(&next) {
+            buffer.next = Some(next + 1);
+            return Playout::Packet(packet);
+        }
+        if buffer.packets.is_empty() {
+            // Underrun: rebuffer before playing again
+            buffer.next = None;
+            return Playout::Idle;
+        }
+        // Later packets are here; give up on this one once they cover the jitter delay
+        if buffer.buffered_ticks() >= jitter_ticks {
+            buffer.next = Some(next + 1);
+            return Playout::Lost;
+        }
+        Playout::Idle
+    }
+
+    /// The next chunk of audio: a received packet, a concealed one, or silence.
+    pub fn next_chunk(&mut self) -> RatedChunk {
+        let (rate, channels, frames) = self.last_format;
+        match self.playout() {
+            Playout::Packet(packet) => {
+                let (rate, channels) = self.format(packet.payload_type);
+                let samples = if self.is_opus(packet.payload_type) {
+                    self.decode_opus(Some(&packet.payload), channels)
+                } else {
+                    packet
+                        .payload
+                        .chunks_exact(2)
+                        .map(|s| i16::from_be_bytes([s[0], s[1]]) as f32 / 32768.0)
+                        .collect()
+                };
+                self.last_format = (rate, channels, samples.len() / channels.max(1));
+                (samples, channels, Some(rate))
+            }
+            Playout::Lost if self.opus.is_some() => {
+                // Let libopus conceal the gap
+                let samples = self.decode_opus(None, channels);
+                (samples, channels, Some(rate))
+            }
+            Playout::Lost => (vec![0.0; frames * channels], channels, Some(rate)),
+            Playout::Idle => (vec![0.0; IDLE_FRAMES * channels], channels, Some(rate)),
+        }
+    }
+
+    fn decode_opus(&mut self, payload: Option<&[u8]>, channels: usize) -> Vec<f32> {
+        let opus_channels = if channels == 1 {
+            OpusChannels::Mono
+        } else {
+            OpusChannels::Stereo
+        };
+        if self.opus.is_none() {
+            match OpusDecoder::new(SampleRate::Hz48000, opus_channels) {
+                Ok(decoder) => self.opus = Some(decoder),
+                Err(e) => {
+                    warn!("Failed to create Opus decoder: {}", e);
+                    return Vec::new();
+                }
+            }
+        }
+        let decoder = self.opus.as_mut().unwrap();
+
+        let mut pcm = vec![0.0; MAX_OPUS_FRAMES * channels];
+        let input = payload.and_then(|payload| OpusPacket::try_from(payload).ok());
+        let frames = MutSignals::try_from(&mut pcm[..])
+            .map_err(|e| e.to_string())
+            .and_then(|output| {
+                decoder
+                    .decode_float(input, output, false)
+                    .map_err(|e| e.to_string())
+            });
+        match frames {
+            Ok(frames) => {
+                pcm.truncate(frames * channels);
+                pcm
+            }
+            Err(e) => {
+                warn!("Opus decode error: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+fn receive(socket: UdpSocket, buffer: Arc<Mutex<JitterBuffer>>) {
+    let mut data = [0u8; 65536];
+    let mut sender: Option<SocketAddr> = None;
+    loop {
+        match socket.recv_from(&mut data) {
+            // Stop once playback has moved on to another input
+            _ if Arc::strong_count(&buffer) == 1 => return,
+            Ok((len, from)) => {
+                let Some((sequence, ssrc, packet)) = parse_packet(&data[..len]) else {
+                    debug!("Ignoring a non-RTP datagram from {}", from);
+                    continue;
+                };
+                if sender != Some(from) {
+                    info!("Receiving RTP from {}", from);
+                    sender = Some(from);
+                }
+                buffer.lock().unwrap().insert(sequence, ssrc, packet);
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                if sender.take().is_some() {
+                    info!("RTP sender went quiet");
+                    *buffer.lock().unwrap() = JitterBuffer::default();
+                }
+            }
+            Err(e) => {
+                warn!("RTP receive failed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receiver() -> RtpReceiver {
+        RtpReceiver {
+            params: RtpParams {
+                addr: "127.0.0.1:0".parse().unwrap(),
+                encoding: RtpEncoding::L16,
+                rate: 8000,
+                channels: 1,
+                jitter: Duration::ZERO,
+            },
+            buffer: Arc::default(),
+            opus: None,
+            last_format: (8000, 1, IDLE_FRAMES),
+        }
+    }
+
+    fn packet(timestamp: u32) -> RtpPacket {
+        RtpPacket {
+            payload_type: 96,
+            timestamp,
+            payload: vec![0; 320],
+        }
+    }
+
+    fn played(receiver: &mut RtpReceiver) -> Option<u32> {
+        match receiver.playout() {
+            Playout::Packet(packet) => Some(packet.timestamp),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn sequence_jump_rebuffers() {
+        let mut receiver = receiver();
+        let insert = |receiver: &RtpReceiver, sequence, timestamp| {
+            let mut buffer = receiver.buffer.lock().unwrap();
+            buffer.insert(sequence, 1, packet(timestamp));
+        };
+        insert(&receiver, 10, 0);
+        insert(&receiver, 11, 160);
+        assert_eq!(played(&mut receiver), Some(0));
+        assert_eq!(played(&mut receiver), Some(160));
+
+        // The sender restarts far ahead; play on from there rather than
+        // counting every packet in between as lost
+        insert(&receiver, 30_000, 320);
+        insert(&receiver, 30_001, 480);
+        assert_eq!(played(&mut receiver), Some(320));
+        assert_eq!(played(&mut receiver), Some(480));
+    }
+
+    #[test]
+    fn jitter_buffer_is_capped() {
+        let mut buffer = JitterBuffer::default();
+        for sequence in 0..MAX_PACKETS as u16 + 10 {
+            buffer.insert(sequence, 1, packet(u32::from(sequence) * 160));
+        }
+        assert_eq!(buffer.packets.len(), MAX_PACKETS);
+        let (_, oldest) = buffer.packets.first_key_value().unwrap();
+        assert_eq!(oldest.timestamp, 10 * 160);
+    }
+}
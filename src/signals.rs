@@ -0,0 +1,94 @@
+//! Playback control by signal, for scripts: `kill -USR1` pauses or resumes,
+//! `kill -USR2` skips to the next entry and `kill -HUP` reopens the playing
+//! file. SIGINT and SIGTERM still shut down.
+//!
+//! The handler only writes the signal's number to a pipe; a thread reads it
+//! and passes the command on to the main loop.
+
+use crate::control::ControlCommand;
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use tracing::{debug, info};
+
+/// Signals and the commands they send.
+const SIGNALS: &[(libc::c_int, ControlCommand)] = &[
+    (libc::SIGUSR1, ControlCommand::TogglePause),
+    (libc::SIGUSR2, ControlCommand::Next),
+    (libc::SIGHUP, ControlCommand::Reopen),
+];
+
+/// Write end of the pipe, for the handler.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn handle(signal: libc::c_int) {
+    let byte = signal as u8;
+    // SAFETY: only async-signal-safe calls; errno is put back for the
+    // interrupted code
+    unsafe {
+        let errno = *libc::__errno_location();
+        libc::write(
+            PIPE.load(Ordering::Relaxed),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+        *libc::__errno_location() = errno;
+    }
+}
+
+/// Handle the control signals, replacing their default of ending the process
+/// (and the Ctrl+C handler's for SIGHUP), and yield the commands they send.
+pub fn watch() -> Result<Receiver<ControlCommand>> {
+    let mut fds = [0; 2];
+    // SAFETY: plain libc calls on the new pipe, then installing `handle`,
+    // which only writes to it
+    let reader = unsafe {
+        if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+            return Err(anyhow!(
+                "Failed to create a pipe: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        // A burst of signals is dropped rather than blocking in the handler
+        libc::fcntl(fds[1], libc::F_SETFL, libc::O_NONBLOCK);
+        PIPE.store(fds[1], Ordering::Relaxed);
+
+        for (signal, _) in SIGNALS {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(*signal, &action, std::ptr::null_mut()) != 0 {
+                return Err(anyhow!(
+                    "Failed to handle signal {}: {}",
+                    signal,
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+        File::from_raw_fd(fds[0])
+    };
+
+    let (tx, rx) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("signals".into())
+        .spawn(move || read(reader, tx))?;
+    info!("Signals: USR1 pauses or resumes, USR2 skips to the next track, HUP reopens the file");
+    Ok(rx)
+}
+
+fn read(mut reader: File, tx: Sender<ControlCommand>) {
+    let mut byte = [0];
+    while reader.read_exact(&mut byte).is_ok() {
+        let Some((_, command)) = SIGNALS.iter().find(|(s, _)| *s == byte[0] as libc::c_int) else {
+            continue;
+        };
+        debug!("Signal {} sends {:?}", byte[0], command);
+        if tx.send(command.clone()).is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! `soundboard`: short clips held in memory and played the moment their key
+//! is pressed.
+//!
+//! The clips are decoded up front, at the output rate and channel count, so a
+//! press only has to start mixing samples that are already there. The board
+//! is the input of the decoder like any live input: it never ends, and streams
+//! silence while no clip plays.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver, Sender};
+use tracing::{debug, info};
+
+/// What pressing a key does while another clip still plays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Overlap {
+    /// Stop the playing clip and start the new one
+    Cut,
+    /// Play the new clip once the ones before it have finished
+    Queue,
+    /// Play the new clip on top of the others
+    Mix,
+}
+
+/// Length of each chunk of output. Short chunks keep a press from waiting
+/// behind silence that was already handed over.
+const CHUNK_MS: usize = 10;
+
+/// How long a clip that is cut off takes to fade out, so it doesn't click.
+const CUT_FADE_MS: usize = 10;
+
+/// A preloaded clip: interleaved samples at the output rate and channel count.
+pub struct Clip {
+    pub name: String,
+    pub samples: Vec<f32>,
+}
+
+/// A clip being played.
+struct Voice {
+    clip: usize,
+    /// Next sample to play.
+    position: usize,
+    /// Frames left of the fade-out of a clip that was cut off.
+    fading: Option<usize>,
+}
+
+/// Presses the board's keys, from the main loop.
+pub struct Pads {
+    presses: Sender<usize>,
+    names: Vec<String>,
+}
+
+impl Pads {
+    /// Play the clip on key `pad`, counting from 1.
+    pub fn press(&self, pad: usize) -> anyhow::Result<()> {
+        let name = pad
+            .checked_sub(1)
+            .and_then(|index| self.names.get(index))
+            .ok_or_else(|| {
+                anyhow::anyhow!("No clip on pad {}; there are {}", pad, self.names.len())
+            })?;
+        info!("Pad {}: {}", pad, name);
+        // The board is only gone once playback is over
+        let _ = self.presses.send(pad - 1);
+        Ok(())
+    }
+}
+
+/// The clips and what of them is playing.
+pub struct Soundboard {
+    clips: Vec<Clip>,
+    channels: usize,
+    overlap: Overlap,
+    presses: Receiver<usize>,
+    playing: Vec<Voice>,
+    /// Clips waiting their turn with [`Overlap::Queue`].
+    queued: VecDeque<usize>,
+    /// Frames per chunk, and of the fade when a clip is cut off.
+    chunk: usize,
+    cut_fade: usize,
+}
+
+impl Soundboard {
+    /// A board for `clips`, and the pads that play them.
+    pub fn new(clips: Vec<Clip>, overlap: Overlap, rate: u32, channels: usize) -> (Self, Pads) {
+        let (tx, rx) = mpsc::channel();
+        let pads = Pads {
+            presses: tx,
+            names: clips.iter().map(|clip| clip.name.clone()).collect(),
+        };
+        let board = Self {
+            clips,
+            channels,
+            overlap,
+            presses: rx,
+            playing: Vec::new(),
+            queued: VecDeque::new(),
+            chunk: (rate as usize * CHUNK_MS / 1000).max(1),
+            cut_fade: (rate as usize * CUT_FADE_MS / 1000).max(1),
+        };
+        (board, pads)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    /// The next chunk of output: the clips playing, mixed, or silence.
+    pub fn next_chunk(&mut self) -> Vec<f32> {
+        while let Ok(clip) = self.presses.try_recv() {
+            self.press(clip);
+        }
+        let mut output = vec![0.0; self.chunk * self.channels];
+        let mut start = 0;
+        loop {
+            let ended_at = self.mix(&mut output[start..]);
+            // With --overlap queue the next clip starts right where the last one ended
+            if !self.playing.is_empty() {
+                break;
+            }
+            let Some(clip) = self.queued.pop_front() else {
+                break;
+            };
+            debug!("Playing queued clip {}", self.clips[clip].name);
+            self.playing.push(Voice {
+                clip,
+                position: 0,
+                fading: None,
+            });
+            start += ended_at;
+        }
+        output
+    }
+
+    fn press(&mut self, clip: usize) {
+        match self.overlap {
+            Overlap::Cut => {
+                for voice in &mut self.playing {
+                    voice.fading.get_or_insert(self.cut_fade);
+                }
+            }
+            Overlap::Queue if !self.playing.is_empty() => {
+                self.queued.push_back(clip);
+                return;
+            }
+            Overlap::Queue | Overlap::Mix => {}
+        }
+        self.playing.push(Voice {
+            clip,
+            position: 0,
+            fading: None,
+        });
+    }
+
+    /// Add the playing clips to `output`, dropping those that end. Returns
+    /// where in `output` the last of them ended.
+    fn mix(&mut self, output: &mut [f32]) -> usize {
+        let (channels, cut_fade) = (self.channels, self.cut_fade);
+        let mut ended_at = 0;
+        for voice in &mut self.playing {
+            let samples = &self.clips[voice.clip].samples[voice.position..];
+            let mut length = samples.len().min(output.len());
+            if let Some(fading) = voice.fading {
+                length = length.min(fading * channels);
+            }
+            for (frame, (out, clip)) in output[..length]
+                .chunks_mut(channels)
+                .zip(samples[..length].chunks(channels))
+                .enumerate()
+            {
+                let gain = match voice.fading {
+                    Some(fading) => (fading - frame) as f32 / cut_fade as f32,
+                    None => 1.0,
+                };
+                for (out, sample) in out.iter_mut().zip(clip) {
+                    *out += sample * gain;
+                }
+            }
+            voice.position += length;
+            if let Some(fading) = &mut voice.fading {
+                *fading -= length / channels;
+            }
+            if voice.position == self.clips[voice.clip].samples.len() || voice.fading == Some(0) {
+                ended_at = ended_at.max(length);
+            }
+        }
+        let clips = &self.clips;
+        self.playing.retain(|voice| {
+            voice.position < clips[voice.clip].samples.len() && voice.fading != Some(0)
+        });
+        ended_at
+    }
+}
@@ -0,0 +1,904 @@
+//! Opening inputs and reading them as interleaved float samples.
+
+use crate::auth::Access;
+use crate::exec::ChildOutput;
+use crate::exit::Exit;
+use crate::feed::Feed;
+use crate::fifo;
+use crate::generator::{GenerateOptions, Generator};
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::hls::HlsStream;
+use crate::http::{self, HttpStream};
+use crate::midi::{self, MidiOptions, MidiRenderer};
+use crate::mp4;
+use crate::opus;
+use crate::raw::{self, RawParams, RawReader};
+use crate::replaygain::ReplayGain;
+use crate::rtp::{RtpParams, RtpReceiver};
+use crate::soundboard::Soundboard;
+use crate::spool;
+use crate::tracker;
+use crate::tts::{self, TtsOptions};
+use crate::ws;
+use crate::ytdlp;
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CodecRegistry, Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream, ReadOnlySource};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::Time;
+use tracing::{debug, error, info, warn};
+
+/// Human-readable list of what the probe and codec registry can handle.
+const SUPPORTED_FORMATS: &str = "containers: wav, aiff, caf, flac, mp3, ogg, mp4/m4a, mkv/webm; \
+    codecs: pcm, adpcm, mp3, aac, alac, flac, vorbis, opus";
+
+/// Symphonia's default codecs plus the libopus-backed Opus decoder.
+fn codec_registry() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        registry.register_all::<opus::OpusDecoder>();
+        registry
+    })
+}
+
+/// Build a probe hint from an explicit `--format-hint` or the file extension.
+///
+/// Hints containing a `/` are treated as mime types (e.g. an HTTP `Content-Type`).
+fn probe_hint(path: &Path, format_hint: Option<&str>) -> Hint {
+    let mut hint = Hint::new();
+    match format_hint {
+        Some(mime) if mime.contains('/') => {
+            hint.mime_type(mime);
+        }
+        Some(ext) => {
+            hint.with_extension(ext.trim_start_matches('.'));
+        }
+        None => {
+            if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+                hint.with_extension(ext);
+            }
+        }
+    }
+    hint
+}
+
+/// A one-line summary of a track for logs, e.g. "aac, eng, 2 ch, 48000 Hz".
+fn describe_track(track: &Track) -> String {
+    let params = &track.codec_params;
+    let mut parts = vec![codec_registry()
+        .get_codec(params.codec)
+        .map_or("unsupported codec", |codec| codec.short_name)
+        .to_string()];
+    parts.extend(track.language.clone());
+    parts.extend(params.channels.map(|c| format!("{} ch", c.count())));
+    parts.extend(params.sample_rate.map(|rate| format!("{} Hz", rate)));
+    parts.join(", ")
+}
+
+/// Whether a server's `Content-Type` says anything about the audio format.
+fn is_specific_mime(mime: &str) -> bool {
+    !matches!(
+        mime,
+        "application/octet-stream" | "binary/octet-stream" | "text/plain"
+    )
+}
+
+/// Whether the path is a URL of an HLS playlist.
+fn is_hls_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|url| {
+        http::is_url(path)
+            && http::without_query(url)
+                .to_ascii_lowercase()
+                .ends_with(".m3u8")
+    })
+}
+
+/// How to open an input: its path plus any format overrides from the command line.
+#[derive(Debug, Clone)]
+pub struct InputSpec {
+    pub path: PathBuf,
+    pub format_hint: Option<String>,
+    /// Set when the input is headerless PCM rather than a container. For
+    /// `exec`, only used when the output turns out not to be encoded.
+    pub raw: Option<RawParams>,
+    /// Format of `.raw`/`.pcm` files, which are never probed.
+    pub raw_files: Option<RawParams>,
+    /// Audio track to play from containers with several, counting from 1.
+    pub track: Option<usize>,
+    /// Shell command whose stdout is the input; `path` is then just its name.
+    pub exec: Option<String>,
+    /// yt-dlp binary to resolve `path`, a video page, to its audio with.
+    pub ytdlp: Option<String>,
+    /// Speak `path` (the text, or a text file) instead of opening it.
+    pub tts: Option<TtsOptions>,
+    /// Synthesize a test signal instead of opening `path`, its name.
+    pub generate: Option<GenerateOptions>,
+    /// How to render MIDI files, when a SoundFont was given.
+    pub midi: Option<MidiOptions>,
+    /// Play only this part of the input, e.g. one track of a cue sheet.
+    pub span: Option<Span>,
+    /// Fail on a packet that doesn't decode, rather than skipping it.
+    pub strict: bool,
+}
+
+/// A stretch of an input, from `start` to `end` (or its end).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: Duration,
+    pub end: Option<Duration>,
+}
+
+impl Span {
+    /// This span taken within `outer`, as times in the input.
+    pub fn within(self, outer: Span) -> Span {
+        let end = self.end.map(|end| outer.start + end);
+        Span {
+            start: outer.start + self.start,
+            end: match (end, outer.end) {
+                (Some(end), Some(outer)) => Some(end.min(outer)),
+                (end, outer) => end.or(outer),
+            },
+        }
+    }
+}
+
+/// The [`Span`] of the input that plays, with frame counts at the source's
+/// rate that are counted down as chunks go by.
+#[derive(Debug, Clone, Copy, Default)]
+struct Window {
+    span: Span,
+    /// Frames still to drop before the position played from.
+    skip: u64,
+    /// Frames left until the span ends, if it ends before the input does.
+    remaining: Option<u64>,
+    /// Whether anything has been read yet.
+    started: bool,
+}
+
+enum Input {
+    Decoded {
+        format: Box<dyn FormatReader>,
+        decoder: Box<dyn Decoder>,
+        track_id: u32,
+        /// Packets that fail to decode are errors rather than skipped.
+        strict: bool,
+    },
+    Raw {
+        reader: RawReader,
+        channels: usize,
+    },
+    /// Pushed in by a background thread; never ends.
+    Feed(Feed),
+    Rtp(RtpReceiver),
+    /// Synthesized mono test signal.
+    Generator(Generator),
+    /// Preloaded clips played on key presses; never ends.
+    Soundboard(Soundboard),
+    /// MIDI file rendered to stereo.
+    Midi(MidiRenderer),
+    /// Tracker module rendered to stereo.
+    #[cfg(feature = "tracker")]
+    Module(tracker::Module),
+}
+
+/// Interleaved samples and their channel count.
+pub type Chunk = (Vec<f32>, usize);
+
+/// An opened input that yields interleaved float samples chunk by chunk.
+pub struct Source {
+    input: Input,
+    /// Native rate of the samples this source yields, if known.
+    pub sample_rate: Option<u32>,
+    /// Total length, when the container reports a frame count.
+    pub duration: Option<Duration>,
+    /// Gains from the file's ReplayGain or R128 tags.
+    pub replay_gain: Option<ReplayGain>,
+    /// A chunk read ahead by [`Source::prime`], returned before anything else.
+    primed: Option<Chunk>,
+    window: Window,
+}
+
+impl Source {
+    pub fn open(spec: &InputSpec) -> Result<Self> {
+        let mut source = Self::open_input(spec)?;
+        if let Input::Decoded { strict, .. } = &mut source.input {
+            *strict = spec.strict;
+        }
+        if let Some(span) = spec.span {
+            source.restrict(span)?;
+        }
+        Ok(source)
+    }
+
+    fn open_input(spec: &InputSpec) -> Result<Self> {
+        if let Some(options) = &spec.generate {
+            return Ok(Self::generate(options.clone()));
+        }
+        if let Some(command) = &spec.exec {
+            return Self::open_exec(command, spec);
+        }
+        if let Some(program) = &spec.ytdlp {
+            return Self::open_page(program, spec);
+        }
+        if let Some(tts) = &spec.tts {
+            return Self::open_speech(tts, spec);
+        }
+        if fifo::is_fifo(&spec.path) {
+            return Self::open_fifo(spec);
+        }
+        Self::open_direct(spec)
+    }
+
+    /// Open the input as a single stream, even if it is a FIFO.
+    pub fn open_direct(spec: &InputSpec) -> Result<Self> {
+        match spec.raw {
+            Some(params) => Self::open_raw(&spec.path, params),
+            None if raw::is_raw_file(&spec.path) => match spec.raw_files {
+                Some(params) => Self::open_raw(&spec.path, params),
+                None => Err(anyhow!(
+                    "{:?} is headerless PCM; describe it with --raw-rate, --raw-channels and --raw-format",
+                    spec.path
+                )),
+            },
+            None if midi::is_midi_file(&spec.path) && !http::is_url(&spec.path) => {
+                Self::open_midi(spec)
+            }
+            None if tracker::is_module_file(&spec.path) && !http::is_url(&spec.path) => {
+                Self::open_module(&spec.path)
+            }
+            None => Self::open_decoded(&spec.path, spec.format_hint.as_deref(), spec.track),
+        }
+    }
+
+    /// Render a MIDI file through the SoundFont.
+    fn open_midi(spec: &InputSpec) -> Result<Self> {
+        let options = spec.midi.as_ref().ok_or_else(|| {
+            anyhow!(
+                "{:?} is a MIDI file; pass --soundfont <file.sf2>",
+                spec.path
+            )
+        })?;
+        let renderer = MidiRenderer::open(&spec.path, options)?;
+        Ok(Self {
+            sample_rate: Some(renderer.rate),
+            duration: Some(renderer.duration),
+            input: Input::Midi(renderer),
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        })
+    }
+
+    /// Render a tracker module with libopenmpt.
+    fn open_module(path: &Path) -> Result<Self> {
+        #[cfg(feature = "tracker")]
+        return tracker::Module::open(path).map(|module| Self {
+            sample_rate: Some(tracker::RENDER_RATE),
+            duration: Some(module.duration),
+            input: Input::Module(module),
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        });
+        #[cfg(not(feature = "tracker"))]
+        Err(anyhow!(
+            "Cannot play {:?}: virtual-mic was built without the tracker feature",
+            path
+        ))
+    }
+
+    /// Probe and decode a container from the file, an HTTP(S) URL, or stdin
+    /// when the path is "-".
+    fn open_decoded(path: &Path, format_hint: Option<&str>, track: Option<usize>) -> Result<Self> {
+        let mut hint_path = path;
+        let mut format_hint = format_hint.map(str::to_string);
+        let media: Box<dyn MediaSource> = if path == Path::new("-") {
+            // Not seekable, so the format has to be detectable from the leading bytes
+            mp4::media_source(std::io::stdin())?
+        } else if let Some(url) = path.to_str().filter(|_| is_hls_url(path)) {
+            let stream = HlsStream::open(url)?;
+            hint_path = Path::new("");
+            if format_hint.is_none() {
+                format_hint = stream.extension().map(str::to_string);
+            }
+            Box::new(ReadOnlySource::new(stream))
+        } else if let Some(url) = path.to_str().filter(|_| http::is_url(path)) {
+            let stream = HttpStream::open(url)?;
+            // The query string would hide the extension
+            hint_path = Path::new(http::without_query(url));
+            if format_hint.is_none() {
+                format_hint = stream
+                    .content_type()
+                    .filter(|mime| is_specific_mime(mime))
+                    .map(str::to_string);
+            }
+            Box::new(stream)
+        } else {
+            Box::new(File::open(path)?)
+        };
+        let hint = probe_hint(hint_path, format_hint.as_deref());
+        Self::decode(media, &hint, path, track)
+    }
+
+    /// Probe a container and set up the decoder for an audio track: the
+    /// `track`th one, or else the first that can be decoded. Other tracks, such
+    /// as video, are skipped. `path` is only used in messages.
+    fn decode(
+        media: Box<dyn MediaSource>,
+        hint: &Hint,
+        path: &Path,
+        track: Option<usize>,
+    ) -> Result<Self> {
+        let mss = MediaSourceStream::new(media, Default::default());
+        let mut probed = symphonia::default::get_probe()
+            .format(
+                hint,
+                mss,
+                // Drop encoder delay and padding, so loops and playlists are gapless
+                &FormatOptions {
+                    enable_gapless: true,
+                    ..Default::default()
+                },
+                &MetadataOptions::default(),
+            )
+            .map_err(|e| {
+                anyhow!(
+                    "Could not detect the format of {:?} ({}); try --format-hint. Supported {}",
+                    path,
+                    e,
+                    SUPPORTED_FORMATS
+                )
+            })?;
+
+        let mut format = probed.format;
+        // Tags before the container (ID3v2) and in it (Vorbis comments, MP4 atoms)
+        let mut tags = Vec::new();
+        if let Some(revision) = probed
+            .metadata
+            .get()
+            .as_mut()
+            .and_then(|m| m.skip_to_latest())
+        {
+            tags.extend_from_slice(revision.tags());
+        }
+        if let Some(revision) = format.metadata().skip_to_latest() {
+            tags.extend_from_slice(revision.tags());
+        }
+        let replay_gain = ReplayGain::from_tags(&tags);
+
+        let audio_tracks: Vec<&Track> = format
+            .tracks()
+            .iter()
+            .filter(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .collect();
+        if audio_tracks.len() > 1 {
+            info!("Audio tracks in {:?}:", path);
+            for (number, track) in audio_tracks.iter().enumerate() {
+                info!("  {}: {}", number + 1, describe_track(track));
+            }
+        }
+        let track = match track {
+            Some(number) => *audio_tracks.get(number - 1).ok_or_else(|| {
+                anyhow!(
+                    "{:?} has {} audio track(s); there is no track {}",
+                    path,
+                    audio_tracks.len(),
+                    number
+                )
+            })?,
+            None => audio_tracks
+                .iter()
+                .find(|t| codec_registry().get_codec(t.codec_params.codec).is_some())
+                .or(audio_tracks.first())
+                .copied()
+                .ok_or_else(|| anyhow!("No audio track found"))?,
+        };
+
+        let track_id = track.id;
+        let codec_params = &track.codec_params;
+
+        let decoder = codec_registry()
+            .make(codec_params, &DecoderOptions::default())
+            .map_err(|e| {
+                anyhow!(
+                    "No decoder for the audio track in {:?} ({}). Supported {}",
+                    path,
+                    e,
+                    SUPPORTED_FORMATS
+                )
+            })?;
+
+        // Some decoders (e.g. Opus) always output a fixed rate regardless of the container
+        let decoder_params = decoder.codec_params();
+        let sample_rate = decoder_params.sample_rate.or(codec_params.sample_rate);
+        info!(
+            "Audio: {} Hz, {} channels",
+            sample_rate.unwrap_or(0),
+            decoder_params
+                .channels
+                .or(codec_params.channels)
+                .map(|c| c.count())
+                .unwrap_or(0)
+        );
+
+        // Total duration is only known when the container reports a frame count
+        let duration = match (codec_params.n_frames, sample_rate) {
+            (Some(frames), Some(rate)) if rate > 0 => {
+                Some(Duration::from_secs_f64(frames as f64 / rate as f64))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            input: Input::Decoded {
+                format,
+                decoder,
+                track_id,
+                strict: false,
+            },
+            sample_rate,
+            duration,
+            replay_gain,
+            primed: None,
+            window: Window::default(),
+        })
+    }
+
+    /// Open headerless PCM from the file, an HTTP(S) URL, or stdin when the path is "-".
+    fn open_raw(path: &Path, params: RawParams) -> Result<Self> {
+        let duration_of = |bytes: u64| {
+            let frames = bytes / params.frame_bytes() as u64;
+            Duration::from_secs_f64(frames as f64 / params.rate as f64)
+        };
+        let (reader, duration): (Box<dyn std::io::Read + Send>, _) = if path == Path::new("-") {
+            (Box::new(std::io::stdin()), None)
+        } else if let Some(url) = path.to_str().filter(|_| http::is_url(path)) {
+            let stream = HttpStream::open(url)?;
+            let duration = stream.byte_len().map(duration_of);
+            (Box::new(stream), duration)
+        } else {
+            let file = File::open(path)?;
+            let duration = duration_of(file.metadata()?.len());
+            (Box::new(file), Some(duration))
+        };
+
+        Ok(Self::read_raw(reader, params, duration))
+    }
+
+    fn read_raw(
+        reader: Box<dyn std::io::Read + Send>,
+        params: RawParams,
+        duration: Option<Duration>,
+    ) -> Self {
+        info!(
+            "Raw audio: {} Hz, {} channels, {:?}",
+            params.rate, params.channels, params.format
+        );
+
+        Self {
+            input: Input::Raw {
+                reader: RawReader::new(reader, params),
+                channels: params.channels,
+            },
+            sample_rate: Some(params.rate),
+            duration,
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        }
+    }
+
+    /// Run a command and play its stdout, decoded if it starts with a known
+    /// signature and as raw PCM otherwise; it ends when the command does.
+    fn open_exec(command: &str, spec: &InputSpec) -> Result<Self> {
+        let mut output = ChildOutput::spawn(command)
+            .map_err(|e| anyhow!("Failed to run `{}`: {}", command, e))?;
+        let (head, encoded) = output.sniff()?;
+        if head.is_empty() {
+            return Err(anyhow!("`{}` produced no output", command));
+        }
+        let output = output.with_head(head);
+
+        match (spec.raw, spec.format_hint.as_deref()) {
+            (Some(params), _) if !encoded => Ok(Self::read_raw(Box::new(output), params, None)),
+            (None, None) if !encoded => Err(anyhow!(
+                "Output of `{}` isn't a recognised format; pass --pcm-rate, --pcm-channels \
+                 and --pcm-format if it is raw PCM, or --format-hint",
+                command
+            )),
+            (_, format_hint) => {
+                let media = mp4::media_source(output)?;
+                let hint = probe_hint(Path::new(""), format_hint);
+                Self::decode(media, &hint, Path::new(command), spec.track)
+            }
+        }
+    }
+
+    /// Synthesize the entry's text and play the engine's output as it arrives.
+    fn open_speech(tts: &TtsOptions, spec: &InputSpec) -> Result<Self> {
+        let text = if tts.from_file {
+            std::fs::read_to_string(&spec.path)
+                .map_err(|e| anyhow!("Failed to read {:?}: {}", spec.path, e))?
+        } else {
+            spec.path.to_string_lossy().into_owned()
+        };
+        if text.trim().is_empty() {
+            return Err(anyhow!("Nothing to say in {:?}", spec.path));
+        }
+
+        let (command, input, raw) = tts.synthesis(&text)?;
+        let engine = command.get_program().to_string_lossy().into_owned();
+        let output = ChildOutput::start(command, Some(input), &engine)
+            .map_err(|e| anyhow!("Failed to run {} (is it installed?): {}", engine, e))?;
+        match raw {
+            Some(params) => Ok(Self::read_raw(Box::new(output), params, None)),
+            None => {
+                let hint = probe_hint(Path::new(""), Some("wav"));
+                Self::decode(
+                    Box::new(ReadOnlySource::new(output)),
+                    &hint,
+                    &spec.path,
+                    None,
+                )
+            }
+        }
+    }
+
+    /// Resolve a video page with yt-dlp and stream the chosen audio format.
+    fn open_page(program: &str, spec: &InputSpec) -> Result<Self> {
+        let page = spec
+            .path
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid URL {:?}", spec.path))?;
+        let resolved = ytdlp::resolve(program, page)?;
+        if !resolved.hls {
+            let format_hint = spec.format_hint.as_deref().unwrap_or(&resolved.ext);
+            return Self::open_decoded(Path::new(&resolved.url), Some(format_hint), spec.track);
+        }
+        // The segments' own format is a better hint than the page's extension
+        let stream = HlsStream::open(&resolved.url)?;
+        let hint = probe_hint(
+            Path::new(""),
+            spec.format_hint.as_deref().or(stream.extension()),
+        );
+        Self::decode(
+            Box::new(ReadOnlySource::new(stream)),
+            &hint,
+            &spec.path,
+            spec.track,
+        )
+    }
+
+    /// Read a named pipe across writers; it never reaches end of input.
+    fn open_fifo(spec: &InputSpec) -> Result<Self> {
+        info!("Waiting for writers on FIFO {:?}", spec.path);
+        Ok(Self {
+            input: Input::Feed(fifo::spawn(spec.clone())?),
+            sample_rate: spec.raw.map(|params| params.rate),
+            duration: None,
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        })
+    }
+
+    /// Receive RTP packets on a UDP socket; it never reaches end of input.
+    pub fn listen_rtp(params: RtpParams) -> Result<Self> {
+        Ok(Self {
+            input: Input::Rtp(RtpReceiver::start(params)?),
+            sample_rate: None,
+            duration: None,
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        })
+    }
+
+    /// Accept raw PCM from WebSocket clients; it never reaches end of input.
+    pub fn listen_ws(addr: SocketAddr, params: RawParams, access: Access) -> Result<Self> {
+        Ok(Self {
+            input: Input::Feed(ws::listen(addr, params, access)?),
+            sample_rate: Some(params.rate),
+            duration: None,
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        })
+    }
+
+    /// Accept PushAudio streams from gRPC clients; it never reaches end of input.
+    pub fn listen_grpc(addr: SocketAddr) -> Result<Self> {
+        #[cfg(feature = "grpc")]
+        return Ok(Self {
+            input: Input::Feed(grpc::listen(addr)?),
+            sample_rate: None,
+            duration: None,
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        });
+        #[cfg(not(feature = "grpc"))]
+        Err(anyhow!(
+            "Cannot listen for gRPC on {}: virtual-mic was built without the grpc feature",
+            addr
+        ))
+    }
+
+    /// Play files dropped into the directory `spec.path`; it never reaches end of input.
+    pub fn spool(spec: InputSpec) -> Result<Self> {
+        Ok(Self {
+            sample_rate: spec.raw.map(|params| params.rate),
+            input: Input::Feed(spool::spawn(spec)?),
+            duration: None,
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        })
+    }
+
+    /// Speak lines from stdin as they arrive; it never reaches end of input.
+    pub fn speak_stdin(spec: InputSpec) -> Result<Self> {
+        Ok(Self {
+            input: Input::Feed(tts::speak_stdin(spec)?),
+            sample_rate: None,
+            duration: None,
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        })
+    }
+
+    /// Play a test signal or DTMF sequence.
+    fn generate(options: GenerateOptions) -> Self {
+        info!("Generating {} at {} Hz", options.signal, options.rate);
+        Self {
+            sample_rate: Some(options.rate),
+            duration: options.length(),
+            input: Input::Generator(Generator::new(options)),
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        }
+    }
+
+    /// Play chunks pushed into a feed by the caller, at `sample_rate` until a
+    /// chunk says otherwise; it never reaches end of input.
+    pub fn from_feed(feed: Feed, sample_rate: u32) -> Self {
+        Self {
+            input: Input::Feed(feed),
+            sample_rate: Some(sample_rate),
+            duration: None,
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        }
+    }
+
+    /// Play a soundboard, whose clips are already at `sample_rate` and the
+    /// output channel count.
+    pub fn soundboard(board: Soundboard, sample_rate: u32) -> Self {
+        Self {
+            input: Input::Soundboard(board),
+            sample_rate: Some(sample_rate),
+            duration: None,
+            replay_gain: None,
+            primed: None,
+            window: Window::default(),
+        }
+    }
+
+    /// Read the first chunk ahead of time to prove the input actually decodes.
+    pub fn prime(&mut self) -> Result<()> {
+        match self.next_chunk()? {
+            Some(chunk) => {
+                self.primed = Some(chunk);
+                Ok(())
+            }
+            None => Err(anyhow!("No audio could be decoded")),
+        }
+    }
+
+    /// Play only `span` of the input, to the sample. Containers seek to its
+    /// start where they can; otherwise the audio before it is decoded and dropped.
+    fn restrict(&mut self, span: Span) -> Result<()> {
+        let end = span.end.map(|end| end.max(span.start));
+        self.window.span = Span {
+            start: span.start,
+            end,
+        };
+        self.duration = match (self.duration, end) {
+            (Some(duration), Some(end)) => Some(duration.min(end)),
+            (duration, end) => duration.or(end),
+        }
+        .map(|end| end.saturating_sub(span.start));
+        self.move_to(Duration::ZERO, true)
+    }
+
+    /// Jump to `position` within the span, counting from its start, and
+    /// return where playback resumes. A position past the end ends the input.
+    ///
+    /// Before anything has been read, inputs that can't seek get there by
+    /// decoding and dropping the audio before it.
+    pub fn seek(&mut self, position: Duration) -> Result<Duration> {
+        let fresh = !self.window.started;
+        self.primed = None;
+        if self.duration.is_some_and(|duration| position >= duration) {
+            self.window.remaining = Some(0);
+            return Ok(self.duration.unwrap_or(position));
+        }
+        self.move_to(position, fresh)?;
+        Ok(position)
+    }
+
+    /// Set the window up to play from `position` within the span. A fresh
+    /// input may decode and drop its way there when it can't seek.
+    fn move_to(&mut self, position: Duration, fresh: bool) -> Result<()> {
+        let rate = self
+            .sample_rate
+            .ok_or_else(|| anyhow!("Can't seek in an input of unknown sample rate"))?;
+        let frames = |time: Duration| (time.as_secs_f64() * rate as f64).round() as u64;
+        let target = self.window.span.start + position;
+
+        let mut skip = frames(target);
+        match &mut self.input {
+            Input::Decoded {
+                format,
+                decoder,
+                track_id,
+                ..
+            } if !fresh || skip > 0 => {
+                let to = SeekTo::Time {
+                    time: Time::from(target),
+                    track_id: Some(*track_id),
+                };
+                match format.seek(SeekMode::Accurate, to) {
+                    Ok(seeked) => {
+                        decoder.reset();
+                        // The seek lands on a packet boundary at or before the target
+                        let time_base = decoder.codec_params().time_base;
+                        let early = seeked.required_ts.saturating_sub(seeked.actual_ts);
+                        skip = time_base.map_or(0, |base| frames(base.calc_time(early).into()));
+                    }
+                    Err(e) if fresh => {
+                        debug!("Seek failed ({}); decoding up to the start instead", e)
+                    }
+                    Err(e) => return Err(anyhow!("Seek failed: {}", e)),
+                }
+            }
+            _ if !fresh => return Err(anyhow!("Seeking isn't supported for this input")),
+            _ => {}
+        }
+
+        self.window.skip = skip;
+        self.window.remaining = self
+            .window
+            .span
+            .end
+            .map(|end| frames(end).saturating_sub(frames(target)));
+        Ok(())
+    }
+
+    /// Read the next chunk of samples, or `None` at end of input.
+    pub fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        if let Some(chunk) = self.primed.take() {
+            return Ok(Some(chunk));
+        }
+
+        loop {
+            if self.window.remaining == Some(0) {
+                return Ok(None);
+            }
+            self.window.started = true;
+            let Some((mut samples, channels)) = self.read_chunk()? else {
+                return Ok(None);
+            };
+            let frames = (samples.len() / channels) as u64;
+            if self.window.skip >= frames {
+                self.window.skip -= frames;
+                continue;
+            }
+            samples.drain(..self.window.skip as usize * channels);
+            self.window.skip = 0;
+            if let Some(remaining) = &mut self.window.remaining {
+                let keep = (*remaining).min((samples.len() / channels) as u64);
+                samples.truncate(keep as usize * channels);
+                *remaining -= keep;
+            }
+            return Ok(Some((samples, channels)));
+        }
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<Chunk>> {
+        let (format, decoder, track_id, strict) = match &mut self.input {
+            Input::Decoded {
+                format,
+                decoder,
+                track_id,
+                strict,
+            } => (format, decoder, *track_id, *strict),
+            Input::Raw { reader, channels } => {
+                let mut samples = Vec::new();
+                return Ok(reader.read(&mut samples)?.then_some((samples, *channels)));
+            }
+            Input::Feed(feed) => {
+                // Each FIFO writer may send a different rate
+                let (samples, channels, sample_rate) = feed.next_chunk();
+                self.sample_rate = sample_rate;
+                return Ok(Some((samples, channels)));
+            }
+            Input::Rtp(receiver) => {
+                let (samples, channels, sample_rate) = receiver.next_chunk();
+                self.sample_rate = sample_rate;
+                return Ok(Some((samples, channels)));
+            }
+            Input::Generator(generator) => {
+                return Ok(generator.next_chunk().map(|samples| (samples, 1)));
+            }
+            Input::Soundboard(board) => {
+                let samples = board.next_chunk();
+                let channels = board.channels();
+                return Ok(Some((samples, channels)));
+            }
+            Input::Midi(renderer) => {
+                return Ok(renderer.next_chunk().map(|samples| (samples, 2)));
+            }
+            #[cfg(feature = "tracker")]
+            Input::Module(module) => {
+                return Ok(module.next_chunk().map(|samples| (samples, 2)));
+            }
+        };
+
+        loop {
+            match format.next_packet() {
+                Ok(packet) => {
+                    if packet.track_id() != track_id {
+                        continue;
+                    }
+
+                    match decoder.decode(&packet) {
+                        Ok(decoded) => {
+                            let spec = *decoded.spec();
+                            let duration = decoded.capacity() as u64;
+
+                            let mut sample_buf = SampleBuffer::<f32>::new(duration, spec);
+                            sample_buf.copy_interleaved_ref(decoded);
+
+                            return Ok(Some((
+                                sample_buf.samples().to_vec(),
+                                spec.channels.count(),
+                            )));
+                        }
+                        Err(e) if strict => {
+                            return Err(anyhow::Error::new(e).context(Exit::Decode));
+                        }
+                        Err(e) => {
+                            warn!("Decode error: {}", e);
+                            continue;
+                        }
+                    }
+                }
+                Err(symphonia::core::errors::Error::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    // End of file
+                    return Ok(None);
+                }
+                Err(e) => {
+                    error!("Format error: {}", e);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Drop-box directory for `--spool`.
+//!
+//! Audio files that appear in the directory play one after another in the
+//! order they arrived, then move to its `done/` subdirectory. A file is picked
+//! up once it is complete: when its writer closes it, or when it is renamed or
+//! moved in. Files already waiting at startup play first, oldest first. The mic
+//! plays silence while the spool is empty.
+
+use crate::feed::{Feed, RatedChunk};
+use crate::playlist;
+use crate::source::{InputSpec, Source};
+use inotify::{EventMask, Inotify, WatchMask};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use tracing::{info, warn};
+
+/// Subdirectory that played files are moved to.
+const DONE_DIR: &str = "done";
+
+/// Whether a file in the spool should be played: audio files by extension, or
+/// any visible file when the spool holds raw PCM.
+fn is_spooled(path: &Path, raw: bool) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with('.'));
+    !hidden && path.is_file() && (raw || playlist::is_audio_file(path))
+}
+
+/// Files already in the spool, oldest first.
+fn waiting_files(dir: &Path, raw: bool) -> std::io::Result<Vec<PathBuf>> {
+    let mut files: Vec<_> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| is_spooled(&entry.path(), raw))
+        .map(|entry| {
+            let modified = entry.metadata().and_then(|meta| meta.modified()).ok();
+            (modified, entry.path())
+        })
+        .collect();
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Watch the directory `spec.path` and play what arrives on a background thread.
+pub fn spawn(spec: InputSpec) -> std::io::Result<Feed> {
+    let dir = spec.path.clone();
+    std::fs::create_dir_all(dir.join(DONE_DIR))?;
+
+    // Watch before listing so nothing slips in between; duplicates are skipped later
+    let inotify = Inotify::init()?;
+    inotify
+        .watches()
+        .add(&dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)?;
+    let (arrived, arrivals) = mpsc::channel();
+    let waiting = waiting_files(&dir, spec.raw.is_some())?;
+    info!(
+        "Watching spool directory {:?} ({} files waiting)",
+        dir,
+        waiting.len()
+    );
+    for path in waiting {
+        let _ = arrived.send(path);
+    }
+
+    let raw = spec.raw.is_some();
+    std::thread::Builder::new()
+        .name("spool-watch".into())
+        .spawn(move || watch(inotify, dir, raw, arrived))?;
+
+    let (tx, feed) = Feed::channel();
+    std::thread::Builder::new()
+        .name("spool".into())
+        .spawn(move || play_arrivals(spec, arrivals, tx))?;
+    Ok(feed)
+}
+
+fn watch(mut inotify: Inotify, dir: PathBuf, raw: bool, arrived: Sender<PathBuf>) {
+    let mut buffer = [0u8; 4096];
+    loop {
+        let events = match inotify.read_events_blocking(&mut buffer) {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("Stopped watching spool directory {:?}: {}", dir, e);
+                return;
+            }
+        };
+        for event in events {
+            if event.mask.contains(EventMask::ISDIR) {
+                continue;
+            }
+            let Some(name) = event.name else { continue };
+            let path = dir.join(name);
+            // Stop once the player has gone
+            if is_spooled(&path, raw) && arrived.send(path).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Play each arrival in turn, then move it out of the way.
+fn play_arrivals(spec: InputSpec, arrivals: Receiver<PathBuf>, chunks: SyncSender<RatedChunk>) {
+    let done = spec.path.join(DONE_DIR);
+    for path in arrivals {
+        // Seen twice (listed at startup and reported by the watch) and already played
+        if !path.is_file() {
+            continue;
+        }
+        info!("Spool: playing {:?}", path);
+        let file = InputSpec {
+            path: path.clone(),
+            ..spec.clone()
+        };
+        match Source::open_direct(&file) {
+            Ok(mut source) => loop {
+                match source.next_chunk() {
+                    Ok(Some((samples, channels))) => {
+                        let chunk = (samples, channels, source.sample_rate);
+                        // Stop once playback has moved on to another input
+                        if chunks.send(chunk).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Spool: decode error in {:?}: {}", path, e);
+                        break;
+                    }
+                }
+            },
+            Err(e) => warn!("Spool: could not play {:?}: {}", path, e),
+        }
+
+        // Failed files move too, or they would be retried on every start
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        if let Err(e) = std::fs::rename(&path, done.join(name)) {
+            warn!("Spool: could not move {:?} to {:?}: {}", path, done, e);
+        }
+    }
+}
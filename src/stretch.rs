@@ -0,0 +1,175 @@
+//! Time stretching that keeps the pitch (WSOLA).
+//!
+//! Playback is cut into overlapping segments that are laid back down half a
+//! segment apart in the output, but taken from the input `tempo` times that
+//! far apart. Each segment may move a little from where it nominally starts,
+//! to wherever its waveform best lines up with how the previous segment
+//! carries on, so the crossfades between them don't smear or beat. Speech
+//! comes out at the same pitch, just faster or slower.
+
+/// Length of one segment.
+const SEGMENT_MS: u32 = 30;
+
+/// How far a segment may move to line up with the one before it.
+const TOLERANCE_MS: u32 = 10;
+
+/// Streaming WSOLA time stretcher for interleaved audio.
+pub struct Wsola {
+    channels: usize,
+    tempo: f64,
+    /// Hann window over one segment; its halves fade out and in.
+    window: Vec<f32>,
+    /// Output frames per segment, half a segment.
+    hop: usize,
+    /// How far either side of its nominal start a segment may be taken from.
+    tolerance: usize,
+    /// Input not yet used up, interleaved.
+    input: Vec<f32>,
+    /// Nominal start of the next segment, in frames into `input`.
+    next: f64,
+    /// Where the input carries on from the last segment; `None` before the first.
+    natural: Option<usize>,
+    /// The faded-out second half of the last segment, to add to the next one.
+    overlap: Vec<f32>,
+}
+
+impl Wsola {
+    pub fn new(channels: usize, rate: u32, tempo: f64) -> Self {
+        let hop = (rate * SEGMENT_MS / 2000) as usize;
+        let window = (0..hop * 2)
+            .map(|i| {
+                let phase = std::f64::consts::PI * i as f64 / hop as f64;
+                (0.5 - 0.5 * phase.cos()) as f32
+            })
+            .collect();
+        Self {
+            channels,
+            tempo,
+            window,
+            hop,
+            tolerance: (rate * TOLERANCE_MS / 1000) as usize,
+            input: Vec::new(),
+            next: 0.0,
+            natural: None,
+            overlap: Vec::new(),
+        }
+    }
+
+    /// Drop any held audio, e.g. before starting a new file or after a seek.
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.next = 0.0;
+        self.natural = None;
+        self.overlap.clear();
+    }
+
+    /// Stretch a chunk of interleaved frames, appending what is ready to
+    /// `output`. Up to a segment and a bit is held back until more arrives.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        self.input.extend_from_slice(input);
+        self.run(output);
+    }
+
+    /// Play out the audio held back at the end of the input, then reset.
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        let channels = self.channels;
+        if self.natural.is_none() {
+            // Shorter than a segment: nothing to stretch
+            output.append(&mut self.input);
+            self.reset();
+            return;
+        }
+
+        let frames = self.input.len() / channels;
+        let wanted = ((frames as f64 - self.next).max(0.0) / self.tempo).round() as usize;
+        // Pad with silence so the segments can run past the last input frame
+        let padding = (self.window.len() + self.tolerance) * channels;
+        self.input.extend(std::iter::repeat_n(0.0, padding));
+        let mut tail = Vec::new();
+        self.run(&mut tail);
+        tail.append(&mut self.overlap);
+        tail.truncate(wanted * channels);
+        output.extend(tail);
+        self.reset();
+    }
+
+    fn run(&mut self, output: &mut Vec<f32>) {
+        let channels = self.channels;
+        let (segment, hop) = (self.window.len(), self.hop);
+        loop {
+            let frames = self.input.len() / channels;
+            let nominal = self.next.round() as usize;
+            let reach = if self.natural.is_some() {
+                self.tolerance
+            } else {
+                0
+            };
+            if frames < nominal + reach + segment {
+                return;
+            }
+
+            let start = match self.natural {
+                Some(natural) => self.best_start(nominal, natural),
+                None => nominal,
+            };
+            let samples = &self.input[start * channels..(start + segment) * channels];
+            let (head, tail) = samples.split_at(hop * channels);
+            if self.natural.is_none() {
+                // Nothing to fade in from at the very start
+                output.extend_from_slice(head);
+            } else {
+                for (i, (&sample, held)) in head.iter().zip(&self.overlap).enumerate() {
+                    output.push(held + sample * self.window[i / channels]);
+                }
+            }
+            self.overlap.clear();
+            for (i, &sample) in tail.iter().enumerate() {
+                self.overlap.push(sample * self.window[hop + i / channels]);
+            }
+
+            self.natural = Some(start + hop);
+            self.next += hop as f64 * self.tempo;
+
+            // Drop input that no later segment can reach
+            let used = (self.next.round() as usize)
+                .saturating_sub(self.tolerance)
+                .min(start + hop);
+            if used >= segment {
+                self.input.drain(..used * channels);
+                self.next -= used as f64;
+                self.natural = Some(start + hop - used);
+            }
+        }
+    }
+
+    /// Where within `tolerance` of `nominal` a segment best continues the
+    /// audio at `natural`, by normalised cross-correlation of the mono mix
+    /// over the crossfade.
+    fn best_start(&self, nominal: usize, natural: usize) -> usize {
+        let channels = self.channels;
+        let mono = |frame: usize| -> f32 {
+            self.input[frame * channels..(frame + 1) * channels]
+                .iter()
+                .sum()
+        };
+        let first = nominal.saturating_sub(self.tolerance);
+        let last = nominal + self.tolerance;
+        let reference: Vec<f32> = (natural..natural + self.hop).map(mono).collect();
+        let candidates: Vec<f32> = (first..last + self.hop).map(mono).collect();
+
+        let mut energy: f32 = candidates[..self.hop].iter().map(|s| s * s).sum();
+        let mut best = (f32::MIN, nominal);
+        for offset in 0..=last - first {
+            let window = &candidates[offset..offset + self.hop];
+            let correlation: f32 = reference.iter().zip(window).map(|(a, b)| a * b).sum();
+            let score = correlation / (energy.max(0.0) + 1e-9).sqrt();
+            if score > best.0 {
+                best = (score, first + offset);
+            }
+            if let Some(&entering) = candidates.get(offset + self.hop) {
+                energy += entering * entering - window[0] * window[0];
+            }
+        }
+        best.1
+    }
+}
@@ -0,0 +1,151 @@
+//! Parsing the times and lengths given on the command line and in files.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Parse a time as seconds (`90`, `2.5`), clock style (`1:30`, `1:02:03.5`)
+/// or with units (`1m30s`, `1h2m`, `500ms`), any part of which may have a
+/// fraction.
+pub fn parse(time: &str) -> Option<Duration> {
+    let time = time.trim();
+    let seconds = if time.contains(':') {
+        parse_clock(time)?
+    } else if time.ends_with(|c: char| c.is_ascii_alphabetic()) {
+        parse_units(time)?
+    } else {
+        time.parse().ok()?
+    };
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+/// `m:ss` or `h:mm:ss`, in seconds.
+fn parse_clock(time: &str) -> Option<f64> {
+    let parts: Vec<&str> = time.split(':').collect();
+    let (seconds, units) = parts.split_last()?;
+    let seconds: f64 = seconds.parse().ok()?;
+    if units.len() > 2 || !(0.0..60.0).contains(&seconds) {
+        return None;
+    }
+    let mut minutes = 0;
+    for (i, unit) in units.iter().enumerate() {
+        let value: u64 = unit.parse().ok()?;
+        // Only the leading unit may go past 59
+        if i > 0 && value >= 60 {
+            return None;
+        }
+        minutes = minutes * 60 + value;
+    }
+    Some(minutes as f64 * 60.0 + seconds)
+}
+
+/// Numbers with `h`, `m`, `s` or `ms` units, largest first, in seconds.
+fn parse_units(time: &str) -> Option<f64> {
+    const UNITS: &[(&str, f64)] = &[("h", 3600.0), ("m", 60.0), ("s", 1.0), ("ms", 0.001)];
+    let mut rest = time;
+    let mut seconds = 0.0;
+    // Index into UNITS of the last unit seen, so they come in order
+    let mut last = None;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let value: f64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let name = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .map_or(rest, |end| &rest[..end]);
+        let unit = UNITS.iter().position(|(unit, _)| *unit == name)?;
+        if last.is_some_and(|last| unit <= last) {
+            return None;
+        }
+        last = Some(unit);
+        seconds += value * UNITS[unit].1;
+        rest = &rest[name.len()..];
+    }
+    Some(seconds)
+}
+
+/// Parse a time of day, `hh:mm` or `hh:mm:ss` with an optional fraction, as
+/// the time since midnight.
+pub fn parse_time_of_day(time: &str) -> Option<Duration> {
+    let parts: Vec<&str> = time.trim().split(':').collect();
+    let (hours, minutes, seconds) = match parts.as_slice() {
+        [hours, minutes] => (hours, minutes, 0.0),
+        [hours, minutes, seconds] => (hours, minutes, seconds.parse().ok()?),
+        _ => return None,
+    };
+    let hours: u64 = hours.parse().ok()?;
+    let minutes: u64 = minutes.parse().ok()?;
+    if hours >= 24 || minutes >= 60 || !(0.0..60.0).contains(&seconds) {
+        return None;
+    }
+    Duration::try_from_secs_f64((hours * 60 + minutes) as f64 * 60.0 + seconds).ok()
+}
+
+/// The next time the local clock shows `time_of_day`: today, or tomorrow if
+/// that has passed.
+pub fn next_local(time_of_day: Duration) -> SystemTime {
+    let seconds = time_of_day.as_secs() as libc::c_int;
+    let local = |days_ahead: libc::c_int| {
+        // SAFETY: plain libc calls filling in a zeroed tm
+        let timestamp = unsafe {
+            let now = libc::time(std::ptr::null_mut());
+            let mut tm: libc::tm = std::mem::zeroed();
+            libc::localtime_r(&now, &mut tm);
+            // mktime carries over into the next day and works out summer time
+            tm.tm_mday += days_ahead;
+            tm.tm_hour = seconds / 3600;
+            tm.tm_min = seconds / 60 % 60;
+            tm.tm_sec = seconds % 60;
+            tm.tm_isdst = -1;
+            libc::mktime(&mut tm)
+        };
+        UNIX_EPOCH
+            + Duration::from_secs(timestamp.max(0) as u64)
+            + Duration::from_nanos(time_of_day.subsec_nanos().into())
+    };
+    match local(0) {
+        today if today > SystemTime::now() => today,
+        _ => local(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(seconds: f64) -> Option<Duration> {
+        Some(Duration::from_secs_f64(seconds))
+    }
+
+    #[test]
+    fn parses_seconds_clock_times_and_units() {
+        assert_eq!(parse("90"), secs(90.0));
+        assert_eq!(parse(" 2.5 "), secs(2.5));
+        assert_eq!(parse("1:30"), secs(90.0));
+        assert_eq!(parse("1:02:03.5"), secs(3723.5));
+        // Only the leading unit may run past 59
+        assert_eq!(parse("75:00"), secs(4500.0));
+        assert_eq!(parse("1m30s"), secs(90.0));
+        assert_eq!(parse("1h2m"), secs(3720.0));
+        assert_eq!(parse("500ms"), secs(0.5));
+        assert_eq!(parse("1.5m"), secs(90.0));
+    }
+
+    #[test]
+    fn rejects_malformed_times() {
+        for time in [
+            "", "-1", "1:60", "1:60:00", "1:2:3:4", "abc", "1x", "30s1m", "1m1m", "1:3a",
+        ] {
+            assert_eq!(parse(time), None, "{:?}", time);
+        }
+    }
+
+    #[test]
+    fn parses_times_of_day() {
+        assert_eq!(parse_time_of_day("09:30"), secs(34_200.0));
+        assert_eq!(parse_time_of_day("23:59:59.5"), secs(86_399.5));
+        for time in ["24:00", "12:60", "12:00:60", "12", "1:2:3:4", "noon"] {
+            assert_eq!(parse_time_of_day(time), None, "{:?}", time);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Timeline scripts: files to play at set times from the start.
+//!
+//! Each cue gives a time and a file to play from then on, replacing whatever
+//! was playing. Cues are separated by `;` or newlines:
+//!
+//! ```text
+//! # Interview bot test
+//! t=0 play intro.wav
+//! t=30s play q1.wav; t=1:15 play "question two.wav"
+//! ```
+//!
+//! Times take the same forms as on the command line. Files with spaces in
+//! their names are quoted, and `#` starts a comment line.
+
+use crate::{cue, time};
+use anyhow::{anyhow, Result};
+use std::time::Duration;
+
+/// One cue of a timeline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimelineCue {
+    /// When to start playing, from the start of playback.
+    pub at: Duration,
+    /// The file, as written in the script.
+    pub file: String,
+}
+
+/// The cues of a timeline script, in the order they play.
+pub fn parse(contents: &str) -> Result<Vec<TimelineCue>> {
+    let mut cues: Vec<TimelineCue> = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim_start_matches('\u{feff}').trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        for statement in line.split(';').map(str::trim) {
+            if statement.is_empty() {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                anyhow!(
+                    "Invalid timeline line {} ({}): {}",
+                    number + 1,
+                    reason,
+                    statement
+                )
+            };
+
+            let fields = cue::tokens(statement);
+            let [at, command, file] = fields.as_slice() else {
+                return Err(invalid("expected t=<time> play <file>"));
+            };
+            let at = at
+                .strip_prefix("t=")
+                .ok_or_else(|| invalid("expected t=<time> play <file>"))?;
+            let at = time::parse(at).ok_or_else(|| invalid("bad time"))?;
+            if command != "play" {
+                return Err(invalid("the only command is play"));
+            }
+            if cues.last().is_some_and(|last| at < last.at) {
+                return Err(invalid("earlier than the cue before it"));
+            }
+            cues.push(TimelineCue {
+                at,
+                file: file.clone(),
+            });
+        }
+    }
+    Ok(cues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cues_on_lines_and_after_semicolons() {
+        let script = [
+            "# Interview bot test",
+            "t=0 play intro.wav",
+            "t=30s play q1.wav; t=1:15 play \"question two.wav\";",
+            "",
+            "t=1:15 play q3.wav",
+        ]
+        .join("\n");
+        let cue = |secs, file: &str| TimelineCue {
+            at: Duration::from_secs(secs),
+            file: file.to_string(),
+        };
+        assert_eq!(
+            parse(&script).unwrap(),
+            [
+                cue(0, "intro.wav"),
+                cue(30, "q1.wav"),
+                cue(75, "question two.wav"),
+                cue(75, "q3.wav"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_bad_cues() {
+        for (script, reason) in [
+            ("0 play a.wav", "expected t=<time>"),
+            ("t=0 play", "expected t=<time>"),
+            ("t=later play a.wav", "bad time"),
+            ("t=0 stop a.wav", "the only command is play"),
+            (
+                "t=10 play a.wav\nt=5 play b.wav",
+                "earlier than the cue before it",
+            ),
+        ] {
+            let error = parse(script).unwrap_err().to_string();
+            assert!(error.contains(reason), "{}: {}", script, error);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Tracker module playback (MOD, XM, IT, S3M) through libopenmpt.
+//!
+//! Needs a build with `--features tracker` and libopenmpt installed. Modules
+//! are loaded whole and rendered to stereo as playback goes; each plays once,
+//! so `--loop` restarts it like any other file.
+
+use std::path::Path;
+
+/// Extensions of the module formats libopenmpt plays.
+const MODULE_EXTENSIONS: &[&str] = &["it", "mod", "mptm", "s3m", "xm"];
+
+pub fn is_module_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| MODULE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+#[cfg(feature = "tracker")]
+pub use module::{Module, RENDER_RATE};
+
+#[cfg(feature = "tracker")]
+mod module {
+    use anyhow::{anyhow, Result};
+    use std::ffi::{c_char, c_int, c_void, CStr};
+    use std::path::Path;
+    use std::ptr;
+    use std::time::Duration;
+    use tracing::info;
+
+    /// Rate modules are rendered at; the output stage resamples if it differs.
+    pub const RENDER_RATE: u32 = 48000;
+    /// Frames rendered per chunk.
+    const CHUNK_FRAMES: usize = 1024;
+
+    #[repr(C)]
+    struct OpenmptModule {
+        _private: [u8; 0],
+    }
+
+    type LogFunc = unsafe extern "C" fn(message: *const c_char, user: *mut c_void);
+    type ErrorFunc = unsafe extern "C" fn(error: c_int, user: *mut c_void) -> c_int;
+
+    #[link(name = "openmpt")]
+    extern "C" {
+        fn openmpt_log_func_silent(message: *const c_char, user: *mut c_void);
+        fn openmpt_module_create_from_memory2(
+            filedata: *const c_void,
+            filesize: usize,
+            logfunc: Option<LogFunc>,
+            loguser: *mut c_void,
+            errfunc: Option<ErrorFunc>,
+            erruser: *mut c_void,
+            error: *mut c_int,
+            error_message: *mut *const c_char,
+            ctls: *const c_void,
+        ) -> *mut OpenmptModule;
+        fn openmpt_module_destroy(module: *mut OpenmptModule);
+        fn openmpt_module_get_duration_seconds(module: *mut OpenmptModule) -> f64;
+        fn openmpt_module_get_metadata(
+            module: *mut OpenmptModule,
+            key: *const c_char,
+        ) -> *const c_char;
+        fn openmpt_module_read_interleaved_float_stereo(
+            module: *mut OpenmptModule,
+            samplerate: i32,
+            count: usize,
+            interleaved_stereo: *mut f32,
+        ) -> usize;
+        fn openmpt_free_string(string: *const c_char);
+    }
+
+    /// Take ownership of a string returned by libopenmpt.
+    unsafe fn take_string(string: *const c_char) -> Option<String> {
+        if string.is_null() {
+            return None;
+        }
+        let owned = CStr::from_ptr(string).to_string_lossy().into_owned();
+        openmpt_free_string(string);
+        Some(owned)
+    }
+
+    /// A loaded module, yielding interleaved stereo chunks. Dropping it frees it.
+    pub struct Module {
+        module: *mut OpenmptModule,
+        pub duration: Duration,
+    }
+
+    impl Module {
+        pub fn open(path: &Path) -> Result<Self> {
+            let data = std::fs::read(path)?;
+            let mut error_message = ptr::null();
+            // SAFETY: libopenmpt copies the data; the out-pointers outlive the call
+            let module = unsafe {
+                openmpt_module_create_from_memory2(
+                    data.as_ptr().cast(),
+                    data.len(),
+                    Some(openmpt_log_func_silent),
+                    ptr::null_mut(),
+                    None,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    &mut error_message,
+                    ptr::null(),
+                )
+            };
+            // SAFETY: set by libopenmpt or still null
+            let message = unsafe { take_string(error_message) };
+            if module.is_null() {
+                return Err(anyhow!(
+                    "Not a playable module {:?}: {}",
+                    path,
+                    message.as_deref().unwrap_or("unknown error")
+                ));
+            }
+
+            // SAFETY: the module is valid until dropped
+            let (seconds, kind, title) = unsafe {
+                (
+                    openmpt_module_get_duration_seconds(module),
+                    take_string(openmpt_module_get_metadata(module, c"type_long".as_ptr())),
+                    take_string(openmpt_module_get_metadata(module, c"title".as_ptr())),
+                )
+            };
+            let duration = Duration::from_secs_f64(seconds.max(0.0));
+            info!(
+                "Module: {} \"{}\", {:.1}s",
+                kind.unwrap_or_default(),
+                title.unwrap_or_default(),
+                duration.as_secs_f64()
+            );
+            Ok(Self { module, duration })
+        }
+
+        /// The next chunk of interleaved stereo samples, or `None` at the end of the song.
+        pub fn next_chunk(&mut self) -> Option<Vec<f32>> {
+            let mut samples = vec![0.0; CHUNK_FRAMES * 2];
+            // SAFETY: the buffer holds CHUNK_FRAMES stereo frames
+            let frames = unsafe {
+                openmpt_module_read_interleaved_float_stereo(
+                    self.module,
+                    RENDER_RATE as i32,
+                    CHUNK_FRAMES,
+                    samples.as_mut_ptr(),
+                )
+            };
+            if frames == 0 {
+                return None;
+            }
+            samples.truncate(frames * 2);
+            Some(samples)
+        }
+    }
+
+    // SAFETY: a module may be used from any thread, one at a time, which `&mut self` ensures
+    unsafe impl Send for Module {}
+
+    impl Drop for Module {
+        fn drop(&mut self) {
+            // SAFETY: created by openmpt_module_create_from_memory2 and not yet freed
+            unsafe { openmpt_module_destroy(self.module) };
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Just enough MPEG transport stream demuxing to pull the audio out of HLS
+//! segments. Symphonia can't read TS, but it can decode the ADTS (AAC) or MPEG
+//! audio elementary streams carried inside it.
+
+const PACKET_SIZE: usize = 188;
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+
+/// Elementary stream types Symphonia can decode once demuxed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    /// AAC in ADTS framing (stream type 0x0F).
+    Adts,
+    /// MPEG-1/2 audio, i.e. MP3 (stream types 0x03 and 0x04).
+    Mpeg,
+}
+
+impl AudioCodec {
+    /// The probe hint for the demuxed stream.
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioCodec::Adts => "aac",
+            AudioCodec::Mpeg => "mp3",
+        }
+    }
+}
+
+pub fn is_transport_stream(data: &[u8]) -> bool {
+    data.len() >= PACKET_SIZE && data[0] == SYNC_BYTE
+}
+
+/// The first audio stream's bytes and codec, or `None` if the segment has no
+/// audio stream we can decode.
+pub fn demux_audio(data: &[u8]) -> Option<(Vec<u8>, AudioCodec)> {
+    let mut pmt_pid = None;
+    let mut audio: Option<(u16, AudioCodec)> = None;
+    let mut out = Vec::with_capacity(data.len() / 2);
+
+    for packet in data.chunks_exact(PACKET_SIZE) {
+        if packet[0] != SYNC_BYTE {
+            continue;
+        }
+        let unit_start = packet[1] & 0x40 != 0;
+        let pid = u16::from(packet[1] & 0x1f) << 8 | u16::from(packet[2]);
+        let adaptation = (packet[3] >> 4) & 0x3;
+        let payload_start = match adaptation {
+            1 => 4,
+            3 => 5 + packet[4] as usize,
+            _ => continue,
+        };
+        let Some(payload) = packet.get(payload_start..) else {
+            continue;
+        };
+
+        if pid == PAT_PID && unit_start {
+            pmt_pid = pmt_pid.or_else(|| parse_pat(payload));
+        } else if Some(pid) == pmt_pid && unit_start && audio.is_none() {
+            audio = parse_pmt(payload);
+        } else if audio.is_some_and(|(audio_pid, _)| audio_pid == pid) {
+            let data = if unit_start {
+                pes_payload(payload).unwrap_or_default()
+            } else {
+                payload
+            };
+            out.extend_from_slice(data);
+        }
+    }
+
+    audio.map(|(_, codec)| (out, codec))
+}
+
+/// Skip the pointer field and return the section and its length-bounded body.
+fn section(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = *payload.first()? as usize;
+    let section = payload.get(1 + pointer..)?;
+    let length = (usize::from(section.get(1)? & 0x0f) << 8) | usize::from(*section.get(2)?);
+    // Drop the trailing CRC
+    section.get(..3 + length.checked_sub(4)?)
+}
+
+/// PID of the first program's map table.
+fn parse_pat(payload: &[u8]) -> Option<u16> {
+    let section = section(payload)?;
+    section.get(8..)?.chunks_exact(4).find_map(|program| {
+        let number = u16::from(program[0]) << 8 | u16::from(program[1]);
+        // Program 0 points at the network information table
+        (number != 0).then(|| u16::from(program[2] & 0x1f) << 8 | u16::from(program[3]))
+    })
+}
+
+/// PID and codec of the first decodable audio stream in a program map table.
+fn parse_pmt(payload: &[u8]) -> Option<(u16, AudioCodec)> {
+    let section = section(payload)?;
+    let info_length = (usize::from(section.get(10)? & 0x0f) << 8) | usize::from(*section.get(11)?);
+    let mut streams = section.get(12 + info_length..)?;
+
+    while streams.len() >= 5 {
+        let stream_type = streams[0];
+        let pid = u16::from(streams[1] & 0x1f) << 8 | u16::from(streams[2]);
+        let es_info_length = (usize::from(streams[3] & 0x0f) << 8) | usize::from(streams[4]);
+        let codec = match stream_type {
+            0x0f => Some(AudioCodec::Adts),
+            0x03 | 0x04 => Some(AudioCodec::Mpeg),
+            _ => None,
+        };
+        if let Some(codec) = codec {
+            return Some((pid, codec));
+        }
+        streams = streams.get(5 + es_info_length..)?;
+    }
+    None
+}
+
+/// The data of a packet that starts a PES packet, past its header.
+fn pes_payload(payload: &[u8]) -> Option<&[u8]> {
+    if payload.get(..3)? != [0, 0, 1] {
+        return None;
+    }
+    let header_length = *payload.get(8)? as usize;
+    payload.get(9 + header_length..)
+}
@@ -0,0 +1,217 @@
+//! Text-to-speech through an external engine, for `--say`, `--say-file` and
+//! `--say-stdin`.
+//!
+//! The text is written to the engine's stdin and the audio read from its
+//! stdout as it is synthesized, so speech starts before the whole text is
+//! done and nothing touches the disk.
+//!
+//! Text that starts with a `<speak>` element is SSML. Engines that understand
+//! it get the markup as is; for the others it is reduced to plain text, with
+//! breaks, paragraphs and sentences becoming line breaks.
+
+use crate::feed::{Feed, RatedChunk};
+use crate::raw::{RawFormat, RawParams};
+use crate::source::{InputSpec, Source};
+use anyhow::{anyhow, Result};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use tracing::{info, warn};
+
+/// Rate of Piper voices whose config can't be read.
+const PIPER_DEFAULT_RATE: u32 = 22050;
+
+/// Supported speech engines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TtsEngine {
+    /// espeak-ng; --tts-voice is a voice name such as "en-us"
+    EspeakNg,
+    /// Piper neural TTS; --tts-voice is the path of a .onnx voice model
+    Piper,
+}
+
+/// How to synthesize text, from the command line.
+#[derive(Debug, Clone)]
+pub struct TtsOptions {
+    pub engine: TtsEngine,
+    pub voice: Option<String>,
+    /// Playlist entries name text files to read rather than the text itself.
+    pub from_file: bool,
+}
+
+impl TtsOptions {
+    /// The engine command for `text`, the input to write to it, and the raw
+    /// PCM format of its output when it doesn't write WAV.
+    pub fn synthesis(&self, text: &str) -> Result<(Command, Vec<u8>, Option<RawParams>)> {
+        let ssml = is_ssml(text);
+        let input = match self.engine {
+            TtsEngine::EspeakNg => text.to_string(),
+            TtsEngine::Piper if ssml => strip_ssml(text),
+            TtsEngine::Piper => text.to_string(),
+        };
+        let (command, raw) = self.command(ssml)?;
+        Ok((command, input.into_bytes(), raw))
+    }
+
+    fn command(&self, ssml: bool) -> Result<(Command, Option<RawParams>)> {
+        match self.engine {
+            TtsEngine::EspeakNg => {
+                let mut command = Command::new("espeak-ng");
+                command.args(["--stdin", "--stdout"]);
+                if ssml {
+                    command.arg("-m");
+                }
+                if let Some(voice) = &self.voice {
+                    command.args(["-v", voice]);
+                }
+                Ok((command, None))
+            }
+            TtsEngine::Piper => {
+                let model = self.voice.as_deref().ok_or_else(|| {
+                    anyhow!("Piper needs a voice model: pass --tts-voice <model.onnx>")
+                })?;
+                let mut command = Command::new("piper");
+                command.args(["--model", model, "--output-raw"]);
+                let params = RawParams {
+                    format: RawFormat::S16le,
+                    rate: piper_sample_rate(Path::new(model)),
+                    channels: 1,
+                };
+                Ok((command, Some(params)))
+            }
+        }
+    }
+}
+
+/// Whether text is an SSML document rather than plain text.
+fn is_ssml(text: &str) -> bool {
+    let text = text.trim_start();
+    let text = match text.strip_prefix("<?xml") {
+        // Skip the XML declaration
+        Some(rest) => rest
+            .split_once("?>")
+            .map_or("", |(_, body)| body.trim_start()),
+        None => text,
+    };
+    text.starts_with("<speak")
+}
+
+/// Plain text for engines without SSML support: markup is dropped, entities
+/// are decoded, and breaks, paragraphs and sentences end a line.
+fn strip_ssml(ssml: &str) -> String {
+    let mut text = String::with_capacity(ssml.len());
+    let mut rest = ssml;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('>') else {
+            rest = "";
+            break;
+        };
+        let tag = rest[start + 1..start + end].trim_start_matches('/');
+        let name: String = tag
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == ':')
+            .collect();
+        if matches!(name.to_ascii_lowercase().as_str(), "break" | "p" | "s") {
+            text.push('\n');
+        }
+        rest = &rest[start + end + 1..];
+    }
+    text.push_str(rest);
+
+    let text = text
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The sample rate in a Piper model's `<model>.json` config.
+fn piper_sample_rate(model: &Path) -> u32 {
+    let mut config = model.as_os_str().to_owned();
+    config.push(".json");
+    std::fs::read_to_string(config)
+        .ok()
+        .and_then(|json| {
+            let value = &json[json.find("\"sample_rate\"")? + "\"sample_rate\"".len()..];
+            let value = value.trim_start().strip_prefix(':')?.trim_start();
+            let end = value
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(value.len());
+            value[..end].parse().ok()
+        })
+        .unwrap_or(PIPER_DEFAULT_RATE)
+}
+
+/// Speak each line read from stdin, in order, on background threads.
+///
+/// Lines queue up while an earlier one is still being spoken, so they never
+/// overlap. `spec` supplies the TTS options.
+pub fn speak_stdin(spec: InputSpec) -> std::io::Result<Feed> {
+    let (line_tx, lines) = mpsc::channel();
+    std::thread::Builder::new()
+        .name("tts-stdin".into())
+        .spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                match line {
+                    Ok(line) if line.trim().is_empty() => {}
+                    Ok(line) => {
+                        if line_tx.send(line).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Failed to read stdin: {}", e);
+                        return;
+                    }
+                }
+            }
+            info!("Stdin closed; no more lines to say");
+        })?;
+
+    let (tx, feed) = Feed::channel();
+    std::thread::Builder::new()
+        .name("tts".into())
+        .spawn(move || speak_lines(spec, lines, tx))?;
+    Ok(feed)
+}
+
+fn speak_lines(spec: InputSpec, lines: Receiver<String>, chunks: SyncSender<RatedChunk>) {
+    for line in lines {
+        info!("Saying: {}", line);
+        let entry = InputSpec {
+            path: PathBuf::from(line),
+            ..spec.clone()
+        };
+        let mut source = match Source::open(&entry) {
+            Ok(source) => source,
+            Err(e) => {
+                warn!("Could not synthesize line: {}", e);
+                continue;
+            }
+        };
+        loop {
+            match source.next_chunk() {
+                Ok(Some((samples, channels))) => {
+                    let chunk = (samples, channels, source.sample_rate);
+                    // Stop once playback has moved on to another input
+                    if chunks.send(chunk).is_err() {
+                        return;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Speech decode error: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,537 @@
+//! `--tui`: a full-screen dashboard in place of the log stream, showing the
+//! device, what is playing and how far in, a level meter of the outgoing
+//! audio, the queue, and the keys.
+//!
+//! It reads `status` and `playlist` through the control requests, like the
+//! remote interfaces do, and its keys are those of [`crate::keys`], plus `q`
+//! to quit. While it is up, stdout and stderr (the log, and the output of
+//! child processes) are captured into its log pane, and the lines still held
+//! are printed once it closes.
+
+use crate::control::{ControlCommand, Request};
+use crate::dsp::Meter;
+use crate::keys;
+use crate::rest::Device;
+use crate::webhook;
+use anyhow::{anyhow, Result};
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::cursor::Show;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Gauge, LineGauge, List, ListItem, ListState, Paragraph};
+use ratatui::{Frame, Terminal};
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::os::fd::FromRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tracing::warn;
+
+/// How often the screen is redrawn.
+const FRAME: Duration = Duration::from_millis(50);
+
+/// Log lines kept for the log pane.
+const LOG_LINES: usize = 500;
+
+/// Range of the level meter, in dBFS below full scale.
+const METER_FLOOR_DB: f32 = -60.0;
+
+/// How far the meter falls per frame, in dB, so peaks stay readable.
+const METER_FALL_DB: f32 = 1.5;
+
+/// The lines written to stdout and stderr, oldest first.
+type Log = Arc<Mutex<VecDeque<String>>>;
+
+/// The running dashboard; the terminal, stdout and stderr are given back
+/// when dropped.
+pub struct Tui {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    output: Option<Capture>,
+    log: Log,
+}
+
+impl Tui {
+    /// Take over the terminal and draw the dashboard on a background thread,
+    /// sending commands to `tx`. With `pads` soundboard clips, the number
+    /// keys play them.
+    pub fn start(device: Device, meter: Meter, pads: usize, tx: Sender<Request>) -> Result<Self> {
+        if !std::io::stdout().is_terminal() {
+            return Err(anyhow!("--tui needs stdout to be a terminal"));
+        }
+        let log: Log = Arc::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let output = Capture::start(log.clone())?;
+        let screen = output.screen();
+        // From here on, dropping it puts everything back
+        let mut tui = Self {
+            stop: stop.clone(),
+            thread: None,
+            output: Some(output),
+            log: log.clone(),
+        };
+        terminal::enable_raw_mode()?;
+        let mut backend = CrosstermBackend::new(screen?);
+        execute!(backend, EnterAlternateScreen)?;
+        let terminal = Terminal::new(backend)?;
+
+        let mut dashboard = Dashboard {
+            device,
+            meter,
+            pads,
+            requests: tx,
+            log,
+            status: Map::new(),
+            entries: Vec::new(),
+            levels: Vec::new(),
+            message: None,
+            pending_status: None,
+            pending_playlist: None,
+            pending_commands: Vec::new(),
+        };
+        let thread = std::thread::Builder::new()
+            .name("tui".into())
+            .spawn(move || {
+                if let Err(e) = dashboard.run(terminal, &stop) {
+                    warn!("The dashboard failed: {}", e);
+                }
+            })?;
+        tui.thread = Some(thread);
+        Ok(tui)
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        if let Some(output) = self.output.take() {
+            output.finish();
+        }
+        let mut stdout = std::io::stdout().lock();
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(stdout, LeaveAlternateScreen, Show);
+        // What scrolled by on the dashboard stays readable afterwards
+        let log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        for line in log.iter() {
+            let _ = writeln!(stdout, "{}", line);
+        }
+    }
+}
+
+/// The terminal, drawn on through a copy of the real stdout.
+type Screen = Terminal<CrosstermBackend<File>>;
+
+/// What the dashboard shows, and the requests it is waiting on.
+struct Dashboard {
+    device: Device,
+    meter: Meter,
+    pads: usize,
+    requests: Sender<Request>,
+    log: Log,
+    /// The fields of the last `status`.
+    status: Map<String, Value>,
+    /// The last `playlist`.
+    entries: Vec<Value>,
+    /// The level shown for each channel, linear.
+    levels: Vec<f32>,
+    /// The error of the last command that failed.
+    message: Option<String>,
+    pending_status: Option<Receiver<Result<String, String>>>,
+    pending_playlist: Option<Receiver<Result<String, String>>>,
+    pending_commands: Vec<Receiver<Result<String, String>>>,
+}
+
+impl Dashboard {
+    fn run(&mut self, mut terminal: Screen, stop: &AtomicBool) -> Result<()> {
+        while !stop.load(Ordering::SeqCst) {
+            self.update();
+            terminal.draw(|frame| self.draw(frame))?;
+            if !event::poll(FRAME)? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind == KeyEventKind::Release {
+                continue;
+            }
+            let command = match key.code {
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    Some(ControlCommand::Quit)
+                }
+                KeyCode::Char('q') | KeyCode::Esc => Some(ControlCommand::Quit),
+                KeyCode::Right => keys::command(b'C', true, self.pads),
+                KeyCode::Left => keys::command(b'D', true, self.pads),
+                KeyCode::Char(c) if c.is_ascii() => keys::command(c as u8, false, self.pads),
+                _ => None,
+            };
+            if let Some(command) = command {
+                let (request, response) = Request::new(command);
+                if self.requests.send(request).is_ok() {
+                    self.pending_commands.push(response);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Take in answers that have arrived, and ask again for those that have.
+    fn update(&mut self) {
+        if let Some(answer) = answer(&mut self.pending_status) {
+            let status = webhook::fields(&answer);
+            // The queue only changes when its length does or a track is jumped to
+            if status.get("tracks") != self.status.get("tracks")
+                || status.get("path") != self.status.get("path")
+            {
+                self.pending_playlist = self.ask(ControlCommand::Playlist);
+            }
+            self.status = status;
+        }
+        if self.pending_status.is_none() {
+            self.pending_status = self.ask(ControlCommand::Status);
+        }
+        if let Some(answer) = answer(&mut self.pending_playlist) {
+            self.entries = serde_json::from_str(&answer).unwrap_or_default();
+        }
+        self.pending_commands
+            .retain(|response| match response.try_recv() {
+                Ok(Ok(_)) => {
+                    self.message = None;
+                    false
+                }
+                Ok(Err(e)) => {
+                    self.message = Some(e);
+                    false
+                }
+                Err(TryRecvError::Empty) => true,
+                Err(TryRecvError::Disconnected) => false,
+            });
+
+        let peaks = self.meter.take();
+        self.levels.resize(peaks.len(), 0.0);
+        let fall = 10f32.powf(-METER_FALL_DB / 20.0);
+        for (level, peak) in self.levels.iter_mut().zip(peaks) {
+            *level = peak.max(*level * fall);
+        }
+    }
+
+    fn ask(&self, command: ControlCommand) -> Option<Receiver<Result<String, String>>> {
+        let (request, response) = Request::new(command);
+        self.requests.send(request).ok().map(|_| response)
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [device, playing, meter, lists, help] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Length(5),
+            Constraint::Length(self.levels.len().max(1) as u16 + 2),
+            Constraint::Min(4),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [queue, log] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(lists);
+
+        self.draw_device(frame, device);
+        self.draw_playing(frame, playing);
+        self.draw_meter(frame, meter);
+        self.draw_queue(frame, queue);
+        self.draw_log(frame, log);
+
+        let footer = match &self.message {
+            Some(message) => Line::from(message.as_str()).red(),
+            None => Line::from(format!("{}, q quits", keys::HELP)).dim(),
+        };
+        frame.render_widget(Paragraph::new(footer), help);
+    }
+
+    fn draw_device(&self, frame: &mut Frame, area: Rect) {
+        let device = &self.device;
+        let lines = vec![
+            Line::from(vec![
+                "Source ".dim(),
+                Span::raw(&device.source),
+                "  Sink ".dim(),
+                Span::raw(&device.sink),
+            ]),
+            Line::from(vec![
+                Span::raw(format!(
+                    "{} Hz, {} channels, {}",
+                    device.rate, device.channels, device.format
+                )),
+                "  pid ".dim(),
+                Span::raw(device.pid.to_string()),
+            ]),
+        ];
+        let block = Block::bordered().title(format!(" virtual-mic: {} ", device.name).bold());
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn draw_playing(&self, frame: &mut Frame, area: Rect) {
+        let text = |field: &str| self.status.get(field).and_then(Value::as_str);
+        let number = |field: &str| self.status.get(field).and_then(Value::as_f64);
+
+        let state = match text("state").unwrap_or("starting") {
+            "playing" => "▶ playing".green(),
+            "paused" => "⏸ paused".yellow(),
+            state => Span::raw(state.to_string()),
+        };
+        let mut line = vec![state];
+        if let (Some(track), Some(tracks)) = (number("track"), number("tracks")) {
+            line.push(Span::raw(format!("  track {} of {}", track, tracks)));
+        }
+        if let Some(volume) = number("volume") {
+            line.push(Span::raw(format!("  volume {:.2}", volume)));
+        }
+        if self.status.get("muted").and_then(Value::as_bool) == Some(true) {
+            line.push("  MUTED".red().bold());
+        }
+
+        let track = number("track").unwrap_or_default() as usize;
+        let title = match self.entries.get(track.wrapping_sub(1)) {
+            Some(Value::Object(entry)) => entry.get("title").and_then(Value::as_str),
+            _ => None,
+        };
+        let title = title.or(text("path")).unwrap_or_default();
+
+        let block = Block::bordered().title(" Now playing ");
+        let [state_area, title_area, progress_area] =
+            Layout::vertical([Constraint::Length(1); 3]).areas(block.inner(area));
+        frame.render_widget(block, area);
+        frame.render_widget(Paragraph::new(Line::from(line)), state_area);
+        frame.render_widget(Paragraph::new(title.bold()), title_area);
+
+        let position = number("position_secs").unwrap_or_default();
+        let label = match number("duration_secs") {
+            Some(duration) => format!("{} / {}", clock(position), clock(duration)),
+            None => clock(position),
+        };
+        let ratio = number("progress_percent").unwrap_or_default() / 100.0;
+        let progress = Gauge::default()
+            .gauge_style(Style::new().fg(Color::Cyan))
+            .ratio(ratio.clamp(0.0, 1.0))
+            .label(label);
+        frame.render_widget(progress, progress_area);
+    }
+
+    fn draw_meter(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Level ");
+        let inner = block.inner(area);
+        frame.render_widget(block, area);
+        let rows = Layout::vertical(vec![Constraint::Length(1); self.levels.len()]).split(inner);
+        for (channel, (level, row)) in self.levels.iter().zip(rows.iter()).enumerate() {
+            let db = 20.0 * level.max(f32::MIN_POSITIVE).log10();
+            let color = match db {
+                db if db > -3.0 => Color::Red,
+                db if db > -12.0 => Color::Yellow,
+                _ => Color::Green,
+            };
+            let label = match db < METER_FLOOR_DB {
+                true => format!("{:>2}    -inf dB", channel_name(channel, self.levels.len())),
+                false => format!(
+                    "{:>2} {:>7.1} dB",
+                    channel_name(channel, self.levels.len()),
+                    db
+                ),
+            };
+            let ratio = (1.0 - db / METER_FLOOR_DB).clamp(0.0, 1.0);
+            let gauge = LineGauge::default()
+                .filled_style(Style::new().fg(color))
+                .ratio(ratio as f64)
+                .label(label);
+            frame.render_widget(gauge, *row);
+        }
+    }
+
+    fn draw_queue(&self, frame: &mut Frame, area: Rect) {
+        let track = self
+            .status
+            .get("track")
+            .and_then(Value::as_u64)
+            .unwrap_or_default() as usize;
+        let items: Vec<ListItem> = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| {
+                let path = entry
+                    .get("path")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let name = match entry.get("title").and_then(Value::as_str) {
+                    Some(title) => title,
+                    None => path.rsplit('/').next().unwrap_or(path),
+                };
+                ListItem::new(format!("{:>3}. {}", i + 1, name))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(Block::bordered().title(" Queue "))
+            .highlight_style(Style::new().fg(Color::Cyan).add_modifier(Modifier::BOLD));
+        let mut state = ListState::default().with_selected(track.checked_sub(1));
+        frame.render_stateful_widget(list, area, &mut state);
+    }
+
+    fn draw_log(&self, frame: &mut Frame, area: Rect) {
+        let block = Block::bordered().title(" Log ");
+        let height = block.inner(area).height as usize;
+        let log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        let lines: Vec<Line> = log
+            .iter()
+            .skip(log.len().saturating_sub(height))
+            .map(|line| Line::raw(line.as_str()))
+            .collect();
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+}
+
+/// The answer to a request, once it has come.
+fn answer(pending: &mut Option<Receiver<Result<String, String>>>) -> Option<String> {
+    let result = match pending.as_ref()?.try_recv() {
+        Err(TryRecvError::Empty) => return None,
+        Ok(result) => result.ok(),
+        Err(TryRecvError::Disconnected) => None,
+    };
+    *pending = None;
+    result
+}
+
+/// Seconds as `m:ss`, or `h:mm:ss`.
+fn clock(secs: f64) -> String {
+    let secs = secs.max(0.0) as u64;
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match h {
+        0 => format!("{}:{:02}", m, s),
+        h => format!("{}:{:02}:{:02}", h, m, s),
+    }
+}
+
+/// `L` and `R` for stereo, `M` for mono, or the channel's number.
+fn channel_name(channel: usize, channels: usize) -> String {
+    match (channels, channel) {
+        (1, _) => "M".to_string(),
+        (2, 0) => "L".to_string(),
+        (2, _) => "R".to_string(),
+        _ => (channel + 1).to_string(),
+    }
+}
+
+/// Sends stdout and stderr into a pipe read into the log, so nothing written
+/// there scribbles over the screen.
+struct Capture {
+    /// Copies of the real stdout and stderr, to put back.
+    saved: [libc::c_int; 2],
+    /// Says when the reader has read to the end of the pipe.
+    drained: Receiver<()>,
+}
+
+impl Capture {
+    const FDS: [libc::c_int; 2] = [libc::STDOUT_FILENO, libc::STDERR_FILENO];
+
+    fn start(log: Log) -> Result<Self> {
+        let mut pipe = [0; 2];
+        let mut saved = [-1; 2];
+        // SAFETY: plain libc calls on descriptors made here and on stdout and stderr
+        unsafe {
+            if libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            for (saved, fd) in saved.iter_mut().zip(Self::FDS) {
+                *saved = libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0);
+            }
+            if saved.contains(&-1) || Self::FDS.iter().any(|&fd| libc::dup2(pipe[1], fd) < 0) {
+                let e = std::io::Error::last_os_error();
+                for (saved, fd) in saved.into_iter().zip(Self::FDS) {
+                    if saved >= 0 {
+                        libc::dup2(saved, fd);
+                        libc::close(saved);
+                    }
+                }
+                libc::close(pipe[0]);
+                libc::close(pipe[1]);
+                return Err(e.into());
+            }
+            libc::close(pipe[1]);
+        }
+        // SAFETY: the read end was just made, and nothing else owns it
+        let pipe = unsafe { File::from_raw_fd(pipe[0]) };
+
+        let (done, drained) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("tui-log".into())
+            .spawn(move || {
+                let mut reader = BufReader::new(pipe);
+                let mut line = Vec::new();
+                while matches!(reader.read_until(b'\n', &mut line), Ok(n) if n > 0) {
+                    let text = strip_escapes(&String::from_utf8_lossy(&line));
+                    let mut log = log.lock().unwrap_or_else(|e| e.into_inner());
+                    if log.len() == LOG_LINES {
+                        log.pop_front();
+                    }
+                    log.push_back(text.trim_end().to_string());
+                    line.clear();
+                }
+                let _ = done.send(());
+            })?;
+        Ok(Self { saved, drained })
+    }
+
+    /// A handle on the real stdout, to draw on.
+    fn screen(&self) -> Result<File> {
+        // SAFETY: `saved[0]` is the copy of stdout made in `start`, still open
+        let fd = unsafe { libc::fcntl(self.saved[0], libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        // SAFETY: the descriptor was just made, and nothing else owns it
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// Put stdout and stderr back, and wait a moment for what is left in the pipe.
+    fn finish(self) {
+        let _ = std::io::stdout().flush();
+        // SAFETY: `saved` holds the copies made in `start`
+        unsafe {
+            for (saved, fd) in self.saved.into_iter().zip(Self::FDS) {
+                libc::dup2(saved, fd);
+                libc::close(saved);
+            }
+        }
+        // A child process still holding the pipe keeps it from ending
+        let _ = self.drained.recv_timeout(Duration::from_millis(200));
+    }
+}
+
+/// Drop the terminal escape sequences (colours, mostly) from a log line.
+fn strip_escapes(line: &str) -> String {
+    let mut text = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            text.push(c);
+            continue;
+        }
+        if chars.next() == Some('[') {
+            // Parameters, then a final byte from @ to ~
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    text
+}
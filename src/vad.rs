@@ -0,0 +1,101 @@
+//! `--reply-to`: taking turns with the far side of a conversation, such as a
+//! voice assistant under test.
+//!
+//! Audio captured from the far side is measured in 20 ms blocks. Once it has
+//! spoken (a block at or over the threshold) and then stayed under it for the
+//! set time, it has finished its turn and the next entry plays.
+
+use std::time::Duration;
+use tracing::info;
+
+/// Length of the blocks whose level is measured.
+const BLOCK: Duration = Duration::from_millis(20);
+
+/// Listens to the far side for the end of its turn.
+pub struct TurnTaking {
+    /// RMS level that counts as speech.
+    threshold: f32,
+    /// Samples of silence after speech that end a turn.
+    silence: usize,
+    /// Interleaved samples per block.
+    block: usize,
+    /// Sum of squares and sample count of the block being measured.
+    sum: f64,
+    count: usize,
+    listening: bool,
+    heard_speech: bool,
+    /// Samples under the threshold since the last speech.
+    quiet: usize,
+    /// The far side finished its turn and nothing has been played for it yet.
+    done: bool,
+}
+
+impl TurnTaking {
+    pub fn new(threshold_dbfs: f32, silence: Duration, rate: u32, channels: usize) -> Self {
+        let samples = |time: Duration| (time.as_secs_f64() * rate as f64) as usize * channels;
+        Self {
+            threshold: 10f32.powf(threshold_dbfs / 20.0),
+            silence: samples(silence),
+            block: samples(BLOCK).max(channels),
+            sum: 0.0,
+            count: 0,
+            listening: false,
+            heard_speech: false,
+            quiet: 0,
+            done: false,
+        }
+    }
+
+    /// Start listening for the far side's next turn.
+    pub fn listen(&mut self) {
+        self.listening = true;
+        self.heard_speech = false;
+        self.quiet = 0;
+    }
+
+    pub fn is_listening(&self) -> bool {
+        self.listening
+    }
+
+    /// Whether the far side has finished its turn since the last call.
+    pub fn take_turn(&mut self) -> bool {
+        std::mem::take(&mut self.done)
+    }
+
+    /// Measure captured audio from the far side.
+    pub fn push(&mut self, samples: &[f32]) {
+        if !self.listening {
+            return;
+        }
+        for &sample in samples {
+            self.sum += (sample * sample) as f64;
+            self.count += 1;
+            if self.count == self.block {
+                let rms = (self.sum / self.count as f64).sqrt() as f32;
+                self.sum = 0.0;
+                self.count = 0;
+                self.measured(rms);
+                if !self.listening {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn measured(&mut self, rms: f32) {
+        if rms >= self.threshold {
+            if !self.heard_speech {
+                info!("The far side is speaking");
+            }
+            self.heard_speech = true;
+            self.quiet = 0;
+        } else if self.heard_speech {
+            self.quiet += self.block;
+            if self.quiet >= self.silence {
+                info!("The far side has finished speaking");
+                self.listening = false;
+                self.done = true;
+            }
+        }
+    }
+}
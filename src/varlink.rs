@@ -0,0 +1,411 @@
+//! `--varlink`: the `io.virtualmic` [varlink](https://varlink.org) interface
+//! on a Unix socket, a lighter alternative to D-Bus that `varlinkctl` can
+//! introspect and call.
+//!
+//! Each message is a JSON object ended by a NUL byte. The service answers
+//! `org.varlink.service` (`GetInfo` and `GetInterfaceDescription`) and the
+//! methods of [`INTERFACE`], which are carried out by the main loop like
+//! control socket commands. Calls marked `oneway` get no reply. `Monitor`
+//! needs `more`, and turns the connection into a stream of playback events.
+
+use crate::control::{self, ControlCommand, Events, Request, SeekTarget, VolumeChange};
+use crate::rest::Device;
+use crate::webhook;
+use anyhow::{anyhow, Result};
+use serde_json::{json, Map, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Socket name under `$XDG_RUNTIME_DIR` used by a bare `--varlink`, after
+/// the interface as systemd names its sockets.
+const SOCKET_NAME: &str = "io.virtualmic";
+
+/// The interface's description, as `GetInterfaceDescription` returns it.
+const INTERFACE: &str = "\
+# Playback control of a virtual-mic instance
+interface io.virtualmic
+
+# The virtual device applications record from
+type Device (
+  name: string,
+  source: string,
+  sink: string,
+  rate: int,
+  channels: int,
+  format: string,
+  pid: int
+)
+
+# What is playing; the durations are null for inputs of unknown length
+type Status (
+  state: string,
+  track: int,
+  tracks: int,
+  position_secs: float,
+  duration_secs: ?float,
+  progress_percent: ?float,
+  playlist_percent: ?float,
+  volume: float,
+  muted: bool,
+  path: string
+)
+
+# A playlist entry; title is set for cue sheet tracks
+type Entry (path: string, title: ?string)
+
+method GetDevice() -> (device: Device)
+method GetStatus() -> (status: Status)
+method GetPlaylist() -> (entries: []Entry)
+
+# Resume, or play path in place of the playing file
+method Play(path: ?string) -> ()
+method Pause() -> ()
+method TogglePause() -> ()
+method Next() -> ()
+method Previous() -> ()
+# Jump to a playlist entry, counting from 1
+method PlayTrack(track: int) -> ()
+# Jump to position seconds, or offset seconds from the current position
+method Seek(position: ?float, offset: ?float) -> ()
+method Enqueue(path: string) -> ()
+# Set the volume (0.0 - 2.0), or change it by change
+method SetVolume(level: ?float, change: ?float) -> ()
+method SetMuted(muted: bool) -> ()
+# Any control socket command, and its answer
+method Command(command: string) -> (answer: string)
+method Quit() -> ()
+
+# Playback events as they happen, e.g. event \"volume\" with fields {\"volume\": 0.5}
+method Monitor() -> (event: string, fields: object)
+
+error CommandFailed (message: string)
+error ShuttingDown ()
+";
+
+/// `org.varlink.service`, which every varlink service answers.
+const SERVICE_INTERFACE: &str = "\
+# The Varlink Service Interface is provided by every varlink service. It
+# describes the service and the interfaces it implements.
+interface org.varlink.service
+
+# Get a list of all the interfaces a service provides and information
+# about the implementation.
+method GetInfo() -> (
+  vendor: string,
+  product: string,
+  version: string,
+  url: string,
+  interfaces: []string
+)
+
+# Get the description of an interface that is implemented by this service.
+method GetInterfaceDescription(interface: string) -> (description: string)
+
+# The requested interface was not found.
+error InterfaceNotFound (interface: string)
+
+# The requested method was not found
+error MethodNotFound (method: string)
+
+# The interface defines the requested method, but the service does not
+# implement it.
+error MethodNotImplemented (method: string)
+
+# One of the passed parameters is invalid.
+error InvalidParameter (parameter: string)
+
+# Client is denied access
+error PermissionDenied ()
+
+# Method is expected to be called with 'more' set to true, but wasn't
+error ExpectedMore ()
+";
+
+/// Where a bare `--varlink` puts the socket.
+pub fn default_path() -> Result<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+        .filter(|dir| !dir.is_empty())
+        .ok_or_else(|| anyhow!("XDG_RUNTIME_DIR isn't set; give --varlink a path"))?;
+    Ok(Path::new(&runtime_dir).join(SOCKET_NAME))
+}
+
+/// An error reply's name and parameters.
+struct Error(&'static str, Value);
+
+impl Error {
+    fn invalid(parameter: &str) -> Self {
+        Error(
+            "org.varlink.service.InvalidParameter",
+            json!({ "parameter": parameter }),
+        )
+    }
+
+    fn not_found(method: &str) -> Self {
+        Error(
+            "org.varlink.service.MethodNotFound",
+            json!({ "method": method }),
+        )
+    }
+
+    fn failed(message: String) -> Self {
+        Error("io.virtualmic.CommandFailed", json!({ "message": message }))
+    }
+
+    fn shutting_down() -> Self {
+        Error("io.virtualmic.ShuttingDown", json!({}))
+    }
+}
+
+/// Listens on the varlink socket; removes the socket file when dropped.
+pub struct VarlinkService {
+    path: PathBuf,
+}
+
+impl VarlinkService {
+    /// Bind the socket and start accepting clients on a background thread,
+    /// passing their calls to `tx`.
+    pub fn start(path: &Path, device: Device, events: Events, tx: Sender<Request>) -> Result<Self> {
+        let listener = control::bind(path)?;
+        info!("Varlink service io.virtualmic listening on {:?}", path);
+
+        std::thread::Builder::new()
+            .name("varlink".into())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            let device = device.clone();
+                            let tx = tx.clone();
+                            let events = events.clone();
+                            let _ = std::thread::Builder::new()
+                                .name("varlink-client".into())
+                                .spawn(move || serve_client(stream, &device, tx, events));
+                        }
+                        Err(e) => warn!("Varlink accept failed: {}", e),
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Drop for VarlinkService {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve_client(stream: UnixStream, device: &Device, requests: Sender<Request>, events: Events) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(e) => {
+            warn!("Varlink client error: {}", e);
+            return;
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    let mut message = Vec::new();
+    loop {
+        message.clear();
+        match reader.read_until(0, &mut message) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        if message.last() == Some(&0) {
+            message.pop();
+        }
+        let call = match serde_json::from_slice::<Value>(&message) {
+            Ok(Value::Object(call)) => call,
+            // Not varlink; nothing sensible to answer
+            _ => {
+                debug!("Dropping a varlink client that sent a malformed message");
+                return;
+            }
+        };
+        let method = call.get("method").and_then(Value::as_str).unwrap_or("");
+        debug!("Varlink call: {}", method);
+        let parameters = match call.get("parameters") {
+            Some(Value::Object(parameters)) => parameters.clone(),
+            _ => Map::new(),
+        };
+        let flag = |name: &str| call.get(name).and_then(Value::as_bool).unwrap_or(false);
+
+        let reply = match method.strip_prefix("io.virtualmic.") {
+            Some("Monitor") if flag("more") => return monitor(&mut writer, &events),
+            Some("Monitor") => Err(Error("org.varlink.service.ExpectedMore", json!({}))),
+            Some(name) => handle(name, &parameters, device, &requests),
+            None => service(method, &parameters),
+        };
+        if flag("oneway") {
+            continue;
+        }
+        let reply = match reply {
+            Ok(parameters) => json!({ "parameters": parameters }),
+            Err(Error(error, parameters)) => json!({ "error": error, "parameters": parameters }),
+        };
+        if send(&mut writer, &reply).is_err() {
+            return;
+        }
+    }
+}
+
+/// Answer a call to `org.varlink.service`, or to an interface that isn't here.
+fn service(method: &str, parameters: &Map<String, Value>) -> Result<Value, Error> {
+    match method {
+        "org.varlink.service.GetInfo" => Ok(json!({
+            "vendor": "virtual-mic",
+            "product": "virtual-mic",
+            "version": env!("CARGO_PKG_VERSION"),
+            "url": "",
+            "interfaces": ["org.varlink.service", "io.virtualmic"],
+        })),
+        "org.varlink.service.GetInterfaceDescription" => {
+            let description = match parameters.get("interface").and_then(Value::as_str) {
+                Some("io.virtualmic") => INTERFACE,
+                Some("org.varlink.service") => SERVICE_INTERFACE,
+                Some(interface) => {
+                    return Err(Error(
+                        "org.varlink.service.InterfaceNotFound",
+                        json!({ "interface": interface }),
+                    ))
+                }
+                None => return Err(Error::invalid("interface")),
+            };
+            Ok(json!({ "description": description }))
+        }
+        _ => Err(match method.rsplit_once('.') {
+            Some(("org.varlink.service", _)) => Error::not_found(method),
+            Some((interface, _)) => Error(
+                "org.varlink.service.InterfaceNotFound",
+                json!({ "interface": interface }),
+            ),
+            None => Error::invalid("method"),
+        }),
+    }
+}
+
+/// Carry out the `io.virtualmic` method `name`; the parameters of its reply.
+fn handle(
+    name: &str,
+    parameters: &Map<String, Value>,
+    device: &Device,
+    requests: &Sender<Request>,
+) -> Result<Value, Error> {
+    let param = |name: &str| parameters.get(name).filter(|value| !value.is_null());
+    let number = |name: &str| match param(name) {
+        None => Ok(None),
+        Some(value) => value
+            .as_f64()
+            .filter(|value| value.is_finite())
+            .map(Some)
+            .ok_or_else(|| Error::invalid(name)),
+    };
+    let path = |required: bool| match param("path") {
+        Some(Value::String(path)) if !path.is_empty() => Ok(Some(PathBuf::from(path))),
+        None if !required => Ok(None),
+        _ => Err(Error::invalid("path")),
+    };
+
+    let command = match name {
+        "GetDevice" => return Ok(json!({ "device": device })),
+        "GetStatus" => {
+            let answer = run(ControlCommand::Status, requests)?;
+            return Ok(json!({ "status": webhook::fields(&answer) }));
+        }
+        "GetPlaylist" => {
+            let answer = run(ControlCommand::Playlist, requests)?;
+            let entries: Value = serde_json::from_str(&answer)
+                .map_err(|e| Error::failed(format!("malformed playlist: {}", e)))?;
+            return Ok(json!({ "entries": entries }));
+        }
+        "Play" => match path(false)? {
+            Some(path) => ControlCommand::Load(path),
+            None => ControlCommand::Resume,
+        },
+        "Pause" => ControlCommand::Pause,
+        "TogglePause" => ControlCommand::TogglePause,
+        "Next" => ControlCommand::Next,
+        "Previous" => ControlCommand::Previous,
+        "PlayTrack" => match param("track").and_then(Value::as_u64) {
+            Some(track) if track > 0 => ControlCommand::Track(track as usize),
+            _ => return Err(Error::invalid("track")),
+        },
+        "Seek" => match (number("position")?, number("offset")?) {
+            (Some(position), None) if position >= 0.0 => {
+                ControlCommand::Seek(SeekTarget::To(Duration::from_secs_f64(position)))
+            }
+            (None, Some(offset)) if offset < 0.0 => {
+                ControlCommand::Seek(SeekTarget::Back(Duration::from_secs_f64(-offset)))
+            }
+            (None, Some(offset)) => {
+                ControlCommand::Seek(SeekTarget::Forward(Duration::from_secs_f64(offset)))
+            }
+            (Some(_), _) => return Err(Error::invalid("position")),
+            (None, None) => return Err(Error::invalid("offset")),
+        },
+        "Enqueue" => match path(true)? {
+            Some(path) => ControlCommand::Enqueue(path),
+            None => return Err(Error::invalid("path")),
+        },
+        "SetVolume" => match (number("level")?, number("change")?) {
+            (Some(level), None) => ControlCommand::Volume(VolumeChange::To(level as f32)),
+            (None, Some(change)) => ControlCommand::Volume(VolumeChange::By(change as f32)),
+            (Some(_), _) => return Err(Error::invalid("level")),
+            (None, None) => return Err(Error::invalid("change")),
+        },
+        "SetMuted" => match param("muted").and_then(Value::as_bool) {
+            Some(true) => ControlCommand::Mute,
+            Some(false) => ControlCommand::Unmute,
+            None => return Err(Error::invalid("muted")),
+        },
+        "Command" => {
+            let line = param("command").and_then(Value::as_str).unwrap_or("");
+            let command = ControlCommand::parse(line).map_err(|_| Error::invalid("command"))?;
+            return Ok(json!({ "answer": run(command, requests)? }));
+        }
+        "Quit" => ControlCommand::Quit,
+        _ => return Err(Error::not_found(&format!("io.virtualmic.{}", name))),
+    };
+    run(command, requests)?;
+    Ok(json!({}))
+}
+
+/// Hand a command to the main loop and wait for its answer.
+fn run(command: ControlCommand, requests: &Sender<Request>) -> Result<String, Error> {
+    let (request, response) = Request::new(command);
+    requests.send(request).map_err(|_| Error::shutting_down())?;
+    response
+        .recv()
+        .map_err(|_| Error::shutting_down())?
+        .map_err(Error::failed)
+}
+
+/// Reply to `Monitor` with each event from now on, until the client leaves.
+fn monitor(writer: &mut UnixStream, events: &Events) {
+    for event in events.subscribe() {
+        let Some(mut fields) = webhook::event_object(&event) else {
+            continue;
+        };
+        let name = fields.remove("event").unwrap_or_default();
+        let reply = json!({
+            "parameters": { "event": name, "fields": fields },
+            "continues": true,
+        });
+        if send(writer, &reply).is_err() {
+            return;
+        }
+    }
+}
+
+fn send(writer: &mut UnixStream, reply: &Value) -> std::io::Result<()> {
+    let mut message = reply.to_string().into_bytes();
+    message.push(0);
+    writer.write_all(&message)
+}
@@ -0,0 +1,155 @@
+//! `--webhook`: playback events POSTed as JSON, so orchestration systems can
+//! follow the microphone without parsing its logs.
+//!
+//! The events are those the control socket's `events` command streams, less
+//! the once-a-second `progress`. Each becomes one request, sent from a
+//! background thread in the order the events happened:
+//!
+//! ```json
+//! {"event":"started","mic":"virtual-mic","time":1767225600.125,"path":"/srv/audio/intro.wav"}
+//! ```
+
+use anyhow::Result;
+use serde_json::{Map, Value};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// POST each of `events` to `url` on a background thread.
+pub fn start(url: String, mic: String, events: Receiver<String>) -> Result<()> {
+    info!("Sending events to {}", url);
+    let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+    std::thread::Builder::new()
+        .name("webhook".into())
+        .spawn(move || {
+            // Warn when the endpoint starts failing, not for every event after that
+            let mut failing = false;
+            for event in events {
+                let Some(body) = to_json(&event, &mic) else {
+                    continue;
+                };
+                match agent
+                    .post(&url)
+                    .set("Content-Type", "application/json")
+                    .send_string(&body)
+                {
+                    Ok(_) if failing => {
+                        info!("Webhook {} is accepting events again", url);
+                        failing = false;
+                    }
+                    Ok(_) => {}
+                    Err(e) if !failing => {
+                        warn!(
+                            "Webhook {} failed, dropping events until it recovers: {}",
+                            url, e
+                        );
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// The JSON body for an event line, or `None` for events that aren't sent.
+fn to_json(event: &str, mic: &str) -> Option<String> {
+    if event.starts_with("progress ") {
+        return None;
+    }
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    // `event` first, then where and when, then the event's own fields
+    let mut json = Map::new();
+    let mut fields = event_object(event)?.into_iter();
+    json.extend(fields.next());
+    json.insert("mic".to_string(), mic.into());
+    json.insert(
+        "time".to_string(),
+        ((time * 1000.0).round() / 1000.0).into(),
+    );
+    json.extend(fields);
+    Some(Value::Object(json).to_string())
+}
+
+/// An event line as a JSON object: its name as `event`, then its fields.
+/// `None` if the line doesn't hold what its event should.
+pub fn event_object(event: &str) -> Option<Map<String, Value>> {
+    let (name, rest) = event.split_once(' ').unwrap_or((event, ""));
+    let mut json = Map::new();
+    json.insert("event".to_string(), name.into());
+    let mut insert = |key: &str, value: Value| json.insert(key.to_string(), value);
+    match name {
+        "started" | "finished" | "interject-started" | "interject-finished" => {
+            insert("path", rest.into());
+        }
+        "track" => {
+            // `track <n> <count> <entry>`
+            let mut fields = rest.splitn(3, ' ');
+            let track = fields.next().and_then(|n| n.parse::<u64>().ok())?;
+            let tracks = fields.next().and_then(|n| n.parse::<u64>().ok())?;
+            insert("track", track.into());
+            insert("tracks", tracks.into());
+            insert("path", fields.next().unwrap_or("").into());
+        }
+        "volume" => {
+            let volume: f64 = rest.parse().ok()?;
+            insert("volume", volume.into());
+        }
+        "seeked" => {
+            let position: f64 = rest.parse().ok()?;
+            insert("position_secs", position.into());
+        }
+        "slow-fill" => {
+            let late_ms: f64 = rest.parse().ok()?;
+            insert("late_ms", late_ms.into());
+        }
+        "progress" => json.extend(fields(rest)),
+        _ if !rest.is_empty() => {
+            insert("data", rest.into());
+        }
+        _ => {}
+    }
+    Some(json)
+}
+
+/// `key=value` fields, as `position` and `status` answer, with numbers as
+/// numbers, `unknown` as null and a trailing `path=` taking the rest of the line.
+pub fn fields(line: &str) -> Map<String, Value> {
+    let mut json = Map::new();
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        let (field, after) = match rest.strip_prefix("path=") {
+            Some(path) => (("path", path), ""),
+            None => {
+                let (field, after) = rest.split_once(' ').unwrap_or((rest, ""));
+                let Some(field) = field.split_once('=') else {
+                    rest = after.trim_start();
+                    continue;
+                };
+                (field, after)
+            }
+        };
+        let (key, value) = field;
+        let value = match value {
+            "unknown" => Value::Null,
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ if key == "path" => value.into(),
+            _ => match value.parse::<f64>() {
+                Ok(number) if number.fract() == 0.0 && !value.contains('.') => {
+                    (number as i64).into()
+                }
+                Ok(number) => number.into(),
+                Err(_) => value.into(),
+            },
+        };
+        json.insert(key.to_string(), value);
+        rest = after.trim_start();
+    }
+    json
+}
@@ -0,0 +1,185 @@
+//! WebSocket PCM ingest for `--listen-ws`.
+//!
+//! Clients connect and send binary messages of raw PCM in the `--pcm-*`
+//! format; message boundaries don't need to line up with frames. Only the most
+//! recent client is played: a new connection replaces the previous one.
+//!
+//! With `--auth-token`, a client that doesn't present the token is turned
+//! away before it can replace anyone; see [`crate::auth`].
+
+use crate::auth::{self, Access};
+use crate::feed::{Feed, RatedChunk};
+use crate::http;
+use crate::raw::{RawParams, RawReader};
+use anyhow::Result;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::{Message, WebSocket};
+
+/// Bind the listener and start accepting clients on a background thread.
+pub fn listen(addr: SocketAddr, params: RawParams, access: Access) -> Result<Feed> {
+    #[cfg(feature = "tls")]
+    let tls = access
+        .tls
+        .as_ref()
+        .map(|tls| tls.server_config())
+        .transpose()?;
+    let listener = TcpListener::bind(addr)?;
+    info!(
+        "WebSocket ingest listening on {}://{} ({} Hz, {} channels, {:?})",
+        if access.secure() { "wss" } else { "ws" },
+        listener.local_addr()?,
+        params.rate,
+        params.channels,
+        params.format
+    );
+    access.warn_if_open("WebSocket ingest", addr);
+
+    let (tx, feed) = Feed::channel();
+    let active = Arc::new(AtomicU64::new(0));
+    std::thread::Builder::new()
+        .name("ws".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let (tx, access, active) = (tx.clone(), access.clone(), active.clone());
+                        #[cfg(feature = "tls")]
+                        let tls = tls.clone();
+                        let _ =
+                            std::thread::Builder::new()
+                                .name("ws-client".into())
+                                .spawn(move || {
+                                    let peer = stream.peer_addr().ok();
+                                    #[cfg(feature = "tls")]
+                                    if let Some(config) = tls {
+                                        match rustls::ServerConnection::new(config) {
+                                            Ok(connection) => {
+                                                let stream =
+                                                    rustls::StreamOwned::new(connection, stream);
+                                                serve_client(
+                                                    stream, peer, params, tx, &access, active,
+                                                )
+                                            }
+                                            Err(e) => {
+                                                warn!("TLS setup for {:?} failed: {}", peer, e)
+                                            }
+                                        }
+                                        return;
+                                    }
+                                    serve_client(stream, peer, params, tx, &access, active)
+                                });
+                    }
+                    Err(e) => warn!("WebSocket accept failed: {}", e),
+                }
+            }
+        })?;
+    Ok(feed)
+}
+
+/// Binary message payloads as one continuous byte stream.
+struct MessageReader<S> {
+    socket: WebSocket<S>,
+    pending: Cursor<Vec<u8>>,
+    client: u64,
+    active: Arc<AtomicU64>,
+}
+
+impl<S: Read + Write> Read for MessageReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.pending.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            if self.active.load(Ordering::SeqCst) != self.client {
+                info!("WebSocket client replaced by a newer connection");
+                let _ = self.socket.close(None);
+                return Ok(0);
+            }
+            match self.socket.read() {
+                Ok(Message::Binary(data)) => self.pending = Cursor::new(data),
+                Ok(Message::Close(_)) => return Ok(0),
+                // Pings are answered by tungstenite itself
+                Ok(other) => debug!("Ignoring WebSocket message: {:?}", other),
+                Err(tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed) => {
+                    return Ok(0)
+                }
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+    }
+}
+
+fn serve_client<S: Read + Write + Send + 'static>(
+    stream: S,
+    peer: Option<SocketAddr>,
+    params: RawParams,
+    chunks: SyncSender<RatedChunk>,
+    access: &Access,
+    active: Arc<AtomicU64>,
+) {
+    // The error is the response tungstenite's handshake callback must give
+    #[allow(clippy::result_large_err)]
+    let authorize = |request: &Request, response: Response| {
+        if access.allows(token(request).as_deref()) {
+            return Ok(response);
+        }
+        let mut refusal = ErrorResponse::new(Some("a valid token is required".to_string()));
+        *refusal.status_mut() = tungstenite::http::StatusCode::UNAUTHORIZED;
+        Err(refusal)
+    };
+    let socket = match tungstenite::accept_hdr(stream, authorize) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("WebSocket handshake with {:?} failed: {}", peer, e);
+            return;
+        }
+    };
+    // Only now, so a client turned away replaces no one
+    let client = active.fetch_add(1, Ordering::SeqCst) + 1;
+    info!("WebSocket client connected: {:?}", peer);
+
+    let messages = MessageReader {
+        socket,
+        pending: Cursor::new(Vec::new()),
+        client,
+        active,
+    };
+    let mut reader = RawReader::new(Box::new(messages), params);
+    loop {
+        let mut samples = Vec::new();
+        match reader.read(&mut samples) {
+            Ok(true) => {
+                let chunk = (samples, params.channels, Some(params.rate));
+                // Stop once playback has moved on to another input
+                if chunks.send(chunk).is_err() {
+                    return;
+                }
+            }
+            Ok(false) => break,
+            Err(e) => {
+                warn!("WebSocket client {:?} failed: {}", peer, e);
+                break;
+            }
+        }
+    }
+    info!("WebSocket client disconnected: {:?}", peer);
+}
+
+/// The token a handshake presents, in its `Authorization` header or else its
+/// `token` query parameter.
+fn token(request: &Request) -> Option<String> {
+    match request.headers().get("Authorization") {
+        Some(header) => auth::bearer(header.to_str().ok()?).map(str::to_string),
+        None => request
+            .uri()
+            .query()
+            .and_then(|query| http::query_param(query, "token")),
+    }
+}
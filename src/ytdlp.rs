@@ -0,0 +1,61 @@
+//! Resolving video pages to streamable audio with yt-dlp, for `--url`.
+//!
+//! yt-dlp only picks the format and prints where it lives; the audio itself is
+//! streamed by our own HTTP and HLS readers, so it is seekable and never saved
+//! to disk. Direct URLs expire, so a page is resolved again each time it is
+//! opened (e.g. on every loop).
+
+use anyhow::{anyhow, Result};
+use std::process::Command;
+use tracing::{debug, info};
+
+/// Audio-only formats over plain HTTP(S) first, then any audio, then anything.
+const FORMAT_SELECTOR: &str = "bestaudio[protocol^=http]/bestaudio/best";
+
+/// The chosen format of a page.
+#[derive(Debug)]
+pub struct Resolved {
+    pub url: String,
+    /// Container extension, usable as a probe hint.
+    pub ext: String,
+    /// Whether the URL is an HLS playlist rather than a file.
+    pub hls: bool,
+}
+
+/// Ask yt-dlp (the `program` binary) for the best audio format of `page`.
+pub fn resolve(program: &str, page: &str) -> Result<Resolved> {
+    debug!("Resolving {} with {}", page, program);
+    let output = Command::new(program)
+        .args(["--no-playlist", "--no-warnings", "-f", FORMAT_SELECTOR])
+        .args(["--print", "title", "--print", "ext", "--print", "protocol"])
+        .args(["--print", "urls", "--", page])
+        .output()
+        .map_err(|e| anyhow!("Failed to run {} (is yt-dlp installed?): {}", program, e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} could not resolve {}: {}",
+            program,
+            page,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines().map(str::trim);
+    let (Some(title), Some(ext), Some(protocol), Some(url)) =
+        (lines.next(), lines.next(), lines.next(), lines.next())
+    else {
+        return Err(anyhow!("Unexpected output from {} for {}", program, page));
+    };
+    // Formats that need separate audio and video downloads print one URL per line
+    if lines.next().is_some() {
+        return Err(anyhow!("{} has no single-file audio format", page));
+    }
+    info!("Resolved {}: {} ({}, {})", page, title, ext, protocol);
+
+    Ok(Resolved {
+        url: url.to_string(),
+        ext: ext.to_string(),
+        hls: protocol.starts_with("m3u8"),
+    })
+}